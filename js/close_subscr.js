@@ -14,6 +14,12 @@ const tokenAgent = anchor.workspace.TokenAgent
 const tokenAgentPK = tokenAgent.programId
 //console.log(tokenAgent)
 
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
 async function main() {
     const subscrData = new PublicKey('9SmeJPYfufJyGbJQvvLmkEPnDmF5QAA5JqQ79Ltcnf92')
 
@@ -21,14 +27,31 @@ async function main() {
     console.log('Initial Subscription Data')
     console.log(act)
 
+    const rootKey = await programAddress([tokenAgentPK.toBuffer()])
+    const rootKeyPK = new PublicKey(rootKey.pubkey)
+    const delegateProgram = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+    const delegateRoot = await programAddress([delegateProgram.toBuffer()], delegateProgram)
+    const tokenAccountPK = act.swap ? act.swapAccount : act.tokenAccount
+    const allowance = await programAddress([tokenAccountPK.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
+    const allowancePK = new PublicKey(allowance.pubkey)
+
     console.log('Close Subscription')
     let txsig = await tokenAgent.rpc.closeSubscription(
         {
             accounts: {
                 //subscrData: new PublicKey('Fxg4sFxmiWFPaxS7Xtgnk4J83grzcky9ZpMd6GyutEPd'),
                 subscrData: subscrData,
+                rootKey: rootKeyPK,
                 userKey: provider.wallet.publicKey,
-                feeRecipient: provider.wallet.publicKey,
+                rentPayer: act.rentPayer.equals(PublicKey.default) ? act.userKey : act.rentPayer,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccountPK,
+                delegateProgram: delegateProgram,
+                delegateRoot: new PublicKey(delegateRoot.pubkey),
+                allowance: allowancePK,
+                tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
+                linkPayer: provider.wallet.publicKey,
+                systemProgram: SystemProgram.programId,
             },
         }
     )
@@ -1,6 +1,6 @@
 #!/bin/bash
 
-ROOT=/Users/mfrager/Build/solana/data
+ROOT=$(cd "$(dirname "$0")/../../data" && pwd)
 
 KEY_TOKEN_USDV=$ROOT/export/key-usdv-token-1.json
 
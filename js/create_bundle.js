@@ -0,0 +1,124 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require("luxon")
+const { v4: uuidv4, parse: uuidparse } = require('uuid')
+const { Keypair, PublicKey, SystemProgram, SYSVAR_RENT_PUBKEY } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const netAuth = new PublicKey(netData.netAuthorityProgram)
+    const tokenMint = new PublicKey(netData.tokenMintUSDV)
+    const walletToken = await associatedTokenAddress(provider.wallet.publicKey, tokenMint)
+    const tokenAccount = new PublicKey(walletToken.pubkey)
+
+    const rootKey = await programAddress([tokenAgentPK.toBuffer()])
+    const rootKeyPK = new PublicKey(rootKey.pubkey)
+
+    const delegateProgram = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+    const delegateRoot = await programAddress([delegateProgram.toBuffer()], delegateProgram)
+    const delegateRootPK = new PublicKey(delegateRoot.pubkey)
+    const allowance = await programAddress([tokenAccount.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
+    const allowancePK = new PublicKey(allowance.pubkey)
+
+    const bundleId = uuidv4()
+    const bundleData = anchor.web3.Keypair.generate()
+    const bundleDataBytes = tokenAgent.account.subscrBundle.size
+    const bundleDataRent = await provider.connection.getMinimumBalanceForRentExemption(bundleDataBytes)
+    const merchantAP1 = new PublicKey(netData.merchantApproval1)
+    const merchantAP2 = new PublicKey(netData.merchantApproval2)
+    const managerAP = new PublicKey(netData.managerApproval1)
+    console.log('Bundle Data: ' + bundleData.publicKey.toString())
+
+    const tx = new anchor.web3.Transaction()
+    tx.add(
+        anchor.web3.SystemProgram.createAccount({
+            fromPubkey: provider.wallet.publicKey,
+            newAccountPubkey: bundleData.publicKey,
+            space: bundleDataBytes,
+            lamports: bundleDataRent,
+            programId: tokenAgentPK,
+        })
+    )
+
+    var dt0 = DateTime.now().setZone('utc')
+    dt0 = dt0.minus({ days: dt0.day - 1, hours: dt0.hour, minutes: dt0.minute, seconds: dt0.second }).plus({ months: 1 })
+    console.log('Next Rebill: ' + dt0.toFormat("yyyyLL") + ' - ' + dt0.toISO())
+    tx.add(tokenAgent.instruction.createBundle(
+        true,                                           // inp_link_token
+        new anchor.BN(uuidparse(bundleId)),             // inp_bundle_id
+        2,                                              // inp_period (2 = monthly)
+        new anchor.BN(20000000),                        // inp_period_budget (combined for all merchants)
+        new anchor.BN(Math.floor(dt0.toSeconds())),     // inp_next_rebill
+        0,                                              // inp_rebill_max
+        new anchor.BN(60 * 60 * 24 * 30),               // inp_max_delay
+        [new anchor.BN(9990000), new anchor.BN(4990000)], // inp_amounts (one per merchant approval)
+        {
+            accounts: {
+                bundleData: bundleData.publicKey,
+                netAuth: netAuth,
+                rootKey: rootKeyPK,
+                managerApproval: managerAP,
+                userKey: provider.wallet.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccount,
+                delegateProgram: delegateProgram,
+                delegateRoot: delegateRootPK,
+                allowance: allowancePK,
+                tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
+                systemProgram: SystemProgram.programId,
+            },
+            remainingAccounts: [
+                { pubkey: merchantAP1, isWritable: false, isSigner: false },
+                { pubkey: merchantAP2, isWritable: false, isSigner: false },
+            ],
+        }
+    ))
+    console.log(await provider.sendAndConfirm(tx, [bundleData]))
+
+    var act = await tokenAgent.account.subscrBundle.fetch(bundleData.publicKey)
+    console.log('Bundle Data')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
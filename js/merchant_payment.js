@@ -131,16 +131,20 @@ async function main() {
         0,                                              // inp_swap_inb_nonce
         0,                                              // inp_swap_out_nonce
         0,                                              // inp_swap_dst_nonce
+        new anchor.BN(0),                               // inp_swap_max_input (0 = no limit)
+        false,                                          // inp_swap_exact_output
         {
             accounts: {
                 netAuth: netAuth,
                 rootKey: new PublicKey(rootKey.pubkey),
                 merchantApproval: merchantAP,
                 merchantToken: new PublicKey(merchantTK.pubkey),
+                revenueSplit: new PublicKey((await programAddress([merchantAP.toBuffer(), Buffer.from('split')])).pubkey),
                 userKey: provider.wallet.publicKey,
                 tokenProgram: TOKEN_PROGRAM_ID,
                 tokenAccount: tokenAccount,
                 feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
             },
             /*remainingAccounts: [
                 { pubkey: new PublicKey(userToken1.pubkey), isWritable: true, isSigner: false },
@@ -165,6 +165,8 @@ async function main() {
         tokData1.nonce,                                 // inp_swap_inb_nonce
         tokData2.nonce,                                 // inp_swap_out_nonce
         agentToken.nonce,                               // inp_swap_dst_nonce
+        new anchor.BN(0),                               // inp_swap_max_input (0 = no limit)
+        false,                                          // inp_swap_exact_output
         {
             accounts: {
                 netAuth: netAuth,
@@ -172,11 +174,13 @@ async function main() {
                 merchantKey: merchantPK,
                 merchantApproval: merchantAP,
                 merchantToken: new PublicKey(merchantTK.pubkey),
+                revenueSplit: new PublicKey((await programAddress([merchantAP.toBuffer(), Buffer.from('split')])).pubkey),
                 userKey: provider.wallet.publicKey,
                 tokenProgram: TOKEN_PROGRAM_ID,
                 tokenMint: tokenMint,
                 tokenAccount: tokenAccount,
                 feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
             },
             remainingAccounts: [
                 { pubkey: new PublicKey(userToken1.pubkey), isWritable: true, isSigner: false },
@@ -0,0 +1,34 @@
+const { PublicKey, SystemProgram } = require('@solana/web3.js')
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function main() {
+    const subscrData = new PublicKey('9SmeJPYfufJyGbJQvvLmkEPnDmF5QAA5JqQ79Ltcnf92')
+
+    console.log('Migrate Subscription')
+    let txsig = await tokenAgent.rpc.migrateSubscription(
+        {
+            accounts: {
+                subscrData: subscrData,
+                payer: provider.wallet.publicKey,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.subscrData.fetch(subscrData)
+    console.log('Migrated Subscription Data')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
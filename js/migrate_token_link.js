@@ -0,0 +1,34 @@
+const { PublicKey, SystemProgram } = require('@solana/web3.js')
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+
+async function main() {
+    const tokenLink = new PublicKey('9SmeJPYfufJyGbJQvvLmkEPnDmF5QAA5JqQ79Ltcnf92')
+    const tokenAccount = new PublicKey('25NNEHFiEpRL7JosvruvXYH8Supgd8iuKtfhyEdP62YQ')
+
+    console.log('Migrate Token Link')
+    let txsig = await tokenAgent.rpc.migrateTokenLink(
+        {
+            accounts: {
+                tokenLink: tokenLink,
+                userKey: provider.wallet.publicKey,
+                tokenAccount: tokenAccount,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.tokenLink.fetch(tokenLink)
+    console.log('Migrated Token Link Data')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
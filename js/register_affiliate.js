@@ -0,0 +1,60 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const merchantSK = importSecretKey(netData.merchant1_secret)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const referrerPK = new PublicKey(netData.affiliateWallet1)
+    const affiliate = await programAddress([merchantAP.toBuffer(), referrerPK.toBuffer(), Buffer.from('affiliate')])
+
+    console.log('Register Affiliate: ' + affiliate.pubkey)
+    let txsig = await tokenAgent.rpc.registerAffiliate(
+        true,                                           // inp_active
+        1000,                                           // inp_max_bps (10%)
+        12,                                             // inp_max_rebills (0 = unlimited)
+        {
+            accounts: {
+                affiliate: new PublicKey(affiliate.pubkey),
+                merchantApproval: merchantAP,
+                merchantKey: merchantSK.publicKey,
+                referrerKey: referrerPK,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [merchantSK],
+        }
+    )
+    console.log(txsig)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
@@ -0,0 +1,62 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const merchantSK = importSecretKey(netData.merchant1_secret)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const tokenMint = new PublicKey(netData.tokenMintUSDC)
+    const feesAccount = new PublicKey(netData.feesAccount1)
+    const merchantMint = await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer()])
+
+    console.log('Register Merchant Mint: ' + merchantMint.pubkey)
+    let txsig = await tokenAgent.rpc.registerMerchantMint(
+        true,                                           // inp_active
+        merchantSK.publicKey,                           // inp_dest_account
+        100,                                            // inp_fees_bps (must be >= merchant approval fees)
+        {
+            accounts: {
+                merchantMint: new PublicKey(merchantMint.pubkey),
+                merchantApproval: merchantAP,
+                merchantKey: merchantSK.publicKey,
+                tokenMint: tokenMint,
+                approvalFeesAccount: feesAccount,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [merchantSK],
+        }
+    )
+    console.log(txsig)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
@@ -0,0 +1,153 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require("luxon")
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const netAuth = new PublicKey(netData.netAuthorityProgram)
+    const tokenMint = new PublicKey(netData.tokenMintUSDV)
+    const userPK = provider.wallet.publicKey
+    const walletToken = await associatedTokenAddress(userPK, tokenMint)
+    const tokenAccount = new PublicKey(walletToken.pubkey)
+
+    const rootKey = await programAddress([tokenAgentPK.toBuffer()])
+    const rootKeyPK = new PublicKey(rootKey.pubkey)
+    const delegateProgram = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+    const delegateRoot = await programAddress([delegateProgram.toBuffer()], delegateProgram)
+    const delegateRootPK = new PublicKey(delegateRoot.pubkey)
+    const allowance = await programAddress([tokenAccount.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
+    const allowancePK = new PublicKey(allowance.pubkey)
+    const tokenLinkPK = new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey)
+
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const merchantPK = new PublicKey(netData.merchant1_dest)
+    const merchantTK = await associatedTokenAddress(merchantPK, tokenMint)
+    const feesPK = new PublicKey(netData.fees1)
+    const feesTK = await associatedTokenAddress(feesPK, tokenMint)
+
+    // Ephemeral key held by the app, the user only signs to open and close the session
+    const sessionKey = Keypair.generate()
+    const sessionData = await programAddress([userPK.toBuffer(), sessionKey.publicKey.toBuffer(), Buffer.from('session')])
+    const sessionDataPK = new PublicKey(sessionData.pubkey)
+    console.log('Session Key: ' + sessionKey.publicKey.toString())
+    console.log('Session Data: ' + sessionDataPK.toString())
+
+    var l1 = tokenAgent.addEventListener('SessionEvent', (evt, slot) => {
+        console.log('SessionEvent - Slot: ' + slot)
+        console.log(evt.eventHash.toString())
+        console.log(evt)
+    })
+    var l2 = tokenAgent.addEventListener('PaymentEvent', (evt, slot) => {
+        console.log('PaymentEvent - Slot: ' + slot)
+        console.log(evt.eventHash.toString())
+        console.log(evt)
+    })
+
+    console.log('Open Session')
+    const expires = DateTime.now().setZone('utc').plus({ hours: 2 })
+    console.log(await tokenAgent.rpc.openSession(
+        new anchor.BN(5 * (10**4)),                     // inp_budget
+        new anchor.BN(Math.floor(expires.toSeconds())), // inp_expires
+        [merchantAP],                                   // inp_merchants (merchant approvals)
+        {
+            accounts: {
+                sessionData: sessionDataPK,
+                rootKey: rootKeyPK,
+                userKey: userPK,
+                sessionKey: sessionKey.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccount,
+                delegateProgram: delegateProgram,
+                delegateRoot: delegateRootPK,
+                allowance: allowancePK,
+                tokenLink: tokenLinkPK,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    ))
+
+    console.log('Session Payment')
+    const tx = await tokenAgent.transaction.sessionPayment(
+        merchantTK.nonce,                               // inp_dest_nonce (merchant associated token dest account nonce)
+        rootKey.nonce,                                  // inp_root_nonce
+        new anchor.BN(1234),                            // inp_payment_id
+        new anchor.BN(1 * (10**4)),                     // inp_amount
+        {
+            accounts: {
+                sessionData: sessionDataPK,
+                netAuth: netAuth,
+                rootKey: rootKeyPK,
+                merchantApproval: merchantAP,
+                merchantToken: new PublicKey(merchantTK.pubkey),
+                revenueSplit: new PublicKey((await programAddress([merchantAP.toBuffer(), Buffer.from('split')])).pubkey),
+                userKey: userPK,
+                sessionKey: sessionKey.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccount,
+                feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
+                delegateProgram: delegateProgram,
+                delegateRoot: delegateRootPK,
+                allowance: allowancePK,
+                spendingPolicy: new PublicKey((await programAddress([userPK.toBuffer(), tokenMint.toBuffer(), Buffer.from('spending')])).pubkey),
+                tokenLink: tokenLinkPK,
+            },
+        }
+    )
+    console.log(await provider.sendAndConfirm(tx, [sessionKey]))
+
+    console.log('Close Session')
+    const tokenLink = await tokenAgent.account.tokenLink.fetch(tokenLinkPK)
+    console.log(await tokenAgent.rpc.closeSession(
+        {
+            accounts: {
+                sessionData: sessionDataPK,
+                rootKey: rootKeyPK,
+                userKey: userPK,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccount,
+                delegateProgram: delegateProgram,
+                delegateRoot: delegateRootPK,
+                allowance: allowancePK,
+                tokenLink: tokenLinkPK,
+                linkPayer: tokenLink.rentPayer,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    ))
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
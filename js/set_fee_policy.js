@@ -0,0 +1,74 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const feesSK = importSecretKey(netData.fees1_secret)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const tokenMint = new PublicKey(netData.tokenMintUSDC)
+    const feesAccount = new PublicKey(netData.feesAccount1)
+    const feePolicy = await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])
+
+    console.log('Set Fee Policy: ' + feePolicy.pubkey)
+    let txsig = await tokenAgent.rpc.setFeePolicy(
+        true,                                           // inp_active
+        {                                               // inp_subscr_fees
+            feesBps: 100,                               // 1%
+            flatFee: new anchor.BN(0),
+            minFee: new anchor.BN(10000),               // $0.01
+            maxFee: new anchor.BN(5000000),             // $5.00
+            feePayer: 0,                                // 0 = Merchant, 1 = User
+        },
+        {                                               // inp_payment_fees
+            feesBps: 50,                                // 0.5%
+            flatFee: new anchor.BN(100000),             // $0.10
+            minFee: new anchor.BN(0),
+            maxFee: new anchor.BN(0),                   // no limit
+            feePayer: 1,                                // 0 = Merchant, 1 = User
+        },
+        {
+            accounts: {
+                feePolicy: new PublicKey(feePolicy.pubkey),
+                merchantApproval: merchantAP,
+                feeAuthority: feesSK.publicKey,
+                tokenMint: tokenMint,
+                approvalFeesAccount: feesAccount,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [feesSK],
+        }
+    )
+    console.log(txsig)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
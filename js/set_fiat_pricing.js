@@ -0,0 +1,61 @@
+const { Keypair, PublicKey } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const tokenMint = new PublicKey(netData.tokenMintUSDV)
+    const merchantSK = importSecretKey(netData.merchant1_secret)
+    const subscrData = new PublicKey('9SmeJPYfufJyGbJQvvLmkEPnDmF5QAA5JqQ79Ltcnf92')
+    const priceOracle = new PublicKey('Gnt27xtC473ZT2Mw5u8wZ68Z3gULkSTb5DuxJy7eJotD') // USDC/USD
+
+    console.log('Set Fiat Pricing')
+    let txsig = await tokenAgent.rpc.setFiatPricing(
+        true,                                           // inp_active
+        1,                                              // inp_oracle_type (0 = MockV1, 1 = PythV2)
+        new anchor.BN(2500),                            // inp_fiat_max_amount ($25.00)
+        2,                                              // inp_fiat_decimals (USD cents)
+        new anchor.BN(60),                              // inp_oracle_max_age (seconds)
+        100,                                            // inp_oracle_max_conf_bps (1%)
+        {
+            accounts: {
+                subscrData: subscrData,
+                userKey: provider.wallet.publicKey,
+                merchantKey: merchantSK.publicKey,
+                priceOracle: priceOracle,
+                tokenMint: tokenMint,
+            },
+            signers: [merchantSK],
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.subscrData.fetch(subscrData)
+    console.log('Subscription Data')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
@@ -0,0 +1,50 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const tokenMint = new PublicKey(netData.tokenMintUSDV)
+    const spendingPolicy = await PublicKey.findProgramAddress(
+        [provider.wallet.publicKey.toBuffer(), tokenMint.toBuffer(), Buffer.from('spending')],
+        tokenAgentPK
+    )
+
+    console.log('Set Spending Policy: ' + spendingPolicy[0].toString())
+    let txsig = await tokenAgent.rpc.setSpendingPolicy(
+        true,                                           // inp_active
+        new anchor.BN(50000000),                        // inp_daily_limit ($50.00, 0 = no limit)
+        new anchor.BN(250000000),                       // inp_monthly_limit ($250.00, 0 = no limit)
+        {
+            accounts: {
+                spendingPolicy: spendingPolicy[0],
+                userKey: provider.wallet.publicKey,
+                tokenMint: tokenMint,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy[0])
+    console.log('Spending Policy')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
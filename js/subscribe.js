@@ -60,6 +60,8 @@ async function main() {
     const delegateRootPK = new PublicKey(delegateRoot.pubkey)
     const allowance = await programAddress([tokenAccount.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
     const allowancePK = new PublicKey(allowance.pubkey)
+    // The process instruction records rebills against the user's spending policy for the token mint
+    const spendingPolicyPK = new PublicKey((await programAddress([provider.wallet.publicKey.toBuffer(), tokenMint.toBuffer(), Buffer.from('spending')])).pubkey)
 
     const subscrId = uuidv4()
     const subscrData = anchor.web3.Keypair.generate()
@@ -151,6 +153,15 @@ async function main() {
         0,                                              // inp_swap_inb_nonce
         0,                                              // inp_swap_out_nonce
         0,                                              // inp_swap_dst_nonce
+        new anchor.BN(0),                               // inp_swap_max_input (0 = no limit)
+        0,                                              // inp_swap_slippage_bps (0 = no limit)
+        false,                                          // inp_swap_exact_output
+        PublicKey.default,                              // inp_referrer (default = no referral)
+        0,                                              // inp_referral_bps
+        0,                                              // inp_referral_rebills (0 = unlimited)
+        false,                                          // inp_coupon (coupon account passed after the merchant mint and affiliate)
+        new anchor.BN(0),                               // inp_rent_reclaim_delay (user pays the rent)
+        new anchor.BN(0),                               // inp_sponsor_amount (paid by the user to the rent payer, sponsor token account passed after the coupon)
         {
             accounts: {
                 subscrData: subscrData.publicKey,
@@ -160,12 +171,15 @@ async function main() {
                 merchantToken: new PublicKey(merchantTK.pubkey),
                 managerApproval: managerAP,
                 userKey: provider.wallet.publicKey,
+                rentPayer: provider.wallet.publicKey,
                 tokenProgram: TOKEN_PROGRAM_ID,
                 tokenAccount: tokenAccount,
                 feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                 delegateProgram: delegateProgram,
                 delegateRoot: delegateRootPK,
                 allowance: allowancePK,
+                tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
                 systemProgram: SystemProgram.programId,
             },
         }
@@ -214,14 +228,18 @@ async function main() {
                     rootKey: new PublicKey(rootKey.pubkey),
                     merchantApproval: merchantAP,
                     merchantToken: new PublicKey(merchantTK.pubkey),
+                    revenueSplit: new PublicKey((await programAddress([merchantAP.toBuffer(), Buffer.from('split')])).pubkey),
                     managerKey: managerPK,
                     managerApproval: managerAP,
                     tokenProgram: TOKEN_PROGRAM_ID,
                     tokenAccount: tokenAccount,
                     feesAccount: new PublicKey(feesTK.pubkey),
+                    feePolicy: new PublicKey((await programAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                     delegateProgram: delegateProgram,
                     delegateRoot: delegateRootPK,
                     allowance: allowancePK,
+                    spendingPolicy: spendingPolicyPK,
+                    tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
                 }
             }
         )
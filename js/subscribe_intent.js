@@ -0,0 +1,126 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require("luxon")
+const { Ed25519Program, PublicKey, SystemProgram, SYSVAR_INSTRUCTIONS_PUBKEY } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const netAuth = new PublicKey(netData.netAuthorityProgram)
+    const tokenMint = new PublicKey(netData.tokenMintUSDV)
+
+    // The user signs the intent off-chain, the relayer (provider wallet) submits it and pays the rent
+    // The token account must already be linked to the token delegate (subscribe.js with link_token)
+    const user = provider.wallet.payer
+    const walletToken = await associatedTokenAddress(user.publicKey, tokenMint)
+    const tokenAccount = new PublicKey(walletToken.pubkey)
+
+    const rootKey = await programAddress([tokenAgentPK.toBuffer()])
+    const rootKeyPK = new PublicKey(rootKey.pubkey)
+    const delegateProgram = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+    const allowance = await programAddress([tokenAccount.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
+    const allowancePK = new PublicKey(allowance.pubkey)
+    const tokenLink = await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])
+
+    const subscrData = anchor.web3.Keypair.generate()
+    const subscrDataBytes = tokenAgent.account.subscrData.size
+    const subscrDataRent = await provider.connection.getMinimumBalanceForRentExemption(subscrDataBytes)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const managerAP = new PublicKey(netData.managerApproval1)
+
+    var dt0 = DateTime.now().setZone('utc')
+    dt0 = dt0.minus({ days: dt0.day - 1, hours: dt0.hour, minutes: dt0.minute, seconds: dt0.second }).plus({ months: 1 })
+    const intent = {
+        programId: tokenAgentPK,
+        userKey: user.publicKey,
+        merchantApproval: merchantAP,
+        managerApproval: managerAP,
+        tokenAccount: tokenAccount,
+        subscrId: new anchor.BN(777),
+        period: 2,                                      // monthly
+        periodBudget: new anchor.BN(10000),
+        rebillMax: 12,
+        useTotal: false,
+        totalBudget: new anchor.BN(0),
+        nextRebill: new anchor.BN(Math.floor(dt0.toSeconds())),
+        notValidBefore: new anchor.BN(0),
+        notValidAfter: new anchor.BN(0),
+        nonce: new anchor.BN(Date.now()),
+        expires: new anchor.BN(Math.floor(DateTime.now().plus({ hours: 1 }).toSeconds())),
+    }
+    const message = Buffer.concat([
+        Buffer.from('atellix-subscription-intent:'),
+        tokenAgent.coder.types.encode('SubscrIntent', intent),
+    ])
+    const nonceBytes = intent.nonce.toArrayLike(Buffer, 'le', 8)
+    const intentReceipt = await programAddress([user.publicKey.toBuffer(), nonceBytes, Buffer.from('intent')])
+
+    console.log('Subscription Data: ' + subscrData.publicKey.toString())
+    console.log('Intent Receipt: ' + intentReceipt.pubkey)
+
+    const tx = new anchor.web3.Transaction()
+    tx.add(
+        SystemProgram.createAccount({
+            fromPubkey: provider.wallet.publicKey,
+            newAccountPubkey: subscrData.publicKey,
+            space: subscrDataBytes,
+            lamports: subscrDataRent,
+            programId: tokenAgentPK,
+        })
+    )
+    // The signature check must immediately precede the subscribe_intent instruction
+    tx.add(Ed25519Program.createInstructionWithPrivateKey({ privateKey: user.secretKey, message: message }))
+    tx.add(tokenAgent.instruction.subscribeIntent(
+        intent,
+        {
+            accounts: {
+                subscrData: subscrData.publicKey,
+                netAuth: netAuth,
+                merchantApproval: merchantAP,
+                managerApproval: managerAP,
+                userKey: user.publicKey,
+                tokenAccount: tokenAccount,
+                allowance: allowancePK,
+                tokenLink: new PublicKey(tokenLink.pubkey),
+                intentReceipt: new PublicKey(intentReceipt.pubkey),
+                rentPayer: provider.wallet.publicKey,
+                instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+                systemProgram: SystemProgram.programId,
+            },
+        }
+    ))
+    console.log(await provider.sendAndConfirm(tx, [subscrData]))
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
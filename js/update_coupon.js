@@ -0,0 +1,68 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+const { v4: uuidv4, parse: uuidparse } = require('uuid')
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const merchantSK = importSecretKey(netData.merchant1_secret)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const couponId = new anchor.BN(uuidparse('8e2c6d2a-0a53-4d4e-9b7c-2f4c1d9e5a11'))
+    const coupon = await programAddress([merchantAP.toBuffer(), couponId.toArrayLike(Buffer, 'le', 16), Buffer.from('coupon')])
+
+    console.log('Update Coupon: ' + coupon.pubkey)
+    let txsig = await tokenAgent.rpc.updateCoupon(
+        couponId,                                       // inp_coupon_id
+        true,                                           // inp_active
+        0,                                              // inp_discount_type (0 = Percent, 1 = Fixed)
+        new anchor.BN(2500),                            // inp_discount_value (25%)
+        3,                                              // inp_duration (rebills, 0 = unlimited)
+        1000,                                           // inp_max_redemptions (0 = unlimited)
+        new anchor.BN(0),                               // inp_expires (0 = no expiry)
+        {
+            accounts: {
+                coupon: new PublicKey(coupon.pubkey),
+                merchantApproval: merchantAP,
+                merchantKey: merchantSK.publicKey,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [merchantSK],
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.coupon.fetch(new PublicKey(coupon.pubkey))
+    console.log('Coupon')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
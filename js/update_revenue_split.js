@@ -0,0 +1,63 @@
+const { Keypair, PublicKey, SystemProgram } = require('@solana/web3.js')
+const fs = require('fs').promises
+const base32 = require("base32.js")
+
+const anchor = require('@project-serum/anchor')
+const provider = anchor.AnchorProvider.env()
+//const provider = anchor.Provider.local()
+anchor.setProvider(provider)
+const tokenAgent = anchor.workspace.TokenAgent
+const tokenAgentPK = tokenAgent.programId
+//console.log(tokenAgent)
+
+async function programAddress(inputs, programPK = tokenAgentPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, programPK)
+    const res = { 'pubkey': await addr[0].toString(), 'nonce': addr[1] }
+    return res
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+async function main() {
+    var ndjs
+    try {
+        ndjs = await fs.readFile('../../data/net.json')
+    } catch (error) {
+        console.error('File Error: ', error)
+    }
+    const netData = JSON.parse(ndjs.toString())
+    const merchantSK = importSecretKey(netData.merchant1_secret)
+    const merchantAP = new PublicKey(netData.merchantApproval1)
+    const marketplacePK = new PublicKey(netData.marketplaceWallet1)
+    const affiliatePK = new PublicKey(netData.affiliateWallet1)
+    const revenueSplit = await programAddress([merchantAP.toBuffer(), Buffer.from('split')])
+
+    console.log('Update Revenue Split: ' + revenueSplit.pubkey)
+    let txsig = await tokenAgent.rpc.updateRevenueSplit(
+        [marketplacePK, affiliatePK],                   // inp_recipients (merchant receives the remainder)
+        [1000, 500],                                    // inp_shares_bps (10% marketplace, 5% affiliate)
+        {
+            accounts: {
+                revenueSplit: new PublicKey(revenueSplit.pubkey),
+                merchantApproval: merchantAP,
+                merchantKey: merchantSK.publicKey,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [merchantSK],
+        }
+    )
+    console.log(txsig)
+
+    var act = await tokenAgent.account.revenueSplit.fetch(new PublicKey(revenueSplit.pubkey))
+    console.log('Revenue Split')
+    console.log(act)
+}
+
+console.log('Begin')
+main().then(() => console.log('Success')).catch(error => {
+    console.log(error)
+})
@@ -67,6 +67,8 @@ async function main() {
     const delegateRootPK = new PublicKey(delegateRoot.pubkey)
     const allowance = await programAddress([tokenAccountPK.toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
     const allowancePK = new PublicKey(allowance.pubkey)
+    // The process instruction records rebills against the user's spending policy for the token mint
+    const spendingPolicyPK = new PublicKey((await programAddress([provider.wallet.publicKey.toBuffer(), tokenMint.toBuffer(), Buffer.from('spending')])).pubkey)
 
     var act = await tokenAgent.account.subscrData.fetch(subscrData)
     console.log('Initial Subscription Data')
@@ -96,8 +98,11 @@ async function main() {
         new anchor.BN(100000),                          // inp_amount
         pmtId,                                          // inp_payment_id
         nextRebill,                                     // inp_next_rebill
+        act.rebillMax,                                  // inp_rebill_max
         period,                                         // inp_period (2 = monthly)
         new anchor.BN(periodBudget),                    // inp_period_budget
+        act.useTotal,                                   // inp_use_total
+        act.totalBudget.add(act.budgetSpent),           // inp_total_budget (lifetime cap)
         maxDelay,                                       // inp_max_delay
         new anchor.BN(0),                               // inp_not_valid_before
         new anchor.BN(0),                               // inp_not_valid_after
@@ -108,6 +113,9 @@ async function main() {
         2,                                              // inp_swap_inb_nonce
         3,                                              // inp_swap_out_nonce
         4,                                              // inp_swap_dst_nonce
+        act.swapMaxInput,                               // inp_swap_max_input
+        act.swapSlippageBps,                            // inp_swap_slippage_bps
+        act.swapExactOutput,                            // inp_swap_exact_output
         {
             accounts: {
                 subscrData: subscrData,
@@ -125,10 +133,16 @@ async function main() {
                 tokenMint: act.tokenMint,
                 tokenAccount: new PublicKey(tokenAccount.pubkey),
                 feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                 delegateProgram: delegateProgram,
                 delegateRoot: delegateRootPK,
                 allowance: allowancePK,
+                tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
+                prevTokenLink: new PublicKey((await programAddress([(act.allowance.equals(PublicKey.default) ? allowancePK : act.allowance).toBuffer(), Buffer.from('token-link')])).pubkey),
+                linkPayer: provider.wallet.publicKey,
                 systemProgram: SystemProgram.programId,
+                prevAllowance: act.allowance.equals(PublicKey.default) ? allowancePK : act.allowance,
+                prevTokenAccount: act.swap ? act.swapAccount : act.tokenAccount,
             }
         }
     ))
@@ -168,15 +182,19 @@ async function main() {
                     merchantKey: act.merchantKey,
                     merchantApproval: act.merchantApproval,
                     merchantToken: new PublicKey(merchantToken.pubkey),
+                    revenueSplit: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), Buffer.from('split')])).pubkey),
                     managerKey: act.managerKey,
                     managerApproval: act.managerApproval,
                     tokenProgram: TOKEN_PROGRAM_ID,
                     tokenMint: act.tokenMint,
                     tokenAccount: new PublicKey(tokenAccount.pubkey),
                     feesAccount: new PublicKey(feesTK.pubkey),
+                    feePolicy: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                     delegateProgram: delegateProgram,
                     delegateRoot: delegateRootPK,
                     allowance: allowancePK,
+                    spendingPolicy: spendingPolicyPK,
+                    tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
                 }
             }
         )
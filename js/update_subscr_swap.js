@@ -102,6 +102,8 @@ async function main() {
     const delegateRootPK = new PublicKey(delegateRoot.pubkey)
     const allowance = await programAddress([new PublicKey(userToken1.pubkey).toBuffer(), rootKeyPK.toBuffer()], delegateProgram)
     const allowancePK = new PublicKey(allowance.pubkey)
+    // The process instruction records rebills against the user's spending policy for the swap input mint
+    const spendingPolicyPK = new PublicKey((await programAddress([provider.wallet.publicKey.toBuffer(), tokenMint1.toBuffer(), Buffer.from('spending')])).pubkey)
 
     var act = await tokenAgent.account.subscrData.fetch(subscrData)
     console.log('Initial Subscription Data')
@@ -126,8 +128,11 @@ async function main() {
         new anchor.BN(100000),                          // inp_amount
         new anchor.BN(uuidparse(uuidv4())),             // inp_payment_id
         act.nextRebill,                                 // inp_next_rebill
+        act.rebillMax,                                  // inp_rebill_max
         act.period,                                     // inp_period (2 = monthly)
         act.periodBudget,                               // inp_period_budget
+        act.useTotal,                                   // inp_use_total
+        act.totalBudget.add(act.budgetSpent),           // inp_total_budget (lifetime cap)
         act.maxDelay,                                   // inp_max_delay
         act.notValidBefore,                             // inp_not_valid_before
         act.notValidAfter,                              // inp_not_valid_after
@@ -138,6 +143,9 @@ async function main() {
         tokData1.nonce,                                 // inp_swap_inb_nonce
         tokData2.nonce,                                 // inp_swap_out_nonce
         agentToken.nonce,                               // inp_swap_dst_nonce
+        act.swapMaxInput,                               // inp_swap_max_input
+        0,                                              // inp_swap_slippage_bps (0 = no limit)
+        act.swapExactOutput,                            // inp_swap_exact_output
         {
             accounts: {
                 subscrData: subscrData,
@@ -151,10 +159,16 @@ async function main() {
                 tokenMint: act.tokenMint,
                 tokenAccount: tokenAccount,
                 feesAccount: new PublicKey(feesTK.pubkey),
+                feePolicy: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                 delegateProgram: delegateProgram,
                 delegateRoot: delegateRootPK,
                 allowance: allowancePK,
+                tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
+                prevTokenLink: new PublicKey((await programAddress([(act.allowance.equals(PublicKey.default) ? allowancePK : act.allowance).toBuffer(), Buffer.from('token-link')])).pubkey),
+                linkPayer: provider.wallet.publicKey,
                 systemProgram: SystemProgram.programId,
+                prevAllowance: act.allowance.equals(PublicKey.default) ? allowancePK : act.allowance,
+                prevTokenAccount: act.swap ? act.swapAccount : act.tokenAccount,
             },
             remainingAccounts: [
                 { pubkey: new PublicKey(userToken1.pubkey), isWritable: true, isSigner: false },
@@ -199,14 +213,18 @@ async function main() {
                     rootKey: new PublicKey(rootKey.pubkey),
                     merchantApproval: act.merchantApproval,
                     merchantToken: new PublicKey(merchantTK.pubkey),
+                    revenueSplit: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), Buffer.from('split')])).pubkey),
                     managerKey: managerSK.publicKey,
                     managerApproval: act.managerApproval,
                     tokenProgram: TOKEN_PROGRAM_ID,
                     tokenAccount: act.tokenAccount,
                     feesAccount: new PublicKey(feesTK.pubkey),
+                    feePolicy: new PublicKey((await programAddress([act.merchantApproval.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])).pubkey),
                     delegateProgram: delegateProgram,
                     delegateRoot: delegateRootPK,
                     allowance: allowancePK,
+                    spendingPolicy: spendingPolicyPK,
+                    tokenLink: new PublicKey((await programAddress([allowancePK.toBuffer(), Buffer.from('token-link')])).pubkey),
                 },
                 remainingAccounts: [
                     { pubkey: new PublicKey(userToken1.pubkey), isWritable: true, isSigner: false },
@@ -0,0 +1,200 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{ self, TokenAccount, Transfer };
+
+declare_id!("GvVUH74LKgu147PARTYMqMDLjWa24xecaNiaT8N9Rbop");
+
+// Local test program implementing the token agent's constant product pool interface (SwapMode::ConstantProductV1)
+// and mock price accounts (OracleType::MockV1, token agent built with the "mock-oracle" feature)
+
+fn verify_matching_accounts(left: &Pubkey, right: &Pubkey, error_msg: &str) -> anchor_lang::Result<()> {
+    if *left != *right {
+        msg!(error_msg);
+        msg!("Expected: {}", left.to_string());
+        msg!("Received: {}", right.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok(())
+}
+
+// Input required to receive amount_out from the pool (rounded up), the fee is charged on the input amount
+fn quote_input(reserve_in: u64, reserve_out: u64, amount_out: u64, fee_bps: u32) -> anchor_lang::Result<u64> {
+    if amount_out >= reserve_out {
+        msg!("Insufficient pool liquidity");
+        return Err(ErrorCode::InsufficientLiquidity.into());
+    }
+    let q1: u128 = (reserve_in as u128).checked_mul(amount_out as u128).ok_or(error!(ErrorCode::Overflow))?;
+    let q2: u128 = (reserve_out as u128) - (amount_out as u128);
+    let q3: u128 = q1.checked_add(q2 - 1).ok_or(error!(ErrorCode::Overflow))? / q2;
+    let fee_div: u128 = 10000u128.checked_sub(fee_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
+    let q4: u128 = q3.checked_mul(10000).ok_or(error!(ErrorCode::Overflow))?;
+    let q5: u128 = q4.checked_add(fee_div - 1).ok_or(error!(ErrorCode::Overflow))? / fee_div;
+    if q5 > u64::MAX as u128 {
+        return Err(ErrorCode::Overflow.into());
+    }
+    Ok(q5 as u64)
+}
+
+#[program]
+pub mod mock_market {
+    use super::*;
+
+    pub fn initialize_pool(ctx: Context<InitializePool>,
+        inp_authority_nonce: u8,
+        inp_fee_bps: u32,
+    ) -> anchor_lang::Result<()> {
+        if inp_fee_bps >= 10000 {
+            msg!("Invalid pool fee: {}", inp_fee_bps.to_string());
+            return Err(ErrorCode::InvalidFee.into());
+        }
+        let pool_key = ctx.accounts.pool_state.key();
+        let pool_authority = Pubkey::create_program_address(&[pool_key.as_ref(), &[inp_authority_nonce]], ctx.program_id)
+            .map_err(|_| ErrorCode::InvalidAccount)?;
+        verify_matching_accounts(&pool_authority, &ctx.accounts.vault_a.owner, "Vault A not owned by pool authority")?;
+        verify_matching_accounts(&pool_authority, &ctx.accounts.vault_b.owner, "Vault B not owned by pool authority")?;
+        let pool = &mut ctx.accounts.pool_state;
+        pool.fee_bps = inp_fee_bps;
+        pool.authority_nonce = inp_authority_nonce;
+        pool.vault_a = ctx.accounts.vault_a.key();
+        pool.vault_b = ctx.accounts.vault_b.key();
+        Ok(())
+    }
+
+    pub fn swap_exact_output(ctx: Context<SwapExactOutput>,
+        amount_out: u64,
+        max_amount_in: u64,
+    ) -> anchor_lang::Result<()> {
+        let pool = &ctx.accounts.pool_state;
+        let vault_in = ctx.accounts.pool_vault_in.key();
+        let vault_out = ctx.accounts.pool_vault_out.key();
+        if !((vault_in == pool.vault_a && vault_out == pool.vault_b) || (vault_in == pool.vault_b && vault_out == pool.vault_a)) {
+            msg!("Vaults do not match pool");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let amount_in: u64 = quote_input(ctx.accounts.pool_vault_in.amount, ctx.accounts.pool_vault_out.amount, amount_out, pool.fee_bps)?;
+        if amount_in > max_amount_in {
+            msg!("Swap input: {} exceeds maximum: {}", amount_in.to_string(), max_amount_in.to_string());
+            return Err(ErrorCode::SlippageExceeded.into());
+        }
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_token_in.to_account_info(),
+            to: ctx.accounts.pool_vault_in.to_account_info(),
+            authority: ctx.accounts.user_authority.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token::transfer(cpi_ctx, amount_in)?;
+
+        let pool_key = ctx.accounts.pool_state.key();
+        let seeds = &[pool_key.as_ref(), &[ctx.accounts.pool_state.authority_nonce]];
+        let signer = &[&seeds[..]];
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.pool_vault_out.to_account_info(),
+            to: ctx.accounts.user_token_out.to_account_info(),
+            authority: ctx.accounts.pool_authority.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount_out)?;
+
+        msg!("Swap input: {} output: {}", amount_in.to_string(), amount_out.to_string());
+        Ok(())
+    }
+
+    pub fn initialize_price(ctx: Context<InitializePrice>) -> anchor_lang::Result<()> {
+        let price = &mut ctx.accounts.price_account;
+        price.admin = ctx.accounts.admin.key();
+        Ok(())
+    }
+
+    pub fn set_price(ctx: Context<SetPrice>,
+        inp_price: i64,
+        inp_conf: u64,
+        inp_expo: i32,
+        inp_timestamp: i64,
+    ) -> anchor_lang::Result<()> {
+        let price = &mut ctx.accounts.price_account;
+        price.price = inp_price;
+        price.conf = inp_conf;
+        price.expo = inp_expo;
+        price.timestamp = inp_timestamp;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    #[account(init, payer = admin, space = 77)]
+    pub pool_state: Account<'info, PoolState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub vault_a: Account<'info, TokenAccount>,
+    pub vault_b: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SwapExactOutput<'info> {
+    #[account(mut)]
+    pub pool_state: Account<'info, PoolState>,
+    #[account(seeds = [pool_state.key().as_ref()], bump = pool_state.authority_nonce)]
+    pub pool_authority: UncheckedAccount<'info>,
+    pub user_authority: Signer<'info>,
+    #[account(mut)]
+    pub user_token_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_out: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub pool_vault_in: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub pool_vault_out: Account<'info, TokenAccount>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializePrice<'info> {
+    #[account(init, payer = admin, space = 68)]
+    pub price_account: Account<'info, MockPrice>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetPrice<'info> {
+    #[account(mut, has_one = admin)]
+    pub price_account: Account<'info, MockPrice>,
+    pub admin: Signer<'info>,
+}
+
+#[account]
+pub struct PoolState {
+    pub fee_bps: u32,                   // Trading fee charged on the input amount (the token agent reads this at offset 8)
+    pub authority_nonce: u8,            // Pool authority PDA nonce (seeds: pool state)
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+}
+// 8 + 4 + 1 + (32 * 2) = 77
+
+#[account]
+pub struct MockPrice {
+    pub price: i64,                     // The token agent reads these fields at fixed offsets (MOCK_PRICE_LEN)
+    pub conf: u64,
+    pub expo: i32,
+    pub timestamp: i64,
+    pub admin: Pubkey,
+}
+// 8 + 8 + 8 + 4 + 8 + 32 = 68
+
+#[error_code]
+pub enum ErrorCode {
+    #[msg("Invalid account")]
+    InvalidAccount,
+    #[msg("Invalid pool fee")]
+    InvalidFee,
+    #[msg("Insufficient pool liquidity")]
+    InsufficientLiquidity,
+    #[msg("Swap input exceeds maximum")]
+    SlippageExceeded,
+    #[msg("Overflow")]
+    Overflow,
+}
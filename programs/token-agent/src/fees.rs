@@ -0,0 +1,139 @@
+use num_enum::TryFromPrimitive;
+use anchor_lang::prelude::*;
+use solana_program::account_info::AccountInfo;
+
+use crate::{ ErrorCode, FeePolicy, Settlement, load_struct };
+
+#[repr(u8)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive)]
+pub enum FeePayer {
+    Merchant,           // Fees are deducted from the payment amount
+    User,               // Fees are charged to the user on top of the payment amount
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct FeeSchedule {
+    pub fees_bps: u32,                  // Proportional fee in basis points of the payment amount
+    pub flat_fee: u64,                  // Flat fee per transaction
+    pub min_fee: u64,                   // Minimum fee per transaction
+    pub max_fee: u64,                   // Maximum fee per transaction (0 = no limit)
+    pub fee_payer: u8,                  // FeePayer
+}
+// 4 + (8 * 3) + 1 = 29
+
+pub struct FeeQuote {
+    pub total: u64,                     // Amount charged to the user
+    pub net_amount: u64,                // Amount paid to the merchant
+    pub fee_amount: u64,                // Amount paid to the fees account
+}
+
+impl FeeSchedule {
+    pub fn from_bps(fees_bps: u32) -> Self {
+        Self {
+            fees_bps,
+            fee_payer: FeePayer::Merchant as u8,
+            ..Default::default()
+        }
+    }
+
+    pub fn verify(&self) -> anchor_lang::Result<()> {
+        if self.fees_bps > 10000 {
+            msg!("Invalid fees: {}", self.fees_bps.to_string());
+            return Err(ErrorCode::InvalidFees.into());
+        }
+        if FeePayer::try_from_primitive(self.fee_payer).is_err() {
+            msg!("Invalid fee payer");
+            return Err(ErrorCode::InvalidFees.into());
+        }
+        if self.max_fee > 0 && self.min_fee > self.max_fee {
+            msg!("Minimum fee: {} exceeds maximum: {}", self.min_fee.to_string(), self.max_fee.to_string());
+            return Err(ErrorCode::InvalidFees.into());
+        }
+        Ok(())
+    }
+}
+
+pub fn proportional_fee(amount: u64, fees_bps: u32) -> anchor_lang::Result<u64> {
+    let f1: u128 = (amount as u128) << 64;
+    let f2: u128 = f1.checked_mul(fees_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
+    let f3: u128 = f2.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
+    let fees: u64 = (f3 >> 64) as u64;
+    Ok(fees)
+}
+
+// Split a payment amount into the user charge, merchant net amount and fees
+pub fn quote_fees(schedule: &FeeSchedule, amount: u64) -> anchor_lang::Result<FeeQuote> {
+    if amount == 0 {
+        return Ok(FeeQuote { total: 0, net_amount: 0, fee_amount: 0 });
+    }
+    let mut fee: u64 = proportional_fee(amount, schedule.fees_bps)?;
+    fee = fee.checked_add(schedule.flat_fee).ok_or(error!(ErrorCode::Overflow))?;
+    fee = fee.max(schedule.min_fee);
+    if schedule.max_fee > 0 {
+        fee = fee.min(schedule.max_fee);
+    }
+    let fee_payer = FeePayer::try_from_primitive(schedule.fee_payer).map_err(|_| ErrorCode::InvalidFees)?;
+    match fee_payer {
+        FeePayer::Merchant => {
+            let fee_amount: u64 = fee.min(amount);
+            Ok(FeeQuote {
+                total: amount,
+                net_amount: amount.checked_sub(fee_amount).ok_or(error!(ErrorCode::Overflow))?,
+                fee_amount,
+            })
+        },
+        FeePayer::User => {
+            Ok(FeeQuote {
+                total: amount.checked_add(fee).ok_or(error!(ErrorCode::Overflow))?,
+                net_amount: amount,
+                fee_amount: fee,
+            })
+        },
+    }
+}
+
+// Network fees for a payment in the settlement mint: user-paid fees are charged on top of the amount
+pub fn quote_settlement_fees(
+    fee_policy: &AccountInfo,
+    merchant_approval: &Pubkey,
+    settlement: &Settlement,
+    subscription: bool,
+    amount: u64,
+) -> anchor_lang::Result<FeeQuote> {
+    let fee_schedule = load_fee_schedule(fee_policy, merchant_approval, &settlement.token_mint, settlement.fees_bps, subscription)?;
+    quote_fees(&fee_schedule, amount)
+}
+
+pub fn fee_policy_address(merchant_approval: &Pubkey, token_mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[merchant_approval.as_ref(), token_mint.as_ref(), b"fee-policy"], &crate::ID)
+}
+
+// Fee schedule for a settlement mint: the network fee policy if one is set, otherwise the settlement fees_bps
+pub fn load_fee_schedule(
+    fee_policy: &AccountInfo,
+    merchant_approval: &Pubkey,
+    token_mint: &Pubkey,
+    fees_bps: u32,
+    subscription: bool,
+) -> anchor_lang::Result<FeeSchedule> {
+    // Always derive the policy address so an unrelated empty account cannot bypass the policy
+    let (policy_key, _) = fee_policy_address(merchant_approval, token_mint);
+    if policy_key != *fee_policy.key {
+        msg!("Invalid fee policy account");
+        msg!("Expected: {}", policy_key.to_string());
+        msg!("Received: {}", fee_policy.key.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    if fee_policy.data_is_empty() {
+        return Ok(FeeSchedule::from_bps(fees_bps));
+    }
+    if *fee_policy.owner != crate::ID {
+        msg!("Invalid fee policy owner");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    let policy = load_struct::<FeePolicy>(fee_policy)?;
+    if !policy.active {
+        return Ok(FeeSchedule::from_bps(fees_bps));
+    }
+    Ok(if subscription { policy.subscr_fees } else { policy.payment_fees })
+}
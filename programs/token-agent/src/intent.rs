@@ -0,0 +1,86 @@
+use anchor_lang::prelude::*;
+use solana_program::{ account_info::AccountInfo, ed25519_program, sysvar::instructions::{ load_current_index_checked, load_instruction_at_checked } };
+
+use crate::ErrorCode;
+
+pub const INTENT_DOMAIN: &[u8] = b"atellix-subscription-intent:";
+
+// Subscription terms signed off-chain by the user
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct SubscrIntent {
+    pub program_id: Pubkey,             // Token agent program (intents are not valid for other deployments)
+    pub user_key: Pubkey,               // The user authorizing the subscription
+    pub merchant_approval: Pubkey,      // The merchant approval record from the network authority
+    pub manager_approval: Pubkey,       // The rebill manager approval from the network authority
+    pub token_account: Pubkey,          // The token account to pay for the subscription
+    pub subscr_id: u128,                // External subscription UUID
+    pub period: u8,                     // Subscription rebill period
+    pub period_budget: u64,             // Per-rebill budget
+    pub rebill_max: u32,                // Maximum number of times to rebill (0 = unlimited)
+    pub use_total: bool,                // Enable a total budget for the entire subscription
+    pub total_budget: u64,              // Total budget for the entire subscription
+    pub next_rebill: i64,               // The start of the first rebilling period
+    pub not_valid_before: i64,          // UTC timestamp before which no subscription processing can occur
+    pub not_valid_after: i64,           // UTC timestamp after which no subscription processing can occur
+    pub nonce: u64,                     // User chosen nonce (each nonce can be used once)
+    pub expires: i64,                   // UTC timestamp after which the intent cannot be submitted
+}
+
+// The message the user signs: the domain prefix followed by the serialized intent
+pub fn intent_message(intent: &SubscrIntent) -> anchor_lang::Result<Vec<u8>> {
+    let mut message: Vec<u8> = INTENT_DOMAIN.to_vec();
+    message.extend_from_slice(&intent.try_to_vec()?);
+    Ok(message)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> anchor_lang::Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or(error!(ErrorCode::InvalidIntent))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+// Verify the instruction before this one is an ed25519 signature check of the message by the signer
+pub fn verify_intent_signature(
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> anchor_lang::Result<()> {
+    let current_index: u16 = load_current_index_checked(instructions)?;
+    if current_index == 0 {
+        msg!("Missing ed25519 signature instruction");
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+    let ix = load_instruction_at_checked((current_index - 1) as usize, instructions)?;
+    if ix.program_id != ed25519_program::ID {
+        msg!("Invalid signature instruction program: {}", ix.program_id.to_string());
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+
+    // Ed25519 instruction layout: signature count, padding, then offsets for each signature
+    let data: &[u8] = &ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        msg!("Expected a single ed25519 signature");
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+    let signature_ix: u16 = read_u16(data, 4)?;
+    let pubkey_offset: usize = read_u16(data, 6)? as usize;
+    let pubkey_ix: u16 = read_u16(data, 8)?;
+    let message_offset: usize = read_u16(data, 10)? as usize;
+    let message_size: usize = read_u16(data, 12)? as usize;
+    let message_ix: u16 = read_u16(data, 14)?;
+    // Signature, public key and message must be in the ed25519 instruction itself
+    if signature_ix != u16::MAX || pubkey_ix != u16::MAX || message_ix != u16::MAX {
+        msg!("Signature data must be in the ed25519 instruction");
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+    let ix_pubkey = data.get(pubkey_offset..pubkey_offset + 32).ok_or(error!(ErrorCode::InvalidIntent))?;
+    if ix_pubkey != signer.as_ref() {
+        msg!("Intent not signed by user: {}", signer.to_string());
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+    let ix_message = data.get(message_offset..message_offset + message_size).ok_or(error!(ErrorCode::InvalidIntent))?;
+    if ix_message != message {
+        msg!("Signed message does not match intent");
+        return Err(ErrorCode::InvalidIntent.into());
+    }
+    Ok(())
+}
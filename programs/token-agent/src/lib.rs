@@ -4,19 +4,34 @@ use arrayref::array_ref;
 use num_enum::TryFromPrimitive;
 use chrono::{ NaiveDateTime, Datelike };
 use anchor_lang::prelude::*;
-use anchor_spl::token::{ self, Token, TokenAccount, Transfer };
+use anchor_lang::Discriminator;
+use anchor_spl::token::{ self, Token, TokenAccount, Mint, Transfer };
 use anchor_spl::associated_token::{ AssociatedToken };
-use solana_program::{ system_program, account_info::AccountInfo, clock::Clock };
+use solana_program::{ system_program, account_info::AccountInfo, clock::Clock, instruction::{ Instruction, AccountMeta }, program::invoke_signed };
 
 use net_authority::{ self, cpi::accounts::RecordTransaction, MerchantApproval, ManagerApproval };
 use swap_contract::{ cpi::accounts::Swap };
-use token_delegate::{ self, cpi::accounts::{ DelegateApprove, DelegateTransfer } };
+use token_delegate::{ self, cpi::accounts::{ DelegateApprove, DelegateTransfer, DelegateClose } };
 
 declare_id!("AGNTcdPiqzTvTczVNihCFQAoaT6Q6xqrtRMWkExyHCdm");
 
+pub mod fees;
+use fees::{ FeeSchedule, quote_settlement_fees };
+pub mod intent;
+use intent::{ SubscrIntent, intent_message, verify_intent_signature };
+
+// 1.1.0: Accounts added to existing instructions are appended after the 1.0 accounts (process: revenue_split, fee_policy, spending_policy, token_link, merchant_payment: revenue_split, fee_policy)
 pub const VERSION_MAJOR: u32 = 1;
-pub const VERSION_MINOR: u32 = 0;
-pub const VERSION_PATCH: u32 = 2;
+pub const VERSION_MINOR: u32 = 1;
+pub const VERSION_PATCH: u32 = 0;
+
+pub const SUBSCR_DATA_VERSION: u8 = 1;
+pub const MAX_FALLBACK_ACCOUNTS: usize = 3;
+pub const MAX_SPLIT_RECIPIENTS: usize = 4;
+pub const MAX_BUNDLE_ITEMS: usize = 4;
+pub const MAX_SESSION_MERCHANTS: usize = 4;
+pub const MAX_ALLOWANCE_REBILLS: u64 = 12; // Rebills approved ahead without a rebill limit (the user links the token account again to renew)
+pub const MANAGER_CLOSE_DELAY: i64 = 7776000; // 90 days inactive before the manager can close a subscription
 
 #[repr(u8)]
 #[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive)]
@@ -43,10 +58,561 @@ pub fn get_period_string(ts: i64, period: SubscriptionPeriod) -> FnResult<String
     }
 }
 
+#[repr(u8)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive)]
+pub enum DiscountType {
+    Percent,            // Discount value in basis points
+    Fixed,              // Discount value in pricing units
+}
+
 #[repr(u8)]
 #[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive)]
 pub enum SwapMode {
     AtxSwapContractV1,
+    ConstantProductV1,
+}
+
+// Constant product pool interface: swap_exact_output(amount_out: u64, max_amount_in: u64)
+// Accounts: pool_state, pool_authority, user_authority (signer), user_token_in, user_token_out, pool_vault_in, pool_vault_out, token_program
+pub const CP_SWAP_EXACT_OUTPUT: [u8; 8] = [45, 99, 76, 242, 223, 112, 168, 162]; // sha256("global:swap_exact_output")[..8]
+// Pool state: account discriminator, then fee_bps: u32 @ 8 (trading fee charged on the input amount)
+pub const CP_POOL_FEE_OFFSET: usize = 8;
+
+// Constant product pool program (the mock market program implements the pool interface for local testing)
+#[cfg(feature = "mock-market")]
+pub mod constant_product {
+    anchor_lang::declare_id!("GvVUH74LKgu147PARTYMqMDLjWa24xecaNiaT8N9Rbop");
+}
+
+// The program each swap backend calls (None = backend not available in this build)
+fn swap_program_id(swap_mode: SwapMode) -> Option<Pubkey> {
+    match swap_mode {
+        SwapMode::AtxSwapContractV1 => Some(swap_contract::ID),
+        #[cfg(feature = "mock-market")]
+        SwapMode::ConstantProductV1 => Some(constant_product::ID),
+        #[cfg(not(feature = "mock-market"))]
+        SwapMode::ConstantProductV1 => None,
+    }
+}
+
+#[repr(u8)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive)]
+pub enum OracleType {
+    MockV1,
+    PythV2,
+}
+
+// Mock price account layout (local testing only, owned by the mock market program):
+//   discriminator @ 0, price: i64 @ 8, conf: u64 @ 16, expo: i32 @ 24, timestamp: i64 @ 28
+pub const MOCK_PRICE_LEN: usize = 36;
+
+#[cfg(feature = "mock-oracle")]
+pub mod mock_oracle {
+    anchor_lang::declare_id!("GvVUH74LKgu147PARTYMqMDLjWa24xecaNiaT8N9Rbop");
+}
+
+// Pyth oracle program (price account owner)
+pub mod pyth_oracle {
+    #[cfg(feature = "devnet")]
+    anchor_lang::declare_id!("gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s");
+    #[cfg(not(feature = "devnet"))]
+    anchor_lang::declare_id!("FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH");
+}
+
+// Pyth v2 price account layout
+pub const PYTH_MAGIC: u32 = 0xa1b2c3d4;
+pub const PYTH_ACCOUNT_TYPE_PRICE: u32 = 3;
+pub const PYTH_STATUS_TRADING: u32 = 1;
+pub const PYTH_PRICE_LEN: usize = 240;
+
+pub struct OraclePrice {
+    pub price: i64,         // Reference currency per payment token
+    pub conf: u64,
+    pub expo: i32,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Copy)]
+pub struct SwapParams {
+    pub direction: bool,
+    pub data_nonce: u8,
+    pub inb_nonce: u8,
+    pub out_nonce: u8,
+}
+
+pub enum SwapBackend<'info> {
+    AtxSwapContractV1 {
+        swap_data: AccountInfo<'info>,
+        inb_token_dst: AccountInfo<'info>,
+        out_token_src: AccountInfo<'info>,
+        fees_token: AccountInfo<'info>,
+        oracle: Option<AccountInfo<'info>>,
+    },
+    ConstantProductV1 {
+        pool_state: AccountInfo<'info>,
+        pool_authority: AccountInfo<'info>,
+        pool_vault_in: AccountInfo<'info>,
+        pool_vault_out: AccountInfo<'info>,
+    },
+}
+
+pub struct SwapAccounts<'info> {
+    pub swap_token: AccountInfo<'info>,             // User swap token
+    pub swap_program: AccountInfo<'info>,
+    pub swap_input: Option<AccountInfo<'info>>,     // Token agent swap input token (rebills only)
+    pub backend: SwapBackend<'info>,
+}
+
+fn swap_token_account<'a, 'info>(accounts: &'a [AccountInfo<'info>]) -> anchor_lang::Result<&'a AccountInfo<'info>> {
+    let acc_swap_token = accounts.get(0);
+    if acc_swap_token.is_none() {
+        msg!("Missing swap token account");
+        return Err(ErrorCode::InvalidSwapAccounts.into());
+    }
+    Ok(acc_swap_token.unwrap())
+}
+
+fn load_swap_token(acc: &AccountInfo, name: &str) -> anchor_lang::Result<TokenAccount> {
+    if *acc.owner != Token::id() {
+        msg!("Invalid swap accounts: {} is not a token account", name);
+        return Err(ErrorCode::InvalidSwapAccounts.into());
+    }
+    Ok(load_struct::<TokenAccount>(acc)?)
+}
+
+fn verify_swap_mint(expected: &Pubkey, acc: &AccountInfo, name: &str) -> anchor_lang::Result<()> {
+    let token = load_swap_token(acc, name)?;
+    if *expected != token.mint {
+        msg!("Invalid swap accounts: {} mint does not match", name);
+        msg!("Expected: {}", expected.to_string());
+        msg!("Received: {}", token.mint.to_string());
+        return Err(ErrorCode::InvalidSwapMint.into());
+    }
+    Ok(())
+}
+
+// Swap accounts (remaining accounts):
+//   swap_token, swap_program, backend accounts...
+//   Rebills pass the token agent swap input token after the first backend account
+// Swap backend accounts:
+//   AtxSwapContractV1: swap_data, inb_token_dst, out_token_src, fees_token, [oracle]
+//   ConstantProductV1: pool_state, pool_authority, pool_vault_in, pool_vault_out
+fn parse_swap_accounts<'info>(
+    swap_mode: SwapMode,
+    accounts: &[AccountInfo<'info>],
+    swap_input_owner: Option<&Pubkey>,
+    output_mint: &Pubkey,
+) -> anchor_lang::Result<SwapAccounts<'info>> {
+    let base_len: usize = if swap_input_owner.is_some() { 3 } else { 2 };
+    let (backend_min, backend_max): (usize, usize) = match swap_mode {
+        SwapMode::AtxSwapContractV1 => (4, 5),
+        SwapMode::ConstantProductV1 => (4, 4),
+    };
+    if accounts.len() < base_len + backend_min || accounts.len() > base_len + backend_max {
+        msg!("Invalid swap accounts: expected {} to {} for swap mode {:?}, received {}",
+            (base_len + backend_min).to_string(), (base_len + backend_max).to_string(), swap_mode, accounts.len().to_string());
+        return Err(ErrorCode::InvalidSwapAccounts.into());
+    }
+    let swap_token = accounts[0].clone();
+    let swap_program = accounts[1].clone();
+    let mut backend: Vec<AccountInfo<'info>> = vec![accounts[2].clone()];
+    let mut swap_input: Option<AccountInfo<'info>> = None;
+    if swap_input_owner.is_some() {
+        swap_input = Some(accounts[3].clone());
+        backend.extend_from_slice(&accounts[4..]);
+    } else {
+        backend.extend_from_slice(&accounts[3..]);
+    }
+
+    // Verify swap program
+    let program_id: Pubkey = match swap_program_id(swap_mode) {
+        Some(program_id) => program_id,
+        None => {
+            msg!("Swap mode not available: {:?}", swap_mode);
+            return Err(ErrorCode::InvalidSwapMode.into());
+        },
+    };
+    if !swap_program.executable || *swap_program.key != program_id {
+        msg!("Invalid swap program: {} expected: {}", swap_program.key.to_string(), program_id.to_string());
+        return Err(ErrorCode::InvalidSwapProgram.into());
+    }
+    if *backend[0].owner != *swap_program.key {
+        msg!("Invalid swap accounts: swap data not owned by swap program");
+        return Err(ErrorCode::InvalidSwapAccounts.into());
+    }
+
+    // Verify token accounts
+    let input_mint: Pubkey = load_swap_token(&swap_token, "swap token")?.mint;
+    if swap_input.is_some() {
+        let acc_swap_input = swap_input.as_ref().unwrap();
+        verify_swap_mint(&input_mint, acc_swap_input, "swap input")?;
+        if load_struct::<TokenAccount>(acc_swap_input)?.owner != *swap_input_owner.unwrap() {
+            msg!("Invalid swap accounts: swap input not owned by token agent");
+            return Err(ErrorCode::InvalidSwapAccounts.into());
+        }
+    }
+    let backend = match swap_mode {
+        SwapMode::AtxSwapContractV1 => {
+            verify_swap_mint(&input_mint, &backend[1], "swap inbound")?;
+            verify_swap_mint(output_mint, &backend[2], "swap outbound")?;
+            load_swap_token(&backend[3], "swap fees")?;
+            SwapBackend::AtxSwapContractV1 {
+                swap_data: backend[0].clone(),
+                inb_token_dst: backend[1].clone(),
+                out_token_src: backend[2].clone(),
+                fees_token: backend[3].clone(),
+                oracle: backend.get(4).cloned(),
+            }
+        },
+        SwapMode::ConstantProductV1 => {
+            verify_swap_mint(&input_mint, &backend[2], "pool vault in")?;
+            verify_swap_mint(output_mint, &backend[3], "pool vault out")?;
+            SwapBackend::ConstantProductV1 {
+                pool_state: backend[0].clone(),
+                pool_authority: backend[1].clone(),
+                pool_vault_in: backend[2].clone(),
+                pool_vault_out: backend[3].clone(),
+            }
+        },
+    };
+    Ok(SwapAccounts { swap_token, swap_program, swap_input, backend })
+}
+
+fn swap_tokens<'info>(
+    swap: &SwapAccounts<'info>,
+    swap_user: &AccountInfo<'info>,
+    token_src: &AccountInfo<'info>,
+    token_dst: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    params: SwapParams,
+    amount_out: u64,
+    max_amount_in: u64,
+    exact_output: bool,
+) -> anchor_lang::Result<()> {
+    let src_amount: u64 = load_struct::<TokenAccount>(token_src)?.amount;
+    let dst_amount: u64 = load_struct::<TokenAccount>(token_dst)?.amount;
+    match &swap.backend {
+        SwapBackend::AtxSwapContractV1 { swap_data, inb_token_dst, out_token_src, fees_token, oracle } => {
+            let sw_accounts = Swap {
+                swap_user: swap_user.clone(),
+                swap_data: swap_data.clone(),
+                inb_token_src: token_src.clone(),
+                inb_token_dst: inb_token_dst.clone(),
+                out_token_src: out_token_src.clone(),
+                out_token_dst: token_dst.clone(),
+                fees_token: fees_token.clone(),
+                token_program: token_program.clone(),
+            };
+            let mut sw_ctx = CpiContext::new_with_signer(swap.swap_program.clone(), sw_accounts, signer_seeds);
+            if oracle.is_some() { // Oracle Data Account (if needed)
+                sw_ctx = sw_ctx.with_remaining_accounts(vec![oracle.as_ref().unwrap().clone()]);
+            }
+            swap_contract::cpi::swap(sw_ctx, params.data_nonce, params.inb_nonce, params.out_nonce, 0, params.direction, false, true, amount_out)?;
+        },
+        SwapBackend::ConstantProductV1 { pool_state, pool_authority, pool_vault_in, pool_vault_out } => {
+            let mut data: Vec<u8> = CP_SWAP_EXACT_OUTPUT.to_vec();
+            data.extend_from_slice(&amount_out.to_le_bytes());
+            data.extend_from_slice(&max_amount_in.min(src_amount).to_le_bytes());
+            let ix = Instruction {
+                program_id: *swap.swap_program.key,
+                accounts: vec![
+                    AccountMeta::new(*pool_state.key, false),
+                    AccountMeta::new_readonly(*pool_authority.key, false),
+                    AccountMeta::new_readonly(*swap_user.key, true),
+                    AccountMeta::new(*token_src.key, false),
+                    AccountMeta::new(*token_dst.key, false),
+                    AccountMeta::new(*pool_vault_in.key, false),
+                    AccountMeta::new(*pool_vault_out.key, false),
+                    AccountMeta::new_readonly(*token_program.key, false),
+                ],
+                data,
+            };
+            invoke_signed(&ix, &[
+                pool_state.clone(),
+                pool_authority.clone(),
+                swap_user.clone(),
+                token_src.clone(),
+                token_dst.clone(),
+                pool_vault_in.clone(),
+                pool_vault_out.clone(),
+                token_program.clone(),
+                swap.swap_program.clone(),
+            ], signer_seeds)?;
+        },
+    }
+
+    // Verify swap bounds by measuring balances
+    let src_post_amount: u64 = load_struct::<TokenAccount>(token_src)?.amount;
+    let dst_post_amount: u64 = load_struct::<TokenAccount>(token_dst)?.amount;
+    let amount_in: u64 = src_amount.checked_sub(src_post_amount).ok_or(error!(ErrorCode::Overflow))?;
+    let amount_recv: u64 = dst_post_amount.checked_sub(dst_amount).ok_or(error!(ErrorCode::Overflow))?;
+    if amount_in > max_amount_in {
+        msg!("Swap input: {} exceeds maximum: {}", amount_in.to_string(), max_amount_in.to_string());
+        return Err(ErrorCode::SlippageExceeded.into());
+    }
+    if amount_recv < amount_out {
+        msg!("Swap output: {} below minimum: {}", amount_recv.to_string(), amount_out.to_string());
+        return Err(ErrorCode::SlippageExceeded.into());
+    }
+    if exact_output && amount_recv != amount_out {
+        msg!("Swap output: {} expected: {}", amount_recv.to_string(), amount_out.to_string());
+        return Err(ErrorCode::InvalidPayout.into());
+    }
+    Ok(())
+}
+
+// Maximum swap input for a rebill: the subscription's absolute limit and the slippage limit over the swap backend quote (u64::MAX = no limit)
+fn swap_input_limit(max_input: u64, slippage_bps: u32, quote: u64) -> anchor_lang::Result<u64> {
+    let mut limit: u64 = if max_input > 0 { max_input } else { u64::MAX };
+    if slippage_bps > 0 {
+        if quote == 0 {
+            msg!("Swap quote required with maximum slippage");
+            return Err(ErrorCode::SlippageExceeded.into());
+        }
+        let l1: u128 = (quote as u128).checked_mul((slippage_bps as u128).checked_add(10000).ok_or(error!(ErrorCode::Overflow))?).ok_or(error!(ErrorCode::Overflow))?;
+        let l2: u128 = l1.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
+        limit = limit.min(if l2 > u64::MAX as u128 { u64::MAX } else { l2 as u64 });
+    }
+    Ok(limit)
+}
+
+// Swap input required to receive exactly amount_out, computed from the swap backend state
+fn swap_quote_input(backend: &SwapBackend, amount_out: u64) -> anchor_lang::Result<u64> {
+    match backend {
+        SwapBackend::AtxSwapContractV1 { .. } => {
+            msg!("Swap quotes not supported by swap mode");
+            Err(ErrorCode::InvalidSwapMode.into())
+        },
+        SwapBackend::ConstantProductV1 { pool_state, pool_vault_in, pool_vault_out, .. } => {
+            let fee_bps: u128 = {
+                let data = pool_state.try_borrow_data()?;
+                if data.len() < CP_POOL_FEE_OFFSET + 4 {
+                    msg!("Invalid swap pool state");
+                    return Err(ErrorCode::InvalidSwapAccounts.into());
+                }
+                u32::from_le_bytes(*array_ref![data, CP_POOL_FEE_OFFSET, 4]) as u128
+            };
+            if fee_bps >= 10000 {
+                msg!("Invalid swap pool fee: {}", fee_bps.to_string());
+                return Err(ErrorCode::InvalidSwapAccounts.into());
+            }
+            let reserve_in: u128 = load_struct::<TokenAccount>(pool_vault_in)?.amount as u128;
+            let reserve_out: u128 = load_struct::<TokenAccount>(pool_vault_out)?.amount as u128;
+            let out: u128 = amount_out as u128;
+            if out >= reserve_out {
+                msg!("Insufficient swap pool liquidity");
+                return Err(ErrorCode::InsufficientFunds.into());
+            }
+            // Round up at each step so the pool always receives enough input
+            let q1: u128 = reserve_in.checked_mul(out).ok_or(error!(ErrorCode::Overflow))?;
+            let q2: u128 = reserve_out.checked_sub(out).ok_or(error!(ErrorCode::Overflow))?;
+            let q3: u128 = q1.checked_add(q2 - 1).ok_or(error!(ErrorCode::Overflow))?.checked_div(q2).ok_or(error!(ErrorCode::Overflow))?;
+            let fee_div: u128 = 10000 - fee_bps;
+            let q4: u128 = q3.checked_mul(10000).ok_or(error!(ErrorCode::Overflow))?;
+            let q5: u128 = q4.checked_add(fee_div - 1).ok_or(error!(ErrorCode::Overflow))?.checked_div(fee_div).ok_or(error!(ErrorCode::Overflow))?;
+            if q5 > u64::MAX as u128 {
+                return Err(ErrorCode::Overflow.into());
+            }
+            Ok(q5 as u64)
+        },
+    }
+}
+
+fn read_oracle_price(oracle_type: OracleType, oracle: &AccountInfo) -> anchor_lang::Result<OraclePrice> {
+    let data = oracle.try_borrow_data()?;
+    match oracle_type {
+        #[cfg(not(feature = "mock-oracle"))]
+        OracleType::MockV1 => {
+            msg!("Mock oracle not enabled");
+            Err(ErrorCode::InvalidOracle.into())
+        },
+        #[cfg(feature = "mock-oracle")]
+        OracleType::MockV1 => {
+            if *oracle.owner != mock_oracle::ID || data.len() < MOCK_PRICE_LEN {
+                msg!("Invalid mock price account");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            Ok(OraclePrice {
+                price: i64::from_le_bytes(*array_ref![data, 8, 8]),
+                conf: u64::from_le_bytes(*array_ref![data, 16, 8]),
+                expo: i32::from_le_bytes(*array_ref![data, 24, 4]),
+                timestamp: i64::from_le_bytes(*array_ref![data, 28, 8]),
+            })
+        },
+        OracleType::PythV2 => {
+            if *oracle.owner != pyth_oracle::ID {
+                msg!("Price account not owned by the Pyth program");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            if data.len() < PYTH_PRICE_LEN ||
+                u32::from_le_bytes(*array_ref![data, 0, 4]) != PYTH_MAGIC ||
+                u32::from_le_bytes(*array_ref![data, 8, 4]) != PYTH_ACCOUNT_TYPE_PRICE {
+                msg!("Invalid Pyth price account");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            if u32::from_le_bytes(*array_ref![data, 224, 4]) != PYTH_STATUS_TRADING {
+                msg!("Pyth price not trading");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            Ok(OraclePrice {
+                price: i64::from_le_bytes(*array_ref![data, 208, 8]),
+                conf: u64::from_le_bytes(*array_ref![data, 216, 8]),
+                expo: i32::from_le_bytes(*array_ref![data, 20, 4]),
+                timestamp: i64::from_le_bytes(*array_ref![data, 96, 8]),
+            })
+        },
+    }
+}
+
+// Verify the oracle price is positive, recent and within the confidence limit
+fn verify_oracle_price(oracle_price: &OraclePrice, max_age: i64, max_conf_bps: u32, ts: i64) -> anchor_lang::Result<()> {
+    if oracle_price.price <= 0 {
+        msg!("Invalid oracle price: {}", oracle_price.price.to_string());
+        return Err(ErrorCode::InvalidOracle.into());
+    }
+    if ts.checked_sub(oracle_price.timestamp).ok_or(error!(ErrorCode::Overflow))? > max_age {
+        msg!("Stale oracle price: {}", oracle_price.timestamp.to_string());
+        return Err(ErrorCode::StaleOraclePrice.into());
+    }
+    if max_conf_bps > 0 {
+        let c1: u128 = (oracle_price.conf as u128).checked_mul(10000).ok_or(error!(ErrorCode::Overflow))?;
+        if c1.checked_div(oracle_price.price as u128).ok_or(error!(ErrorCode::Overflow))? > max_conf_bps as u128 {
+            msg!("Oracle confidence: {} exceeds maximum", oracle_price.conf.to_string());
+            return Err(ErrorCode::OracleConfidence.into());
+        }
+    }
+    Ok(())
+}
+
+// Convert a reference currency amount to payment tokens at the oracle price (rounded up)
+fn fiat_to_tokens(subscr: &SubscrData, oracle: &AccountInfo, fiat_amount: u64, ts: i64) -> anchor_lang::Result<u64> {
+    verify_matching_accounts(&subscr.price_oracle, oracle.key,
+        Some(String::from("Price oracle does not match subscription"))
+    )?;
+    let oracle_type = OracleType::try_from_primitive(subscr.oracle_type).map_err(|_| ErrorCode::InvalidOracle)?;
+    let oracle_price = read_oracle_price(oracle_type, oracle)?;
+    verify_oracle_price(&oracle_price, subscr.oracle_max_age, subscr.oracle_max_conf_bps, ts)?;
+    let price: u128 = oracle_price.price as u128;
+    // tokens = fiat_amount * 10^(token_decimals - fiat_decimals - expo) / price
+    let scale: i32 = (subscr.token_decimals as i32) - (subscr.fiat_decimals as i32) - oracle_price.expo;
+    let mut num: u128 = fiat_amount as u128;
+    let mut den: u128 = price;
+    if scale >= 0 {
+        num = num.checked_mul(10u128.checked_pow(scale as u32).ok_or(error!(ErrorCode::Overflow))?).ok_or(error!(ErrorCode::Overflow))?;
+    } else {
+        den = den.checked_mul(10u128.checked_pow(scale.unsigned_abs()).ok_or(error!(ErrorCode::Overflow))?).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    let tokens: u128 = num.checked_add(den - 1).ok_or(error!(ErrorCode::Overflow))?.checked_div(den).ok_or(error!(ErrorCode::Overflow))?;
+    if tokens > u64::MAX as u128 {
+        return Err(ErrorCode::Overflow.into());
+    }
+    Ok(tokens as u64)
+}
+
+// Exact output and slippage bounds are computed from the swap backend quote
+fn verify_swap_quote(quote_required: bool, swap_mode: u8) -> anchor_lang::Result<()> {
+    if quote_required && swap_mode != SwapMode::ConstantProductV1 as u8 {
+        msg!("Swap quotes not supported by swap mode");
+        return Err(ErrorCode::InvalidSwapMode.into());
+    }
+    Ok(())
+}
+
+// Revenue split payout to a recipient's associated token account
+pub struct SplitPayout<'info> {
+    pub recipient: Pubkey,
+    pub token_account: AccountInfo<'info>,
+    pub share_bps: u32,
+    pub amount: u64,
+}
+
+fn load_revenue_split(revenue_split: &AccountInfo) -> anchor_lang::Result<Option<RevenueSplit>> {
+    if revenue_split.data_is_empty() {
+        return Ok(None);
+    }
+    verify_matching_accounts(&crate::ID, revenue_split.owner,
+        Some(String::from("Invalid revenue split owner"))
+    )?;
+    let split = load_struct::<RevenueSplit>(revenue_split)?;
+    if split.recipient_count == 0 {
+        return Ok(None);
+    }
+    Ok(Some(split))
+}
+
+// Revenue split recipient token accounts are passed in order at the end of the remaining accounts
+fn split_revenue_accounts<'a, 'info>(
+    revenue_split: &Option<RevenueSplit>,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> anchor_lang::Result<(&'a [AccountInfo<'info>], &'a [AccountInfo<'info>])> {
+    let split_len: usize = revenue_split.as_ref().map_or(0, |split| split.recipient_count as usize);
+    if remaining_accounts.len() < split_len {
+        msg!("Missing revenue split accounts");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok(remaining_accounts.split_at(remaining_accounts.len() - split_len))
+}
+
+// Recipients receive their share of the net amount, the merchant receives the remainder
+fn revenue_split_payouts<'info>(
+    revenue_split: &Option<RevenueSplit>,
+    token_mint: &Pubkey,
+    split_accounts: &[AccountInfo<'info>],
+    net_amount: u64,
+) -> anchor_lang::Result<(Vec<SplitPayout<'info>>, u64)> {
+    let mut payouts: Vec<SplitPayout<'info>> = Vec::new();
+    let mut merchant_amount: u64 = net_amount;
+    if revenue_split.is_none() {
+        return Ok((payouts, merchant_amount));
+    }
+    let split = revenue_split.as_ref().unwrap();
+    for i in 0..split.recipient_count as usize {
+        let (recipient_token, _) = Pubkey::find_program_address(
+            &[
+                &split.recipients[i].to_bytes(),
+                &Token::id().to_bytes(),
+                &token_mint.to_bytes(),
+            ],
+            &AssociatedToken::id()
+        );
+        verify_matching_accounts(&recipient_token, split_accounts[i].key,
+            Some(String::from("Invalid revenue split recipient token account"))
+        )?;
+        let share: u128 = (net_amount as u128).checked_mul(split.shares_bps[i] as u128).ok_or(error!(ErrorCode::Overflow))?;
+        let amount: u64 = (share / 10000) as u64;
+        merchant_amount = merchant_amount.checked_sub(amount).ok_or(error!(ErrorCode::Overflow))?;
+        payouts.push(SplitPayout {
+            recipient: split.recipients[i],
+            token_account: split_accounts[i].clone(),
+            share_bps: split.shares_bps[i],
+            amount,
+        });
+    }
+    Ok((payouts, merchant_amount))
+}
+
+fn emit_split_payouts(
+    payouts: &[SplitPayout],
+    slot: u64,
+    merchant_key: &Pubkey,
+    merchant_approval: &Pubkey,
+    payment_id: u128,
+) {
+    for payout in payouts.iter() {
+        msg!("atellix-log");
+        emit!(SplitEvent {
+            event_hash: 8004311814922625262180398454301205095, // solana/program/token-agent/split_payout
+            slot,
+            merchant_key: *merchant_key,
+            merchant_approval: *merchant_approval,
+            payment_id,
+            recipient: payout.recipient,
+            recipient_token: *payout.token_account.key,
+            share_bps: payout.share_bps,
+            amount: payout.amount,
+        });
+    }
 }
 
 fn verify_matching_accounts(left: &Pubkey, right: &Pubkey, error_msg: Option<String>) -> anchor_lang::Result<()> {
@@ -78,30 +644,198 @@ fn get_manager_key(manager_approval: &AccountInfo) -> anchor_lang::Result<Pubkey
     Ok(mgr_approval.manager_key)
 }
 
-fn verify_merchant_approval(
-    dest_nonce: u8,
-    netauth: &Pubkey,
-    merchant_approval: &AccountInfo,
-    merchant_token: &AccountInfo,
-    fees_account: &AccountInfo,
-) -> anchor_lang::Result<u32> {
-    verify_matching_accounts(netauth, &merchant_approval.owner,
-        Some(String::from("Invalid merchant approval owner"))
+// Settlement terms for a payment mint: the merchant approval's own mint, or a registered merchant mint
+pub struct Settlement {
+    pub token_mint: Pubkey,
+    pub dest_account: Pubkey,
+    pub fees_account: Pubkey,
+    pub fees_bps: u32,
+}
+
+// Payments in a mint other than the merchant approval's mint pass the merchant mint as the first remaining account
+fn split_merchant_mint<'a, 'info>(
+    mrch_approval: &MerchantApproval,
+    token_mint: &Pubkey,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> anchor_lang::Result<(Option<&'a AccountInfo<'info>>, &'a [AccountInfo<'info>])> {
+    if mrch_approval.token_mint == *token_mint {
+        return Ok((None, remaining_accounts));
+    }
+    if remaining_accounts.is_empty() {
+        msg!("Missing merchant mint account");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok((Some(&remaining_accounts[0]), &remaining_accounts[1..]))
+}
+
+fn get_settlement(
+    mrch_approval: &MerchantApproval,
+    merchant_approval: &Pubkey,
+    token_mint: &Pubkey,
+    merchant_mint: Option<&AccountInfo>,
+) -> anchor_lang::Result<Settlement> {
+    if merchant_mint.is_none() {
+        verify_matching_accounts(&mrch_approval.token_mint, token_mint,
+            Some(String::from("Token mint does not match approval"))
+        )?;
+        return Ok(Settlement {
+            token_mint: mrch_approval.token_mint,
+            dest_account: mrch_approval.dest_account,
+            fees_account: mrch_approval.fees_account,
+            fees_bps: mrch_approval.fees_bps,
+        });
+    }
+    let acc_merchant_mint = merchant_mint.unwrap();
+    verify_matching_accounts(&crate::ID, acc_merchant_mint.owner,
+        Some(String::from("Invalid merchant mint owner"))
     )?;
-    let mrch_approval = load_struct::<MerchantApproval>(merchant_approval)?;
-    if !mrch_approval.active {
-        msg!("Inactive merchant approval");
+    let mrch_mint = load_struct::<MerchantMint>(acc_merchant_mint)?;
+    let derived_key = Pubkey::create_program_address(
+        &[merchant_approval.as_ref(), token_mint.as_ref(), &[mrch_mint.bump]],
+        &crate::ID
+    ).map_err(|_| ErrorCode::InvalidNonce)?;
+    verify_matching_accounts(&derived_key, acc_merchant_mint.key,
+        Some(String::from("Invalid merchant mint account"))
+    )?;
+    if !mrch_mint.active {
+        msg!("Inactive merchant mint");
         return Err(ErrorCode::NotApproved.into());
     }
-    verify_matching_accounts(&mrch_approval.fees_account, fees_account.key,
+    Ok(Settlement {
+        token_mint: mrch_mint.token_mint,
+        dest_account: mrch_mint.dest_account,
+        fees_account: mrch_mint.fees_account,
+        fees_bps: mrch_mint.fees_bps,
+    })
+}
+
+// Optional accounts (affiliate, coupon) are passed in order after the merchant mint
+fn split_optional_account<'a, 'info>(
+    present: bool,
+    name: &str,
+    remaining_accounts: &'a [AccountInfo<'info>],
+) -> anchor_lang::Result<(Option<&'a AccountInfo<'info>>, &'a [AccountInfo<'info>])> {
+    if !present {
+        return Ok((None, remaining_accounts));
+    }
+    if remaining_accounts.is_empty() {
+        msg!("Missing {} account", name);
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok((Some(&remaining_accounts[0]), &remaining_accounts[1..]))
+}
+
+// Load the affiliate registration for the merchant approval and referrer
+fn load_affiliate(merchant_approval: &Pubkey, affiliate: &AccountInfo, referrer: &Pubkey) -> anchor_lang::Result<Affiliate> {
+    verify_matching_accounts(&crate::ID, affiliate.owner,
+        Some(String::from("Invalid affiliate owner"))
+    )?;
+    let afl = load_struct::<Affiliate>(affiliate)?;
+    let afl_key = Pubkey::create_program_address(
+        &[merchant_approval.as_ref(), referrer.as_ref(), b"affiliate", &[afl.bump]],
+        &crate::ID,
+    ).map_err(|_| ErrorCode::InvalidDerivedAccount)?;
+    verify_matching_accounts(&afl_key, affiliate.key,
+        Some(String::from("Invalid affiliate account"))
+    )?;
+    Ok(afl)
+}
+
+// Referral terms must be within the limits the merchant registered for the referrer
+fn verify_referral(
+    merchant_approval: &Pubkey,
+    affiliate: &AccountInfo,
+    referrer: &Pubkey,
+    referral_bps: u32,
+    referral_rebills: u32,
+) -> anchor_lang::Result<()> {
+    let afl = load_affiliate(merchant_approval, affiliate, referrer)?;
+    if !afl.active {
+        msg!("Inactive affiliate");
+        return Err(ErrorCode::NotApproved.into());
+    }
+    if referral_bps > afl.max_bps {
+        msg!("Referral commission: {} exceeds maximum: {}", referral_bps.to_string(), afl.max_bps.to_string());
+        return Err(ErrorCode::InvalidReferral.into());
+    }
+    if afl.max_rebills > 0 && (referral_rebills == 0 || referral_rebills > afl.max_rebills) {
+        msg!("Referral rebills: {} exceeds maximum: {}", referral_rebills.to_string(), afl.max_rebills.to_string());
+        return Err(ErrorCode::InvalidReferral.into());
+    }
+    Ok(())
+}
+
+// Referral commission is due until the referral rebill limit is reached (0 = every rebill)
+fn referral_due(subscr: &SubscrData) -> bool {
+    subscr.referrer != Pubkey::default() && subscr.referral_bps > 0 &&
+        (subscr.referral_rebills == 0 || subscr.referral_count < subscr.referral_rebills)
+}
+
+// Verify a coupon can be redeemed for the merchant approval and count the redemption
+// A redemption is counted when the coupon is attached to a subscription (subscribe or redeem_coupon), whether or not a
+// discounted charge follows, so max_redemptions caps the subscriptions holding the coupon rather than the discounted charges
+fn redeem_coupon_terms(coupon: &mut Coupon, merchant_approval: &Pubkey, ts: i64) -> anchor_lang::Result<()> {
+    verify_matching_accounts(&coupon.merchant_approval, merchant_approval,
+        Some(String::from("Coupon does not match merchant approval"))
+    )?;
+    if !coupon.active {
+        msg!("Inactive coupon");
+        return Err(ErrorCode::InvalidCoupon.into());
+    }
+    if coupon.expires > 0 && ts >= coupon.expires {
+        msg!("Coupon expired");
+        return Err(ErrorCode::InvalidCoupon.into());
+    }
+    if coupon.max_redemptions > 0 && coupon.redemptions >= coupon.max_redemptions {
+        msg!("Coupon redemptions exhausted");
+        return Err(ErrorCode::InvalidCoupon.into());
+    }
+    coupon.redemptions = coupon.redemptions.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    Ok(())
+}
+
+fn apply_coupon(subscr: &mut SubscrData, coupon_key: &Pubkey, coupon: &Coupon) {
+    subscr.coupon = *coupon_key;
+    subscr.coupon_type = coupon.discount_type;
+    subscr.coupon_value = coupon.discount_value;
+    subscr.coupon_duration = coupon.duration;
+    subscr.coupon_uses = 0;
+}
+
+// Fixed discounts are in the rebill's pricing units (reference currency units for fiat priced subscriptions)
+fn discount_amount(discount_type: u8, discount_value: u64, amount: u64) -> anchor_lang::Result<u64> {
+    let dtype = DiscountType::try_from_primitive(discount_type).map_err(|_| ErrorCode::InvalidCoupon)?;
+    let discount: u64 = match dtype {
+        DiscountType::Percent => {
+            let d1: u128 = (amount as u128).checked_mul(discount_value as u128).ok_or(error!(ErrorCode::Overflow))?;
+            (d1 / 10000) as u64
+        },
+        DiscountType::Fixed => discount_value,
+    };
+    Ok(discount.min(amount))
+}
+
+// Coupon discount is applied until the coupon duration is reached (0 = every rebill)
+fn coupon_due(subscr: &SubscrData) -> bool {
+    subscr.coupon != Pubkey::default() &&
+        (subscr.coupon_duration == 0 || subscr.coupon_uses < subscr.coupon_duration)
+}
+
+fn verify_settlement_accounts(
+    settlement: &Settlement,
+    dest_nonce: u8,
+    merchant_token: &AccountInfo,
+    fees_account: &AccountInfo,
+) -> anchor_lang::Result<()> {
+    verify_matching_accounts(&settlement.fees_account, fees_account.key,
         Some(String::from("Fees account does not match approval"))
     )?;
     // Verify merchant's associated token
     let derived_merchant_key = Pubkey::create_program_address(
         &[
-            &mrch_approval.dest_account.to_bytes(),
+            &settlement.dest_account.to_bytes(),
             &Token::id().to_bytes(),
-            &mrch_approval.token_mint.to_bytes(),
+            &settlement.token_mint.to_bytes(),
             &[dest_nonce]
         ],
         &AssociatedToken::id()
@@ -110,22 +844,39 @@ fn verify_merchant_approval(
         msg!("Invalid merchant token account");
         return Err(ErrorCode::InvalidDerivedAccount.into());
     }
-    Ok(mrch_approval.fees_bps)
+    Ok(())
 }
 
-fn get_token_mint(merchant_approval: &AccountInfo) -> anchor_lang::Result<Pubkey> {
+fn verify_merchant_approval(netauth: &Pubkey, merchant_approval: &AccountInfo) -> anchor_lang::Result<MerchantApproval> {
+    verify_matching_accounts(netauth, &merchant_approval.owner,
+        Some(String::from("Invalid merchant approval owner"))
+    )?;
     let mrch_approval = load_struct::<MerchantApproval>(merchant_approval)?;
-    Ok(mrch_approval.token_mint)
+    if !mrch_approval.active {
+        msg!("Inactive merchant approval");
+        return Err(ErrorCode::NotApproved.into());
+    }
+    Ok(mrch_approval)
 }
 
-fn get_merchant_key(merchant_approval: &AccountInfo) -> anchor_lang::Result<Pubkey> {
-    let mrch_approval = load_struct::<MerchantApproval>(merchant_approval)?;
-    Ok(mrch_approval.merchant_key)
+fn verify_merchant_settlement(
+    dest_nonce: u8,
+    netauth: &Pubkey,
+    merchant_approval: &AccountInfo,
+    merchant_token: &AccountInfo,
+    fees_account: &AccountInfo,
+    token_mint: &Pubkey,
+    merchant_mint: Option<&AccountInfo>,
+) -> anchor_lang::Result<Settlement> {
+    let mrch_approval = verify_merchant_approval(netauth, merchant_approval)?;
+    let settlement = get_settlement(&mrch_approval, merchant_approval.key, token_mint, merchant_mint)?;
+    verify_settlement_accounts(&settlement, dest_nonce, merchant_token, fees_account)?;
+    Ok(settlement)
 }
 
-fn get_dest_account(merchant_approval: &AccountInfo) -> anchor_lang::Result<Pubkey> {
+fn get_merchant_key(merchant_approval: &AccountInfo) -> anchor_lang::Result<Pubkey> {
     let mrch_approval = load_struct::<MerchantApproval>(merchant_approval)?;
-    Ok(mrch_approval.dest_account)
+    Ok(mrch_approval.merchant_key)
 }
 
 fn get_tx_count(merchant_approval: &AccountInfo) -> anchor_lang::Result<u64> {
@@ -133,47 +884,655 @@ fn get_tx_count(merchant_approval: &AccountInfo) -> anchor_lang::Result<u64> {
     Ok(mrch_approval.tx_count)
 }
 
-fn calculate_fees(net_amount: u64, fees_bps: u32) -> anchor_lang::Result<u64> {
-    let f1: u128 = (net_amount as u128) << 64;
-    let f2: u128 = f1.checked_mul(fees_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
-    let f3: u128 = f2.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
-    let fees: u64 = (f3 >> 64) as u64;
-    Ok(fees)
-}
+// Verify the timeframe of new subscription terms, returns the rebill delay for the period (or the requested delay)
+fn verify_subscr_timeframe(
+    period: u8,
+    max_delay_override: i64,
+    not_valid_before: i64,
+    not_valid_after: i64,
+    next_rebill: i64,
+    ts: i64,
+) -> anchor_lang::Result<i64> {
+    let period = SubscriptionPeriod::try_from_primitive(period);
+    if period.is_err() {
+        msg!("Invalid subscription period");
+        return Err(ErrorCode::InvalidSubscriptionPeriod.into());
+    }
+    let mut max_delay: i64 = match period.unwrap() {                // Delay from start of billing cycle to accept rebills
+        SubscriptionPeriod::Daily => (60 * 60 * 24 * 90),       // 3 months
+        SubscriptionPeriod::Weekly => (60 * 60 * 24 * 90),      // 3 months
+        SubscriptionPeriod::Monthly => (60 * 60 * 24 * 365),    // 1 year
+        SubscriptionPeriod::Quarterly => (60 * 60 * 24 * 365),  // 1 year
+        SubscriptionPeriod::Yearly => (60 * 60 * 24 * 365 * 2), // 2 years
+    };
+    if max_delay_override != 0 {
+        if max_delay_override < 43200 { // 12 hours
+            msg!("Invalid max_delay below minimum of 12 hours (43200 seconds)");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+        max_delay = max_delay_override;
+    }
 
-#[inline]
-fn load_struct<T: AccountDeserialize>(acc: &AccountInfo) -> FnResult<T, ProgramError> {
-    let mut data: &[u8] = &acc.try_borrow_data()?;
-    Ok(T::try_deserialize(&mut data)?)
-}
+    if not_valid_before < 0 || (not_valid_before > 0 && not_valid_before < ts) {
+        msg!("Invalid subscription start");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    if not_valid_after < 0 || (not_valid_after > 0 && not_valid_after < ts) {
+        msg!("Invalid subscription end");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    if not_valid_after != 0 && not_valid_before != 0 {
+        if not_valid_after <= not_valid_before {
+            msg!("Invalid timeframe");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+    }
 
-#[inline]
-fn store_struct<T: AccountSerialize>(obj: &T, acc: &AccountInfo) -> FnResult<(), Error> {
-    let mut data = acc.try_borrow_mut_data()?;
-    let disc_bytes = array_ref![data, 0, 8];
-    if disc_bytes != &[0; 8] {
-        msg!("Account already initialized");
-        return Err(error!(ErrorCode::InvalidAccount));
+    if next_rebill < 0 {
+        msg!("Invalid negative next_rebill");
+        return Err(ErrorCode::InvalidTimeframe.into());
     }
-    let dst: &mut [u8] = &mut data;
-    let mut crs = Cursor::new(dst);
-    obj.try_serialize(&mut crs)
-}
 
-#[program]
-mod token_agent {
-    use super::*;
+    if not_valid_before > 0 && next_rebill < not_valid_before {
+        msg!("Next rebill is before start");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    let mut timeframe_start: i64 = ts;
+    if not_valid_before > 0 {
+        timeframe_start = not_valid_before;
+    }
 
-    pub fn store_metadata(ctx: Context<UpdateMetadata>,
-        inp_program_name: String,
-        inp_developer_name: String,
-        inp_developer_url: String,
-        inp_source_url: String,
-        inp_verify_url: String,
-    ) -> anchor_lang::Result<()> {
-        let md = &mut ctx.accounts.program_info;
-        md.semvar_major = VERSION_MAJOR;
-        md.semvar_minor = VERSION_MINOR;
+    let timeframe_end = timeframe_start.checked_add(max_delay).ok_or(error!(ErrorCode::Overflow))?;
+    if next_rebill < timeframe_start || next_rebill > timeframe_end {
+        msg!("Next rebill not within timeframe");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    let d1 = get_period_string(next_rebill, period.unwrap())?;
+    let prev_period = next_rebill.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+    let d2 = get_period_string(prev_period, period.unwrap())?;
+    if d1 == d2 {
+        msg!("Next rebill not beginning of period");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    Ok(max_delay)
+}
+
+// Verify a rebill is within its scheduled period and the next rebill starts the following period
+fn verify_rebill_timeframe(
+    period: u8,
+    next_rebill: i64,
+    max_delay: i64,
+    not_valid_before: i64,
+    not_valid_after: i64,
+    ts: i64,
+    inp_rebill_ts: i64,
+    inp_rebill_str: &str,
+    inp_next_rebill: i64,
+) -> anchor_lang::Result<()> {
+    let period = SubscriptionPeriod::try_from_primitive(period);
+    if period.is_err() {
+        msg!("Invalid subscription period");
+        return Err(ErrorCode::InvalidSubscriptionPeriod.into());
+    }
+    if not_valid_before > 0 && ts < not_valid_before {
+        msg!("Subscription not valid yet");
+        return Err(ErrorCode::NotValidYet.into());
+    }
+    if not_valid_after > 0 && ts > not_valid_after {
+        msg!("Subscription expired");
+        return Err(ErrorCode::Expired.into());
+    }
+    if inp_rebill_ts < 0 {
+        msg!("Invalid negative rebill timestamp");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    if ts < inp_rebill_ts {
+        msg!("Attempted rebill before scheduled time");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    if next_rebill != inp_rebill_ts {
+        msg!("Rebill timestamp does not match subscription");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    let timeframe_end = inp_rebill_ts.checked_add(max_delay).ok_or(error!(ErrorCode::Overflow))?;
+    if ts > timeframe_end {
+        msg!("Rebill expired");
+        return Err(ErrorCode::Expired.into());
+    }
+    let d1 = get_period_string(inp_rebill_ts, period.unwrap())?;
+    if inp_rebill_str != d1 {   
+        msg!("Invalid rebill period string");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    let d2 = get_period_string(inp_next_rebill, period.unwrap())?;
+    let prev_period = inp_next_rebill.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+    let d3 = get_period_string(prev_period, period.unwrap())?;
+    if d2 == d3 {
+        msg!("Next rebill not beginning of period");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    if d1 != d3 {
+        msg!("Next rebill out of sequence");
+        return Err(ErrorCode::InvalidTimeframe.into());
+    }
+    Ok(())
+}
+
+// Spending policies cap the user's total delegated outflow per token mint
+fn spending_policy_address(user_key: &Pubkey, token_mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[user_key.as_ref(), token_mint.as_ref(), b"spending"], &crate::ID)
+}
+
+fn record_spending(
+    spending_policy: &AccountInfo,
+    user_key: &Pubkey,
+    token_mint: &Pubkey,
+    amount: u64,
+    ts: i64,
+) -> anchor_lang::Result<()> {
+    // The manager supplies this account, so only the user's policy address for the mint is accepted (an empty account there means the user set no limits)
+    let (policy_key, _) = spending_policy_address(user_key, token_mint);
+    verify_matching_accounts(&policy_key, spending_policy.key,
+        Some(String::from("Invalid spending policy account"))
+    )?;
+    if spending_policy.data_is_empty() || amount == 0 {
+        return Ok(());
+    }
+    verify_matching_accounts(&crate::ID, spending_policy.owner,
+        Some(String::from("Invalid spending policy owner"))
+    )?;
+    let mut policy = load_struct::<SpendingPolicy>(spending_policy)?;
+    if !policy.active {
+        return Ok(());
+    }
+    let day: i64 = ts.checked_div(86400).ok_or(error!(ErrorCode::Overflow))?;
+    let dt = NaiveDateTime::from_timestamp(ts, 0);
+    let month: i64 = (dt.year() as i64).checked_mul(12).ok_or(error!(ErrorCode::Overflow))?.checked_add(dt.month0() as i64).ok_or(error!(ErrorCode::Overflow))?;
+    if policy.day != day {
+        policy.day = day;
+        policy.day_spent = 0;
+    }
+    if policy.month != month {
+        policy.month = month;
+        policy.month_spent = 0;
+    }
+    policy.day_spent = policy.day_spent.checked_add(amount).ok_or(error!(ErrorCode::Overflow))?;
+    policy.month_spent = policy.month_spent.checked_add(amount).ok_or(error!(ErrorCode::Overflow))?;
+    if policy.daily_limit > 0 && policy.day_spent > policy.daily_limit {
+        msg!("Daily spending: {} exceeds limit: {}", policy.day_spent.to_string(), policy.daily_limit.to_string());
+        return Err(ErrorCode::SpendingLimitExceeded.into());
+    }
+    if policy.monthly_limit > 0 && policy.month_spent > policy.monthly_limit {
+        msg!("Monthly spending: {} exceeds limit: {}", policy.month_spent.to_string(), policy.monthly_limit.to_string());
+        return Err(ErrorCode::SpendingLimitExceeded.into());
+    }
+    update_struct(&policy, spending_policy)?;
+    Ok(())
+}
+
+// Token links track the allowance reserved by each subscription linked to a token delegate allowance
+fn token_link_address(allowance: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[allowance.as_ref(), b"token-link"], &crate::ID)
+}
+
+// Allowance needed for the remaining rebills (subscriptions without a rebill limit reserve MAX_ALLOWANCE_REBILLS rebills)
+fn subscription_allowance(subscr: &SubscrData) -> anchor_lang::Result<u64> {
+    // Swap subscriptions pull the swap input from the swap account, which the budgets do not bound
+    let rebill_amount: u64 = if subscr.swap { subscr.swap_max_input } else { subscr.period_budget };
+    if subscr.swap && rebill_amount == 0 {
+        msg!("Linked swap subscriptions require a maximum swap input");
+        return Err(ErrorCode::InvalidSlippage.into());
+    }
+    let remaining: u64 = if subscr.rebill_max > 0 {
+        subscr.rebill_max.saturating_sub(subscr.rebill_events) as u64
+    } else {
+        MAX_ALLOWANCE_REBILLS
+    };
+    let mut allowance: u64 = rebill_amount.saturating_mul(remaining);
+    if subscr.use_total && !subscr.swap {
+        allowance = allowance.min(subscr.total_budget);
+    }
+    Ok(allowance)
+}
+
+fn approved_allowance(link: &TokenLink) -> u64 {
+    if link.approved > u64::MAX as u128 { u64::MAX } else { link.approved as u64 }
+}
+
+// Record the account that funded a new token link (links created without a reservation keep their original payer)
+fn record_link_payer(link: &mut TokenLink, rent_payer: &Pubkey, bump: u8) {
+    if link.rent_payer == Pubkey::default() {
+        link.rent_payer = *rent_payer;
+        link.bump = bump;
+    }
+}
+
+// Initialize a new token link, or verify an existing one is for the token account
+fn init_token_link(
+    link: &mut TokenLink,
+    allowance: &Pubkey,
+    token_account: &Pubkey,
+    rent_payer: &Pubkey,
+    bump: u8,
+) -> anchor_lang::Result<()> {
+    if link.allowance == Pubkey::default() {
+        link.allowance = *allowance;
+        link.token_account = *token_account;
+        record_link_payer(link, rent_payer, bump);
+    } else {
+        verify_matching_accounts(&link.token_account, token_account,
+            Some(String::from("Token account does not match token link"))
+        )?;
+    }
+    Ok(())
+}
+
+// Replace the subscription's reservation on the token link, returns the allowance to approve
+fn reserve_allowance(
+    subscr: &mut SubscrData,
+    link: &mut TokenLink,
+    allowance: &Pubkey,
+    token_account: &Pubkey,
+    rent_payer: &Pubkey,
+    bump: u8,
+) -> anchor_lang::Result<u64> {
+    init_token_link(link, allowance, token_account, rent_payer, bump)?;
+    if subscr.allowance == *allowance {
+        link.approved = link.approved.saturating_sub(subscr.allowance_amount as u128);
+    } else {
+        if subscr.allowance != Pubkey::default() {
+            msg!("Subscription reservation not released from allowance: {}", subscr.allowance.to_string());
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        link.subscriptions = link.subscriptions.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    let amount: u64 = subscription_allowance(subscr)?;
+    link.approved = link.approved.checked_add(amount as u128).ok_or(error!(ErrorCode::Overflow))?;
+    subscr.allowance = *allowance;
+    subscr.allowance_amount = amount;
+    Ok(approved_allowance(link))
+}
+
+fn unlink_allowance(subscr: &mut SubscrData, link: &mut TokenLink) {
+    link.approved = link.approved.saturating_sub(subscr.allowance_amount as u128);
+    link.subscriptions = link.subscriptions.saturating_sub(1);
+    subscr.allowance = Pubkey::default();
+    subscr.allowance_amount = 0;
+}
+
+// Release the subscription's reservation from the token link of its allowance
+fn release_allowance(subscr: &mut SubscrData, token_link: &AccountInfo) -> anchor_lang::Result<()> {
+    if subscr.allowance == Pubkey::default() {
+        return Ok(());
+    }
+    let (link_key, _) = token_link_address(&subscr.allowance);
+    verify_matching_accounts(&link_key, token_link.key,
+        Some(String::from("Invalid token link account"))
+    )?;
+    verify_matching_accounts(&crate::ID, token_link.owner,
+        Some(String::from("Invalid token link owner"))
+    )?;
+    let mut link = load_struct::<TokenLink>(token_link)?;
+    unlink_allowance(subscr, &mut link);
+    update_struct(&link, token_link)?;
+    Ok(())
+}
+
+// Release the session's unspent budget reservation from the token link of its allowance (payments already reduced the reservation)
+fn release_session_allowance(session: &SessionKey, token_link: &AccountInfo) -> anchor_lang::Result<TokenLink> {
+    let (link_key, _) = token_link_address(&session.allowance);
+    verify_matching_accounts(&link_key, token_link.key,
+        Some(String::from("Invalid token link account"))
+    )?;
+    verify_matching_accounts(&crate::ID, token_link.owner,
+        Some(String::from("Invalid token link owner"))
+    )?;
+    let mut link = load_struct::<TokenLink>(token_link)?;
+    link.approved = link.approved.saturating_sub(session.budget.saturating_sub(session.spent) as u128);
+    link.subscriptions = link.subscriptions.saturating_sub(1);
+    update_struct(&link, token_link)?;
+    Ok(link)
+}
+
+// Release the bundle's remaining reservation from the token link of its allowance
+fn release_bundle_allowance(bundle: &mut SubscrBundle, token_link: &AccountInfo) -> anchor_lang::Result<TokenLink> {
+    let (link_key, _) = token_link_address(&bundle.allowance);
+    verify_matching_accounts(&link_key, token_link.key,
+        Some(String::from("Invalid token link account"))
+    )?;
+    verify_matching_accounts(&crate::ID, token_link.owner,
+        Some(String::from("Invalid token link owner"))
+    )?;
+    let mut link = load_struct::<TokenLink>(token_link)?;
+    link.approved = link.approved.saturating_sub(bundle.allowance_amount as u128);
+    link.subscriptions = link.subscriptions.saturating_sub(1);
+    update_struct(&link, token_link)?;
+    bundle.allowance = Pubkey::default();
+    bundle.allowance_amount = 0;
+    Ok(link)
+}
+
+// Allowance needed for the remaining bundle rebills
+fn bundle_allowance(bundle: &SubscrBundle) -> u64 {
+    let remaining: u64 = if bundle.rebill_max > 0 {
+        bundle.rebill_max.saturating_sub(bundle.rebill_events) as u64
+    } else {
+        MAX_ALLOWANCE_REBILLS
+    };
+    bundle.period_budget.saturating_mul(remaining)
+}
+
+// Reduce a reservation on the token link by the amount transferred with its allowance
+fn spend_allowance(allowance: &Pubkey, token_link: &AccountInfo, amount: u64) -> anchor_lang::Result<()> {
+    let (link_key, _) = token_link_address(allowance);
+    verify_matching_accounts(&link_key, token_link.key,
+        Some(String::from("Invalid token link account"))
+    )?;
+    verify_matching_accounts(&crate::ID, token_link.owner,
+        Some(String::from("Invalid token link owner"))
+    )?;
+    let mut link = load_struct::<TokenLink>(token_link)?;
+    link.approved = link.approved.saturating_sub(amount as u128);
+    update_struct(&link, token_link)?;
+    Ok(())
+}
+
+// Create the token link for an allowance passed as a remaining account (instructions with a single allowance use init_if_needed)
+fn create_token_link<'info>(
+    token_link: &AccountInfo<'info>,
+    allowance: &Pubkey,
+    token_account: &Pubkey,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    let (link_key, link_bump) = token_link_address(allowance);
+    verify_matching_accounts(&link_key, token_link.key,
+        Some(String::from("Invalid token link account"))
+    )?;
+    let space: usize = 125;
+    let seeds = &[allowance.as_ref(), b"token-link", &[link_bump]];
+    let signer = &[&seeds[..]];
+    let cpi_accounts = anchor_lang::system_program::CreateAccount {
+        from: payer.clone(),
+        to: token_link.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(system_program.clone(), cpi_accounts, signer);
+    anchor_lang::system_program::create_account(cpi_ctx, Rent::get()?.minimum_balance(space), space as u64, &crate::ID)?;
+    let link = TokenLink {
+        allowance: *allowance,
+        token_account: *token_account,
+        subscriptions: 0,
+        approved: 0,
+        rent_payer: *payer.key,
+        bump: link_bump,
+    };
+    store_struct::<TokenLink>(&link, token_link)?;
+    Ok(())
+}
+
+// Release the reservations of linked fallback accounts and clear the fallbacks, the fallback token links are passed in fallback order
+// (the fallback allowances shrink when the user next links the token accounts)
+fn release_fallback_allowances(subscr: &mut SubscrData, token_links: &[AccountInfo]) -> anchor_lang::Result<()> {
+    let mut link_index: usize = 0;
+    for i in 0..(subscr.fallback_count as usize) {
+        if subscr.fallback_allowances[i] == Pubkey::default() {
+            continue;
+        }
+        let token_link = token_links.get(link_index).ok_or(error!(ErrorCode::InvalidAccount))?;
+        link_index = link_index + 1;
+        let (link_key, _) = token_link_address(&subscr.fallback_allowances[i]);
+        verify_matching_accounts(&link_key, token_link.key,
+            Some(String::from("Invalid fallback token link account"))
+        )?;
+        verify_matching_accounts(&crate::ID, token_link.owner,
+            Some(String::from("Invalid token link owner"))
+        )?;
+        let mut link = load_struct::<TokenLink>(token_link)?;
+        link.approved = link.approved.saturating_sub(subscr.fallback_amounts[i] as u128);
+        link.subscriptions = link.subscriptions.saturating_sub(1);
+        update_struct(&link, token_link)?;
+    }
+    subscr.fallback_count = 0;
+    subscr.fallback_accounts = [Pubkey::default(); MAX_FALLBACK_ACCOUNTS];
+    subscr.fallback_allowances = [Pubkey::default(); MAX_FALLBACK_ACCOUNTS];
+    subscr.fallback_amounts = [0; MAX_FALLBACK_ACCOUNTS];
+    Ok(())
+}
+
+// Number of fallback token links passed to release_fallback_allowances
+fn linked_fallback_count(subscr: &SubscrData) -> usize {
+    subscr.fallback_allowances[..(subscr.fallback_count as usize)].iter().filter(|allowance| **allowance != Pubkey::default()).count()
+}
+
+// Reduce the reservation of the funding account (primary or fallback) by the amount the delegate transferred
+fn spend_funding_allowance(
+    subscr: &mut SubscrData,
+    fallback: Option<usize>,
+    token_link: &AccountInfo,
+    fallback_accounts: &[AccountInfo],
+    amount: u64,
+) -> anchor_lang::Result<()> {
+    match fallback {
+        None => {
+            if subscr.allowance != Pubkey::default() {
+                spend_allowance(&subscr.allowance, token_link, amount)?;
+                subscr.allowance_amount = subscr.allowance_amount.saturating_sub(amount);
+            }
+        },
+        Some(i) => {
+            if subscr.fallback_allowances[i] != Pubkey::default() {
+                let acc_fallback_link = fallback_accounts.get((i * 3) + 2).ok_or(error!(ErrorCode::InvalidAccount))?;
+                spend_allowance(&subscr.fallback_allowances[i], acc_fallback_link, amount)?;
+                subscr.fallback_amounts[i] = subscr.fallback_amounts[i].saturating_sub(amount);
+            }
+        },
+    }
+    Ok(())
+}
+
+// Subscription rent returns to the account that paid it (subscriptions created before rent payers were recorded refund the user)
+fn rent_recipient(subscr: &SubscrData) -> Pubkey {
+    if subscr.rent_payer == Pubkey::default() { subscr.user_key } else { subscr.rent_payer }
+}
+
+// Timestamp since which a cancelled subscription has been inactive (subscriptions cancelled before cancellation times were recorded count from their last payment)
+fn inactive_since(subscr: &SubscrData) -> i64 {
+    if subscr.cancelled_at > 0 { subscr.cancelled_at } else { subscr.last_payment_ts }
+}
+
+// Timestamp since which a subscription has ended: cancelled, expired or with all rebills processed (None = still running)
+fn ended_since(subscr: &SubscrData, ts: i64) -> Option<i64> {
+    if !subscr.active {
+        return Some(inactive_since(subscr));
+    }
+    if subscr.rebill_max > 0 && subscr.rebill_events >= subscr.rebill_max {
+        return Some(subscr.last_payment_ts);
+    }
+    if subscr.not_valid_after > 0 && ts > subscr.not_valid_after {
+        return Some(subscr.not_valid_after);
+    }
+    None
+}
+
+// Close an account owned by this program and return the rent to the recipient
+fn close_account(acc: &AccountInfo, recipient: &AccountInfo) -> anchor_lang::Result<()> {
+    let lamports: u64 = acc.lamports();
+    **recipient.try_borrow_mut_lamports()? = recipient.lamports().checked_add(lamports).ok_or(error!(ErrorCode::Overflow))?;
+    **acc.try_borrow_mut_lamports()? = 0;
+    acc.try_borrow_mut_data()?.fill(0);
+    Ok(())
+}
+
+// Close the allowance and its token link once no subscription or session holds a reservation, the rent returns to the payers
+fn revoke_allowance<'info>(
+    link: &TokenLink,
+    token_link: &AccountInfo<'info>,
+    allowance: &AccountInfo<'info>,
+    token_account: &AccountInfo<'info>,
+    user_key: &AccountInfo<'info>,
+    link_payer: &AccountInfo<'info>,
+    root_key: &AccountInfo<'info>,
+    root_nonce: u8,
+    delegate_program: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    verify_matching_accounts(&link.allowance, allowance.key,
+        Some(String::from("Allowance does not match token link"))
+    )?;
+    verify_matching_accounts(&link.token_account, token_account.key,
+        Some(String::from("Token account does not match token link"))
+    )?;
+    verify_matching_accounts(&link.rent_payer, link_payer.key,
+        Some(String::from("Rent payer does not match token link"))
+    )?;
+    let root_pda_seeds = &[crate::ID.as_ref(), &[root_nonce]];
+    let root_pda_signer = &[&root_pda_seeds[..]];
+    let cpi_accounts = DelegateClose {
+        allowance: allowance.clone(),
+        owner: user_key.clone(),
+        delegate: root_key.clone(),
+        token_account: token_account.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(delegate_program.clone(), cpi_accounts, root_pda_signer);
+    token_delegate::cpi::delegate_close(cpi_ctx)?;
+    close_account(token_link, link_payer)?;
+    msg!("Revoked Allowance: {}", allowance.key.to_string());
+    Ok(())
+}
+
+// Release the subscription's reservation from its previous allowance when it moves to another one,
+// shrinking the previous delegate approval to the remaining reservations or revoking it if none remain
+fn release_prev_allowance<'info>(
+    subscr: &mut SubscrData,
+    prev_token_link: &AccountInfo<'info>,
+    prev_allowance: &AccountInfo<'info>,
+    prev_token_account: &AccountInfo<'info>,
+    user_key: &AccountInfo<'info>,
+    link_payer: &AccountInfo<'info>,
+    root_key: &AccountInfo<'info>,
+    root_nonce: u8,
+    delegate_root: &AccountInfo<'info>,
+    delegate_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    if subscr.allowance == Pubkey::default() {
+        return Ok(());
+    }
+    verify_matching_accounts(&subscr.allowance, prev_allowance.key,
+        Some(String::from("Previous allowance does not match subscription"))
+    )?;
+    release_allowance(subscr, prev_token_link)?;
+    let link = load_struct::<TokenLink>(prev_token_link)?;
+    if link.subscriptions == 0 {
+        revoke_allowance(&link, prev_token_link, prev_allowance, prev_token_account, user_key, link_payer, root_key, root_nonce, delegate_program)?;
+    } else {
+        verify_matching_accounts(&link.token_account, prev_token_account.key,
+            Some(String::from("Previous token account does not match token link"))
+        )?;
+        let approve_amount: u64 = approved_allowance(&link);
+        let cpi_accounts = DelegateApprove {
+            allowance: prev_allowance.clone(),
+            allowance_payer: user_key.clone(),
+            owner: user_key.clone(),
+            delegate: root_key.clone(),
+            delegate_root: delegate_root.clone(),
+            token_account: prev_token_account.clone(),
+            token_program: token_program.clone(),
+            system_program: system_program.clone(),
+        };
+        let cpi_ctx = CpiContext::new(delegate_program.clone(), cpi_accounts);
+        token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+    }
+    Ok(())
+}
+
+// Returns the funding token account, its allowance and the fallback index (None = primary)
+fn select_funding_account<'info>(
+    subscr: &SubscrData,
+    primary: &AccountInfo<'info>,
+    primary_allowance: &AccountInfo<'info>,
+    fallback_accounts: &[AccountInfo<'info>],
+    amount: u64,
+) -> anchor_lang::Result<(AccountInfo<'info>, AccountInfo<'info>, Option<usize>)> {
+    // Zero amount means any available balance
+    let primary_amount: u64 = load_struct::<TokenAccount>(primary)?.amount;
+    if primary_amount > 0 && primary_amount >= amount {
+        return Ok((primary.clone(), primary_allowance.clone(), None));
+    }
+    for i in 0..(subscr.fallback_count as usize) {
+        let acc_fallback = fallback_accounts.get(i * 3).ok_or(error!(ErrorCode::InvalidAccount))?;
+        let acc_fallback_allowance = fallback_accounts.get((i * 3) + 1).ok_or(error!(ErrorCode::InvalidAccount))?;
+        verify_matching_accounts(&subscr.fallback_accounts[i], acc_fallback.key,
+            Some(String::from("Fallback token account does not match subscription"))
+        )?;
+        let fallback_amount: u64 = load_struct::<TokenAccount>(acc_fallback)?.amount;
+        if fallback_amount > 0 && fallback_amount >= amount {
+            msg!("Using fallback token account: {}", acc_fallback.key.to_string());
+            return Ok((acc_fallback.clone(), acc_fallback_allowance.clone(), Some(i)));
+        }
+    }
+    if subscr.fallback_count == 0 {
+        // No fallbacks, let the transfer report the error
+        return Ok((primary.clone(), primary_allowance.clone(), None));
+    }
+    msg!("Insufficient funds in primary and fallback token accounts");
+    Err(ErrorCode::InsufficientFunds.into())
+}
+
+// Load subscription data in the current layout (subscriptions in the original layout are upgraded by migrate_subscription)
+fn load_subscr(acc: &AccountInfo) -> anchor_lang::Result<SubscrData> {
+    verify_matching_accounts(&crate::ID, acc.owner,
+        Some(String::from("Invalid subscription owner"))
+    )?;
+    let subscr = load_struct::<SubscrData>(acc)?;
+    if subscr.version != SUBSCR_DATA_VERSION {
+        msg!("Unsupported subscription data version: {}", subscr.version.to_string());
+        return Err(ErrorCode::InvalidDataVersion.into());
+    }
+    Ok(subscr)
+}
+
+#[inline]
+fn load_struct<T: AccountDeserialize>(acc: &AccountInfo) -> FnResult<T, ProgramError> {
+    let mut data: &[u8] = &acc.try_borrow_data()?;
+    Ok(T::try_deserialize(&mut data)?)
+}
+
+#[inline]
+fn store_struct<T: AccountSerialize>(obj: &T, acc: &AccountInfo) -> FnResult<(), Error> {
+    let mut data = acc.try_borrow_mut_data()?;
+    let disc_bytes = array_ref![data, 0, 8];
+    if disc_bytes != &[0; 8] {
+        msg!("Account already initialized");
+        return Err(error!(ErrorCode::InvalidAccount));
+    }
+    let dst: &mut [u8] = &mut data;
+    let mut crs = Cursor::new(dst);
+    obj.try_serialize(&mut crs)
+}
+
+#[inline]
+fn update_struct<T: AccountSerialize>(obj: &T, acc: &AccountInfo) -> FnResult<(), Error> {
+    let mut data = acc.try_borrow_mut_data()?;
+    let dst: &mut [u8] = &mut data;
+    let mut crs = Cursor::new(dst);
+    obj.try_serialize(&mut crs)
+}
+
+#[program]
+mod token_agent {
+    use super::*;
+
+    pub fn store_metadata(ctx: Context<UpdateMetadata>,
+        inp_program_name: String,
+        inp_developer_name: String,
+        inp_developer_url: String,
+        inp_source_url: String,
+        inp_verify_url: String,
+    ) -> anchor_lang::Result<()> {
+        let md = &mut ctx.accounts.program_info;
+        md.semvar_major = VERSION_MAJOR;
+        md.semvar_minor = VERSION_MINOR;
         md.semvar_patch = VERSION_PATCH;
         md.program = ctx.accounts.program.key();
         md.program_name = inp_program_name;
@@ -191,6 +1550,241 @@ mod token_agent {
         Ok(())
     }
 
+    pub fn register_merchant_mint(ctx: Context<RegisterMerchantMint>,
+        inp_active: bool,
+        inp_dest_account: Pubkey,
+        inp_fees_bps: u32,
+    ) -> anchor_lang::Result<()> {
+        let mrch_approval = verify_merchant_approval(&net_authority::ID, &ctx.accounts.merchant_approval.to_account_info())?;
+        verify_matching_accounts(&mrch_approval.merchant_key, ctx.accounts.merchant_key.key,
+            Some(String::from("Merchant key does not match approval"))
+        )?;
+        if mrch_approval.token_mint == ctx.accounts.token_mint.key() {
+            msg!("Token mint already settled by merchant approval");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        // Network fees may not be lowered per mint
+        if inp_fees_bps < mrch_approval.fees_bps || inp_fees_bps > 10000 {
+            msg!("Invalid fees: {} minimum: {}", inp_fees_bps.to_string(), mrch_approval.fees_bps.to_string());
+            return Err(ErrorCode::InvalidFees.into());
+        }
+        // Fees go to the associated token of the approval's fees account owner
+        verify_matching_accounts(&mrch_approval.fees_account, &ctx.accounts.approval_fees_account.key(),
+            Some(String::from("Fees account does not match approval"))
+        )?;
+        let (fees_account, _) = Pubkey::find_program_address(
+            &[
+                &ctx.accounts.approval_fees_account.owner.to_bytes(),
+                &Token::id().to_bytes(),
+                &ctx.accounts.token_mint.key().to_bytes(),
+            ],
+            &AssociatedToken::id()
+        );
+
+        let mrch_mint = &mut ctx.accounts.merchant_mint;
+        mrch_mint.active = inp_active;
+        mrch_mint.merchant_approval = ctx.accounts.merchant_approval.key();
+        mrch_mint.merchant_key = mrch_approval.merchant_key;
+        mrch_mint.token_mint = ctx.accounts.token_mint.key();
+        mrch_mint.dest_account = inp_dest_account;
+        mrch_mint.fees_account = fees_account;
+        mrch_mint.fees_bps = inp_fees_bps;
+        mrch_mint.bump = *ctx.bumps.get("merchant_mint").unwrap();
+        msg!("Merchant Mint: {} Active: {}", mrch_mint.token_mint.to_string(), inp_active.to_string());
+        msg!("Destination: {} Fees: {}", inp_dest_account.to_string(), inp_fees_bps.to_string());
+        Ok(())
+    }
+
+    pub fn update_revenue_split(ctx: Context<UpdateRevenueSplit>,
+        inp_recipients: Vec<Pubkey>,
+        inp_shares_bps: Vec<u32>,
+    ) -> anchor_lang::Result<()> {
+        let mrch_approval = verify_merchant_approval(&net_authority::ID, &ctx.accounts.merchant_approval.to_account_info())?;
+        verify_matching_accounts(&mrch_approval.merchant_key, ctx.accounts.merchant_key.key,
+            Some(String::from("Merchant key does not match approval"))
+        )?;
+        if inp_recipients.len() > MAX_SPLIT_RECIPIENTS || inp_recipients.len() != inp_shares_bps.len() {
+            msg!("Invalid revenue split recipients");
+            return Err(ErrorCode::InvalidRevenueSplit.into());
+        }
+        let mut total_bps: u32 = 0;
+        for share_bps in inp_shares_bps.iter() {
+            total_bps = total_bps.checked_add(*share_bps).ok_or(error!(ErrorCode::Overflow))?;
+        }
+        if total_bps > 10000 {
+            msg!("Revenue split shares: {} exceed 10000 bps", total_bps.to_string());
+            return Err(ErrorCode::InvalidRevenueSplit.into());
+        }
+
+        let split = &mut ctx.accounts.revenue_split;
+        split.merchant_approval = ctx.accounts.merchant_approval.key();
+        split.recipient_count = inp_recipients.len() as u8;
+        split.recipients = [Pubkey::default(); MAX_SPLIT_RECIPIENTS];
+        split.shares_bps = [0; MAX_SPLIT_RECIPIENTS];
+        for i in 0..inp_recipients.len() {
+            split.recipients[i] = inp_recipients[i];
+            split.shares_bps[i] = inp_shares_bps[i];
+            msg!("Recipient: {} Share: {}", inp_recipients[i].to_string(), inp_shares_bps[i].to_string());
+        }
+        Ok(())
+    }
+
+    pub fn set_fee_policy(ctx: Context<SetFeePolicy>,
+        inp_active: bool,
+        inp_subscr_fees: FeeSchedule,
+        inp_payment_fees: FeeSchedule,
+    ) -> anchor_lang::Result<()> {
+        // Fee policies are set by the owner of the merchant approval's fees account
+        let mrch_approval = verify_merchant_approval(&net_authority::ID, &ctx.accounts.merchant_approval.to_account_info())?;
+        verify_matching_accounts(&mrch_approval.fees_account, &ctx.accounts.approval_fees_account.key(),
+            Some(String::from("Fees account does not match approval"))
+        )?;
+        verify_matching_accounts(&ctx.accounts.approval_fees_account.owner, ctx.accounts.fee_authority.key,
+            Some(String::from("Fee authority does not own approval fees account"))
+        )?;
+        inp_subscr_fees.verify()?;
+        inp_payment_fees.verify()?;
+
+        let policy = &mut ctx.accounts.fee_policy;
+        policy.active = inp_active;
+        policy.merchant_approval = ctx.accounts.merchant_approval.key();
+        policy.token_mint = ctx.accounts.token_mint.key();
+        policy.fee_authority = ctx.accounts.fee_authority.key();
+        policy.subscr_fees = inp_subscr_fees;
+        policy.payment_fees = inp_payment_fees;
+        policy.bump = *ctx.bumps.get("fee_policy").unwrap();
+        msg!("Fee Policy: {} Active: {}", policy.token_mint.to_string(), inp_active.to_string());
+        Ok(())
+    }
+
+    pub fn update_coupon(ctx: Context<UpdateCoupon>,
+        inp_coupon_id: u128,
+        inp_active: bool,
+        inp_discount_type: u8,
+        inp_discount_value: u64,
+        inp_duration: u32,
+        inp_max_redemptions: u32,
+        inp_expires: i64,
+    ) -> anchor_lang::Result<()> {
+        let mrch_approval = verify_merchant_approval(&net_authority::ID, &ctx.accounts.merchant_approval.to_account_info())?;
+        verify_matching_accounts(&mrch_approval.merchant_key, ctx.accounts.merchant_key.key,
+            Some(String::from("Merchant key does not match approval"))
+        )?;
+        let discount_type = DiscountType::try_from_primitive(inp_discount_type);
+        if discount_type.is_err() || (discount_type.unwrap() == DiscountType::Percent && inp_discount_value > 10000) {
+            msg!("Invalid coupon discount");
+            return Err(ErrorCode::InvalidCoupon.into());
+        }
+        if inp_expires < 0 {
+            msg!("Invalid coupon expiry");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+
+        let coupon = &mut ctx.accounts.coupon;
+        coupon.active = inp_active;
+        coupon.merchant_approval = ctx.accounts.merchant_approval.key();
+        coupon.coupon_id = inp_coupon_id;
+        coupon.discount_type = inp_discount_type;
+        coupon.discount_value = inp_discount_value;
+        coupon.duration = inp_duration;
+        coupon.max_redemptions = inp_max_redemptions;
+        coupon.expires = inp_expires;
+        coupon.bump = *ctx.bumps.get("coupon").unwrap();
+        msg!("Coupon: {} Active: {} Redemptions: {}", inp_coupon_id.to_string(), inp_active.to_string(), coupon.redemptions.to_string());
+        Ok(())
+    }
+
+    pub fn redeem_coupon<'info>(ctx: Context<'_, '_, '_, 'info, RedeemCoupon<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match subscription"))
+        )?;
+        if !subscr.active {
+            msg!("Inactive subscription");
+            return Err(ErrorCode::InactiveSubscription.into());
+        }
+        if coupon_due(subscr) {
+            msg!("Subscription coupon already in use");
+            return Err(ErrorCode::InvalidCoupon.into());
+        }
+        let coupon = &mut ctx.accounts.coupon;
+        redeem_coupon_terms(coupon, &subscr.merchant_approval, clock.unix_timestamp)?;
+        apply_coupon(subscr, &coupon.key(), coupon);
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                3120694831022099789038249256531559991, // solana/program/token-agent/redeem_coupon
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    pub fn register_affiliate(ctx: Context<RegisterAffiliate>,
+        inp_active: bool,
+        inp_max_bps: u32,
+        inp_max_rebills: u32,
+    ) -> anchor_lang::Result<()> {
+        let mrch_approval = verify_merchant_approval(&net_authority::ID, &ctx.accounts.merchant_approval.to_account_info())?;
+        verify_matching_accounts(&mrch_approval.merchant_key, ctx.accounts.merchant_key.key,
+            Some(String::from("Merchant key does not match approval"))
+        )?;
+        if inp_max_bps > 10000 {
+            msg!("Invalid referral commission: {}", inp_max_bps.to_string());
+            return Err(ErrorCode::InvalidReferral.into());
+        }
+
+        let afl = &mut ctx.accounts.affiliate;
+        afl.active = inp_active;
+        afl.merchant_approval = ctx.accounts.merchant_approval.key();
+        afl.referrer = ctx.accounts.referrer_key.key();
+        afl.max_bps = inp_max_bps;
+        afl.max_rebills = inp_max_rebills;
+        afl.bump = *ctx.bumps.get("affiliate").unwrap();
+
+        msg!("atellix-log");
+        emit!(AffiliateEvent {
+            event_hash: 48907965039785899912789102236219489704, // solana/program/token-agent/register_affiliate
+            slot: Clock::get()?.slot,
+            merchant_key: mrch_approval.merchant_key,
+            merchant_approval: afl.merchant_approval,
+            referrer: afl.referrer,
+            active: inp_active,
+            max_bps: inp_max_bps,
+            max_rebills: inp_max_rebills,
+        });
+        Ok(())
+    }
+
+    pub fn set_spending_policy(ctx: Context<SetSpendingPolicy>,
+        inp_active: bool,
+        inp_daily_limit: u64,
+        inp_monthly_limit: u64,
+    ) -> anchor_lang::Result<()> {
+        let policy = &mut ctx.accounts.spending_policy;
+        policy.active = inp_active;
+        policy.user_key = ctx.accounts.user_key.key();
+        policy.token_mint = ctx.accounts.token_mint.key();
+        policy.daily_limit = inp_daily_limit;
+        policy.monthly_limit = inp_monthly_limit;
+        policy.bump = *ctx.bumps.get("spending_policy").unwrap();
+
+        msg!("atellix-log");
+        emit!(SpendingPolicyEvent {
+            event_hash: 255674895787693651333047390128816111498, // solana/program/token-agent/set_spending_policy
+            slot: Clock::get()?.slot,
+            user_key: policy.user_key,
+            token_mint: policy.token_mint,
+            active: inp_active,
+            daily_limit: inp_daily_limit,
+            monthly_limit: inp_monthly_limit,
+        });
+        Ok(())
+    }
+
     pub fn subscribe<'info>(ctx: Context<'_, '_, '_, 'info, CreateSubscr<'info>>,
         inp_link_token: bool,
         inp_initial_amount: u64,
@@ -214,6 +1808,15 @@ mod token_agent {
         inp_swap_inb_nonce: u8,
         inp_swap_out_nonce: u8,
         inp_swap_dst_nonce: u8,
+        inp_swap_max_input: u64,
+        inp_swap_slippage_bps: u32,
+        inp_swap_exact_output: bool,
+        inp_referrer: Pubkey,
+        inp_referral_bps: u32,
+        inp_referral_rebills: u32,
+        inp_coupon: bool,
+        inp_rent_reclaim_delay: i64,
+        inp_sponsor_amount: u64,
     ) -> anchor_lang::Result<()> {
         let clock = Clock::get()?;
         let ts = clock.unix_timestamp;
@@ -235,12 +1838,27 @@ mod token_agent {
             msg!("Inactive merchant approval");
             return Err(ErrorCode::NotApproved.into());
         }
-        /*verify_matching_accounts(&mrch_approval.token_mint, &ctx.accounts.token_account.mint,
-            Some(String::from("Token mint does not match approval"))
-        )?;*/
-        verify_matching_accounts(&mrch_approval.fees_account, &ctx.accounts.fees_account.to_account_info().key,
-            Some(String::from("Fees account does not match approval"))
-        )?;
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
+        let token_mint: Pubkey = load_struct::<TokenAccount>(&ctx.accounts.token_account.to_account_info())?.mint;
+        let (acc_merchant_mint, remaining_accounts) = split_merchant_mint(&mrch_approval, &token_mint, ctx.remaining_accounts)?;
+        let settlement = get_settlement(&mrch_approval, ctx.accounts.merchant_approval.key, &token_mint, acc_merchant_mint)?;
+        let (acc_affiliate, remaining_accounts) = split_optional_account(inp_referrer != Pubkey::default(), "affiliate", remaining_accounts)?;
+        if acc_affiliate.is_some() {
+            verify_referral(ctx.accounts.merchant_approval.key, acc_affiliate.unwrap(), &inp_referrer, inp_referral_bps, inp_referral_rebills)?;
+        }
+        let (acc_coupon, remaining_accounts) = split_optional_account(inp_coupon, "coupon", remaining_accounts)?;
+        let (acc_sponsor_token, remaining_accounts) = split_optional_account(inp_sponsor_amount > 0, "sponsor token", remaining_accounts)?;
+        let mut coupon: Option<Coupon> = None;
+        if acc_coupon.is_some() {
+            let acc_coupon = acc_coupon.unwrap();
+            verify_matching_accounts(&crate::ID, acc_coupon.owner,
+                Some(String::from("Invalid coupon owner"))
+            )?;
+            let mut cpn = load_struct::<Coupon>(acc_coupon)?;
+            redeem_coupon_terms(&mut cpn, ctx.accounts.merchant_approval.key, ts)?;
+            update_struct(&cpn, acc_coupon)?;
+            coupon = Some(cpn);
+        }
         let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
         if !mgr_approval.active {
             msg!("Inactive manager approval");
@@ -248,99 +1866,41 @@ mod token_agent {
         }
 
         // Verify input
-        let period = SubscriptionPeriod::try_from_primitive(inp_period);
-        if period.is_err() {
-            msg!("Invalid subscription period");
-            return Err(ErrorCode::InvalidSubscriptionPeriod.into());
-        }
-        let mut max_delay: i64 = match period.unwrap() {                // Delay from start of billing cycle to accept rebills
-            SubscriptionPeriod::Daily => (60 * 60 * 24 * 90),       // 3 months
-            SubscriptionPeriod::Weekly => (60 * 60 * 24 * 90),      // 3 months
-            SubscriptionPeriod::Monthly => (60 * 60 * 24 * 365),    // 1 year
-            SubscriptionPeriod::Quarterly => (60 * 60 * 24 * 365),  // 1 year
-            SubscriptionPeriod::Yearly => (60 * 60 * 24 * 365 * 2), // 2 years
-        };
-        if inp_max_delay != 0 {
-            if inp_max_delay < 43200 { // 12 hours
-                msg!("Invalid max_delay below minimum of 12 hours (43200 seconds)");
-                return Err(ErrorCode::InvalidTimeframe.into());
-            }
-            max_delay = inp_max_delay;
-        }
-
-        if inp_not_valid_before < 0 || (inp_not_valid_before > 0 && inp_not_valid_before < ts) {
-            msg!("Invalid subscription start");
+        if inp_rent_reclaim_delay < MANAGER_CLOSE_DELAY && *ctx.accounts.rent_payer.key != *ctx.accounts.user_key.key {
+            msg!("Invalid rent_reclaim_delay: {} below minimum of {} seconds", inp_rent_reclaim_delay.to_string(), MANAGER_CLOSE_DELAY.to_string());
             return Err(ErrorCode::InvalidTimeframe.into());
         }
-        if inp_not_valid_after < 0 || (inp_not_valid_after > 0 && inp_not_valid_after < ts) {
-            msg!("Invalid subscription end");
-            return Err(ErrorCode::InvalidTimeframe.into());
-        }
-        if inp_not_valid_after != 0 && inp_not_valid_before != 0 {
-            if inp_not_valid_after <= inp_not_valid_before {
-                msg!("Invalid timeframe");
-                return Err(ErrorCode::InvalidTimeframe.into());
-            }
-        }
-
-        if inp_next_rebill < 0 {
-            msg!("Invalid negative next_rebill");
-            return Err(ErrorCode::InvalidTimeframe.into());
+        if inp_sponsor_amount > 0 && *ctx.accounts.rent_payer.key == *ctx.accounts.user_key.key {
+            msg!("Sponsor reimbursement requires a separate rent payer");
+            return Err(ErrorCode::AccessDenied.into());
         }
-
-        if inp_not_valid_before > 0 && inp_next_rebill < inp_not_valid_before {
-            msg!("Next rebill is before start");
-            return Err(ErrorCode::InvalidTimeframe.into());
+        if inp_sponsor_amount > 0 && inp_swap {
+            msg!("Sponsor reimbursement is paid from the token account and requires a subscription without a swap");
+            return Err(ErrorCode::AccessDenied.into());
         }
-        let mut timeframe_start: i64 = ts;
-        if inp_not_valid_before > 0 {
-            timeframe_start = inp_not_valid_before;
+        let max_delay: i64 = verify_subscr_timeframe(inp_period, inp_max_delay, inp_not_valid_before, inp_not_valid_after, inp_next_rebill, ts)?;
+        if inp_swap_slippage_bps > 10000 {
+            msg!("Invalid swap slippage");
+            return Err(ErrorCode::InvalidSlippage.into());
         }
+        verify_swap_quote(inp_swap && (inp_swap_exact_output || inp_swap_slippage_bps > 0), inp_swap_mode)?;
 
-        let timeframe_end = timeframe_start.checked_add(max_delay).ok_or(error!(ErrorCode::Overflow))?;
-        if inp_next_rebill < timeframe_start || inp_next_rebill > timeframe_end {
-            msg!("Next rebill not within timeframe");
-            return Err(ErrorCode::InvalidTimeframe.into());
-        }
-        let d1 = get_period_string(inp_next_rebill, period.unwrap())?;
-        let prev_period = inp_next_rebill.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-        let d2 = get_period_string(prev_period, period.unwrap())?;
-        if d1 == d2 {
-            msg!("Next rebill not beginning of period");
-            return Err(ErrorCode::InvalidTimeframe.into());
-        }
+        // Verify merchant's associated token and fees account
+        verify_settlement_accounts(&settlement, inp_dest_nonce,
+            &ctx.accounts.merchant_token.to_account_info(),
+            &ctx.accounts.fees_account.to_account_info(),
+        )?;
 
-        // Verify merchant's associated token
-        let derived_merchant_key = Pubkey::create_program_address(
-            &[
-                &mrch_approval.dest_account.to_bytes(),
-                &Token::id().to_bytes(),
-                &mrch_approval.token_mint.to_bytes(),
-                &[inp_dest_nonce]
-            ],
-            &AssociatedToken::id()
-        ).map_err(|_| ErrorCode::InvalidNonce)?;
-        if derived_merchant_key != *ctx.accounts.merchant_token.to_account_info().key {
-            msg!("Invalid merchant token account");
-            return Err(ErrorCode::InvalidDerivedAccount.into());
-        }
+        // Coupon discount on the initial payment
+        let discount: u64 = if coupon.is_some() && inp_initial_amount > 0 {
+            discount_amount(coupon.as_ref().unwrap().discount_type, coupon.as_ref().unwrap().discount_value, inp_initial_amount)?
+        } else {
+            0
+        };
+        let inp_initial_amount: u64 = inp_initial_amount.checked_sub(discount).ok_or(error!(ErrorCode::Overflow))?;
 
-        // Setup up token delegate if needed
-        if !inp_swap && inp_link_token {
-            let cpi_accounts = DelegateApprove {
-                allowance: ctx.accounts.allowance.to_account_info(),
-                allowance_payer: ctx.accounts.user_key.to_account_info(),
-                owner: ctx.accounts.user_key.to_account_info(),
-                delegate: ctx.accounts.root_key.to_account_info(),
-                delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                token_account: ctx.accounts.token_account.to_account_info(),
-                token_program: ctx.accounts.token_program.to_account_info(),
-                system_program: ctx.accounts.system_program.to_account_info(),
-            };
-            let cpi_program = ctx.accounts.delegate_program.to_account_info();
-            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-            token_delegate::cpi::delegate_approve(cpi_ctx, true, u64::MAX, u64::MAX)?;
-        }
+        let fee_quote = quote_settlement_fees(&ctx.accounts.fee_policy.to_account_info(), ctx.accounts.merchant_approval.key, &settlement, true, inp_initial_amount)?;
+        let inp_initial_amount: u64 = fee_quote.total;
 
         // Perform transfer
         let mut swap_account: Pubkey = Pubkey::default();
@@ -349,31 +1909,14 @@ mod token_agent {
         if inp_initial_amount > 0 {
             // Swap if requested
             if inp_swap {
-                let acc_swap_token = ctx.remaining_accounts.get(0).unwrap();        // User Swap Token
-
-                // Setup up token delegate if needed
-                if inp_link_token {
-                    let cpi_accounts = DelegateApprove {
-                        allowance: ctx.accounts.allowance.to_account_info(),
-                        allowance_payer: ctx.accounts.user_key.to_account_info(),
-                        owner: ctx.accounts.user_key.to_account_info(),
-                        delegate: ctx.accounts.root_key.to_account_info(),
-                        delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                        token_account: acc_swap_token.clone(),
-                        token_program: ctx.accounts.token_program.to_account_info(),
-                        system_program: ctx.accounts.system_program.to_account_info(),
-                    };
-                    let cpi_program = ctx.accounts.delegate_program.to_account_info();
-                    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-                    token_delegate::cpi::delegate_approve(cpi_ctx, true, u64::MAX, u64::MAX)?;
-                }
+                let acc_swap_token = swap_token_account(remaining_accounts)?;        // User Swap Token
 
                 // Verify token agent's swap destination associated token
                 let derived_swap_key = Pubkey::create_program_address(
                     &[
                         &ctx.accounts.root_key.to_account_info().key.to_bytes(),
                         &Token::id().to_bytes(),
-                        &mrch_approval.token_mint.to_bytes(),
+                        &settlement.token_mint.to_bytes(),
                         &[inp_swap_dst_nonce]
                     ],
                     &AssociatedToken::id()
@@ -388,53 +1931,42 @@ mod token_agent {
                     msg!("Invalid swap mode");
                     return Err(ErrorCode::InvalidSwapMode.into());
                 }
-                if swap_mode.unwrap() == SwapMode::AtxSwapContractV1 {
-                    //msg!("Atellix: Attempt swap");
-                    swap_account = acc_swap_token.key();
-                    let sw_program = ctx.remaining_accounts.get(1).unwrap().clone();
-                    let sw_accounts = Swap {
-                        swap_user: ctx.accounts.user_key.to_account_info(),
-                        swap_data: ctx.remaining_accounts.get(2).unwrap().clone(),
-                        inb_token_src: acc_swap_token.clone(),
-                        inb_token_dst: ctx.remaining_accounts.get(3).unwrap().clone(),
-                        out_token_src: ctx.remaining_accounts.get(4).unwrap().clone(),
-                        out_token_dst: ctx.accounts.token_account.to_account_info(),    // Token agent swap destination
-                        fees_token: ctx.remaining_accounts.get(5).unwrap().clone(),
-                        token_program: ctx.accounts.token_program.to_account_info(),
-                    };
-                    let mut sw_ctx = CpiContext::new(sw_program, sw_accounts);
-                    if ctx.remaining_accounts.len() > 6 { // Oracle Data Account (if needed)
-                        sw_ctx = sw_ctx.with_remaining_accounts(vec![ctx.remaining_accounts.get(6).unwrap().clone()]);
-                    }
-                    swap_contract::cpi::swap(sw_ctx, inp_swap_data_nonce, inp_swap_inb_nonce, inp_swap_out_nonce, 0, inp_swap_direction, false, true, inp_initial_amount)?;
-                }
+                let swap_accounts = parse_swap_accounts(swap_mode.unwrap(), remaining_accounts, None, &settlement.token_mint)?;
+                //msg!("Atellix: Attempt swap");
+                swap_account = acc_swap_token.key();
+                swap_tokens(&swap_accounts,
+                    &ctx.accounts.user_key.to_account_info(),
+                    &swap_accounts.swap_token,
+                    &ctx.accounts.token_account.to_account_info(),  // Token agent swap destination
+                    &ctx.accounts.token_program.to_account_info(),
+                    &[],
+                    SwapParams { direction: inp_swap_direction, data_nonce: inp_swap_data_nonce, inb_nonce: inp_swap_inb_nonce, out_nonce: inp_swap_out_nonce },
+                    inp_initial_amount,
+                    swap_input_limit(inp_swap_max_input, 0, 0)?,
+                    inp_swap_exact_output,
+                )?;
             }
 
             let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
             let root_pda_signer = &[&root_pda_seeds[..]];
 
             // Calculate fees
-            if mrch_approval.fees_bps > 0 {
-                let f1: u128 = (net_amount as u128) << 64;
-                let f2: u128 = f1.checked_mul(mrch_approval.fees_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
-                let f3: u128 = f2.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
-                let fees: u64 = (f3 >> 64) as u64;
-                if fees > 0 {
-                    net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
-                    fee_amount = fees;
-                    let cpi_accounts = Transfer {
-                        from: ctx.accounts.token_account.to_account_info(),
-                        to: ctx.accounts.fees_account.to_account_info(),
-                        authority: if inp_swap { ctx.accounts.root_key.to_account_info() } else { ctx.accounts.user_key.to_account_info() },
-                    };
-                    let cpi_program = ctx.accounts.token_program.to_account_info();
-                    let cpi_ctx = if inp_swap {
-                        CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer)
-                    } else {
-                        CpiContext::new(cpi_program, cpi_accounts)
-                    };
-                    token::transfer(cpi_ctx, fees)?;
-                }
+            let fees: u64 = fee_quote.fee_amount;
+            if fees > 0 {
+                net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
+                fee_amount = fees;
+                let cpi_accounts = Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: ctx.accounts.fees_account.to_account_info(),
+                    authority: if inp_swap { ctx.accounts.root_key.to_account_info() } else { ctx.accounts.user_key.to_account_info() },
+                };
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx = if inp_swap {
+                    CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer)
+                } else {
+                    CpiContext::new(cpi_program, cpi_accounts)
+                };
+                token::transfer(cpi_ctx, fees)?;
             }
 
             let cpi_accounts = Transfer {
@@ -472,7 +2004,7 @@ mod token_agent {
         subscr.merchant_approval = *ctx.accounts.merchant_approval.to_account_info().key;
         subscr.manager_key = mgr_approval.manager_key;
         subscr.manager_approval = *ctx.accounts.manager_approval.to_account_info().key;
-        subscr.token_mint = mrch_approval.token_mint;
+        subscr.token_mint = settlement.token_mint;
         subscr.token_account = *ctx.accounts.token_account.to_account_info().key;
         subscr.swap_account = swap_account;
         subscr.subscr_id = inp_subscr_id;
@@ -488,18 +2020,82 @@ mod token_agent {
         subscr.swap = inp_swap;
         subscr.swap_direction = inp_swap_direction;
         subscr.swap_mode = inp_swap_mode;
+        subscr.swap_max_input = inp_swap_max_input;
+        subscr.swap_slippage_bps = inp_swap_slippage_bps;
+        subscr.swap_exact_output = inp_swap_exact_output;
+        subscr.referrer = inp_referrer;
+        subscr.referral_bps = inp_referral_bps;
+        subscr.referral_rebills = inp_referral_rebills;
+        subscr.rent_payer = *ctx.accounts.rent_payer.to_account_info().key;
+        subscr.rent_reclaim_delay = inp_rent_reclaim_delay;
+        if coupon.is_some() {
+            apply_coupon(&mut subscr, acc_coupon.unwrap().key, coupon.as_ref().unwrap());
+            if inp_initial_amount > 0 || discount > 0 {
+                subscr.coupon_uses = 1;
+            }
+        }
+        if inp_initial_amount > 0 {
+            subscr.total_charged = inp_initial_amount;
+            subscr.total_fees = fee_amount;
+            subscr.last_payment = inp_initial_amount;
+            subscr.last_payment_ts = ts;
+        }
+
+        // The user reimburses the sponsor in addition to the initial payment
+        if acc_sponsor_token.is_some() {
+            let acc_sponsor_token = acc_sponsor_token.unwrap();
+            let (sponsor_token, _) = Pubkey::find_program_address(
+                &[
+                    &ctx.accounts.rent_payer.key.to_bytes(),
+                    &Token::id().to_bytes(),
+                    &settlement.token_mint.to_bytes(),
+                ],
+                &AssociatedToken::id()
+            );
+            verify_matching_accounts(&sponsor_token, acc_sponsor_token.key,
+                Some(String::from("Invalid sponsor token account"))
+            )?;
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.token_account.to_account_info(),
+                to: acc_sponsor_token.clone(),
+                authority: ctx.accounts.user_key.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token::transfer(cpi_ctx, inp_sponsor_amount)?;
+            msg!("Sponsor reimbursement: {}", inp_sponsor_amount.to_string());
+        }
+
+        // Link the funding account to the token delegate for the allowance needed by the subscription terms
+        if inp_link_token {
+            let acc_funding = if inp_swap { swap_token_account(remaining_accounts)?.clone() } else { ctx.accounts.token_account.to_account_info() };
+            let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+            let approve_amount: u64 = reserve_allowance(&mut subscr, &mut ctx.accounts.token_link,
+                ctx.accounts.allowance.key, acc_funding.key, ctx.accounts.rent_payer.key, link_bump,
+            )?;
+            let cpi_accounts = DelegateApprove {
+                allowance: ctx.accounts.allowance.to_account_info(),
+                allowance_payer: ctx.accounts.user_key.to_account_info(),
+                owner: ctx.accounts.user_key.to_account_info(),
+                delegate: ctx.accounts.root_key.to_account_info(),
+                delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                token_account: acc_funding,
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.delegate_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+        } else {
+            record_link_payer(&mut ctx.accounts.token_link, ctx.accounts.rent_payer.key, *ctx.bumps.get("token_link").unwrap());
+        }
         store_struct::<SubscrData>(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
 
         msg!("atellix-log");
         emit!(SubscrEvent {
-            event_hash: 176440469768111763486207729736362869784, // solana/program/token-agent/subscribe
-            slot: clock.slot,
             merchant_tx_id: mrch_approval.tx_count,
-            merchant_key: subscr.merchant_key,
             merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
-            dest_account: get_dest_account(&ctx.accounts.merchant_approval.to_account_info())?,
-            user_key: subscr.user_key,
-            subscr_data: ctx.accounts.subscr_data.key(),
+            dest_account: settlement.dest_account,
             subscr_id: inp_subscr_id,
             payment_id: inp_payment_id,
             rebill_event: 0,
@@ -508,6 +2104,148 @@ mod token_agent {
             fees: fee_amount,
             next_rebill: inp_next_rebill,
             swap: inp_swap,
+            funding_account: if inp_swap { swap_account } else { subscr.token_account },
+            discount,
+            sponsor_amount: inp_sponsor_amount,
+            ..SubscrEvent::for_subscription(&subscr, ctx.accounts.subscr_data.key(),
+                176440469768111763486207729736362869784, // solana/program/token-agent/subscribe
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    // Create a subscription from terms the user signed off-chain, submitted by a relayer that pays for the accounts
+    pub fn subscribe_intent<'info>(ctx: Context<'_, '_, '_, 'info, CreateSubscrIntent<'info>>,
+        inp_intent: SubscrIntent,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let ts = clock.unix_timestamp;
+
+        // Verify the intent matches the accounts and the user's signature
+        if ts > inp_intent.expires {
+            msg!("Intent expired");
+            return Err(ErrorCode::Expired.into());
+        }
+        verify_matching_accounts(&inp_intent.program_id, &crate::ID,
+            Some(String::from("Intent program does not match"))
+        )?;
+        verify_matching_accounts(&inp_intent.user_key, ctx.accounts.user_key.key,
+            Some(String::from("Intent user does not match"))
+        )?;
+        verify_matching_accounts(&inp_intent.merchant_approval, ctx.accounts.merchant_approval.key,
+            Some(String::from("Intent merchant approval does not match"))
+        )?;
+        verify_matching_accounts(&inp_intent.manager_approval, ctx.accounts.manager_approval.key,
+            Some(String::from("Intent manager approval does not match"))
+        )?;
+        verify_matching_accounts(&inp_intent.token_account, ctx.accounts.token_account.key,
+            Some(String::from("Intent token account does not match"))
+        )?;
+        verify_intent_signature(&ctx.accounts.instructions.to_account_info(), ctx.accounts.user_key.key, &intent_message(&inp_intent)?)?;
+
+        // Verify network authority
+        let netauth = &ctx.accounts.net_auth.to_account_info().key;
+        verify_matching_accounts(netauth, &ctx.accounts.merchant_approval.owner,
+            Some(String::from("Invalid merchant approval owner"))
+        )?;
+        verify_matching_accounts(netauth, &ctx.accounts.manager_approval.owner,
+            Some(String::from("Invalid manager approval owner"))
+        )?;
+        let mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
+        if !mrch_approval.active {
+            msg!("Inactive merchant approval");
+            return Err(ErrorCode::NotApproved.into());
+        }
+        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
+        if !mgr_approval.active {
+            msg!("Inactive manager approval");
+            return Err(ErrorCode::NotApproved.into());
+        }
+
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
+        let user_token = load_struct::<TokenAccount>(&ctx.accounts.token_account.to_account_info())?;
+        verify_matching_accounts(&user_token.owner, ctx.accounts.user_key.key,
+            Some(String::from("Token account owner does not match user"))
+        )?;
+        let (acc_merchant_mint, _) = split_merchant_mint(&mrch_approval, &user_token.mint, ctx.remaining_accounts)?;
+        let settlement = get_settlement(&mrch_approval, ctx.accounts.merchant_approval.key, &user_token.mint, acc_merchant_mint)?;
+
+        // Verify input
+        let max_delay: i64 = verify_subscr_timeframe(inp_intent.period, 0,
+            inp_intent.not_valid_before, inp_intent.not_valid_after, inp_intent.next_rebill, ts,
+        )?;
+
+        // Create subscription data
+        let mut subscr = SubscrData::default();
+        subscr.user_key = inp_intent.user_key;
+        subscr.approval_program = *ctx.accounts.net_auth.to_account_info().key;
+        subscr.merchant_key = mrch_approval.merchant_key;
+        subscr.merchant_approval = inp_intent.merchant_approval;
+        subscr.manager_key = mgr_approval.manager_key;
+        subscr.manager_approval = inp_intent.manager_approval;
+        subscr.token_mint = settlement.token_mint;
+        subscr.token_account = inp_intent.token_account;
+        subscr.subscr_id = inp_intent.subscr_id;
+        subscr.rebill_max = inp_intent.rebill_max;
+        subscr.next_rebill = inp_intent.next_rebill;
+        subscr.max_delay = max_delay;
+        subscr.not_valid_before = inp_intent.not_valid_before;
+        subscr.not_valid_after = inp_intent.not_valid_after;
+        subscr.period = inp_intent.period;
+        subscr.period_budget = inp_intent.period_budget;
+        subscr.use_total = inp_intent.use_total;
+        subscr.total_budget = inp_intent.total_budget;
+        subscr.rent_payer = *ctx.accounts.rent_payer.to_account_info().key;
+        subscr.rent_reclaim_delay = MANAGER_CLOSE_DELAY;
+
+        // Reserve the allowance on the user's token link, approving more requires the user's signature so rebills
+        // are limited to the allowance the user already approved until the user next links the token account
+        let acc_token_link = ctx.accounts.token_link.to_account_info();
+        if acc_token_link.data_is_empty() {
+            msg!("Intent subscriptions require a token account already linked to the token delegate");
+            return Err(ErrorCode::InvalidIntent.into());
+        }
+        let (link_key, _) = token_link_address(ctx.accounts.allowance.key);
+        verify_matching_accounts(&link_key, acc_token_link.key,
+            Some(String::from("Invalid token link account"))
+        )?;
+        verify_matching_accounts(&crate::ID, acc_token_link.owner,
+            Some(String::from("Invalid token link owner"))
+        )?;
+        let mut link = load_struct::<TokenLink>(&acc_token_link)?;
+        verify_matching_accounts(&link.allowance, ctx.accounts.allowance.key,
+            Some(String::from("Allowance does not match token link"))
+        )?;
+        let link_bump: u8 = link.bump;
+        let link_payer: Pubkey = link.rent_payer;
+        reserve_allowance(&mut subscr, &mut link, ctx.accounts.allowance.key, &inp_intent.token_account, &link_payer, link_bump)?;
+        update_struct(&link, &acc_token_link)?;
+        store_struct::<SubscrData>(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
+
+        // Each intent nonce can only be used once
+        let receipt = &mut ctx.accounts.intent_receipt;
+        receipt.user_key = inp_intent.user_key;
+        receipt.nonce = inp_intent.nonce;
+        receipt.subscr_data = ctx.accounts.subscr_data.key();
+        receipt.bump = *ctx.bumps.get("intent_receipt").unwrap();
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            merchant_tx_id: mrch_approval.tx_count,
+            dest_account: settlement.dest_account,
+            rebill_event: 0,
+            swap: false,
+            total_charged: 0,
+            total_fees: 0,
+            last_payment: 0,
+            last_payment_ts: 0,
+            funding_account: subscr.token_account,
+            ..SubscrEvent::for_subscription(&subscr, ctx.accounts.subscr_data.key(),
+                333670100698283471276307893693457039288, // solana/program/token-agent/subscribe_intent
+                clock.slot,
+            )
         });
 
         Ok(())
@@ -521,11 +2259,11 @@ mod token_agent {
         inp_amount: u64,
         inp_payment_id: u128,
         inp_next_rebill: i64,
-        //inp_rebill_max: u32,
+        inp_rebill_max: u32,
         inp_period: u8,
         inp_period_budget: u64,
-        //inp_use_total: bool,
-        //inp_total_budget: u64,
+        inp_use_total: bool,
+        inp_total_budget: u64,
         inp_max_delay: i64,
         inp_not_valid_before: i64,
         inp_not_valid_after: i64,
@@ -536,6 +2274,9 @@ mod token_agent {
         inp_swap_inb_nonce: u8,
         inp_swap_out_nonce: u8,
         inp_swap_dst_nonce: u8,
+        inp_swap_max_input: u64,
+        inp_swap_slippage_bps: u32,
+        inp_swap_exact_output: bool,
     ) -> anchor_lang::Result<()> {
 
         /*msg!("inp_merchant_nonce: {}", inp_merchant_nonce.to_string());
@@ -547,6 +2288,9 @@ mod token_agent {
         msg!("inp_period: {}", inp_period.to_string());
         msg!("inp_period_budget: {}", inp_period_budget.to_string());
         msg!("inp_next_rebill: {}", inp_next_rebill.to_string());
+        msg!("inp_rebill_max: {}", inp_rebill_max.to_string());
+        msg!("inp_use_total: {}", inp_use_total.to_string());
+        msg!("inp_total_budget: {}", inp_total_budget.to_string());
         msg!("inp_max_delay: {}", inp_max_delay.to_string());
         msg!("inp_not_valid_before: {}", inp_not_valid_before.to_string());
         msg!("inp_not_valid_after: {}", inp_not_valid_after.to_string());
@@ -560,7 +2304,7 @@ mod token_agent {
 
         let clock = Clock::get()?;
         let ts = clock.unix_timestamp;
-        let mut subscr = load_struct::<SubscrData>(&ctx.accounts.subscr_data.to_account_info())?;
+        let mut subscr = load_subscr(&ctx.accounts.subscr_data.to_account_info())?;
         // Verify user key is the same
         verify_matching_accounts(&subscr.user_key, &ctx.accounts.user_key.to_account_info().key,
             Some(String::from("User key does not match"))
@@ -569,24 +2313,60 @@ mod token_agent {
         // Deactivate if requested by user
         if !inp_active {
             subscr.active = false;
+            subscr.cancelled_at = ts;
+
+            // Shrink the allowance by the subscription's reservation, or revoke it if no other subscription uses it
+            if subscr.allowance != Pubkey::default() {
+                verify_matching_accounts(&subscr.allowance, ctx.accounts.allowance.key,
+                    Some(String::from("Allowance does not match subscription"))
+                )?;
+                let acc_funding = if subscr.swap { swap_token_account(ctx.remaining_accounts)?.clone() } else { ctx.accounts.token_account.to_account_info() };
+                verify_matching_accounts(&ctx.accounts.token_link.token_account, acc_funding.key,
+                    Some(String::from("Token account does not match token link"))
+                )?;
+                unlink_allowance(&mut subscr, &mut ctx.accounts.token_link);
+                if ctx.accounts.token_link.subscriptions == 0 {
+                    revoke_allowance(&ctx.accounts.token_link,
+                        &ctx.accounts.token_link.to_account_info(),
+                        &ctx.accounts.allowance.to_account_info(),
+                        &acc_funding,
+                        &ctx.accounts.user_key.to_account_info(),
+                        &ctx.accounts.link_payer.to_account_info(),
+                        &ctx.accounts.root_key.to_account_info(),
+                        inp_root_nonce,
+                        &ctx.accounts.delegate_program.to_account_info(),
+                    )?;
+                } else {
+                    let approve_amount: u64 = approved_allowance(&ctx.accounts.token_link);
+                    let cpi_accounts = DelegateApprove {
+                        allowance: ctx.accounts.allowance.to_account_info(),
+                        allowance_payer: ctx.accounts.user_key.to_account_info(),
+                        owner: ctx.accounts.user_key.to_account_info(),
+                        delegate: ctx.accounts.root_key.to_account_info(),
+                        delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                        token_account: acc_funding,
+                        token_program: ctx.accounts.token_program.to_account_info(),
+                        system_program: ctx.accounts.system_program.to_account_info(),
+                    };
+                    let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                    let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                    token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+                }
+            }
+
+            // Release the fallback reservations, the fallback token links follow the swap token account
+            let link_start: usize = if subscr.swap { 1 } else { 0 };
+            release_fallback_allowances(&mut subscr, ctx.remaining_accounts.get(link_start..).unwrap_or(&[]))?;
+            update_struct(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
+
             msg!("atellix-log");
             emit!(SubscrEvent {
-                event_hash: 163361025719893016519135760137561968517, // solana/program/token-agent/update_subscription/cancel
-                slot: clock.slot,
-                merchant_tx_id: 0,
-                merchant_key: subscr.merchant_key,
-                merchant_token: Pubkey::default(),
-                dest_account: Pubkey::default(),
-                user_key: subscr.user_key,
-                subscr_data: *ctx.accounts.subscr_data.to_account_info().key,
-                subscr_id: subscr.subscr_id,
-                payment_id: 0,
                 rebill_event: 0,
-                total: 0,
-                amount: 0,
-                fees: 0,
                 next_rebill: -1,
-                swap: subscr.swap,
+                ..SubscrEvent::for_subscription(&subscr, *ctx.accounts.subscr_data.to_account_info().key,
+                    163361025719893016519135760137561968517, // solana/program/token-agent/update_subscription/cancel
+                    clock.slot,
+                )
             });
             return Ok(());
         }
@@ -596,13 +2376,21 @@ mod token_agent {
             Some(String::from("Approval program does not match"))
         )?;
 
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
+        let token_mint: Pubkey = load_struct::<TokenAccount>(&ctx.accounts.token_account.to_account_info())?.mint;
+        let (acc_merchant_mint, remaining_accounts) = split_merchant_mint(
+            &load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?, &token_mint, ctx.remaining_accounts,
+        )?;
+
         // Verify network authority accounts
-        let fees_bps: u32 = verify_merchant_approval(
+        let settlement = verify_merchant_settlement(
             inp_dest_nonce,
             &ctx.accounts.net_auth.to_account_info().key,
             &ctx.accounts.merchant_approval.to_account_info(),
             &ctx.accounts.merchant_token.to_account_info(),
             &ctx.accounts.fees_account.to_account_info(),
+            &token_mint,
+            acc_merchant_mint,
         )?;
         verify_manager_approval(&ctx.accounts.net_auth.to_account_info().key, &ctx.accounts.manager_approval.to_account_info())?;
 
@@ -654,46 +2442,27 @@ mod token_agent {
             msg!("Next rebill not beginning of period");
             return Err(ErrorCode::InvalidTimeframe.into());
         }
-
-       // Setup up token delegate if needed
-        if !inp_swap && inp_link_token {
-            let cpi_accounts = DelegateApprove {
-                allowance: ctx.accounts.allowance.to_account_info(),
-                allowance_payer: ctx.accounts.user_key.to_account_info(),
-                owner: ctx.accounts.user_key.to_account_info(),
-                delegate: ctx.accounts.root_key.to_account_info(),
-                delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                token_account: ctx.accounts.token_account.to_account_info(),
-                token_program: ctx.accounts.token_program.to_account_info(),
-                system_program: ctx.accounts.system_program.to_account_info(),
-            };
-            let cpi_program = ctx.accounts.delegate_program.to_account_info();
-            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-            token_delegate::cpi::delegate_approve(cpi_ctx, true, u64::MAX, u64::MAX)?;
-        }
-
-        // Link swap token if requested
-        if inp_swap {
-            let acc_swap_token = ctx.remaining_accounts.get(0).unwrap();        // User Swap Token
-
-            // Setup up token delegate if needed
-            if inp_link_token {
-                let cpi_accounts = DelegateApprove {
-                    allowance: ctx.accounts.allowance.to_account_info(),
-                    allowance_payer: ctx.accounts.user_key.to_account_info(),
-                    owner: ctx.accounts.user_key.to_account_info(),
-                    delegate: ctx.accounts.root_key.to_account_info(),
-                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                    token_account: acc_swap_token.clone(),
-                    token_program: ctx.accounts.token_program.to_account_info(),
-                    system_program: ctx.accounts.system_program.to_account_info(),
-                };
-                let cpi_program = ctx.accounts.delegate_program.to_account_info();
-                let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-                token_delegate::cpi::delegate_approve(cpi_ctx, true, u64::MAX, u64::MAX)?;
-            }
+        if inp_swap_slippage_bps > 10000 {
+            msg!("Invalid swap slippage");
+            return Err(ErrorCode::InvalidSlippage.into());
+        }
+        verify_swap_quote(inp_swap && (inp_swap_exact_output || inp_swap_slippage_bps > 0), inp_swap_mode)?;
+        if inp_rebill_max > 0 && inp_rebill_max < subscr.rebill_events {
+            msg!("Maximum rebills below rebill events: {}", subscr.rebill_events.to_string());
+            return Err(ErrorCode::InvalidRebillMax.into());
+        }
+        let mut total_budget: u64 = 0;
+        if inp_use_total {
+            // Total budget is the lifetime cap, the remaining budget excludes the amount already spent
+            if inp_total_budget < subscr.budget_spent {
+                msg!("Total budget below amount already spent: {}", subscr.budget_spent.to_string());
+                return Err(ErrorCode::InvalidTotalBudget.into());
+            }
+            total_budget = inp_total_budget.checked_sub(subscr.budget_spent).ok_or(error!(ErrorCode::Overflow))?;
         }
 
+        let fee_quote = quote_settlement_fees(&ctx.accounts.fee_policy.to_account_info(), ctx.accounts.merchant_approval.key, &settlement, true, inp_amount)?;
+        let inp_amount: u64 = fee_quote.total;
         let mut net_amount: u64 = inp_amount;
         let mut fee_amount: u64 = 0;
         if inp_amount > 0 {
@@ -705,7 +2474,7 @@ mod token_agent {
                     &[
                         &ctx.accounts.root_key.to_account_info().key.to_bytes(),
                         &Token::id().to_bytes(),
-                        &get_token_mint(&ctx.accounts.merchant_approval.to_account_info())?.to_bytes(),
+                        &settlement.token_mint.to_bytes(),
                         &[inp_swap_dst_nonce]
                     ],
                     &AssociatedToken::id()
@@ -719,50 +2488,40 @@ mod token_agent {
                     msg!("Invalid swap mode: {}", inp_swap_mode.to_string());
                     return Err(ErrorCode::InvalidSwapMode.into());
                 }
-                if swap_mode.unwrap() == SwapMode::AtxSwapContractV1 {
-                    let acc_swap_token = ctx.remaining_accounts.get(0).unwrap();        // User Swap Token
-                    let sw_program = ctx.remaining_accounts.get(1).unwrap().clone();
-                    let sw_accounts = Swap {
-                        swap_user: ctx.accounts.user_key.to_account_info(),
-                        swap_data: ctx.remaining_accounts.get(2).unwrap().clone(),
-                        inb_token_src: acc_swap_token.clone(),
-                        inb_token_dst: ctx.remaining_accounts.get(3).unwrap().clone(),
-                        out_token_src: ctx.remaining_accounts.get(4).unwrap().clone(),
-                        out_token_dst: ctx.accounts.token_account.to_account_info(),    // Token Agent PDA
-                        fees_token: ctx.remaining_accounts.get(5).unwrap().clone(),
-                        token_program: ctx.accounts.token_program.to_account_info(),
-                    };
-                    let mut sw_ctx = CpiContext::new(sw_program, sw_accounts);
-                    if ctx.remaining_accounts.len() > 6 { // Oracle Data Account (if needed)
-                        sw_ctx = sw_ctx.with_remaining_accounts(vec![ctx.remaining_accounts.get(6).unwrap().clone()]);
-                    }
-                    swap_contract::cpi::swap(sw_ctx, inp_swap_data_nonce, inp_swap_inb_nonce, inp_swap_out_nonce, 0, inp_swap_direction, false, true, inp_amount)?;
-                }
+                let swap_accounts = parse_swap_accounts(swap_mode.unwrap(), remaining_accounts, None, &settlement.token_mint)?;
+                swap_tokens(&swap_accounts,
+                    &ctx.accounts.user_key.to_account_info(),
+                    &swap_accounts.swap_token,                      // User Swap Token
+                    &ctx.accounts.token_account.to_account_info(),  // Token Agent PDA
+                    &ctx.accounts.token_program.to_account_info(),
+                    &[],
+                    SwapParams { direction: inp_swap_direction, data_nonce: inp_swap_data_nonce, inb_nonce: inp_swap_inb_nonce, out_nonce: inp_swap_out_nonce },
+                    inp_amount,
+                    swap_input_limit(inp_swap_max_input, 0, 0)?,
+                    inp_swap_exact_output,
+                )?;
             }
 
             let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
             let root_pda_signer = &[&root_pda_seeds[..]];
 
             // Calculate fees
-            if fees_bps > 0 {
-                let fees: u64 = calculate_fees(net_amount, fees_bps)?;
-                if fees > 0 {
-                    net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
-                    fee_amount = fees;
-                    let cpi_accounts = Transfer {
-                        from: ctx.accounts.token_account.to_account_info(),
-                        to: ctx.accounts.fees_account.to_account_info(),
-                        authority: if inp_swap { ctx.accounts.root_key.to_account_info() } else { ctx.accounts.user_key.to_account_info() },
-                    };
-                    let cpi_program = ctx.accounts.token_program.to_account_info();
-                    let cpi_ctx = if inp_swap {
-                        CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer)
-                    } else {
-                        CpiContext::new(cpi_program, cpi_accounts)
-                    };
-                    token::transfer(cpi_ctx, fees)?;
-                }
-                //msg!("Starting Amount: {} Ending Amount: {} Fees: {}", inp_amount.to_string(), amount.to_string(), fees.to_string());
+            let fees: u64 = fee_quote.fee_amount;
+            if fees > 0 {
+                net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
+                fee_amount = fees;
+                let cpi_accounts = Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: ctx.accounts.fees_account.to_account_info(),
+                    authority: if inp_swap { ctx.accounts.root_key.to_account_info() } else { ctx.accounts.user_key.to_account_info() },
+                };
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx = if inp_swap {
+                    CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer)
+                } else {
+                    CpiContext::new(cpi_program, cpi_accounts)
+                };
+                token::transfer(cpi_ctx, fees)?;
             }
             let cpi_accounts = Transfer {
                 from: ctx.accounts.token_account.to_account_info(),
@@ -790,40 +2549,93 @@ mod token_agent {
             }
         }
 
+        // Referral and coupon terms belong to the merchant that issued them
+        if subscr.merchant_approval != *ctx.accounts.merchant_approval.to_account_info().key {
+            subscr.referrer = Pubkey::default();
+            subscr.referral_bps = 0;
+            subscr.referral_rebills = 0;
+            subscr.referral_count = 0;
+            subscr.coupon = Pubkey::default();
+            subscr.coupon_uses = 0;
+        }
+
         // Update subscription data
         subscr.active = true;
         subscr.merchant_key = get_merchant_key(&ctx.accounts.merchant_approval.to_account_info())?;
         subscr.merchant_approval = *ctx.accounts.merchant_approval.to_account_info().key;
         subscr.manager_key = get_manager_key(&ctx.accounts.manager_approval.to_account_info())?;
         subscr.manager_approval = *ctx.accounts.manager_approval.to_account_info().key;
-        subscr.token_mint = get_token_mint(&ctx.accounts.merchant_approval.to_account_info())?;
+        subscr.token_mint = settlement.token_mint;
         subscr.token_account = *ctx.accounts.token_account.to_account_info().key;
-        subscr.swap_account = if inp_swap { *ctx.remaining_accounts.get(0).unwrap().key } else { Pubkey::default() };
-        //subscr.rebill_max = inp_rebill_max;
+        subscr.swap_account = if inp_swap { *swap_token_account(remaining_accounts)?.key } else { Pubkey::default() };
+        subscr.rebill_max = inp_rebill_max;
         subscr.next_rebill = inp_next_rebill;
         subscr.max_delay = inp_max_delay;
         subscr.not_valid_before = inp_not_valid_before;
         subscr.not_valid_after = inp_not_valid_after;
         subscr.period = inp_period;
         subscr.period_budget = inp_period_budget;
-        //subscr.use_total = inp_use_total;
-        //subscr.total_budget = inp_total_budget;
+        subscr.use_total = inp_use_total;
+        subscr.total_budget = total_budget;
         subscr.swap = inp_swap;
         subscr.swap_direction = inp_swap_direction;
         subscr.swap_mode = inp_swap_mode;
-        store_struct(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
+        subscr.swap_max_input = inp_swap_max_input;
+        subscr.swap_slippage_bps = inp_swap_slippage_bps;
+        subscr.swap_exact_output = inp_swap_exact_output;
+        if inp_amount > 0 {
+            subscr.total_charged = subscr.total_charged.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+            subscr.total_fees = subscr.total_fees.checked_add(fee_amount).ok_or(error!(ErrorCode::Overflow))?;
+            subscr.last_payment = inp_amount;
+            subscr.last_payment_ts = ts;
+        }
+
+        // Link the funding account to the token delegate for the allowance needed by the updated terms
+        let link_token: bool = inp_link_token || subscr.allowance != Pubkey::default();
+        if subscr.allowance != Pubkey::default() && subscr.allowance != *ctx.accounts.allowance.key {
+            release_prev_allowance(&mut subscr,
+                &ctx.accounts.prev_token_link.to_account_info(),
+                &ctx.accounts.prev_allowance.to_account_info(),
+                &ctx.accounts.prev_token_account.to_account_info(),
+                &ctx.accounts.user_key.to_account_info(),
+                &ctx.accounts.link_payer.to_account_info(),
+                &ctx.accounts.root_key.to_account_info(),
+                inp_root_nonce,
+                &ctx.accounts.delegate_root.to_account_info(),
+                &ctx.accounts.delegate_program.to_account_info(),
+                &ctx.accounts.token_program.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+        }
+        if link_token {
+            let acc_funding = if inp_swap { swap_token_account(remaining_accounts)?.clone() } else { ctx.accounts.token_account.to_account_info() };
+            let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+            let approve_amount: u64 = reserve_allowance(&mut subscr, &mut ctx.accounts.token_link,
+                ctx.accounts.allowance.key, acc_funding.key, ctx.accounts.user_key.key, link_bump,
+            )?;
+            let cpi_accounts = DelegateApprove {
+                allowance: ctx.accounts.allowance.to_account_info(),
+                allowance_payer: ctx.accounts.user_key.to_account_info(),
+                owner: ctx.accounts.user_key.to_account_info(),
+                delegate: ctx.accounts.root_key.to_account_info(),
+                delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                token_account: acc_funding,
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.delegate_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+        } else {
+            record_link_payer(&mut ctx.accounts.token_link, ctx.accounts.user_key.key, *ctx.bumps.get("token_link").unwrap());
+        }
+        update_struct(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
 
         msg!("atellix-log");
         emit!(SubscrEvent {
-            event_hash: 298296161986799263364555576740275705662, // solana/program/token-agent/update_subscription
-            slot: clock.slot,
             merchant_tx_id: get_tx_count(&ctx.accounts.merchant_approval.to_account_info())?,
-            merchant_key: subscr.merchant_key,
             merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
-            dest_account: get_dest_account(&ctx.accounts.merchant_approval.to_account_info())?,
-            user_key: subscr.user_key,
-            subscr_data: *ctx.accounts.subscr_data.to_account_info().key,
-            subscr_id: subscr.subscr_id,
+            dest_account: settlement.dest_account,
             payment_id: inp_payment_id,
             rebill_event: 0,
             total: inp_amount,
@@ -831,151 +2643,581 @@ mod token_agent {
             fees: fee_amount,
             next_rebill: inp_next_rebill,
             swap: inp_swap,
+            funding_account: if inp_swap { subscr.swap_account } else { subscr.token_account },
+            ..SubscrEvent::for_subscription(&subscr, *ctx.accounts.subscr_data.to_account_info().key,
+                298296161986799263364555576740275705662, // solana/program/token-agent/update_subscription
+                clock.slot,
+            )
         });
 
         Ok(())
     }
 
     pub fn close_subscription(ctx: Context<CloseSubscr>) -> anchor_lang::Result<()> {
-        let subscr = &ctx.accounts.subscr_data;
+        let clock = Clock::get()?;
+        let subscr = &mut ctx.accounts.subscr_data;
         verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
             Some(String::from("User key does not match subscription"))
         )?;
+        verify_matching_accounts(&rent_recipient(subscr), ctx.accounts.rent_payer.key,
+            Some(String::from("Rent payer does not match subscription"))
+        )?;
+
+        // Closing an active subscription cancels it
+        if subscr.active {
+            subscr.active = false;
+            subscr.cancelled_at = clock.unix_timestamp;
+            msg!("atellix-log");
+            emit!(SubscrEvent {
+                rebill_event: 0,
+                next_rebill: -1,
+                ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                    72520587959778696249734394624991253021, // solana/program/token-agent/close_subscription/cancel
+                    clock.slot,
+                )
+            });
+        }
+
+        // Shrink the allowance by the subscription's reservation, or revoke it if no other subscription uses it
+        if subscr.allowance != Pubkey::default() {
+            verify_matching_accounts(&subscr.allowance, ctx.accounts.allowance.key,
+                Some(String::from("Allowance does not match subscription"))
+            )?;
+            release_allowance(subscr, &ctx.accounts.token_link.to_account_info())?;
+            let link = load_struct::<TokenLink>(&ctx.accounts.token_link.to_account_info())?;
+            verify_matching_accounts(&link.token_account, ctx.accounts.token_account.key,
+                Some(String::from("Token account does not match token link"))
+            )?;
+            if link.subscriptions == 0 {
+                revoke_allowance(&link,
+                    &ctx.accounts.token_link.to_account_info(),
+                    &ctx.accounts.allowance.to_account_info(),
+                    &ctx.accounts.token_account.to_account_info(),
+                    &ctx.accounts.user_key.to_account_info(),
+                    &ctx.accounts.link_payer.to_account_info(),
+                    &ctx.accounts.root_key.to_account_info(),
+                    *ctx.bumps.get("root_key").unwrap(),
+                    &ctx.accounts.delegate_program.to_account_info(),
+                )?;
+            } else {
+                let approve_amount: u64 = approved_allowance(&link);
+                let cpi_accounts = DelegateApprove {
+                    allowance: ctx.accounts.allowance.to_account_info(),
+                    allowance_payer: ctx.accounts.user_key.to_account_info(),
+                    owner: ctx.accounts.user_key.to_account_info(),
+                    delegate: ctx.accounts.root_key.to_account_info(),
+                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                    token_account: ctx.accounts.token_account.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                };
+                let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+            }
+        }
 
-        msg!("Closed Subscription: {}", ctx.accounts.subscr_data.to_account_info().key.to_string());
+        // Release the fallback reservations, the fallback token links are passed as remaining accounts
+        release_fallback_allowances(subscr, ctx.remaining_accounts)?;
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            rebill_event: 0,
+            next_rebill: -1,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                134504778440784166306465195405125346465, // solana/program/token-agent/close_subscription
+                clock.slot,
+            )
+        });
+
+        msg!("Closed Subscription: {}", subscr.key().to_string());
         Ok(())
     }
 
-    pub fn update_manager<'info>(ctx: Context<'_, '_, '_, 'info, UpdateManager<'info>>) -> anchor_lang::Result<()> {
-        let subscr = &mut ctx.accounts.subscr_data;
-        verify_matching_accounts(&subscr.manager_key, &ctx.accounts.manager_prev.to_account_info().key,
-            Some(String::from("Previous manager does not match subscription"))
-        )?;
-        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
-        if !mgr_approval.active {
-            msg!("Inactive manager approval");
-            return Err(ErrorCode::NotApproved.into());
+    pub fn migrate_subscription(ctx: Context<MigrateSubscr>) -> anchor_lang::Result<()> {
+        let acc_subscr = ctx.accounts.subscr_data.to_account_info();
+        let data_len: usize = SubscrData::default().try_to_vec()?.len().checked_add(8).ok_or(error!(ErrorCode::Overflow))?;
+        if acc_subscr.data_len() >= data_len {
+            msg!("Subscription already migrated");
+            return Err(ErrorCode::AlreadyMigrated.into());
         }
-        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
-            Some(String::from("Manager key does not match approval"))
+
+        // Load subscription data in the original (unversioned) layout
+        let legacy = {
+            let data = acc_subscr.try_borrow_data()?;
+            if data.len() < 8 || array_ref![data, 0, 8] != &SubscrData::discriminator() {
+                msg!("Invalid subscription data");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let mut legacy_data: &[u8] = &data[8..];
+            SubscrDataV0::deserialize(&mut legacy_data)?
+        };
+
+        let mut subscr = SubscrData::default();
+        subscr.user_key = legacy.user_key;
+        subscr.approval_program = legacy.approval_program;
+        subscr.merchant_key = legacy.merchant_key;
+        subscr.merchant_approval = legacy.merchant_approval;
+        subscr.manager_key = legacy.manager_key;
+        subscr.manager_approval = legacy.manager_approval;
+        subscr.token_mint = legacy.token_mint;
+        subscr.token_account = legacy.token_account;
+        subscr.swap_account = legacy.swap_account;
+        subscr.subscr_id = legacy.subscr_id;
+        subscr.rebill_events = legacy.rebill_events;
+        subscr.rebill_max = legacy.rebill_max;
+        subscr.next_rebill = legacy.next_rebill;
+        subscr.not_valid_before = legacy.not_valid_before;
+        subscr.not_valid_after = legacy.not_valid_after;
+        subscr.max_delay = legacy.max_delay;
+        subscr.period = legacy.period;
+        subscr.period_budget = legacy.period_budget;
+        subscr.use_total = legacy.use_total;
+        subscr.total_budget = legacy.total_budget;
+        subscr.active = legacy.active;
+        subscr.swap = legacy.swap;
+        subscr.swap_direction = legacy.swap_direction;
+        subscr.swap_mode = legacy.swap_mode;
+
+        // Fund the rent difference for the larger account
+        let rent_min: u64 = Rent::get()?.minimum_balance(data_len);
+        let rent_cur: u64 = acc_subscr.lamports();
+        if rent_min > rent_cur {
+            let cpi_accounts = anchor_lang::system_program::Transfer {
+                from: ctx.accounts.payer.to_account_info(),
+                to: acc_subscr.clone(),
+            };
+            let cpi_program = ctx.accounts.system_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            anchor_lang::system_program::transfer(cpi_ctx, rent_min.checked_sub(rent_cur).ok_or(error!(ErrorCode::Overflow))?)?;
+        }
+        acc_subscr.realloc(data_len, true)?;
+        update_struct(&subscr, &acc_subscr)?;
+
+        msg!("Migrated Subscription: {} Version: {}", acc_subscr.key.to_string(), SUBSCR_DATA_VERSION.to_string());
+        Ok(())
+    }
+
+    pub fn migrate_token_link(ctx: Context<MigrateTokenLink>) -> anchor_lang::Result<()> {
+        let acc_link = ctx.accounts.token_link.to_account_info();
+        let data_len: usize = 125;
+        if acc_link.data_len() >= data_len {
+            msg!("Token link already migrated");
+            return Err(ErrorCode::AlreadyMigrated.into());
+        }
+
+        // Load the token link in the original layout (before rent payers were recorded)
+        let legacy = {
+            let data = acc_link.try_borrow_data()?;
+            if data.len() < 8 || array_ref![data, 0, 8] != &TokenLink::discriminator() {
+                msg!("Invalid token link data");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let mut legacy_data: &[u8] = &data[8..];
+            TokenLinkV0::deserialize(&mut legacy_data)?
+        };
+        let (link_key, _) = token_link_address(&legacy.allowance);
+        verify_matching_accounts(&link_key, acc_link.key,
+            Some(String::from("Invalid token link account"))
+        )?;
+        verify_matching_accounts(&legacy.token_account, &ctx.accounts.token_account.key(),
+            Some(String::from("Token account does not match token link"))
+        )?;
+        verify_matching_accounts(&ctx.accounts.token_account.owner, ctx.accounts.user_key.key,
+            Some(String::from("Token account owner does not match user"))
         )?;
 
-        subscr.manager_key = *ctx.accounts.manager_key.to_account_info().key;
-        subscr.manager_approval = *ctx.accounts.manager_approval.to_account_info().key;
+        // The user funds the larger account and receives the rent when the link closes
+        let link = TokenLink {
+            allowance: legacy.allowance,
+            token_account: legacy.token_account,
+            subscriptions: legacy.subscriptions,
+            approved: legacy.approved,
+            rent_payer: *ctx.accounts.user_key.key,
+            bump: legacy.bump,
+        };
+        let rent_min: u64 = Rent::get()?.minimum_balance(data_len);
+        let rent_cur: u64 = acc_link.lamports();
+        if rent_min > rent_cur {
+            let cpi_accounts = anchor_lang::system_program::Transfer {
+                from: ctx.accounts.user_key.to_account_info(),
+                to: acc_link.clone(),
+            };
+            let cpi_program = ctx.accounts.system_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            anchor_lang::system_program::transfer(cpi_ctx, rent_min.checked_sub(rent_cur).ok_or(error!(ErrorCode::Overflow))?)?;
+        }
+        acc_link.realloc(data_len, true)?;
+        update_struct(&link, &acc_link)?;
+
+        msg!("Migrated Token Link: {}", acc_link.key.to_string());
         Ok(())
     }
 
-    pub fn manager_cancel<'info>(ctx: Context<'_, '_, '_, 'info, ManagerCancel<'info>>) -> anchor_lang::Result<()> {
+    pub fn transfer_subscription<'info>(ctx: Context<'_, '_, '_, 'info, TransferSubscr<'info>>) -> anchor_lang::Result<()> {
         let clock = Clock::get()?;
-
         let subscr = &mut ctx.accounts.subscr_data;
-        verify_matching_accounts(&subscr.manager_key, &ctx.accounts.manager_key.to_account_info().key,
-            Some(String::from("Manager key does not match subscription"))
+        verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match subscription"))
         )?;
-        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
-        if !mgr_approval.active {
-            msg!("Inactive manager approval");
-            return Err(ErrorCode::NotApproved.into());
+        verify_matching_accounts(ctx.accounts.new_user_key.to_account_info().key, &ctx.accounts.token_account.owner,
+            Some(String::from("Token account owner does not match new user"))
+        )?;
+        if subscr.swap {
+            // The current swap token account is passed as a remaining account to verify the swap mint
+            let acc_prev_swap = ctx.remaining_accounts.get(0).ok_or(error!(ErrorCode::InvalidAccount))?;
+            verify_matching_accounts(&subscr.swap_account, acc_prev_swap.key,
+                Some(String::from("Swap token account does not match subscription"))
+            )?;
+            let prev_swap_token = load_struct::<TokenAccount>(acc_prev_swap)?;
+            verify_matching_accounts(&prev_swap_token.mint, &ctx.accounts.token_account.mint,
+                Some(String::from("Swap token mint does not match subscription"))
+            )?;
+        } else {
+            verify_matching_accounts(&subscr.token_mint, &ctx.accounts.token_account.mint,
+                Some(String::from("Token mint does not match subscription"))
+            )?;
         }
-        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
-            Some(String::from("Manager key does not match approval"))
+
+        // The previous owner's fallback accounts are released, their token links follow the swap token account
+        let link_start: usize = if subscr.swap { 1 } else { 0 };
+        release_fallback_allowances(subscr, &ctx.remaining_accounts[link_start..])?;
+
+        // Move the subscription's reservation to the new owner's allowance
+        if subscr.allowance != *ctx.accounts.allowance.key {
+            release_prev_allowance(subscr,
+                &ctx.accounts.prev_token_link.to_account_info(),
+                &ctx.accounts.prev_allowance.to_account_info(),
+                &ctx.accounts.prev_token_account.to_account_info(),
+                &ctx.accounts.user_key.to_account_info(),
+                &ctx.accounts.link_payer.to_account_info(),
+                &ctx.accounts.root_key.to_account_info(),
+                *ctx.bumps.get("root_key").unwrap(),
+                &ctx.accounts.delegate_root.to_account_info(),
+                &ctx.accounts.delegate_program.to_account_info(),
+                &ctx.accounts.token_program.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+        }
+        let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+        let approve_amount: u64 = reserve_allowance(subscr, &mut ctx.accounts.token_link,
+            ctx.accounts.allowance.key, &ctx.accounts.token_account.key(), ctx.accounts.new_user_key.key, link_bump,
         )?;
 
-        subscr.active = false;
+        // Link the new owner's token account to the token delegate
+        let cpi_accounts = DelegateApprove {
+            allowance: ctx.accounts.allowance.to_account_info(),
+            allowance_payer: ctx.accounts.new_user_key.to_account_info(),
+            owner: ctx.accounts.new_user_key.to_account_info(),
+            delegate: ctx.accounts.root_key.to_account_info(),
+            delegate_root: ctx.accounts.delegate_root.to_account_info(),
+            token_account: ctx.accounts.token_account.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.delegate_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+
+        // Swap subscriptions pay from the swap account, the token account remains the token agent's swap destination
+        let prev_user_key = subscr.user_key;
+        subscr.user_key = *ctx.accounts.new_user_key.to_account_info().key;
+        if subscr.swap {
+            subscr.swap_account = ctx.accounts.token_account.key();
+        } else {
+            subscr.token_account = ctx.accounts.token_account.key();
+        }
+        msg!("Transferred Subscription: {} From: {} To: {}", subscr.key().to_string(), prev_user_key.to_string(), subscr.user_key.to_string());
 
         msg!("atellix-log");
         emit!(SubscrEvent {
-            event_hash: 14511983483732720963723889670203659368, // solana/program/token-agent/manager_cancel
-            slot: clock.slot,
-            merchant_tx_id: 0,
-            merchant_key: subscr.merchant_key,
-            merchant_token: Pubkey::default(),
-            dest_account: Pubkey::default(),
-            user_key: subscr.user_key,
-            subscr_data: subscr.key(),
-            subscr_id: subscr.subscr_id,
-            payment_id: 0,
-            rebill_event: 0,
-            total: 0,
-            amount: 0,
-            fees: 0,
-            next_rebill: -1,
-            swap: subscr.swap,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                157016267120624746724564801298191030887, // solana/program/token-agent/transfer_subscription
+                clock.slot,
+            )
         });
 
         Ok(())
     }
 
-    pub fn process<'info>(ctx: Context<'_, '_, '_, 'info, ProcessSubscr<'info>>,
-        inp_dest_nonce: u8,
-        inp_root_nonce: u8,
-        inp_rebill_ts: i64,
-        inp_rebill_str: String,
-        inp_next_rebill: i64,
-        inp_amount: u64,
-        inp_payment_id: u128,
-        inp_swap_data_nonce: u8,
-        inp_swap_inb_nonce: u8,
-        inp_swap_out_nonce: u8,
-        inp_swap_estimate: u64,
+    pub fn update_token_account<'info>(ctx: Context<'_, '_, '_, 'info, UpdateTokenAccount<'info>>,
+        inp_swap: bool,
+        inp_swap_direction: bool,
+        inp_swap_mode: u8,
+        inp_swap_dst_nonce: u8,
     ) -> anchor_lang::Result<()> {
         let clock = Clock::get()?;
-        let ts = clock.unix_timestamp;
-
-        // Validate accounts
-        let mut subscr = load_struct::<SubscrData>(&ctx.accounts.subscr_data.to_account_info())?;
-        verify_matching_accounts(&subscr.manager_approval, ctx.accounts.manager_approval.to_account_info().key,
-            Some(String::from("Manager approval does not match subscription"))
-        )?;
-        verify_matching_accounts(&subscr.merchant_approval, ctx.accounts.merchant_approval.to_account_info().key,
-            Some(String::from("Merchant approval does not match subscription"))
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match subscription"))
         )?;
 
-        if !subscr.active {
-            msg!("Inactive subscription");
-            return Err(ErrorCode::InactiveSubscription.into());
+        // Funding account: the user's token account, or the user's swap token account if swapping
+        let acc_funding: AccountInfo<'info>;
+        if inp_swap {
+            if SwapMode::try_from_primitive(inp_swap_mode).is_err() {
+                msg!("Invalid swap mode: {}", inp_swap_mode.to_string());
+                return Err(ErrorCode::InvalidSwapMode.into());
+            }
+            verify_swap_quote(subscr.swap_exact_output || subscr.swap_slippage_bps > 0, inp_swap_mode)?;
+            // Verify token agent's swap destination associated token
+            let derived_swap_key = Pubkey::create_program_address(
+                &[
+                    &ctx.accounts.root_key.to_account_info().key.to_bytes(),
+                    &Token::id().to_bytes(),
+                    &subscr.token_mint.to_bytes(),
+                    &[inp_swap_dst_nonce]
+                ],
+                &AssociatedToken::id()
+            ).map_err(|_| ErrorCode::InvalidNonce)?;
+            if derived_swap_key != *ctx.accounts.token_account.to_account_info().key {
+                msg!("Invalid swap destination token account");
+                return Err(ErrorCode::InvalidDerivedAccount.into());
+            }
+            acc_funding = swap_token_account(ctx.remaining_accounts)?.clone(); // User Swap Token
+        } else {
+            acc_funding = ctx.accounts.token_account.to_account_info();
         }
-        if subscr.rebill_max > 0 && subscr.rebill_max >= subscr.rebill_events {
-            msg!("Maximum rebills reached");
-            return Err(ErrorCode::MaxRebills.into());
+        let funding_token = load_struct::<TokenAccount>(&acc_funding)?;
+        verify_matching_accounts(&subscr.user_key, &funding_token.owner,
+            Some(String::from("Token account owner does not match subscription"))
+        )?;
+        if !inp_swap {
+            verify_matching_accounts(&subscr.token_mint, &funding_token.mint,
+                Some(String::from("Token mint does not match subscription"))
+            )?;
         }
 
-        // Verfiy token account and mint
-        verify_matching_accounts(&subscr.token_account, &ctx.accounts.token_account.to_account_info().key,
-            Some(String::from("Token account does not match subscription"))
+        // Move the subscription's reservation to the new funding account's allowance
+        if subscr.allowance != *ctx.accounts.allowance.key {
+            release_prev_allowance(subscr,
+                &ctx.accounts.prev_token_link.to_account_info(),
+                &ctx.accounts.prev_allowance.to_account_info(),
+                &ctx.accounts.prev_token_account.to_account_info(),
+                &ctx.accounts.user_key.to_account_info(),
+                &ctx.accounts.link_payer.to_account_info(),
+                &ctx.accounts.root_key.to_account_info(),
+                *ctx.bumps.get("root_key").unwrap(),
+                &ctx.accounts.delegate_root.to_account_info(),
+                &ctx.accounts.delegate_program.to_account_info(),
+                &ctx.accounts.token_program.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+            )?;
+        }
+        subscr.swap = inp_swap;
+        let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+        let approve_amount: u64 = reserve_allowance(subscr, &mut ctx.accounts.token_link,
+            ctx.accounts.allowance.key, acc_funding.key, ctx.accounts.user_key.key, link_bump,
         )?;
-        verify_matching_accounts(&subscr.token_mint, &ctx.accounts.token_account.mint,
-            Some(String::from("Token mint does not match subscription"))
+
+        // Link the new funding account to the token delegate
+        let cpi_accounts = DelegateApprove {
+            allowance: ctx.accounts.allowance.to_account_info(),
+            allowance_payer: ctx.accounts.user_key.to_account_info(),
+            owner: ctx.accounts.user_key.to_account_info(),
+            delegate: ctx.accounts.root_key.to_account_info(),
+            delegate_root: ctx.accounts.delegate_root.to_account_info(),
+            token_account: acc_funding.clone(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.delegate_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+
+        // Billing terms are left unchanged
+        subscr.token_account = *ctx.accounts.token_account.to_account_info().key;
+        subscr.swap_account = if inp_swap { *acc_funding.key } else { Pubkey::default() };
+        if inp_swap {
+            subscr.swap_direction = inp_swap_direction;
+            subscr.swap_mode = inp_swap_mode;
+        }
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                176790708749332040680502051609315199592, // solana/program/token-agent/update_token_account
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    pub fn update_fallback_accounts<'info>(ctx: Context<'_, '_, '_, 'info, UpdateFallbackAccounts<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match subscription"))
         )?;
 
-        // Verify merchant's associated token
-        let mut mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
-        let derived_merchant_key = Pubkey::create_program_address(
-            &[
-                &mrch_approval.dest_account.to_bytes(),
-                &Token::id().to_bytes(),
-                &ctx.accounts.token_account.mint.to_bytes(),
-                &[inp_dest_nonce]
-            ],
-            &AssociatedToken::id()
-        ).map_err(|_| ErrorCode::InvalidNonce)?;
-        verify_matching_accounts(&derived_merchant_key, ctx.accounts.merchant_token.to_account_info().key,
-            Some(String::from("Invalid merchant token account"))
+        // Fallback token accounts must have the funding account's mint, swap subscriptions swap from fallbacks of the swap token mint
+        let funding_key: Pubkey = if subscr.swap { subscr.swap_account } else { subscr.token_account };
+        verify_matching_accounts(&funding_key, ctx.accounts.funding_account.key,
+            Some(String::from("Funding account does not match subscription"))
         )?;
+        let funding_mint: Pubkey = load_struct::<TokenAccount>(&ctx.accounts.funding_account.to_account_info())?.mint;
 
-        // Verify network authority accounts
-        if !mrch_approval.active {
-            msg!("Inactive merchant approval");
-            return Err(ErrorCode::NotApproved.into());
+        // Remaining accounts: the token links of the current linked fallbacks, then ordered (token account, allowance, token link) triples
+        let prev_len: usize = linked_fallback_count(subscr);
+        if ctx.remaining_accounts.len() < prev_len {
+            msg!("Missing fallback token links");
+            return Err(ErrorCode::InvalidAccount.into());
         }
-        verify_matching_accounts(&subscr.merchant_key, &mrch_approval.merchant_key,
-            Some(String::from("Merchant key does not match subscription"))
+        release_fallback_allowances(subscr, &ctx.remaining_accounts[..prev_len])?;
+        let fallback_remaining = &ctx.remaining_accounts[prev_len..];
+        let fallback_len: usize = fallback_remaining.len();
+        if fallback_len % 3 != 0 || fallback_len > MAX_FALLBACK_ACCOUNTS * 3 {
+            msg!("Invalid fallback accounts");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let (root_key, _) = Pubkey::find_program_address(&[crate::ID.as_ref()], &crate::ID);
+        let mut fallback_accounts: [Pubkey; MAX_FALLBACK_ACCOUNTS] = [Pubkey::default(); MAX_FALLBACK_ACCOUNTS];
+        for i in 0..(fallback_len / 3) {
+            let acc_fallback = &fallback_remaining[i * 3];
+            let acc_fallback_allowance = &fallback_remaining[(i * 3) + 1];
+            let acc_fallback_link = &fallback_remaining[(i * 3) + 2];
+            let fallback_token = load_struct::<TokenAccount>(acc_fallback)?;
+            verify_matching_accounts(&subscr.user_key, &fallback_token.owner,
+                Some(String::from("Fallback token account owner does not match subscription"))
+            )?;
+            verify_matching_accounts(&funding_mint, &fallback_token.mint,
+                Some(String::from("Fallback token mint does not match funding account"))
+            )?;
+            if *acc_fallback.key == subscr.token_account || *acc_fallback.key == subscr.swap_account || fallback_accounts.contains(acc_fallback.key) {
+                msg!("Duplicate fallback token account: {}", acc_fallback.key.to_string());
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+
+            // Reserve the subscription's allowance on the fallback's token link
+            let (allowance_key, _) = Pubkey::find_program_address(&[acc_fallback.key.as_ref(), root_key.as_ref()], &token_delegate::ID);
+            verify_matching_accounts(&allowance_key, acc_fallback_allowance.key,
+                Some(String::from("Invalid fallback allowance account"))
+            )?;
+            if acc_fallback_link.data_is_empty() {
+                create_token_link(acc_fallback_link, &allowance_key, acc_fallback.key,
+                    &ctx.accounts.user_key.to_account_info(), &ctx.accounts.system_program.to_account_info(),
+                )?;
+            }
+            let (link_key, _) = token_link_address(&allowance_key);
+            verify_matching_accounts(&link_key, acc_fallback_link.key,
+                Some(String::from("Invalid fallback token link account"))
+            )?;
+            verify_matching_accounts(&crate::ID, acc_fallback_link.owner,
+                Some(String::from("Invalid token link owner"))
+            )?;
+            let mut link = load_struct::<TokenLink>(acc_fallback_link)?;
+            verify_matching_accounts(&link.token_account, acc_fallback.key,
+                Some(String::from("Token account does not match token link"))
+            )?;
+            let amount: u64 = subscription_allowance(subscr)?;
+            link.subscriptions = link.subscriptions.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            link.approved = link.approved.checked_add(amount as u128).ok_or(error!(ErrorCode::Overflow))?;
+            update_struct(&link, acc_fallback_link)?;
+            subscr.fallback_allowances[i] = allowance_key;
+            subscr.fallback_amounts[i] = amount;
+
+            // Link the fallback token account to the token delegate
+            let approve_amount: u64 = approved_allowance(&link);
+            let cpi_accounts = DelegateApprove {
+                allowance: acc_fallback_allowance.clone(),
+                allowance_payer: ctx.accounts.user_key.to_account_info(),
+                owner: ctx.accounts.user_key.to_account_info(),
+                delegate: ctx.accounts.root_key.to_account_info(),
+                delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                token_account: acc_fallback.clone(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.delegate_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+            fallback_accounts[i] = *acc_fallback.key;
+        }
+        subscr.fallback_count = (fallback_len / 3) as u8;
+        subscr.fallback_accounts = fallback_accounts;
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                127323633271836827612066645088262272065, // solana/program/token-agent/update_fallback_accounts
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    pub fn set_fiat_pricing<'info>(ctx: Context<'_, '_, '_, 'info, SetFiatPricing<'info>>,
+        inp_active: bool,
+        inp_oracle_type: u8,
+        inp_fiat_max_amount: u64,
+        inp_fiat_decimals: u8,
+        inp_oracle_max_age: i64,
+        inp_oracle_max_conf_bps: u32,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match subscription"))
         )?;
-        verify_matching_accounts(&mrch_approval.token_mint, &ctx.accounts.token_account.mint,
-            Some(String::from("Token mint does not match approval"))
+        verify_matching_accounts(&subscr.merchant_key, ctx.accounts.merchant_key.to_account_info().key,
+            Some(String::from("Merchant key does not match subscription"))
         )?;
-        verify_matching_accounts(&mrch_approval.fees_account, &ctx.accounts.fees_account.to_account_info().key,
-            Some(String::from("Fees account does not match approval"))
+
+        if inp_active {
+            verify_matching_accounts(&subscr.token_mint, &ctx.accounts.token_mint.key(),
+                Some(String::from("Token mint does not match subscription"))
+            )?;
+            let oracle_type = OracleType::try_from_primitive(inp_oracle_type);
+            if oracle_type.is_err() {
+                msg!("Invalid oracle type: {}", inp_oracle_type.to_string());
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            if inp_oracle_max_age <= 0 {
+                msg!("Invalid oracle maximum age");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            if inp_oracle_max_conf_bps > 10000 {
+                msg!("Invalid oracle maximum confidence");
+                return Err(ErrorCode::InvalidOracle.into());
+            }
+            // Verify the price account and the current price
+            let oracle_price = read_oracle_price(oracle_type.unwrap(), &ctx.accounts.price_oracle.to_account_info())?;
+            verify_oracle_price(&oracle_price, inp_oracle_max_age, inp_oracle_max_conf_bps, clock.unix_timestamp)?;
+            subscr.price_oracle = ctx.accounts.price_oracle.key();
+            subscr.oracle_type = inp_oracle_type;
+            subscr.fiat_max_amount = inp_fiat_max_amount;
+            subscr.fiat_decimals = inp_fiat_decimals;
+            subscr.token_decimals = ctx.accounts.token_mint.decimals;
+            subscr.oracle_max_age = inp_oracle_max_age;
+            subscr.oracle_max_conf_bps = inp_oracle_max_conf_bps;
+        } else {
+            subscr.price_oracle = Pubkey::default();
+            subscr.oracle_type = 0;
+            subscr.fiat_max_amount = 0;
+            subscr.fiat_decimals = 0;
+            subscr.token_decimals = 0;
+            subscr.oracle_max_age = 0;
+            subscr.oracle_max_conf_bps = 0;
+        }
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            fiat_amount: subscr.fiat_max_amount,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                271998134705113359863531357572103244549, // solana/program/token-agent/set_fiat_pricing
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    pub fn update_manager<'info>(ctx: Context<'_, '_, '_, 'info, UpdateManager<'info>>) -> anchor_lang::Result<()> {
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.manager_key, &ctx.accounts.manager_prev.to_account_info().key,
+            Some(String::from("Previous manager does not match subscription"))
         )?;
         let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
         if !mgr_approval.active {
@@ -986,58 +3228,291 @@ mod token_agent {
             Some(String::from("Manager key does not match approval"))
         )?;
 
-        // Validate timeframe
-        let period = SubscriptionPeriod::try_from_primitive(subscr.period);
-        if period.is_err() {
-            msg!("Invalid subscription period");
-            return Err(ErrorCode::InvalidSubscriptionPeriod.into());
-        }
-        if subscr.not_valid_before > 0 && ts < subscr.not_valid_before {
-            msg!("Subscription not valid yet");
-            return Err(ErrorCode::NotValidYet.into());
+        subscr.manager_key = *ctx.accounts.manager_key.to_account_info().key;
+        subscr.manager_approval = *ctx.accounts.manager_approval.to_account_info().key;
+        Ok(())
+    }
+
+    pub fn manager_cancel<'info>(ctx: Context<'_, '_, '_, 'info, ManagerCancel<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match subscription"))
+        )?;
+        verify_matching_accounts(&subscr.manager_approval, ctx.accounts.manager_approval.key,
+            Some(String::from("Manager approval does not match subscription"))
+        )?;
+        verify_matching_accounts(&net_authority::ID, ctx.accounts.manager_approval.owner,
+            Some(String::from("Invalid manager approval owner"))
+        )?;
+        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
+        if !mgr_approval.active {
+            msg!("Inactive manager approval");
+            return Err(ErrorCode::NotApproved.into());
         }
-        if subscr.not_valid_after > 0 && ts > subscr.not_valid_after {
-            msg!("Subscription expired");
-            return Err(ErrorCode::Expired.into());
+        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match approval"))
+        )?;
+
+        subscr.active = false;
+        subscr.cancelled_at = clock.unix_timestamp;
+
+        // Release the subscription's reservation, closing or approving the allowance requires the user's signature
+        // (the allowance shrinks or closes when the user next links the token account or closes a subscription using it)
+        release_allowance(subscr, &ctx.accounts.token_link.to_account_info())?;
+
+        // Release the fallback reservations, the fallback token links are passed as remaining accounts
+        release_fallback_allowances(subscr, ctx.remaining_accounts)?;
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            rebill_event: 0,
+            next_rebill: -1,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                14511983483732720963723889670203659368, // solana/program/token-agent/manager_cancel
+                clock.slot,
+            )
+        });
+
+        Ok(())
+    }
+
+    pub fn manager_close<'info>(ctx: Context<'_, '_, '_, 'info, ManagerClose<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+
+        let subscr = &mut ctx.accounts.subscr_data;
+        verify_matching_accounts(&subscr.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match subscription"))
+        )?;
+        verify_matching_accounts(&subscr.manager_approval, ctx.accounts.manager_approval.key,
+            Some(String::from("Manager approval does not match subscription"))
+        )?;
+        verify_matching_accounts(&net_authority::ID, ctx.accounts.manager_approval.owner,
+            Some(String::from("Invalid manager approval owner"))
+        )?;
+        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
+        if !mgr_approval.active {
+            msg!("Inactive manager approval");
+            return Err(ErrorCode::NotApproved.into());
         }
-        if inp_rebill_ts < 0 {
-            msg!("Invalid negative rebill timestamp");
+        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match approval"))
+        )?;
+        verify_matching_accounts(&rent_recipient(subscr), ctx.accounts.rent_payer.key,
+            Some(String::from("Rent payer does not match subscription"))
+        )?;
+
+        // Only subscriptions cancelled, expired or with all rebills processed for the close delay can be cleaned up
+        let ended_ts: i64 = match ended_since(subscr, clock.unix_timestamp) {
+            Some(ended_ts) => ended_ts,
+            None => {
+                msg!("Subscription is active");
+                return Err(ErrorCode::SubscriptionActive.into());
+            },
+        };
+        let close_after: i64 = ended_ts.checked_add(MANAGER_CLOSE_DELAY).ok_or(error!(ErrorCode::Overflow))?;
+        if clock.unix_timestamp < close_after {
+            msg!("Subscription ended: {} can be closed after: {}", ended_ts.to_string(), close_after.to_string());
             return Err(ErrorCode::InvalidTimeframe.into());
         }
-        if ts < inp_rebill_ts && false { // TODO: REMOVE THIS BEFORE LAUNCH!!!
-            msg!("Attempted rebill before scheduled time");
-            return Err(ErrorCode::InvalidTimeframe.into());
+
+        // Expired subscriptions still hold their reservations, the fallback token links are passed as remaining accounts
+        release_allowance(subscr, &ctx.accounts.token_link.to_account_info())?;
+        release_fallback_allowances(subscr, ctx.remaining_accounts)?;
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            rebill_event: 0,
+            next_rebill: -1,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                165611547199643586694374355752456864512, // solana/program/token-agent/manager_close
+                clock.slot,
+            )
+        });
+
+        msg!("Closed Subscription: {}", subscr.key().to_string());
+        Ok(())
+    }
+
+    pub fn reclaim_rent<'info>(ctx: Context<'_, '_, '_, 'info, ReclaimRent<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+
+        let subscr = &mut ctx.accounts.subscr_data;
+        if subscr.rent_payer == Pubkey::default() || subscr.rent_payer == subscr.user_key {
+            msg!("Subscription rent not sponsored");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&subscr.rent_payer, ctx.accounts.rent_payer.to_account_info().key,
+            Some(String::from("Rent payer does not match subscription"))
+        )?;
+
+        // Sponsors can reclaim the rent of subscriptions inactive for the reclaim delay
+        if subscr.active {
+            msg!("Subscription is active");
+            return Err(ErrorCode::SubscriptionActive.into());
         }
-        if subscr.next_rebill != inp_rebill_ts {
-            msg!("Rebill timestamp does not match subscription");
+        let reclaim_after: i64 = inactive_since(subscr).checked_add(subscr.rent_reclaim_delay).ok_or(error!(ErrorCode::Overflow))?;
+        if clock.unix_timestamp < reclaim_after {
+            msg!("Subscription inactive since: {} rent can be reclaimed after: {}", inactive_since(subscr).to_string(), reclaim_after.to_string());
             return Err(ErrorCode::InvalidTimeframe.into());
         }
-        let timeframe_end = inp_rebill_ts.checked_add(subscr.max_delay).ok_or(error!(ErrorCode::Overflow))?;
-        if ts > timeframe_end {
-            msg!("Rebill expired");
-            return Err(ErrorCode::Expired.into());
+
+        // Release any reservations still held on the allowances, the fallback token links are passed as remaining accounts
+        release_allowance(subscr, &ctx.accounts.token_link.to_account_info())?;
+        release_fallback_allowances(subscr, ctx.remaining_accounts)?;
+
+        msg!("atellix-log");
+        emit!(SubscrEvent {
+            rebill_event: 0,
+            next_rebill: -1,
+            ..SubscrEvent::for_subscription(subscr, subscr.key(),
+                293607970682083533522434074676083179606, // solana/program/token-agent/reclaim_rent
+                clock.slot,
+            )
+        });
+
+        msg!("Reclaimed Subscription Rent: {}", subscr.key().to_string());
+        Ok(())
+    }
+
+    pub fn process<'info>(ctx: Context<'_, '_, '_, 'info, ProcessSubscr<'info>>,
+        inp_dest_nonce: u8,
+        inp_root_nonce: u8,
+        inp_rebill_ts: i64,
+        inp_rebill_str: String,
+        inp_next_rebill: i64,
+        inp_amount: u64,
+        inp_payment_id: u128,
+        inp_swap_data_nonce: u8,
+        inp_swap_inb_nonce: u8,
+        inp_swap_out_nonce: u8,
+        inp_swap_estimate: u64,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let ts = clock.unix_timestamp;
+
+        // Validate accounts
+        let mut subscr = load_subscr(&ctx.accounts.subscr_data.to_account_info())?;
+        verify_matching_accounts(&subscr.manager_approval, ctx.accounts.manager_approval.to_account_info().key,
+            Some(String::from("Manager approval does not match subscription"))
+        )?;
+        verify_matching_accounts(&subscr.merchant_approval, ctx.accounts.merchant_approval.to_account_info().key,
+            Some(String::from("Merchant approval does not match subscription"))
+        )?;
+
+        if !subscr.active {
+            msg!("Inactive subscription");
+            return Err(ErrorCode::InactiveSubscription.into());
         }
-        let d1 = get_period_string(inp_rebill_ts, period.unwrap())?;
-        if inp_rebill_str != d1 {   
-            msg!("Invalid rebill period string");
-            return Err(ErrorCode::InvalidTimeframe.into());
+        if subscr.rebill_max > 0 && subscr.rebill_events >= subscr.rebill_max {
+            msg!("Maximum rebills reached");
+            return Err(ErrorCode::MaxRebills.into());
         }
-        let d2 = get_period_string(inp_next_rebill, period.unwrap())?;
-        let prev_period = inp_next_rebill.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-        let d3 = get_period_string(prev_period, period.unwrap())?;
-        if d2 == d3 {
-            msg!("Next rebill not beginning of period");
-            return Err(ErrorCode::InvalidTimeframe.into());
+
+        // Verfiy token account and mint
+        verify_matching_accounts(&subscr.token_account, &ctx.accounts.token_account.to_account_info().key,
+            Some(String::from("Token account does not match subscription"))
+        )?;
+        verify_matching_accounts(&subscr.token_mint, &ctx.accounts.token_account.mint,
+            Some(String::from("Token mint does not match subscription"))
+        )?;
+
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
+        let mut mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
+        let (acc_merchant_mint, remaining_accounts) = split_merchant_mint(&mrch_approval, &ctx.accounts.token_account.mint, ctx.remaining_accounts)?;
+        let settlement = get_settlement(&mrch_approval, ctx.accounts.merchant_approval.key, &ctx.accounts.token_account.mint, acc_merchant_mint)?;
+
+        // Verify merchant's associated token and fees account
+        verify_settlement_accounts(&settlement, inp_dest_nonce,
+            &ctx.accounts.merchant_token.to_account_info(),
+            &ctx.accounts.fees_account.to_account_info(),
+        )?;
+
+        // Verify network authority accounts
+        if !mrch_approval.active {
+            msg!("Inactive merchant approval");
+            return Err(ErrorCode::NotApproved.into());
         }
-        if d1 != d3 {
-            msg!("Next rebill out of sequence");
-            return Err(ErrorCode::InvalidTimeframe.into());
+        verify_matching_accounts(&subscr.merchant_key, &mrch_approval.merchant_key,
+            Some(String::from("Merchant key does not match subscription"))
+        )?;
+        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
+        if !mgr_approval.active {
+            msg!("Inactive manager approval");
+            return Err(ErrorCode::NotApproved.into());
         }
+        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match approval"))
+        )?;
+
+        // Validate timeframe
+        verify_rebill_timeframe(subscr.period, subscr.next_rebill, subscr.max_delay, subscr.not_valid_before, subscr.not_valid_after,
+            ts, inp_rebill_ts, &inp_rebill_str, inp_next_rebill,
+        )?;
 
         //msg!("Atellix: Process rebill");
 
+        // Fiat priced subscriptions pass the price oracle as the first remaining account
+        let fiat_pricing: bool = subscr.price_oracle != Pubkey::default();
+        let oracle_len: usize = if fiat_pricing { 1 } else { 0 };
+
+        // Affiliate and referrer token accounts follow the oracle while a referral commission is due
+        let due_referral: bool = referral_due(&subscr);
+        let referral_len: usize = if due_referral { 2 } else { 0 };
+
+        // Revenue split recipient token accounts follow the fallback accounts
+        let revenue_split = load_revenue_split(&ctx.accounts.revenue_split.to_account_info())?;
+        let (remaining_accounts, split_accounts) = split_revenue_accounts(&revenue_split, remaining_accounts)?;
+
+        // Fallback funding accounts (token account, allowance, token link) are passed in order at the end of the remaining accounts
+        let fallback_len: usize = (subscr.fallback_count as usize).checked_mul(3).ok_or(error!(ErrorCode::Overflow))?;
+        if remaining_accounts.len() < fallback_len + oracle_len + referral_len {
+            msg!("Missing fallback, oracle or referrer accounts");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let fallback_start: usize = remaining_accounts.len().checked_sub(fallback_len).ok_or(error!(ErrorCode::Overflow))?;
+        let swap_remaining = &remaining_accounts[(oracle_len + referral_len)..fallback_start];
+        let fallback_accounts = &remaining_accounts[fallback_start..];
+        let mut funding_account: Pubkey = Pubkey::default();
+
+        // Commissions are only paid while the merchant keeps the affiliate active
+        let mut pay_referral: bool = false;
+        if due_referral {
+            let afl = load_affiliate(&subscr.merchant_approval, &remaining_accounts[oracle_len], &subscr.referrer)?;
+            if afl.active {
+                pay_referral = true;
+            } else {
+                msg!("Inactive affiliate: referral commission not paid");
+            }
+        }
+
+        // Coupon discount for the covered rebills
+        let discount: u64 = if inp_amount > 0 && coupon_due(&subscr) {
+            subscr.coupon_uses = subscr.coupon_uses.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            discount_amount(subscr.coupon_type, subscr.coupon_value, inp_amount)?
+        } else {
+            0
+        };
+        let inp_amount: u64 = inp_amount.checked_sub(discount).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Fiat pricing: inp_amount is in reference currency units, charge the payment token amount at the oracle price
+        let fiat_amount: u64 = if fiat_pricing { inp_amount } else { 0 };
+        let inp_amount: u64 = if fiat_pricing && inp_amount > 0 {
+            if inp_amount > subscr.fiat_max_amount {
+                msg!("Fiat amount: {} exceeds maximum: {}", inp_amount.to_string(), subscr.fiat_max_amount.to_string());
+                return Err(ErrorCode::FiatAmountExceeded.into());
+            }
+            fiat_to_tokens(&subscr, &remaining_accounts[0], inp_amount, ts)?
+        } else {
+            inp_amount
+        };
+
+        let fee_quote = quote_settlement_fees(&ctx.accounts.fee_policy.to_account_info(), ctx.accounts.merchant_approval.key, &settlement, true, inp_amount)?;
+        let inp_amount: u64 = fee_quote.total;
         let mut net_amount: u64 = inp_amount;
         let mut fee_amount: u64 = 0;
+        let mut referral_amount: u64 = 0;
         if inp_amount > 0 {
             if inp_amount > subscr.period_budget {
                 msg!("Amount exceeds budget");
@@ -1050,19 +3525,52 @@ mod token_agent {
                 }
                 subscr.total_budget = subscr.total_budget.checked_sub(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
             }
+            subscr.budget_spent = subscr.budget_spent.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
             // Swap if requested
             let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
             let root_pda_signer = &[&root_pda_seeds[..]];
             if subscr.swap {
                 //msg!("Atellix: Attempt swap");
-                let acc_swap_token = ctx.remaining_accounts.get(0).unwrap();        // User Swap Token
+                let swap_mode = SwapMode::try_from_primitive(subscr.swap_mode);
+                if swap_mode.is_err() {
+                    msg!("Invalid swap mode");
+                    return Err(ErrorCode::InvalidSwapMode.into());
+                }
+                let swap_accounts = parse_swap_accounts(swap_mode.unwrap(), swap_remaining,
+                    Some(ctx.accounts.root_key.to_account_info().key), &subscr.token_mint,
+                )?;
+                let acc_swap_token = &swap_accounts.swap_token;                 // User Swap Token
+                let acc_swap_input = swap_accounts.swap_input.as_ref().unwrap(); // Token Agent PDA (input)
                 verify_matching_accounts(&subscr.swap_account, &acc_swap_token.key(),
                     Some(String::from("Swap token does not match subscription"))
                 )?;
-                let token_user_amount: u64 = load_struct::<TokenAccount>(acc_swap_token).unwrap().amount;
-                let token_swap_amount: u64 = load_struct::<TokenAccount>(ctx.remaining_accounts.get(3).unwrap()).unwrap().amount;
+                // Slippage is bounded relative to the swap backend quote, the manager's estimate only sets the amount pulled
+                let swap_quote: u64 = if subscr.swap_exact_output || subscr.swap_slippage_bps > 0 { swap_quote_input(&swap_accounts.backend, inp_amount)? } else { 0 };
+                let swap_limit: u64 = swap_input_limit(subscr.swap_max_input, subscr.swap_slippage_bps, swap_quote)?;
+                // Exact output: pull the quoted input instead of the client estimate
+                let mut swap_estimate: u64 = inp_swap_estimate;
+                if subscr.swap_exact_output {
+                    swap_estimate = swap_quote;
+                    if swap_estimate > swap_limit {
+                        msg!("Swap quote: {} exceeds maximum: {}", swap_estimate.to_string(), swap_limit.to_string());
+                        return Err(ErrorCode::SlippageExceeded.into());
+                    }
+                }
+                // Use a fallback swap token account if the primary cannot cover the swap estimate
+                let (acc_source, acc_source_allowance, source_fallback) = select_funding_account(&subscr,
+                    acc_swap_token, &ctx.accounts.allowance.to_account_info(), fallback_accounts, swap_estimate,
+                )?;
+                funding_account = *acc_source.key;
+                let token_user_amount: u64 = load_struct::<TokenAccount>(&acc_source)?.amount;
+                let token_swap_amount: u64 = load_struct::<TokenAccount>(acc_swap_input)?.amount;
                 let token_transfer;
-                if inp_swap_estimate == 0 || token_user_amount < inp_swap_estimate {
+                if subscr.swap_exact_output {
+                    if token_user_amount < swap_estimate {
+                        msg!("Insufficient swap tokens");
+                        return Err(ErrorCode::InsufficientFunds.into());
+                    }
+                    token_transfer = swap_estimate;
+                } else if inp_swap_estimate == 0 || token_user_amount < inp_swap_estimate {
                     // swap estimate >= user tokens, transfer all
                     token_transfer = token_user_amount;
                 } else {
@@ -1071,111 +3579,169 @@ mod token_agent {
                 }
                 // Delegated transfer all tokens to token-agent owned swap input account then transfer remaining back below
                 let cpi_accounts = DelegateTransfer {
-                    allowance: ctx.accounts.allowance.to_account_info(),
+                    allowance: acc_source_allowance.clone(),
                     delegate: ctx.accounts.root_key.to_account_info(),
                     delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                    from: acc_swap_token.clone(),
-                    to: ctx.remaining_accounts.get(3).unwrap().clone(),
+                    from: acc_source.clone(),
+                    to: acc_swap_input.clone(),
                     token_program: ctx.accounts.token_program.to_account_info(),
                 };
                 let cpi_program = ctx.accounts.delegate_program.to_account_info();
                 let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                token_delegate::cpi::delegate_transfer(cpi_ctx, token_transfer)?; // TODO: use swap estimate if less
+                token_delegate::cpi::delegate_transfer(cpi_ctx, token_transfer)?;
+                spend_funding_allowance(&mut subscr, source_fallback, &ctx.accounts.token_link.to_account_info(), fallback_accounts, token_transfer)?;
 
-                let swap_mode = SwapMode::try_from_primitive(subscr.swap_mode);
-                if swap_mode.is_err() {
-                    msg!("Invalid swap mode");
-                    return Err(ErrorCode::InvalidSwapMode.into());
-                }
-                if swap_mode.unwrap() == SwapMode::AtxSwapContractV1 {
-                    // Perform swap
-                    let sw_program = ctx.remaining_accounts.get(1).unwrap().clone();
-                    let sw_accounts = Swap {
-                        swap_user: ctx.accounts.root_key.to_account_info(),             // Root key (signer)
-                        swap_data: ctx.remaining_accounts.get(2).unwrap().clone(),
-                        inb_token_src: ctx.remaining_accounts.get(3).unwrap().clone(),  // Token Agent PDA (input)
-                        inb_token_dst: ctx.remaining_accounts.get(4).unwrap().clone(),
-                        out_token_src: ctx.remaining_accounts.get(5).unwrap().clone(),
-                        out_token_dst: ctx.accounts.token_account.to_account_info(),    // Token Agent PDA (output)
-                        fees_token: ctx.remaining_accounts.get(6).unwrap().clone(),
-                        token_program: ctx.accounts.token_program.to_account_info(),
-                    };
-                    let mut sw_ctx = CpiContext::new_with_signer(sw_program, sw_accounts, root_pda_signer);
-                    if ctx.remaining_accounts.len() > 7 { // Oracle Data Account (if needed)
-                        sw_ctx = sw_ctx.with_remaining_accounts(vec![ctx.remaining_accounts.get(7).unwrap().clone()]);
-                    }
-                    swap_contract::cpi::swap(sw_ctx, inp_swap_data_nonce, inp_swap_inb_nonce, inp_swap_out_nonce, 0, subscr.swap_direction, false, true, inp_amount)?;
-                }
+                // Perform swap
+                swap_tokens(&swap_accounts,
+                    &ctx.accounts.root_key.to_account_info(),      // Root key (signer)
+                    acc_swap_input,                                // Token Agent PDA (input)
+                    &ctx.accounts.token_account.to_account_info(), // Token Agent PDA (output)
+                    &ctx.accounts.token_program.to_account_info(),
+                    root_pda_signer,
+                    SwapParams { direction: subscr.swap_direction, data_nonce: inp_swap_data_nonce, inb_nonce: inp_swap_inb_nonce, out_nonce: inp_swap_out_nonce },
+                    inp_amount,
+                    swap_limit,
+                    subscr.swap_exact_output,
+                )?;
 
                 // Transfer remaining tokens back ;)
-                let token_post_amount: u64 = load_struct::<TokenAccount>(ctx.remaining_accounts.get(3).unwrap()).unwrap().amount;
+                let token_post_amount: u64 = load_struct::<TokenAccount>(acc_swap_input)?.amount;
                 let token_return: u64 = token_post_amount.checked_sub(token_swap_amount).ok_or(error!(ErrorCode::Overflow))?;
                 let cpi_accounts = Transfer {
-                    from: ctx.remaining_accounts.get(3).unwrap().clone(),
-                    to: acc_swap_token.clone(),
+                    from: acc_swap_input.clone(),
+                    to: acc_source.clone(),
                     authority: ctx.accounts.root_key.to_account_info(),
                 };
                 let cpi_program = ctx.accounts.token_program.to_account_info();
                 let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
                 token::transfer(cpi_ctx, token_return)?;
-            }
+
+                // Spending limits apply to the swap tokens pulled from the user
+                let swap_spent: u64 = token_transfer.checked_sub(token_return).ok_or(error!(ErrorCode::Overflow))?;
+                record_spending(&ctx.accounts.spending_policy.to_account_info(), &subscr.user_key,
+                    &load_struct::<TokenAccount>(&acc_source)?.mint, swap_spent, ts,
+                )?;
+            }
+
+            // Use a fallback token account if the primary cannot cover the payment
+            let acc_token = ctx.accounts.token_account.to_account_info();
+            let acc_allowance = ctx.accounts.allowance.to_account_info();
+            let (acc_source, acc_source_allowance, source_fallback) = if subscr.swap {
+                (acc_token.clone(), acc_allowance.clone(), None)
+            } else {
+                select_funding_account(&subscr, &acc_token, &acc_allowance, fallback_accounts, inp_amount)?
+            };
+            if !subscr.swap {
+                funding_account = *acc_source.key;
+                record_spending(&ctx.accounts.spending_policy.to_account_info(), &subscr.user_key, &subscr.token_mint, inp_amount, ts)?;
+                spend_funding_allowance(&mut subscr, source_fallback, &ctx.accounts.token_link.to_account_info(), fallback_accounts, inp_amount)?;
+            }
 
             // Calculate fees
-            if mrch_approval.fees_bps > 0 {
-                let f1: u128 = (net_amount as u128) << 64;
-                let f2: u128 = f1.checked_mul(mrch_approval.fees_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
-                let f3: u128 = f2.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
-                let fees: u64 = (f3 >> 64) as u64;
-                if fees > 0 {
-                    net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
-                    fee_amount = fees;
+            let fees: u64 = fee_quote.fee_amount;
+            if fees > 0 {
+                net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
+                fee_amount = fees;
+                if subscr.swap {
+                    let cpi_accounts = Transfer {
+                        from: ctx.accounts.token_account.to_account_info(),
+                        to: ctx.accounts.fees_account.to_account_info(),
+                        authority: ctx.accounts.root_key.to_account_info(),
+                    };
+                    let cpi_program = ctx.accounts.token_program.to_account_info();
+                    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                    token::transfer(cpi_ctx, fees)?;
+                } else {
+                    let cpi_accounts = DelegateTransfer {
+                        allowance: acc_source_allowance.clone(),
+                        delegate: ctx.accounts.root_key.to_account_info(),
+                        delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                        from: acc_source.clone(),
+                        to: ctx.accounts.fees_account.to_account_info(),
+                        token_program: ctx.accounts.token_program.to_account_info(),
+                    };
+                    let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                    token_delegate::cpi::delegate_transfer(cpi_ctx, fees)?;
+                }
+            }
+            // Referral commission
+            if pay_referral {
+                let acc_referrer_token = &remaining_accounts[oracle_len + 1];
+                let (referrer_token, _) = Pubkey::find_program_address(
+                    &[
+                        &subscr.referrer.to_bytes(),
+                        &Token::id().to_bytes(),
+                        &settlement.token_mint.to_bytes(),
+                    ],
+                    &AssociatedToken::id()
+                );
+                verify_matching_accounts(&referrer_token, acc_referrer_token.key,
+                    Some(String::from("Invalid referrer token account"))
+                )?;
+                let r1: u128 = (net_amount as u128).checked_mul(subscr.referral_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
+                referral_amount = (r1 / 10000) as u64;
+                if referral_amount > 0 {
+                    net_amount = net_amount.checked_sub(referral_amount).ok_or(error!(ErrorCode::Overflow))?;
                     if subscr.swap {
                         let cpi_accounts = Transfer {
                             from: ctx.accounts.token_account.to_account_info(),
-                            to: ctx.accounts.fees_account.to_account_info(),
+                            to: acc_referrer_token.clone(),
                             authority: ctx.accounts.root_key.to_account_info(),
                         };
                         let cpi_program = ctx.accounts.token_program.to_account_info();
                         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                        token::transfer(cpi_ctx, fees)?;
+                        token::transfer(cpi_ctx, referral_amount)?;
                     } else {
                         let cpi_accounts = DelegateTransfer {
-                            allowance: ctx.accounts.allowance.to_account_info(),
+                            allowance: acc_source_allowance.clone(),
                             delegate: ctx.accounts.root_key.to_account_info(),
                             delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                            from: ctx.accounts.token_account.to_account_info(),
-                            to: ctx.accounts.fees_account.to_account_info(),
+                            from: acc_source.clone(),
+                            to: acc_referrer_token.clone(),
                             token_program: ctx.accounts.token_program.to_account_info(),
                         };
                         let cpi_program = ctx.accounts.delegate_program.to_account_info();
                         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                        token_delegate::cpi::delegate_transfer(cpi_ctx, fees)?;
+                        token_delegate::cpi::delegate_transfer(cpi_ctx, referral_amount)?;
                     }
                 }
-                //msg!("Starting Amount: {} Ending Amount: {} Fees: {}", inp_amount.to_string(), amount.to_string(), fees.to_string());
+                subscr.referral_count = subscr.referral_count.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+                subscr.total_referral = subscr.total_referral.checked_add(referral_amount).ok_or(error!(ErrorCode::Overflow))?;
             }
-            if subscr.swap {
-                let cpi_accounts = Transfer {
-                    from: ctx.accounts.token_account.to_account_info(),
-                    to: ctx.accounts.merchant_token.to_account_info(),
-                    authority: ctx.accounts.root_key.to_account_info(),
-                };
-                let cpi_program = ctx.accounts.token_program.to_account_info();
-                let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                token::transfer(cpi_ctx, net_amount)?;
-            } else {
-                let cpi_accounts = DelegateTransfer {
-                    allowance: ctx.accounts.allowance.to_account_info(),
-                    delegate: ctx.accounts.root_key.to_account_info(),
-                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
-                    from: ctx.accounts.token_account.to_account_info(),
-                    to: ctx.accounts.fees_account.to_account_info(),
-                    token_program: ctx.accounts.token_program.to_account_info(),
-                };
-                let cpi_program = ctx.accounts.delegate_program.to_account_info();
-                let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                token_delegate::cpi::delegate_transfer(cpi_ctx, net_amount)?;
+
+            // Pay revenue split recipients, then the merchant the remainder
+            let (split_payouts, merchant_amount) = revenue_split_payouts(&revenue_split, &settlement.token_mint, split_accounts, net_amount)?;
+            let mut payouts: Vec<(AccountInfo<'info>, u64)> = split_payouts.iter().map(|payout| (payout.token_account.clone(), payout.amount)).collect();
+            payouts.push((ctx.accounts.merchant_token.to_account_info(), merchant_amount));
+            for (acc_payout, payout_amount) in payouts.iter() {
+                if *payout_amount == 0 {
+                    continue;
+                }
+                if subscr.swap {
+                    let cpi_accounts = Transfer {
+                        from: ctx.accounts.token_account.to_account_info(),
+                        to: acc_payout.clone(),
+                        authority: ctx.accounts.root_key.to_account_info(),
+                    };
+                    let cpi_program = ctx.accounts.token_program.to_account_info();
+                    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                    token::transfer(cpi_ctx, *payout_amount)?;
+                } else {
+                    let cpi_accounts = DelegateTransfer {
+                        allowance: acc_source_allowance.clone(),
+                        delegate: ctx.accounts.root_key.to_account_info(),
+                        delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                        from: acc_source.clone(),
+                        to: acc_payout.clone(),
+                        token_program: ctx.accounts.token_program.to_account_info(),
+                    };
+                    let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                    token_delegate::cpi::delegate_transfer(cpi_ctx, *payout_amount)?;
+                }
             }
+            emit_split_payouts(&split_payouts, clock.slot, &subscr.merchant_key, ctx.accounts.merchant_approval.key, inp_payment_id);
 
             // Record merchant revenue
             let na_program = ctx.accounts.net_auth.to_account_info();
@@ -1194,26 +3760,43 @@ mod token_agent {
         // Update parameters
         subscr.next_rebill = inp_next_rebill;
         subscr.rebill_events = subscr.rebill_events.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-        store_struct(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
+
+        // The final rebill releases the subscription's reservations (closing the allowance requires the user's signature, as in manager_cancel)
+        if subscr.rebill_max > 0 && subscr.rebill_events >= subscr.rebill_max {
+            release_allowance(&mut subscr, &ctx.accounts.token_link.to_account_info())?;
+            let fallback_links: Vec<AccountInfo<'info>> = (0..(subscr.fallback_count as usize))
+                .filter(|i| subscr.fallback_allowances[*i] != Pubkey::default())
+                .map(|i| fallback_accounts[(i * 3) + 2].clone())
+                .collect();
+            release_fallback_allowances(&mut subscr, &fallback_links)?;
+            msg!("Released allowance reservations after the final rebill");
+        }
+        if inp_amount > 0 {
+            subscr.total_charged = subscr.total_charged.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+            subscr.total_fees = subscr.total_fees.checked_add(fee_amount).ok_or(error!(ErrorCode::Overflow))?;
+            subscr.last_payment = inp_amount;
+            subscr.last_payment_ts = ts;
+        }
+        update_struct(&subscr, &ctx.accounts.subscr_data.to_account_info())?;
 
         msg!("atellix-log");
         emit!(SubscrEvent {
-            event_hash: 196800858676461937700417377973077375575, // solana/program/token-agent/process
-            slot: clock.slot,
             merchant_tx_id: mrch_approval.tx_count,
-            merchant_key: subscr.merchant_key,
             merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
-            dest_account: mrch_approval.dest_account,
-            user_key: subscr.user_key,
-            subscr_data: *ctx.accounts.subscr_data.to_account_info().key,
-            subscr_id: subscr.subscr_id,
+            dest_account: settlement.dest_account,
             payment_id: inp_payment_id,
-            rebill_event: subscr.rebill_events,
             total: inp_amount,
             amount: net_amount,
             fees: fee_amount,
             next_rebill: inp_next_rebill,
-            swap: subscr.swap,
+            funding_account,
+            fiat_amount,
+            referral_amount,
+            discount,
+            ..SubscrEvent::for_subscription(&subscr, *ctx.accounts.subscr_data.to_account_info().key,
+                196800858676461937700417377973077375575, // solana/program/token-agent/process
+                clock.slot,
+            )
         });
 
         Ok(())
@@ -1231,23 +3814,19 @@ mod token_agent {
         inp_swap_inb_nonce: u8,
         inp_swap_out_nonce: u8,
         inp_swap_dst_nonce: u8,
+        inp_swap_max_input: u64,
+        inp_swap_exact_output: bool,
     ) -> anchor_lang::Result<()> {
         let clock = Clock::get()?;
 
-        // Verify merchant's associated token for the destination account
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
         let mut mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
-        let derived_merchant_key = Pubkey::create_program_address(
-            &[
-                &mrch_approval.dest_account.to_bytes(),
-                &Token::id().to_bytes(),
-                &ctx.accounts.token_account.mint.to_bytes(),
-                &[inp_dest_nonce]
-            ],
-            &AssociatedToken::id()
-        ).map_err(|_| ErrorCode::InvalidNonce)?;
-        verify_matching_accounts(&derived_merchant_key, ctx.accounts.merchant_token.to_account_info().key,
-            Some(String::from("Invalid merchant token account"))
-        )?;
+        let (acc_merchant_mint, remaining_accounts) = split_merchant_mint(&mrch_approval, &ctx.accounts.token_account.mint, ctx.remaining_accounts)?;
+        let settlement = get_settlement(&mrch_approval, ctx.accounts.merchant_approval.key, &ctx.accounts.token_account.mint, acc_merchant_mint)?;
+
+        // Revenue split recipient token accounts follow the swap accounts
+        let revenue_split = load_revenue_split(&ctx.accounts.revenue_split.to_account_info())?;
+        let (remaining_accounts, split_accounts) = split_revenue_accounts(&revenue_split, remaining_accounts)?;
 
         let netauth = &ctx.accounts.net_auth.to_account_info().key;
         let acc_mrch_approve = &ctx.accounts.merchant_approval.to_account_info();
@@ -1259,13 +3838,15 @@ mod token_agent {
             msg!("Inactive merchant approval");
             return Err(ErrorCode::NotApproved.into());
         }
-        verify_matching_accounts(&mrch_approval.token_mint, &ctx.accounts.token_account.mint,
-            Some(String::from("Token mint does not match approval"))
-        )?;
-        verify_matching_accounts(&mrch_approval.fees_account, &ctx.accounts.fees_account.to_account_info().key,
-            Some(String::from("Fees account does not match approval"))
+
+        // Verify merchant's associated token for the destination account and fees account
+        verify_settlement_accounts(&settlement, inp_dest_nonce,
+            &ctx.accounts.merchant_token.to_account_info(),
+            &ctx.accounts.fees_account.to_account_info(),
         )?;
 
+        let fee_quote = quote_settlement_fees(&ctx.accounts.fee_policy.to_account_info(), ctx.accounts.merchant_approval.key, &settlement, false, inp_amount)?;
+        let inp_amount: u64 = fee_quote.total;
         let mut net_amount: u64 = inp_amount;
         let mut fee_amount: u64 = 0;
         if inp_amount > 0 {
@@ -1291,103 +3872,767 @@ mod token_agent {
                     msg!("Invalid swap mode");
                     return Err(ErrorCode::InvalidSwapMode.into());
                 }
-                if swap_mode.unwrap() == SwapMode::AtxSwapContractV1 {
-                    let acc_swap_token = ctx.remaining_accounts.get(0).unwrap();        // User Swap Token
-                    let sw_program = ctx.remaining_accounts.get(1).unwrap().clone();
-                    let sw_accounts = Swap {
-                        swap_user: ctx.accounts.user_key.to_account_info(),
-                        swap_data: ctx.remaining_accounts.get(2).unwrap().clone(),
-                        inb_token_src: acc_swap_token.clone(),
-                        inb_token_dst: ctx.remaining_accounts.get(3).unwrap().clone(),
-                        out_token_src: ctx.remaining_accounts.get(4).unwrap().clone(),
-                        out_token_dst: ctx.accounts.token_account.to_account_info(),    // Token Agent PDA
-                        fees_token: ctx.remaining_accounts.get(5).unwrap().clone(),
-                        token_program: ctx.accounts.token_program.to_account_info(),
-                    };
-                    let mut sw_ctx = CpiContext::new(sw_program, sw_accounts);
-                    if ctx.remaining_accounts.len() > 6 { // Oracle Data Account (if needed)
-                        sw_ctx = sw_ctx.with_remaining_accounts(vec![ctx.remaining_accounts.get(6).unwrap().clone()]);
+                let swap_accounts = parse_swap_accounts(swap_mode.unwrap(), remaining_accounts, None, &settlement.token_mint)?;
+                let mut swap_limit: u64 = swap_input_limit(inp_swap_max_input, 0, 0)?;
+                if inp_swap_exact_output {
+                    // Exact output: only allow the input quoted from the swap backend
+                    let swap_quote: u64 = swap_quote_input(&swap_accounts.backend, inp_amount)?;
+                    if swap_quote > swap_limit {
+                        msg!("Swap quote: {} exceeds maximum: {}", swap_quote.to_string(), swap_limit.to_string());
+                        return Err(ErrorCode::SlippageExceeded.into());
                     }
-                    swap_contract::cpi::swap(sw_ctx, inp_swap_data_nonce, inp_swap_inb_nonce, inp_swap_out_nonce, 0, inp_swap_direction, false, true, inp_amount)?;
+                    swap_limit = swap_quote;
+                }
+                swap_tokens(&swap_accounts,
+                    &ctx.accounts.user_key.to_account_info(),
+                    &swap_accounts.swap_token,                      // User Swap Token
+                    &ctx.accounts.token_account.to_account_info(),  // Token Agent PDA
+                    &ctx.accounts.token_program.to_account_info(),
+                    &[],
+                    SwapParams { direction: inp_swap_direction, data_nonce: inp_swap_data_nonce, inb_nonce: inp_swap_inb_nonce, out_nonce: inp_swap_out_nonce },
+                    inp_amount,
+                    swap_limit,
+                    inp_swap_exact_output,
+                )?;
+            }
+
+            // Transfer tokens
+            let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
+            let root_pda_signer = &[&root_pda_seeds[..]];
+            let mut token_auth = ctx.accounts.user_key.to_account_info();
+            if inp_swap {
+                token_auth = ctx.accounts.root_key.to_account_info();
+            }
+
+            // Calculate fees
+            let fees: u64 = fee_quote.fee_amount;
+            if fees > 0 {
+                net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
+                fee_amount = fees;
+                let cpi_accounts = Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: ctx.accounts.fees_account.to_account_info(),
+                    authority: token_auth.clone(),
+                };
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx;
+                if inp_swap {
+                    cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                } else {
+                    cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
                 }
+                token::transfer(cpi_ctx, fees)?;
             }
+            // Pay revenue split recipients, then the merchant the remainder
+            let (split_payouts, merchant_amount) = revenue_split_payouts(&revenue_split, &settlement.token_mint, split_accounts, net_amount)?;
+            let mut payouts: Vec<(AccountInfo<'info>, u64)> = split_payouts.iter().map(|payout| (payout.token_account.clone(), payout.amount)).collect();
+            payouts.push((ctx.accounts.merchant_token.to_account_info(), merchant_amount));
+            for (acc_payout, payout_amount) in payouts.iter() {
+                if *payout_amount == 0 {
+                    continue;
+                }
+                let cpi_accounts = Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: acc_payout.clone(),
+                    authority: token_auth.clone(),
+                };
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx;
+                if inp_swap {
+                    cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                } else {
+                    cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                }
+                token::transfer(cpi_ctx, *payout_amount)?;
+            }
+            emit_split_payouts(&split_payouts, clock.slot, &mrch_approval.merchant_key, ctx.accounts.merchant_approval.key, inp_payment_id);
+
+            // Record merchant transaction
+            let na_program = ctx.accounts.net_auth.to_account_info();
+            if *na_program.key == net_authority::ID {
+                let na_accounts = RecordTransaction {
+                    tx_admin: ctx.accounts.root_key.to_account_info(),
+                    merchant_approval: ctx.accounts.merchant_approval.to_account_info(),
+                };
+                let na_ctx = CpiContext::new_with_signer(na_program, na_accounts, root_pda_signer);
+                //msg!("Atellix: Attempt to record transaction");
+                net_authority::cpi::record_tx(na_ctx)?;
+                mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
+            }
+        }
+
+        msg!("atellix-log");
+        emit!(PaymentEvent {
+            event_hash: 43781034894216267743388154650854733336, // solana/program/token-agent/merchant_payment
+            slot: clock.slot,
+            merchant_tx_id: mrch_approval.tx_count,
+            merchant_key: mrch_approval.merchant_key,
+            merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
+            dest_account: settlement.dest_account,
+            user_key: *ctx.accounts.user_key.to_account_info().key,
+            total: inp_amount,
+            amount: net_amount,
+            fees: fee_amount,
+            payment_id: inp_payment_id,
+            swap: inp_swap,
+        });
+
+        Ok(())
+    }
+
+    // Authorize an ephemeral session key to make payments to specific merchants from the user's token account up to a budget
+    pub fn open_session<'info>(ctx: Context<'_, '_, '_, 'info, OpenSession<'info>>,
+        inp_budget: u64,
+        inp_expires: i64,
+        inp_merchants: Vec<Pubkey>,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        if inp_expires <= clock.unix_timestamp {
+            msg!("Session expiration not in the future");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+        if inp_budget == 0 {
+            msg!("Invalid session budget");
+            return Err(ErrorCode::InvalidSession.into());
+        }
+        if inp_merchants.len() == 0 || inp_merchants.len() > MAX_SESSION_MERCHANTS || inp_merchants.contains(&Pubkey::default()) {
+            msg!("Invalid session merchants");
+            return Err(ErrorCode::InvalidSession.into());
+        }
+        verify_matching_accounts(&ctx.accounts.token_account.owner, ctx.accounts.user_key.key,
+            Some(String::from("Token account owner does not match user"))
+        )?;
+
+        // Reserve the session budget on the token link and approve the allowance
+        let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+        let link = &mut ctx.accounts.token_link;
+        init_token_link(link, ctx.accounts.allowance.key, &ctx.accounts.token_account.key(), ctx.accounts.user_key.key, link_bump)?;
+        link.subscriptions = link.subscriptions.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        link.approved = link.approved.checked_add(inp_budget as u128).ok_or(error!(ErrorCode::Overflow))?;
+        let approve_amount: u64 = approved_allowance(link);
+        let cpi_accounts = DelegateApprove {
+            allowance: ctx.accounts.allowance.to_account_info(),
+            allowance_payer: ctx.accounts.user_key.to_account_info(),
+            owner: ctx.accounts.user_key.to_account_info(),
+            delegate: ctx.accounts.root_key.to_account_info(),
+            delegate_root: ctx.accounts.delegate_root.to_account_info(),
+            token_account: ctx.accounts.token_account.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.delegate_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+
+        let session = &mut ctx.accounts.session_data;
+        session.user_key = ctx.accounts.user_key.key();
+        session.session_key = ctx.accounts.session_key.key();
+        session.token_account = ctx.accounts.token_account.key();
+        session.token_mint = ctx.accounts.token_account.mint;
+        session.allowance = ctx.accounts.allowance.key();
+        session.budget = inp_budget;
+        session.spent = 0;
+        session.expires = inp_expires;
+        session.merchant_count = inp_merchants.len() as u8;
+        for i in 0..inp_merchants.len() {
+            session.merchants[i] = inp_merchants[i];
+        }
+        session.bump = *ctx.bumps.get("session_data").unwrap();
+
+        msg!("atellix-log");
+        emit!(SessionEvent {
+            event_hash: 151742800477407749167782427414813488209, // solana/program/token-agent/open_session
+            slot: clock.slot,
+            session_data: session.key(),
+            user_key: session.user_key,
+            session_key: session.session_key,
+            token_account: session.token_account,
+            budget: session.budget,
+            spent: 0,
+            expires: session.expires,
+        });
+        Ok(())
+    }
+
+    // Payment to a session merchant signed by the session key, transferred with the user's token delegate allowance
+    pub fn session_payment<'info>(ctx: Context<'_, '_, '_, 'info, SessionPayment<'info>>,
+        inp_dest_nonce: u8,
+        inp_root_nonce: u8,
+        inp_payment_id: u128,
+        inp_amount: u64,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let ts = clock.unix_timestamp;
+
+        // Verify the session
+        let session = &ctx.accounts.session_data;
+        if ts >= session.expires {
+            msg!("Session expired");
+            return Err(ErrorCode::Expired.into());
+        }
+        verify_matching_accounts(&session.token_account, &ctx.accounts.token_account.key(),
+            Some(String::from("Token account does not match session"))
+        )?;
+        verify_matching_accounts(&session.allowance, ctx.accounts.allowance.key,
+            Some(String::from("Allowance does not match session"))
+        )?;
+        let merchants = &session.merchants[..(session.merchant_count as usize)];
+        if !merchants.contains(ctx.accounts.merchant_approval.key) {
+            msg!("Merchant not authorized for session: {}", ctx.accounts.merchant_approval.key.to_string());
+            return Err(ErrorCode::AccessDenied.into());
+        }
+
+        // Settlement mint: the merchant approval's mint or a registered merchant mint
+        let mut mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
+        let (acc_merchant_mint, remaining_accounts) = split_merchant_mint(&mrch_approval, &session.token_mint, ctx.remaining_accounts)?;
+        let settlement = get_settlement(&mrch_approval, ctx.accounts.merchant_approval.key, &session.token_mint, acc_merchant_mint)?;
+
+        // Revenue split recipient token accounts follow the merchant mint
+        let revenue_split = load_revenue_split(&ctx.accounts.revenue_split.to_account_info())?;
+        let (_, split_accounts) = split_revenue_accounts(&revenue_split, remaining_accounts)?;
+
+        let netauth = &ctx.accounts.net_auth.to_account_info().key;
+        verify_matching_accounts(netauth, &ctx.accounts.merchant_approval.owner,
+            Some(String::from("Invalid merchant approval owner"))
+        )?;
+        if !mrch_approval.active {
+            msg!("Inactive merchant approval");
+            return Err(ErrorCode::NotApproved.into());
+        }
+
+        // Verify merchant's associated token for the destination account and fees account
+        verify_settlement_accounts(&settlement, inp_dest_nonce,
+            &ctx.accounts.merchant_token.to_account_info(),
+            &ctx.accounts.fees_account.to_account_info(),
+        )?;
+
+        // User-paid fees count toward the session budget
+        let fee_quote = quote_settlement_fees(&ctx.accounts.fee_policy.to_account_info(), ctx.accounts.merchant_approval.key, &settlement, false, inp_amount)?;
+        let inp_amount: u64 = fee_quote.total;
+        let spent: u64 = session.spent.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+        if spent > session.budget {
+            msg!("Session spending: {} exceeds budget: {}", spent.to_string(), session.budget.to_string());
+            return Err(ErrorCode::TotalBudgetExceeded.into());
+        }
+        let net_amount: u64 = fee_quote.net_amount;
+        let fee_amount: u64 = fee_quote.fee_amount;
+        if inp_amount > 0 {
+            record_spending(&ctx.accounts.spending_policy.to_account_info(), &session.user_key, &session.token_mint, inp_amount, ts)?;
+            spend_allowance(&session.allowance, &ctx.accounts.token_link.to_account_info(), inp_amount)?;
+
+            // Pay fees and revenue split recipients, then the merchant the remainder
+            let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
+            let root_pda_signer = &[&root_pda_seeds[..]];
+            let (split_payouts, merchant_amount) = revenue_split_payouts(&revenue_split, &settlement.token_mint, split_accounts, net_amount)?;
+            let mut payouts: Vec<(AccountInfo<'info>, u64)> = vec![(ctx.accounts.fees_account.to_account_info(), fee_amount)];
+            payouts.extend(split_payouts.iter().map(|payout| (payout.token_account.clone(), payout.amount)));
+            payouts.push((ctx.accounts.merchant_token.to_account_info(), merchant_amount));
+            for (acc_payout, payout_amount) in payouts.iter() {
+                if *payout_amount == 0 {
+                    continue;
+                }
+                let cpi_accounts = DelegateTransfer {
+                    allowance: ctx.accounts.allowance.to_account_info(),
+                    delegate: ctx.accounts.root_key.to_account_info(),
+                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: acc_payout.clone(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                };
+                let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                token_delegate::cpi::delegate_transfer(cpi_ctx, *payout_amount)?;
+            }
+            emit_split_payouts(&split_payouts, clock.slot, &mrch_approval.merchant_key, ctx.accounts.merchant_approval.key, inp_payment_id);
+
+            // Record merchant transaction
+            let na_program = ctx.accounts.net_auth.to_account_info();
+            if *na_program.key == net_authority::ID {
+                let na_accounts = RecordTransaction {
+                    tx_admin: ctx.accounts.root_key.to_account_info(),
+                    merchant_approval: ctx.accounts.merchant_approval.to_account_info(),
+                };
+                let na_ctx = CpiContext::new_with_signer(na_program, na_accounts, root_pda_signer);
+                net_authority::cpi::record_tx(na_ctx)?;
+                mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
+            }
+        }
+        ctx.accounts.session_data.spent = spent;
+
+        msg!("atellix-log");
+        emit!(PaymentEvent {
+            event_hash: 80206476509713718280798088029145918801, // solana/program/token-agent/session_payment
+            slot: clock.slot,
+            merchant_tx_id: mrch_approval.tx_count,
+            merchant_key: mrch_approval.merchant_key,
+            merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
+            dest_account: settlement.dest_account,
+            user_key: *ctx.accounts.user_key.to_account_info().key,
+            total: inp_amount,
+            amount: net_amount,
+            fees: fee_amount,
+            payment_id: inp_payment_id,
+            swap: false,
+        });
+
+        Ok(())
+    }
+
+    // End a session and release its budget reservation from the allowance
+    pub fn close_session<'info>(ctx: Context<'_, '_, '_, 'info, CloseSession<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let session = &ctx.accounts.session_data;
+        verify_matching_accounts(&session.allowance, ctx.accounts.allowance.key,
+            Some(String::from("Allowance does not match session"))
+        )?;
+        verify_matching_accounts(&session.token_account, ctx.accounts.token_account.key,
+            Some(String::from("Token account does not match session"))
+        )?;
+
+        // Shrink the allowance by the session's reservation, or revoke it if no subscription or other session uses it
+        let link = release_session_allowance(session, &ctx.accounts.token_link.to_account_info())?;
+        if link.subscriptions == 0 {
+            revoke_allowance(&link,
+                &ctx.accounts.token_link.to_account_info(),
+                &ctx.accounts.allowance.to_account_info(),
+                &ctx.accounts.token_account.to_account_info(),
+                &ctx.accounts.user_key.to_account_info(),
+                &ctx.accounts.link_payer.to_account_info(),
+                &ctx.accounts.root_key.to_account_info(),
+                *ctx.bumps.get("root_key").unwrap(),
+                &ctx.accounts.delegate_program.to_account_info(),
+            )?;
+        } else {
+            let approve_amount: u64 = approved_allowance(&link);
+            let cpi_accounts = DelegateApprove {
+                allowance: ctx.accounts.allowance.to_account_info(),
+                allowance_payer: ctx.accounts.user_key.to_account_info(),
+                owner: ctx.accounts.user_key.to_account_info(),
+                delegate: ctx.accounts.root_key.to_account_info(),
+                delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                token_account: ctx.accounts.token_account.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.delegate_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+        }
+
+        msg!("atellix-log");
+        emit!(SessionEvent {
+            event_hash: 252152154874726747646907388461622091067, // solana/program/token-agent/close_session
+            slot: clock.slot,
+            session_data: session.key(),
+            user_key: session.user_key,
+            session_key: session.session_key,
+            token_account: session.token_account,
+            budget: session.budget,
+            spent: session.spent,
+            expires: session.expires,
+        });
+        Ok(())
+    }
+
+    pub fn create_bundle<'info>(ctx: Context<'_, '_, '_, 'info, CreateBundle<'info>>,
+        inp_link_token: bool,
+        inp_bundle_id: u128,
+        inp_period: u8,
+        inp_period_budget: u64,
+        inp_next_rebill: i64,
+        inp_rebill_max: u32,
+        inp_max_delay: i64,
+        inp_amounts: Vec<u64>,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let ts = clock.unix_timestamp;
+
+        // Merchant approvals for each bundle item are passed as remaining accounts
+        if inp_amounts.len() == 0 || inp_amounts.len() > MAX_BUNDLE_ITEMS || inp_amounts.len() != ctx.remaining_accounts.len() {
+            msg!("Invalid bundle items");
+            return Err(ErrorCode::InvalidBundle.into());
+        }
+        let netauth = ctx.accounts.net_auth.to_account_info().key;
+        verify_manager_approval(netauth, &ctx.accounts.manager_approval.to_account_info())?;
+        verify_matching_accounts(&ctx.accounts.token_account.owner, ctx.accounts.user_key.key,
+            Some(String::from("Token account owner does not match user"))
+        )?;
+        let token_mint: Pubkey = ctx.accounts.token_account.mint;
+
+        // Verify input
+        let period = SubscriptionPeriod::try_from_primitive(inp_period);
+        if period.is_err() {
+            msg!("Invalid subscription period");
+            return Err(ErrorCode::InvalidSubscriptionPeriod.into());
+        }
+        if inp_max_delay < 43200 { // 12 hours
+            msg!("Invalid max_delay below minimum of 12 hours (43200 seconds)");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+        let timeframe_end = ts.checked_add(inp_max_delay).ok_or(error!(ErrorCode::Overflow))?;
+        if inp_next_rebill < ts || inp_next_rebill > timeframe_end {
+            msg!("Next rebill not within timeframe");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+        let d1 = get_period_string(inp_next_rebill, period.unwrap())?;
+        let prev_period = inp_next_rebill.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+        let d2 = get_period_string(prev_period, period.unwrap())?;
+        if d1 == d2 {
+            msg!("Next rebill not beginning of period");
+            return Err(ErrorCode::InvalidTimeframe.into());
+        }
+
+        let mut bundle = SubscrBundle::default();
+        let mut bundle_total: u64 = 0;
+        for i in 0..inp_amounts.len() {
+            let acc_mrch_approve = &ctx.remaining_accounts[i];
+            verify_matching_accounts(netauth, acc_mrch_approve.owner,
+                Some(String::from("Invalid merchant approval owner"))
+            )?;
+            let mrch_approval = load_struct::<MerchantApproval>(acc_mrch_approve)?;
+            if !mrch_approval.active {
+                msg!("Inactive merchant approval");
+                return Err(ErrorCode::NotApproved.into());
+            }
+            verify_matching_accounts(&mrch_approval.token_mint, &token_mint,
+                Some(String::from("Token mint does not match approval"))
+            )?;
+            bundle_total = bundle_total.checked_add(inp_amounts[i]).ok_or(error!(ErrorCode::Overflow))?;
+            bundle.items[i] = BundleItem {
+                merchant_approval: *acc_mrch_approve.key,
+                merchant_key: mrch_approval.merchant_key,
+                amount: inp_amounts[i],
+            };
+        }
+        if bundle_total > inp_period_budget {
+            msg!("Bundle amount: {} exceeds budget: {}", bundle_total.to_string(), inp_period_budget.to_string());
+            return Err(ErrorCode::PeriodBudgetExceeded.into());
+        }
+
+        // Create bundle data
+        bundle.user_key = *ctx.accounts.user_key.to_account_info().key;
+        bundle.approval_program = *netauth;
+        bundle.manager_key = get_manager_key(&ctx.accounts.manager_approval.to_account_info())?;
+        bundle.manager_approval = *ctx.accounts.manager_approval.to_account_info().key;
+        bundle.token_mint = token_mint;
+        bundle.token_account = *ctx.accounts.token_account.to_account_info().key;
+        bundle.bundle_id = inp_bundle_id;
+        bundle.rebill_max = inp_rebill_max;
+        bundle.next_rebill = inp_next_rebill;
+        bundle.max_delay = inp_max_delay;
+        bundle.period = inp_period;
+        bundle.period_budget = inp_period_budget;
+        bundle.active = true;
+        bundle.item_count = inp_amounts.len() as u8;
+
+        // Reserve the allowance for the bundle rebills on the token link and approve it
+        if inp_link_token {
+            let link_bump: u8 = *ctx.bumps.get("token_link").unwrap();
+            let link = &mut ctx.accounts.token_link;
+            init_token_link(link, ctx.accounts.allowance.key, &ctx.accounts.token_account.key(), ctx.accounts.user_key.key, link_bump)?;
+            bundle.allowance = ctx.accounts.allowance.key();
+            bundle.allowance_amount = bundle_allowance(&bundle);
+            link.subscriptions = link.subscriptions.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            link.approved = link.approved.checked_add(bundle.allowance_amount as u128).ok_or(error!(ErrorCode::Overflow))?;
+            let approve_amount: u64 = approved_allowance(link);
+            let cpi_accounts = DelegateApprove {
+                allowance: ctx.accounts.allowance.to_account_info(),
+                allowance_payer: ctx.accounts.user_key.to_account_info(),
+                owner: ctx.accounts.user_key.to_account_info(),
+                delegate: ctx.accounts.root_key.to_account_info(),
+                delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                token_account: ctx.accounts.token_account.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.delegate_program.to_account_info();
+            let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+        } else {
+            record_link_payer(&mut ctx.accounts.token_link, ctx.accounts.user_key.key, *ctx.bumps.get("token_link").unwrap());
+        }
+        store_struct::<SubscrBundle>(&bundle, &ctx.accounts.bundle_data.to_account_info())?;
+
+        for i in 0..(bundle.item_count as usize) {
+            msg!("atellix-log");
+            emit!(BundleEvent {
+                event_hash: 190206351258677306003943917758721089833, // solana/program/token-agent/create_bundle
+                slot: clock.slot,
+                bundle_data: ctx.accounts.bundle_data.key(),
+                bundle_id: inp_bundle_id,
+                user_key: bundle.user_key,
+                merchant_key: bundle.items[i].merchant_key,
+                merchant_approval: bundle.items[i].merchant_approval,
+                merchant_token: Pubkey::default(),
+                payment_id: 0,
+                rebill_event: 0,
+                total: 0,
+                amount: bundle.items[i].amount,
+                fees: 0,
+                next_rebill: inp_next_rebill,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel_bundle<'info>(ctx: Context<'_, '_, '_, 'info, CancelBundle<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let bundle = &mut ctx.accounts.bundle_data;
+        verify_matching_accounts(&bundle.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match bundle"))
+        )?;
+        if !bundle.active {
+            msg!("Inactive bundle");
+            return Err(ErrorCode::InactiveSubscription.into());
+        }
+        bundle.active = false;
+
+        // Shrink the allowance by the bundle's remaining reservation, or revoke it if no subscription or session uses it
+        if bundle.allowance != Pubkey::default() {
+            verify_matching_accounts(&bundle.allowance, ctx.accounts.allowance.key,
+                Some(String::from("Allowance does not match bundle"))
+            )?;
+            verify_matching_accounts(&bundle.token_account, ctx.accounts.token_account.key,
+                Some(String::from("Token account does not match bundle"))
+            )?;
+            let link = release_bundle_allowance(bundle, &ctx.accounts.token_link.to_account_info())?;
+            if link.subscriptions == 0 {
+                revoke_allowance(&link,
+                    &ctx.accounts.token_link.to_account_info(),
+                    &ctx.accounts.allowance.to_account_info(),
+                    &ctx.accounts.token_account.to_account_info(),
+                    &ctx.accounts.user_key.to_account_info(),
+                    &ctx.accounts.link_payer.to_account_info(),
+                    &ctx.accounts.root_key.to_account_info(),
+                    *ctx.bumps.get("root_key").unwrap(),
+                    &ctx.accounts.delegate_program.to_account_info(),
+                )?;
+            } else {
+                let approve_amount: u64 = approved_allowance(&link);
+                let cpi_accounts = DelegateApprove {
+                    allowance: ctx.accounts.allowance.to_account_info(),
+                    allowance_payer: ctx.accounts.user_key.to_account_info(),
+                    owner: ctx.accounts.user_key.to_account_info(),
+                    delegate: ctx.accounts.root_key.to_account_info(),
+                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                    token_account: ctx.accounts.token_account.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                };
+                let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+                token_delegate::cpi::delegate_approve(cpi_ctx, true, approve_amount, approve_amount)?;
+            }
+        }
+
+        msg!("atellix-log");
+        emit!(BundleEvent {
+            event_hash: 96681454613201728028489442011428799312, // solana/program/token-agent/cancel_bundle
+            slot: clock.slot,
+            bundle_data: bundle.key(),
+            bundle_id: bundle.bundle_id,
+            user_key: bundle.user_key,
+            merchant_key: Pubkey::default(),
+            merchant_approval: Pubkey::default(),
+            merchant_token: Pubkey::default(),
+            payment_id: 0,
+            rebill_event: bundle.rebill_events,
+            total: 0,
+            amount: 0,
+            fees: 0,
+            next_rebill: -1,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_bundle(ctx: Context<CloseBundle>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let bundle = &ctx.accounts.bundle_data;
+        verify_matching_accounts(&bundle.user_key, ctx.accounts.user_key.to_account_info().key,
+            Some(String::from("User key does not match bundle"))
+        )?;
+        if bundle.active {
+            msg!("Bundle is active");
+            return Err(ErrorCode::SubscriptionActive.into());
+        }
+
+        msg!("atellix-log");
+        emit!(BundleEvent {
+            event_hash: 44943012368809568405140035052243788105, // solana/program/token-agent/close_bundle
+            slot: clock.slot,
+            bundle_data: bundle.key(),
+            bundle_id: bundle.bundle_id,
+            user_key: bundle.user_key,
+            merchant_key: Pubkey::default(),
+            merchant_approval: Pubkey::default(),
+            merchant_token: Pubkey::default(),
+            payment_id: 0,
+            rebill_event: bundle.rebill_events,
+            total: 0,
+            amount: 0,
+            fees: 0,
+            next_rebill: -1,
+        });
+
+        msg!("Closed Bundle: {}", bundle.key().to_string());
+        Ok(())
+    }
+
+    pub fn process_bundle<'info>(ctx: Context<'_, '_, '_, 'info, ProcessBundle<'info>>,
+        inp_root_nonce: u8,
+        inp_rebill_ts: i64,
+        inp_rebill_str: String,
+        inp_next_rebill: i64,
+        inp_payment_id: u128,
+        inp_dest_nonces: Vec<u8>,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let ts = clock.unix_timestamp;
+
+        // Validate accounts
+        verify_matching_accounts(&crate::ID, ctx.accounts.bundle_data.owner,
+            Some(String::from("Invalid bundle owner"))
+        )?;
+        let mut bundle = load_struct::<SubscrBundle>(&ctx.accounts.bundle_data.to_account_info())?;
+        verify_matching_accounts(&bundle.manager_approval, ctx.accounts.manager_approval.to_account_info().key,
+            Some(String::from("Manager approval does not match bundle"))
+        )?;
+        verify_matching_accounts(&bundle.token_account, &ctx.accounts.token_account.to_account_info().key,
+            Some(String::from("Token account does not match bundle"))
+        )?;
+        if !bundle.active {
+            msg!("Inactive bundle");
+            return Err(ErrorCode::InactiveSubscription.into());
+        }
+        if bundle.rebill_max > 0 && bundle.rebill_events >= bundle.rebill_max {
+            msg!("Maximum rebills reached");
+            return Err(ErrorCode::MaxRebills.into());
+        }
+        let mgr_approval = load_struct::<ManagerApproval>(&ctx.accounts.manager_approval.to_account_info())?;
+        if !mgr_approval.active {
+            msg!("Inactive manager approval");
+            return Err(ErrorCode::NotApproved.into());
+        }
+        verify_matching_accounts(&mgr_approval.manager_key, &ctx.accounts.manager_key.to_account_info().key,
+            Some(String::from("Manager key does not match approval"))
+        )?;
+
+        // Validate timeframe
+        verify_rebill_timeframe(bundle.period, bundle.next_rebill, bundle.max_delay, 0, 0,
+            ts, inp_rebill_ts, &inp_rebill_str, inp_next_rebill,
+        )?;
 
-            // Transfer tokens
-            let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
-            let root_pda_signer = &[&root_pda_seeds[..]];
-            let mut token_auth = ctx.accounts.user_key.to_account_info();
-            if inp_swap {
-                token_auth = ctx.accounts.root_key.to_account_info();
+        // Bundle item accounts (merchant approval, merchant token, fees account, fee policy) are passed in order as remaining accounts
+        let item_count: usize = bundle.item_count as usize;
+        if ctx.remaining_accounts.len() != item_count * 4 || inp_dest_nonces.len() != item_count {
+            msg!("Invalid bundle item accounts");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let netauth = ctx.accounts.net_auth.to_account_info().key;
+        verify_matching_accounts(netauth, &bundle.approval_program,
+            Some(String::from("Approval program does not match"))
+        )?;
+        let root_pda_seeds = &[ctx.program_id.as_ref(), &[inp_root_nonce]];
+        let root_pda_signer = &[&root_pda_seeds[..]];
+        let mut bundle_total: u64 = 0;
+        for i in 0..item_count {
+            let item = bundle.items[i];
+            let acc_mrch_approve = &ctx.remaining_accounts[i * 4];
+            let acc_merchant_token = &ctx.remaining_accounts[(i * 4) + 1];
+            let acc_fees = &ctx.remaining_accounts[(i * 4) + 2];
+            let acc_fee_policy = &ctx.remaining_accounts[(i * 4) + 3];
+            verify_matching_accounts(&item.merchant_approval, acc_mrch_approve.key,
+                Some(String::from("Merchant approval does not match bundle"))
+            )?;
+            verify_matching_accounts(netauth, acc_mrch_approve.owner,
+                Some(String::from("Invalid merchant approval owner"))
+            )?;
+            let mrch_approval = load_struct::<MerchantApproval>(acc_mrch_approve)?;
+            if !mrch_approval.active {
+                msg!("Inactive merchant approval");
+                return Err(ErrorCode::NotApproved.into());
             }
+            let settlement = get_settlement(&mrch_approval, acc_mrch_approve.key, &bundle.token_mint, None)?;
+            verify_settlement_accounts(&settlement, inp_dest_nonces[i], acc_merchant_token, acc_fees)?;
 
-            // Calculate fees
-            if mrch_approval.fees_bps > 0 {
-                let f1: u128 = (net_amount as u128) << 64;
-                let f2: u128 = f1.checked_mul(mrch_approval.fees_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
-                let f3: u128 = f2.checked_div(10000).ok_or(error!(ErrorCode::Overflow))?;
-                let fees: u64 = (f3 >> 64) as u64;
-                if fees > 0 {
-                    net_amount = net_amount.checked_sub(fees).ok_or(error!(ErrorCode::Overflow))?;
-                    fee_amount = fees;
-                    let cpi_accounts = Transfer {
-                        from: ctx.accounts.token_account.to_account_info(),
-                        to: ctx.accounts.fees_account.to_account_info(),
-                        authority: token_auth.clone(),
-                    };
-                    let cpi_program = ctx.accounts.token_program.to_account_info();
-                    let cpi_ctx;
-                    if inp_swap {
-                        cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-                    } else {
-                        cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-                    }
-                    token::transfer(cpi_ctx, fees)?;
-                }
-                //msg!("Atellix: Starting Amount: {} Ending Amount: {} Fees: {}", inp_amount.to_string(), amount.to_string(), fees.to_string());
+            let fee_quote = quote_settlement_fees(acc_fee_policy, acc_mrch_approve.key, &settlement, true, item.amount)?;
+            bundle_total = bundle_total.checked_add(fee_quote.total).ok_or(error!(ErrorCode::Overflow))?;
+            if bundle_total > bundle.period_budget {
+                msg!("Amount exceeds budget");
+                return Err(ErrorCode::PeriodBudgetExceeded.into());
             }
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.token_account.to_account_info(),
-                to: ctx.accounts.merchant_token.to_account_info(),
-                authority: token_auth.clone(),
-            };
-            let cpi_program = ctx.accounts.token_program.to_account_info();
-            let cpi_ctx;
-            if inp_swap {
-                cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
-            } else {
-                cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+            let payouts = [(acc_fees, fee_quote.fee_amount), (acc_merchant_token, fee_quote.net_amount)];
+            for (acc_payout, payout_amount) in payouts.iter() {
+                if *payout_amount == 0 {
+                    continue;
+                }
+                let cpi_accounts = DelegateTransfer {
+                    allowance: ctx.accounts.allowance.to_account_info(),
+                    delegate: ctx.accounts.root_key.to_account_info(),
+                    delegate_root: ctx.accounts.delegate_root.to_account_info(),
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: (*acc_payout).clone(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                };
+                let cpi_program = ctx.accounts.delegate_program.to_account_info();
+                let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, root_pda_signer);
+                token_delegate::cpi::delegate_transfer(cpi_ctx, *payout_amount)?;
             }
-            token::transfer(cpi_ctx, net_amount)?;
 
-            // Record merchant transaction
+            // Record merchant revenue
             let na_program = ctx.accounts.net_auth.to_account_info();
             if *na_program.key == net_authority::ID {
                 let na_accounts = RecordTransaction {
                     tx_admin: ctx.accounts.root_key.to_account_info(),
-                    merchant_approval: ctx.accounts.merchant_approval.to_account_info(),
+                    merchant_approval: acc_mrch_approve.clone(),
                 };
                 let na_ctx = CpiContext::new_with_signer(na_program, na_accounts, root_pda_signer);
-                //msg!("Atellix: Attempt to record transaction");
                 net_authority::cpi::record_tx(na_ctx)?;
-                mrch_approval = load_struct::<MerchantApproval>(&ctx.accounts.merchant_approval.to_account_info())?;
             }
+            bundle.total_fees = bundle.total_fees.checked_add(fee_quote.fee_amount).ok_or(error!(ErrorCode::Overflow))?;
+
+            msg!("atellix-log");
+            emit!(BundleEvent {
+                event_hash: 60077614511926692126394481235314826149, // solana/program/token-agent/process_bundle
+                slot: clock.slot,
+                bundle_data: ctx.accounts.bundle_data.key(),
+                bundle_id: bundle.bundle_id,
+                user_key: bundle.user_key,
+                merchant_key: item.merchant_key,
+                merchant_approval: item.merchant_approval,
+                merchant_token: *acc_merchant_token.key,
+                payment_id: inp_payment_id,
+                rebill_event: bundle.rebill_events.checked_add(1).ok_or(error!(ErrorCode::Overflow))?,
+                total: fee_quote.total,
+                amount: fee_quote.net_amount,
+                fees: fee_quote.fee_amount,
+                next_rebill: inp_next_rebill,
+            });
         }
 
-        msg!("atellix-log");
-        emit!(PaymentEvent {
-            event_hash: 43781034894216267743388154650854733336, // solana/program/token-agent/merchant_payment
-            slot: clock.slot,
-            merchant_tx_id: mrch_approval.tx_count,
-            merchant_key: mrch_approval.merchant_key,
-            merchant_token: *ctx.accounts.merchant_token.to_account_info().key,
-            dest_account: mrch_approval.dest_account,
-            user_key: *ctx.accounts.user_key.to_account_info().key,
-            total: inp_amount,
-            amount: net_amount,
-            fees: fee_amount,
-            payment_id: inp_payment_id,
-            swap: inp_swap,
-        });
+        record_spending(&ctx.accounts.spending_policy.to_account_info(), &bundle.user_key, &bundle.token_mint, bundle_total, ts)?;
+        if bundle.allowance != Pubkey::default() {
+            verify_matching_accounts(&bundle.allowance, ctx.accounts.allowance.key,
+                Some(String::from("Allowance does not match bundle"))
+            )?;
+            spend_allowance(&bundle.allowance, &ctx.accounts.token_link.to_account_info(), bundle_total)?;
+            bundle.allowance_amount = bundle.allowance_amount.saturating_sub(bundle_total);
+        }
+
+        // Update parameters
+        bundle.next_rebill = inp_next_rebill;
+        bundle.rebill_events = bundle.rebill_events.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        bundle.total_charged = bundle.total_charged.checked_add(bundle_total).ok_or(error!(ErrorCode::Overflow))?;
+        bundle.last_payment_ts = ts;
+
+        // Release the remaining reservation after the final rebill (the allowance shrinks when the user next links the token account)
+        if bundle.rebill_max > 0 && bundle.rebill_events >= bundle.rebill_max && bundle.allowance != Pubkey::default() {
+            release_bundle_allowance(&mut bundle, &ctx.accounts.token_link.to_account_info())?;
+            msg!("Released allowance reservation after the final rebill");
+        }
+        update_struct(&bundle, &ctx.accounts.bundle_data.to_account_info())?;
 
         Ok(())
     }
@@ -1560,20 +4805,261 @@ mod token_agent {
             swap: inp_swap,
         });
 
-        Ok(())
-    }*/
+        Ok(())
+    }*/
+}
+
+#[derive(Accounts)]
+pub struct UpdateMetadata<'info> {
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, TokenAgent>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+    #[account(init_if_needed, seeds = [program_id.as_ref(), b"metadata"], bump, payer = program_admin, space = 584)]
+    pub program_info: Account<'info, ProgramMetadata>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterMerchantMint<'info> {
+    #[account(init_if_needed, seeds = [merchant_approval.key().as_ref(), token_mint.key().as_ref()], bump, payer = merchant_key, space = 174)]
+    pub merchant_mint: Account<'info, MerchantMint>,
+    pub merchant_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub merchant_key: Signer<'info>,
+    pub token_mint: Account<'info, Mint>,
+    pub approval_fees_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetSpendingPolicy<'info> {
+    #[account(init_if_needed, seeds = [user_key.key().as_ref(), token_mint.key().as_ref(), b"spending"], bump, payer = user_key, space = 122)]
+    pub spending_policy: Account<'info, SpendingPolicy>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    pub token_mint: Account<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenSession<'info> {
+    #[account(init, seeds = [user_key.key().as_ref(), session_key.key().as_ref(), b"session"], bump, payer = user_key, space = 322)]
+    pub session_data: Account<'info, SessionKey>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    pub session_key: UncheckedAccount<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = user_key, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(inp_dest_nonce: u8, inp_root_nonce: u8)]
+pub struct SessionPayment<'info> {
+    #[account(mut, seeds = [user_key.key().as_ref(), session_key.key().as_ref(), b"session"], bump = session_data.bump)]
+    pub session_data: Account<'info, SessionKey>,
+    pub net_auth: UncheckedAccount<'info>,
+    #[account(seeds = [program_id.as_ref()], bump = inp_root_nonce)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub merchant_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub merchant_token: UncheckedAccount<'info>,
+    #[account(seeds = [merchant_approval.key().as_ref(), b"split"], bump)]
+    pub revenue_split: UncheckedAccount<'info>,
+    pub user_key: UncheckedAccount<'info>,
+    pub session_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub fees_account: UncheckedAccount<'info>,
+    pub fee_policy: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub spending_policy: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseSession<'info> {
+    #[account(mut, seeds = [user_key.key().as_ref(), session_data.session_key.as_ref(), b"session"], bump = session_data.bump, close = user_key)]
+    pub session_data: Account<'info, SessionKey>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateBundle<'info> {
+    #[account(mut)]
+    pub bundle_data: UncheckedAccount<'info>,
+    pub net_auth: UncheckedAccount<'info>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    pub manager_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = user_key, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelBundle<'info> {
+    #[account(mut)]
+    pub bundle_data: Account<'info, SubscrBundle>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseBundle<'info> {
+    #[account(mut, close = user_key)]
+    pub bundle_data: Account<'info, SubscrBundle>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(inp_root_nonce: u8)]
+pub struct ProcessBundle<'info> {
+    #[account(mut)]
+    pub bundle_data: UncheckedAccount<'info>,
+    pub net_auth: UncheckedAccount<'info>,
+    #[account(seeds = [program_id.as_ref()], bump = inp_root_nonce)]
+    pub root_key: UncheckedAccount<'info>,
+    pub manager_key: Signer<'info>,
+    pub manager_approval: UncheckedAccount<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub spending_policy: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeePolicy<'info> {
+    #[account(init_if_needed, seeds = [merchant_approval.key().as_ref(), token_mint.key().as_ref(), b"fee-policy"], bump, payer = fee_authority, space = 164)]
+    pub fee_policy: Account<'info, FeePolicy>,
+    pub merchant_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub fee_authority: Signer<'info>,
+    pub token_mint: Account<'info, Mint>,
+    pub approval_fees_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(inp_coupon_id: u128)]
+pub struct UpdateCoupon<'info> {
+    #[account(init_if_needed, seeds = [merchant_approval.key().as_ref(), inp_coupon_id.to_le_bytes().as_ref(), b"coupon"], bump, payer = merchant_key, space = 87)]
+    pub coupon: Account<'info, Coupon>,
+    pub merchant_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub merchant_key: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RedeemCoupon<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    pub user_key: Signer<'info>,
+    #[account(mut)]
+    pub coupon: Account<'info, Coupon>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterAffiliate<'info> {
+    #[account(init_if_needed, seeds = [merchant_approval.key().as_ref(), referrer_key.key().as_ref(), b"affiliate"], bump, payer = merchant_key, space = 82)]
+    pub affiliate: Account<'info, Affiliate>,
+    pub merchant_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub merchant_key: Signer<'info>,
+    pub referrer_key: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateMetadata<'info> {
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, TokenAgent>,
-    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
-    pub program_data: Account<'info, ProgramData>,
+pub struct UpdateRevenueSplit<'info> {
+    #[account(init_if_needed, seeds = [merchant_approval.key().as_ref(), b"split"], bump, payer = merchant_key, space = 185)]
+    pub revenue_split: Account<'info, RevenueSplit>,
+    pub merchant_approval: UncheckedAccount<'info>,
     #[account(mut)]
-    pub program_admin: Signer<'info>,
-    #[account(init_if_needed, seeds = [program_id.as_ref(), b"metadata"], bump, payer = program_admin, space = 584)]
-    pub program_info: Account<'info, ProgramMetadata>,
+    pub merchant_key: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
@@ -1592,17 +5078,45 @@ pub struct CreateSubscr<'info> {
     pub manager_approval: UncheckedAccount<'info>,
     #[account(mut)]
     pub user_key: Signer<'info>,
+    #[account(mut)]
+    pub rent_payer: Signer<'info>,
     #[account(address = token::ID)]
     pub token_program: UncheckedAccount<'info>,
     #[account(mut)]
     pub token_account: UncheckedAccount<'info>,
     #[account(mut)]
     pub fees_account: UncheckedAccount<'info>,
+    pub fee_policy: UncheckedAccount<'info>,
     #[account(address = token_delegate::ID)]
     pub delegate_program: UncheckedAccount<'info>,
     pub delegate_root: UncheckedAccount<'info>,
     #[account(mut)]
     pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = rent_payer, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(inp_intent: SubscrIntent)]
+pub struct CreateSubscrIntent<'info> {
+    #[account(mut)]
+    pub subscr_data: UncheckedAccount<'info>,
+    pub net_auth: UncheckedAccount<'info>,
+    pub merchant_approval: UncheckedAccount<'info>,
+    pub manager_approval: UncheckedAccount<'info>,
+    pub user_key: UncheckedAccount<'info>,
+    pub token_account: UncheckedAccount<'info>,
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+    #[account(init, seeds = [user_key.key().as_ref(), inp_intent.nonce.to_le_bytes().as_ref(), b"intent"], bump, payer = rent_payer, space = 81)]
+    pub intent_receipt: Account<'info, IntentReceipt>,
+    #[account(mut)]
+    pub rent_payer: Signer<'info>,
+    #[account(address = solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
     #[account(address = system_program::ID)]
     pub system_program: UncheckedAccount<'info>,
 }
@@ -1620,6 +5134,7 @@ pub struct UpdateSubscr<'info> {
     #[account(mut)]
     pub merchant_token: UncheckedAccount<'info>,
     pub manager_approval: UncheckedAccount<'info>,
+    #[account(mut)]
     pub user_key: Signer<'info>,
     #[account(address = token::ID)]
     pub token_program: UncheckedAccount<'info>,
@@ -1627,27 +5142,169 @@ pub struct UpdateSubscr<'info> {
     pub token_account: UncheckedAccount<'info>,
     #[account(mut)]
     pub fees_account: UncheckedAccount<'info>,
+    pub fee_policy: UncheckedAccount<'info>,
     #[account(address = token_delegate::ID)]
     pub delegate_program: UncheckedAccount<'info>,
     pub delegate_root: UncheckedAccount<'info>,
     #[account(mut)]
     pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = user_key, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(mut)]
+    pub prev_token_link: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
     #[account(address = system_program::ID)]
     pub system_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_token_account: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
 pub struct CloseSubscr<'info> {
-    #[account(mut, close = fee_recipient)]
+    #[account(mut, close = rent_payer, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
     pub subscr_data: Account<'info, SubscrData>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
     pub user_key: Signer<'info>,
     #[account(mut)]
-    pub fee_recipient: Signer<'info>,
+    pub rent_payer: UncheckedAccount<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateManager<'info> {
+pub struct MigrateSubscr<'info> {
+    #[account(mut, owner = crate::ID)]
+    pub subscr_data: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateTokenLink<'info> {
+    #[account(mut, owner = crate::ID)]
+    pub token_link: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TransferSubscr<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(mut)]
+    pub new_user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = new_user_key, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(mut)]
+    pub prev_token_link: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_token_account: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTokenAccount<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
     #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_account: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub allowance: UncheckedAccount<'info>,
+    #[account(init_if_needed, seeds = [allowance.key().as_ref(), b"token-link"], bump, payer = user_key, space = 125)]
+    pub token_link: Account<'info, TokenLink>,
+    #[account(mut)]
+    pub prev_token_link: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_allowance: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub prev_token_account: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub link_payer: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFallbackAccounts<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_key: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_key: Signer<'info>,
+    #[account(address = token::ID)]
+    pub token_program: UncheckedAccount<'info>,
+    #[account(address = token_delegate::ID)]
+    pub delegate_program: UncheckedAccount<'info>,
+    pub delegate_root: UncheckedAccount<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: UncheckedAccount<'info>,
+    pub funding_account: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetFiatPricing<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    pub user_key: Signer<'info>,
+    pub merchant_key: Signer<'info>,
+    pub price_oracle: UncheckedAccount<'info>,
+    pub token_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateManager<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
     pub subscr_data: Account<'info, SubscrData>,
     pub manager_prev: Signer<'info>,
     pub manager_key: UncheckedAccount<'info>,
@@ -1656,10 +5313,34 @@ pub struct UpdateManager<'info> {
 
 #[derive(Accounts)]
 pub struct ManagerCancel<'info> {
+    #[account(mut, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    pub manager_key: Signer<'info>,
+    pub manager_approval: UncheckedAccount<'info>,
     #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManagerClose<'info> {
+    #[account(mut, close = rent_payer, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
     pub subscr_data: Account<'info, SubscrData>,
     pub manager_key: Signer<'info>,
     pub manager_approval: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub rent_payer: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReclaimRent<'info> {
+    #[account(mut, close = rent_payer, constraint = subscr_data.version == SUBSCR_DATA_VERSION @ ErrorCode::InvalidDataVersion)]
+    pub subscr_data: Account<'info, SubscrData>,
+    #[account(mut)]
+    pub rent_payer: Signer<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
@@ -1687,6 +5368,13 @@ pub struct ProcessSubscr<'info> {
     pub delegate_root: UncheckedAccount<'info>,
     #[account(mut)]
     pub allowance: UncheckedAccount<'info>,
+    #[account(seeds = [merchant_approval.key().as_ref(), b"split"], bump)]
+    pub revenue_split: UncheckedAccount<'info>,
+    pub fee_policy: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub spending_policy: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub token_link: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
@@ -1706,6 +5394,9 @@ pub struct MerchantPayment<'info> {
     pub token_account: Account<'info, TokenAccount>,
     #[account(mut)]
     pub fees_account: UncheckedAccount<'info>,
+    #[account(seeds = [merchant_approval.key().as_ref(), b"split"], bump)]
+    pub revenue_split: UncheckedAccount<'info>,
+    pub fee_policy: UncheckedAccount<'info>,
 }
 
 /*#[derive(Accounts)]
@@ -1729,6 +5420,7 @@ pub struct MerchantReceive<'info> {
 
 #[account]
 pub struct SubscrData {
+    pub version: u8,                    // Data layout version
     pub user_key: Pubkey,               // The user that owns this subscription
     pub approval_program: Pubkey,       // The address of the network authority program that signs approvals
     pub merchant_key: Pubkey,           // The merchant account that receives subscription payments
@@ -1749,16 +5441,52 @@ pub struct SubscrData {
     pub period: u8,                     // Subscription rebill period
     pub period_budget: u64,             // Per-rebill budget (maximum amount, not necessarily the amount that will be billed which could be less)
     pub use_total: bool,                // Enable a total budget for the entire subscription (for manager initiated payments, user initiated payments do not count towards this limit)
-    pub total_budget: u64,              // Total budget for the entire subscription
+    pub total_budget: u64,              // Remaining total budget for the entire subscription
+    pub budget_spent: u64,              // Amount spent to date by manager initiated payments
+    pub total_charged: u64,             // Total amount charged to date (including fees)
+    pub total_fees: u64,                // Total fees paid to date
+    pub last_payment: u64,              // Amount of the most recent payment (including fees)
+    pub last_payment_ts: i64,           // UTC timestamp of the most recent payment
     pub active: bool,                   // Subscription is active
     pub swap: bool,                     // Swap tokens before payment
     pub swap_direction: bool,           // Swap direction
     pub swap_mode: u8,                  // Swap mode
+    pub fallback_count: u8,             // Number of fallback token accounts
+    pub fallback_accounts: [Pubkey; 3], // Ordered fallback token accounts used when the primary account cannot cover a rebill
+    pub swap_max_input: u64,            // Maximum swap input tokens per rebill (0 = no limit)
+    pub swap_slippage_bps: u32,         // Maximum swap input above the swap backend quote in basis points (0 = no limit)
+    pub swap_exact_output: bool,        // Pull only the swap input quoted from the swap backend and verify exact payouts
+    pub price_oracle: Pubkey,           // Fiat pricing price account (default = rebills are in payment token units)
+    pub oracle_type: u8,                // OracleType
+    pub fiat_max_amount: u64,           // Maximum rebill amount in reference currency units
+    pub fiat_decimals: u8,              // Reference currency decimals (e.g. 2 for USD cents)
+    pub token_decimals: u8,             // Payment token mint decimals
+    pub oracle_max_age: i64,            // Maximum oracle price age in seconds
+    pub oracle_max_conf_bps: u32,       // Maximum oracle confidence interval in basis points of the price (0 = no limit)
+    pub referrer: Pubkey,               // Referrer wallet paid a commission on rebills (default = no referral)
+    pub referral_bps: u32,              // Referral commission in basis points of the net amount after fees
+    pub referral_rebills: u32,          // Number of rebills that pay a referral commission (0 = unlimited)
+    pub referral_count: u32,            // Rebills that have paid a referral commission
+    pub total_referral: u64,            // Total referral commissions paid to date
+    pub coupon: Pubkey,                 // Redeemed coupon (default = no coupon)
+    pub coupon_type: u8,                // DiscountType
+    pub coupon_value: u64,              // Discount in basis points or pricing units
+    pub coupon_duration: u32,           // Number of rebills discounted (0 = unlimited)
+    pub coupon_uses: u32,               // Rebills discounted to date
+    pub allowance: Pubkey,              // Token delegate allowance holding this subscription's reservation (default = not linked)
+    pub allowance_amount: u64,          // Allowance reserved for the remaining rebills
+    pub cancelled_at: i64,              // UTC timestamp of cancellation (0 = not cancelled)
+    pub rent_payer: Pubkey,             // Account that paid the subscription rent and receives it when closed (default = user)
+    pub rent_reclaim_delay: i64,        // Seconds a subscription must be inactive before the rent payer can reclaim the rent
+    pub fallback_allowances: [Pubkey; 3], // Token delegate allowances holding the fallback reservations (default = not linked)
+    pub fallback_amounts: [u64; 3],     // Allowance reserved on each fallback account for the remaining rebills
+    pub reserved: [u8; 38],             // Reserved for future fields (new fields take space from here)
 }
 
 impl Default for SubscrData {
     fn default() -> Self {
         Self {
+            version: SUBSCR_DATA_VERSION,
             user_key: Pubkey::default(),
             approval_program: Pubkey::default(),
             merchant_key: Pubkey::default(),
@@ -1779,14 +5507,79 @@ impl Default for SubscrData {
             period_budget: 0,
             use_total: false,
             total_budget: 0,
+            budget_spent: 0,
+            total_charged: 0,
+            total_fees: 0,
+            last_payment: 0,
+            last_payment_ts: 0,
             active: true,
             swap: false,
             swap_direction: true,
             swap_mode: 0,
+            fallback_count: 0,
+            fallback_accounts: [Pubkey::default(); MAX_FALLBACK_ACCOUNTS],
+            swap_max_input: 0,
+            swap_slippage_bps: 0,
+            swap_exact_output: false,
+            price_oracle: Pubkey::default(),
+            oracle_type: 0,
+            fiat_max_amount: 0,
+            fiat_decimals: 0,
+            token_decimals: 0,
+            oracle_max_age: 0,
+            oracle_max_conf_bps: 0,
+            referrer: Pubkey::default(),
+            referral_bps: 0,
+            referral_rebills: 0,
+            referral_count: 0,
+            total_referral: 0,
+            coupon: Pubkey::default(),
+            coupon_type: 0,
+            coupon_value: 0,
+            coupon_duration: 0,
+            coupon_uses: 0,
+            allowance: Pubkey::default(),
+            allowance_amount: 0,
+            cancelled_at: 0,
+            rent_payer: Pubkey::default(),
+            rent_reclaim_delay: 0,
+            fallback_allowances: [Pubkey::default(); MAX_FALLBACK_ACCOUNTS],
+            fallback_amounts: [0; MAX_FALLBACK_ACCOUNTS],
+            reserved: [0; 38],
         }
     }
 }
 
+// Original subscription data layout (before versioning), read by migrate_subscription
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct SubscrDataV0 {
+    pub user_key: Pubkey,
+    pub approval_program: Pubkey,
+    pub merchant_key: Pubkey,
+    pub merchant_approval: Pubkey,
+    pub manager_key: Pubkey,
+    pub manager_approval: Pubkey,
+    pub token_mint: Pubkey,
+    pub token_account: Pubkey,
+    pub swap_account: Pubkey,
+    pub subscr_id: u128,
+    pub rebill_events: u32,
+    pub rebill_max: u32,
+    pub next_rebill: i64,
+    pub not_valid_before: i64,
+    pub not_valid_after: i64,
+    pub max_delay: i64,
+    pub period: u8,
+    pub period_budget: u64,
+    pub use_total: bool,
+    pub total_budget: u64,
+    pub active: bool,
+    pub swap: bool,
+    pub swap_direction: bool,
+    pub swap_mode: u8,
+}
+
+// Fields are appended to the end of the event, off-chain decoders built against an earlier layout must be updated to read new events
 #[event]
 pub struct SubscrEvent {
     pub event_hash: u128,
@@ -1805,6 +5598,52 @@ pub struct SubscrEvent {
     pub fees: u64,
     pub next_rebill: i64,
     pub swap: bool,
+    pub total_charged: u64,
+    pub total_fees: u64,
+    pub last_payment: u64,
+    pub last_payment_ts: i64,
+    pub funding_account: Pubkey,
+    pub fiat_amount: u64,
+    pub referrer: Pubkey,
+    pub referral_amount: u64,
+    pub coupon: Pubkey,
+    pub discount: u64,
+    pub sponsor_amount: u64,
+}
+
+impl SubscrEvent {
+    // Event carrying the subscription's current state, emit sites override the payment details
+    pub fn for_subscription(subscr: &SubscrData, subscr_data: Pubkey, event_hash: u128, slot: u64) -> Self {
+        Self {
+            event_hash,
+            slot,
+            merchant_tx_id: 0,
+            merchant_key: subscr.merchant_key,
+            merchant_token: Pubkey::default(),
+            dest_account: Pubkey::default(),
+            user_key: subscr.user_key,
+            subscr_data,
+            subscr_id: subscr.subscr_id,
+            payment_id: 0,
+            rebill_event: subscr.rebill_events,
+            total: 0,
+            amount: 0,
+            fees: 0,
+            next_rebill: subscr.next_rebill,
+            swap: subscr.swap,
+            total_charged: subscr.total_charged,
+            total_fees: subscr.total_fees,
+            last_payment: subscr.last_payment,
+            last_payment_ts: subscr.last_payment_ts,
+            funding_account: Pubkey::default(),
+            fiat_amount: 0,
+            referrer: subscr.referrer,
+            referral_amount: 0,
+            coupon: subscr.coupon,
+            discount: 0,
+            sponsor_amount: 0,
+        }
+    }
 }
 
 #[event]
@@ -1823,6 +5662,229 @@ pub struct PaymentEvent {
     pub swap: bool,
 }
 
+#[event]
+pub struct SplitEvent {
+    pub event_hash: u128,
+    pub slot: u64,
+    pub merchant_key: Pubkey,
+    pub merchant_approval: Pubkey,
+    pub payment_id: u128,
+    pub recipient: Pubkey,
+    pub recipient_token: Pubkey,
+    pub share_bps: u32,
+    pub amount: u64,
+}
+
+#[event]
+pub struct AffiliateEvent {
+    pub event_hash: u128,
+    pub slot: u64,
+    pub merchant_key: Pubkey,
+    pub merchant_approval: Pubkey,
+    pub referrer: Pubkey,
+    pub active: bool,
+    pub max_bps: u32,
+    pub max_rebills: u32,
+}
+
+#[event]
+pub struct SpendingPolicyEvent {
+    pub event_hash: u128,
+    pub slot: u64,
+    pub user_key: Pubkey,
+    pub token_mint: Pubkey,
+    pub active: bool,
+    pub daily_limit: u64,
+    pub monthly_limit: u64,
+}
+
+#[event]
+pub struct SessionEvent {
+    pub event_hash: u128,
+    pub slot: u64,
+    pub session_data: Pubkey,
+    pub user_key: Pubkey,
+    pub session_key: Pubkey,
+    pub token_account: Pubkey,
+    pub budget: u64,
+    pub spent: u64,
+    pub expires: i64,
+}
+
+#[event]
+pub struct BundleEvent {
+    pub event_hash: u128,
+    pub slot: u64,
+    pub bundle_data: Pubkey,
+    pub bundle_id: u128,
+    pub user_key: Pubkey,
+    pub merchant_key: Pubkey,
+    pub merchant_approval: Pubkey,
+    pub merchant_token: Pubkey,
+    pub payment_id: u128,
+    pub rebill_event: u32,
+    pub total: u64,
+    pub amount: u64,
+    pub fees: u64,
+    pub next_rebill: i64,
+}
+
+#[account]
+pub struct MerchantMint {
+    pub active: bool,
+    pub merchant_approval: Pubkey,      // The merchant approval this settlement mint extends
+    pub merchant_key: Pubkey,
+    pub token_mint: Pubkey,             // The settlement mint
+    pub dest_account: Pubkey,           // The merchant wallet receiving payments (associated token owner)
+    pub fees_account: Pubkey,           // The network fees associated token account for this mint
+    pub fees_bps: u32,
+    pub bump: u8,
+}
+// 8 + 1 + (32 * 5) + 4 + 1 = 174
+
+#[account]
+pub struct RevenueSplit {
+    pub merchant_approval: Pubkey,      // The merchant approval whose net payments are split
+    pub recipient_count: u8,
+    pub recipients: [Pubkey; 4],        // Recipient wallets (associated token owners)
+    pub shares_bps: [u32; 4],           // Share of the net amount for each recipient, merchant receives the remainder
+}
+// 8 + 32 + 1 + (32 * 4) + (4 * 4) = 185
+
+#[account]
+pub struct SpendingPolicy {
+    pub active: bool,
+    pub user_key: Pubkey,               // The user whose delegated outflow is limited
+    pub token_mint: Pubkey,             // The token mint the limits apply to
+    pub daily_limit: u64,               // Maximum delegated outflow per UTC day (0 = no limit)
+    pub monthly_limit: u64,             // Maximum delegated outflow per UTC month (0 = no limit)
+    pub day: i64,                       // Current day (days since epoch)
+    pub day_spent: u64,                 // Outflow during the current day
+    pub month: i64,                     // Current month (year * 12 + month index)
+    pub month_spent: u64,               // Outflow during the current month
+    pub bump: u8,
+}
+// 8 + 1 + (32 * 2) + (8 * 6) + 1 = 122
+
+#[account]
+pub struct TokenLink {
+    pub allowance: Pubkey,              // Token delegate allowance account
+    pub token_account: Pubkey,          // Token account linked to the allowance
+    pub subscriptions: u32,             // Subscriptions and sessions with a reservation on the allowance
+    pub approved: u128,                 // Sum of the reservations (the approved allowance)
+    pub rent_payer: Pubkey,             // Account that paid the token link rent and receives it when closed
+    pub bump: u8,
+}
+// 8 + (32 * 3) + 4 + 16 + 1 = 125
+
+// Original token link layout (before rent payers were recorded), read by migrate_token_link
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct TokenLinkV0 {
+    pub allowance: Pubkey,
+    pub token_account: Pubkey,
+    pub subscriptions: u32,
+    pub approved: u128,
+    pub bump: u8,
+}
+
+#[account]
+pub struct IntentReceipt {
+    pub user_key: Pubkey,               // The user that signed the intent
+    pub nonce: u64,                     // Intent nonce
+    pub subscr_data: Pubkey,            // Subscription created from the intent
+    pub bump: u8,
+}
+// 8 + 32 + 8 + 32 + 1 = 81
+
+#[account]
+pub struct SessionKey {
+    pub user_key: Pubkey,               // The user that authorized the session
+    pub session_key: Pubkey,            // Ephemeral key allowed to make payments for the user
+    pub token_account: Pubkey,          // The token account payments are made from
+    pub token_mint: Pubkey,             // The token mint of the token account
+    pub allowance: Pubkey,              // Token delegate allowance holding the session's budget reservation
+    pub budget: u64,                    // Maximum total of session payments (including user-paid fees)
+    pub spent: u64,                     // Total of session payments to date
+    pub expires: i64,                   // UTC timestamp after which the session key cannot make payments
+    pub merchant_count: u8,             // Number of merchant approvals the session can pay
+    pub merchants: [Pubkey; 4],         // Merchant approvals the session can pay
+    pub bump: u8,
+}
+// 8 + (32 * 5) + (8 * 3) + 1 + (32 * 4) + 1 = 322
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct BundleItem {
+    pub merchant_approval: Pubkey,      // The merchant approval paid for this item
+    pub merchant_key: Pubkey,
+    pub amount: u64,                    // Amount charged for this item each rebill
+}
+// 32 + 32 + 8 = 72
+
+#[account]
+#[derive(Default)]
+pub struct SubscrBundle {
+    pub user_key: Pubkey,               // The user that owns this bundle
+    pub approval_program: Pubkey,       // The address of the network authority program that signs approvals
+    pub manager_key: Pubkey,            // The rebill manager account
+    pub manager_approval: Pubkey,       // The rebill manager approval from the network authority
+    pub token_mint: Pubkey,             // The token mint to pay for the bundle
+    pub token_account: Pubkey,          // The token account to pay for the bundle
+    pub bundle_id: u128,                // External bundle UUID
+    pub rebill_events: u32,             // Count of rebill events
+    pub rebill_max: u32,                // Maximum number of times to rebill (0 = unlimited)
+    pub next_rebill: i64,               // The start of the next rebilling period
+    pub max_delay: i64,                 // The number of seconds after the start of the rebill period the manager can be delayed in attempting to rebill
+    pub period: u8,                     // Bundle rebill period
+    pub period_budget: u64,             // Combined per-rebill budget for all items (including user-paid fees)
+    pub total_charged: u64,             // Total amount charged to date (including fees)
+    pub total_fees: u64,                // Total fees paid to date
+    pub last_payment_ts: i64,           // UTC timestamp of the most recent payment
+    pub active: bool,                   // Bundle is active
+    pub item_count: u8,                 // Number of bundle items
+    pub items: [BundleItem; 4],         // Merchant plans covered by the bundle
+    pub allowance: Pubkey,              // Token delegate allowance holding the bundle's reservation (default = not linked)
+    pub allowance_amount: u64,          // Remaining allowance reserved for the bundle rebills
+}
+// 8 + (32 * 6) + 16 + (4 * 2) + (8 * 2) + 1 + (8 * 4) + 1 + 1 + (72 * 4) + 32 + 8 = 603
+
+#[account]
+pub struct FeePolicy {
+    pub active: bool,
+    pub merchant_approval: Pubkey,      // The merchant approval the policy applies to
+    pub token_mint: Pubkey,             // The settlement mint (flat fees and caps are in this mint's units)
+    pub fee_authority: Pubkey,          // The owner of the merchant approval's fees account
+    pub subscr_fees: FeeSchedule,       // Fees for subscription payments and rebills
+    pub payment_fees: FeeSchedule,      // Fees for one-off merchant payments
+    pub bump: u8,
+}
+// 8 + 1 + (32 * 3) + (29 * 2) + 1 = 164
+
+#[account]
+pub struct Coupon {
+    pub active: bool,
+    pub merchant_approval: Pubkey,      // The merchant approval issuing the coupon
+    pub coupon_id: u128,                // External coupon code UUID
+    pub discount_type: u8,              // DiscountType
+    pub discount_value: u64,            // Discount in basis points or pricing units
+    pub duration: u32,                  // Number of rebills discounted (0 = unlimited)
+    pub max_redemptions: u32,           // Maximum subscriptions redeeming the coupon (0 = unlimited)
+    pub redemptions: u32,               // Subscriptions that have redeemed the coupon (counted at redemption, not at the first discounted charge)
+    pub expires: i64,                   // UTC timestamp after which the coupon cannot be redeemed (0 = no expiry)
+    pub bump: u8,
+}
+// 8 + 1 + 32 + 16 + 1 + 8 + (4 * 3) + 8 + 1 = 87
+
+#[account]
+pub struct Affiliate {
+    pub active: bool,
+    pub merchant_approval: Pubkey,      // The merchant approval paying referral commissions
+    pub referrer: Pubkey,               // The referrer wallet (associated token owner)
+    pub max_bps: u32,                   // Maximum referral commission in basis points
+    pub max_rebills: u32,               // Maximum rebills paying a commission (0 = unlimited)
+    pub bump: u8,
+}
+// 8 + 1 + (32 * 2) + 4 + 4 + 1 = 82
+
 #[account]
 pub struct ProgramMetadata {
     pub semvar_major: u32,
@@ -1872,4 +5934,52 @@ pub enum ErrorCode {
     MaxRebills,
     #[msg("Overflow")]
     Overflow,
+    #[msg("Invalid rebill maximum")]
+    InvalidRebillMax,
+    #[msg("Invalid total budget")]
+    InvalidTotalBudget,
+    #[msg("Subscription already migrated")]
+    AlreadyMigrated,
+    #[msg("Insufficient funds")]
+    InsufficientFunds,
+    #[msg("Swap slippage exceeded")]
+    SlippageExceeded,
+    #[msg("Invalid swap slippage")]
+    InvalidSlippage,
+    #[msg("Payout amount mismatch")]
+    InvalidPayout,
+    #[msg("Invalid swap accounts")]
+    InvalidSwapAccounts,
+    #[msg("Invalid swap program")]
+    InvalidSwapProgram,
+    #[msg("Invalid swap token mint")]
+    InvalidSwapMint,
+    #[msg("Invalid price oracle")]
+    InvalidOracle,
+    #[msg("Stale oracle price")]
+    StaleOraclePrice,
+    #[msg("Fiat amount exceeds maximum")]
+    FiatAmountExceeded,
+    #[msg("Invalid fees")]
+    InvalidFees,
+    #[msg("Invalid revenue split")]
+    InvalidRevenueSplit,
+    #[msg("Invalid referral terms")]
+    InvalidReferral,
+    #[msg("Invalid coupon")]
+    InvalidCoupon,
+    #[msg("Invalid bundle")]
+    InvalidBundle,
+    #[msg("Spending limit exceeded")]
+    SpendingLimitExceeded,
+    #[msg("Subscription is active")]
+    SubscriptionActive,
+    #[msg("Invalid subscription intent")]
+    InvalidIntent,
+    #[msg("Invalid session")]
+    InvalidSession,
+    #[msg("Oracle price confidence exceeds maximum")]
+    OracleConfidence,
+    #[msg("Unsupported subscription data version")]
+    InvalidDataVersion,
 }
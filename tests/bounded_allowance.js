@@ -0,0 +1,69 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, MAX_ALLOWANCE_REBILLS } = require('./lib/agent')
+
+// Delegate approvals sized to the reservations of the subscriptions using the token account
+
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 3
+
+describe('bounded_allowance', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Approves the budget for the remaining rebills', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+    })
+
+    it('Approves the total budget when it is lower', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: 0, useTotal: true, totalBudget: 25000 }, { tokenAccount: tokenAccount })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(subscr.allowanceAmount.toString(), '25000')
+        assert.equal(link.approved.toString(), '25000')
+    })
+
+    it('Approves the combined reservations of subscriptions sharing a token account', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscr1 = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: 0 }, { tokenAccount: tokenAccount })
+        const link1 = await agent.tokenLink(tokenAccount)
+        assert.equal(link1.subscriptions, 2)
+        assert.equal(link1.approved.toString(), (PERIOD_BUDGET * (REBILL_MAX + MAX_ALLOWANCE_REBILLS)).toString())
+
+        // Raising the budget tops up the approval by the difference
+        await agent.updateSubscription(subscr1, { periodBudget: new anchor.BN(PERIOD_BUDGET * 2) })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscr1)
+        const link2 = await agent.tokenLink(tokenAccount)
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * 2 * REBILL_MAX).toString())
+        assert.equal(link2.subscriptions, 2)
+        assert.equal(link2.approved.toString(), (PERIOD_BUDGET * (2 * REBILL_MAX + MAX_ALLOWANCE_REBILLS)).toString())
+    })
+
+    it('Shrinks the approval when a subscription is cancelled', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscr1 = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: 0 }, { tokenAccount: tokenAccount })
+        await agent.updateSubscription(subscr1, { active: false })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscr1)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.ok(!subscr.active)
+        assert.equal(subscr.allowanceAmount.toString(), '0')
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * MAX_ALLOWANCE_REBILLS).toString())
+    })
+})
@@ -0,0 +1,200 @@
+const { Keypair, SystemProgram } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, DELEGATE_PROGRAM, MAX_ALLOWANCE_REBILLS, nextRebill, paymentId } = require('./lib/agent')
+
+// Subscription bundles covering several merchant plans with one token link reservation
+
+const PERIOD_BUDGET = 5000
+const REBILL_MAX = 6
+const MAX_DELAY = 365 * 86400
+
+describe('bundle', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    async function createBundle(tokenAccount, terms = {}) {
+        const t = Object.assign({
+            periodBudget: PERIOD_BUDGET,
+            rebillMax: REBILL_MAX,
+            maxDelay: MAX_DELAY,
+            amounts: [3000, 2000],
+        }, terms)
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        const bundleData = Keypair.generate()
+        const bundleDataBytes = tokenAgent.account.subscrBundle.size
+        const tx = new anchor.web3.Transaction()
+        tx.add(
+            SystemProgram.createAccount({
+                fromPubkey: wallet.publicKey,
+                newAccountPubkey: bundleData.publicKey,
+                space: bundleDataBytes,
+                lamports: await provider.connection.getMinimumBalanceForRentExemption(bundleDataBytes),
+                programId: tokenAgent.programId,
+            })
+        )
+        tx.add(tokenAgent.instruction.createBundle(
+            true,                                           // inp_link_token
+            paymentId(),                                    // inp_bundle_id
+            2,                                              // inp_period (monthly)
+            new anchor.BN(t.periodBudget),                  // inp_period_budget
+            nextRebill(),                                   // inp_next_rebill
+            t.rebillMax,                                    // inp_rebill_max
+            new anchor.BN(t.maxDelay),                      // inp_max_delay
+            t.amounts.map(amount => new anchor.BN(amount)), // inp_amounts
+            {
+                accounts: {
+                    bundleData: bundleData.publicKey,
+                    netAuth: agent.netAuth,
+                    rootKey: agent.rootKey[0],
+                    managerApproval: agent.managerAP,
+                    userKey: wallet.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: agent.delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: await agent.tokenLinkAddress(allowance),
+                    systemProgram: SystemProgram.programId,
+                },
+                remainingAccounts: t.amounts.map(() => ({ pubkey: agent.merchantAP, isWritable: false, isSigner: false })),
+            }
+        ))
+        await provider.sendAndConfirm(tx, [bundleData])
+        return bundleData.publicKey
+    }
+
+    async function cancelBundle(bundleData) {
+        const act = await tokenAgent.account.subscrBundle.fetch(bundleData)
+        const allowance = await agent.allowanceAddress(act.tokenAccount)
+        return await tokenAgent.rpc.cancelBundle({
+            accounts: {
+                bundleData: bundleData,
+                rootKey: agent.rootKey[0],
+                userKey: wallet.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: act.tokenAccount,
+                delegateProgram: DELEGATE_PROGRAM,
+                delegateRoot: agent.delegateRoot[0],
+                allowance: allowance,
+                tokenLink: await agent.tokenLinkAddress(allowance),
+                linkPayer: await agent.linkPayer(allowance, wallet.publicKey),
+                systemProgram: SystemProgram.programId,
+            },
+        })
+    }
+
+    async function closeBundle(bundleData) {
+        return await tokenAgent.rpc.closeBundle({
+            accounts: {
+                bundleData: bundleData,
+                userKey: wallet.publicKey,
+            },
+        })
+    }
+
+    async function processBundle(bundleData) {
+        const act = await tokenAgent.account.subscrBundle.fetch(bundleData)
+        const allowance = await agent.allowanceAddress(act.tokenAccount)
+        const remainingAccounts = []
+        for (const item of act.items.slice(0, act.itemCount)) {
+            remainingAccounts.push({ pubkey: item.merchantApproval, isWritable: true, isSigner: false })
+            remainingAccounts.push({ pubkey: agent.merchantTK.pubkey, isWritable: true, isSigner: false })
+            remainingAccounts.push({ pubkey: agent.feesTK.pubkey, isWritable: true, isSigner: false })
+            remainingAccounts.push({ pubkey: await agent.feePolicyAddress(item.merchantApproval, agent.tokenMint), isWritable: false, isSigner: false })
+        }
+        return await tokenAgent.rpc.processBundle(
+            agent.rootKey[1],                               // inp_root_nonce
+            act.nextRebill,                                 // inp_rebill_ts
+            '',                                             // inp_rebill_str
+            act.nextRebill,                                 // inp_next_rebill
+            paymentId(),                                    // inp_payment_id
+            act.items.slice(0, act.itemCount).map(() => agent.merchantTK.nonce), // inp_dest_nonces
+            {
+                accounts: {
+                    bundleData: bundleData,
+                    netAuth: agent.netAuth,
+                    rootKey: agent.rootKey[0],
+                    managerKey: agent.managerSK.publicKey,
+                    managerApproval: agent.managerAP,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: act.tokenAccount,
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: agent.delegateRoot[0],
+                    allowance: allowance,
+                    spendingPolicy: await agent.spendingPolicyAddress(wallet.publicKey, agent.tokenMint),
+                    tokenLink: await agent.tokenLinkAddress(allowance),
+                },
+                remainingAccounts: remainingAccounts,
+                signers: [agent.managerSK],
+            }
+        )
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Reserves the bundle budget on the token link', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const bundleData = await createBundle(tokenAccount)
+        const bundle = await tokenAgent.account.subscrBundle.fetch(bundleData)
+        assert.ok(bundle.active)
+        assert.ok(bundle.userKey.equals(wallet.publicKey))
+        assert.ok(bundle.tokenAccount.equals(tokenAccount))
+        assert.equal(bundle.itemCount, 2)
+        assert.ok(bundle.items[0].merchantApproval.equals(agent.merchantAP))
+        assert.equal(bundle.items[0].amount.toString(), '3000')
+        assert.equal(bundle.items[1].amount.toString(), '2000')
+        assert.equal(bundle.allowanceAmount.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+    })
+
+    it('Releases the reservation of a cancelled bundle', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const bundle1 = await createBundle(tokenAccount)
+        const bundle2 = await createBundle(tokenAccount, { rebillMax: 0 })
+        const link1 = await agent.tokenLink(tokenAccount)
+        assert.equal(link1.subscriptions, 2)
+        assert.equal(link1.approved.toString(), (PERIOD_BUDGET * (REBILL_MAX + MAX_ALLOWANCE_REBILLS)).toString())
+
+        await cancelBundle(bundle1)
+        const bundle = await tokenAgent.account.subscrBundle.fetch(bundle1)
+        assert.ok(!bundle.active)
+        assert.equal(bundle.allowanceAmount.toString(), '0')
+        const link2 = await agent.tokenLink(tokenAccount)
+        assert.equal(link2.subscriptions, 1)
+        assert.equal(link2.approved.toString(), (PERIOD_BUDGET * MAX_ALLOWANCE_REBILLS).toString())
+
+        // Cancelling the last bundle revokes the allowance and closes the token link
+        await cancelBundle(bundle2)
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+
+    it('Closes a cancelled bundle', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const bundleData = await createBundle(tokenAccount)
+        await assert.rejects(closeBundle(bundleData), /SubscriptionActive/)
+        await cancelBundle(bundleData)
+        await assert.rejects(processBundle(bundleData), /InactiveSubscription/)
+        await assert.rejects(cancelBundle(bundleData), /InactiveSubscription/)
+        await closeBundle(bundleData)
+        assert.equal(await tokenAgent.account.subscrBundle.fetchNullable(bundleData), null)
+    })
+
+    it('Rejects bundle items above the period budget', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        await assert.rejects(createBundle(tokenAccount, { amounts: [3000, 2001] }), /PeriodBudgetExceeded/)
+        await assert.rejects(createBundle(tokenAccount, { amounts: [] }), /InvalidBundle/)
+        await assert.rejects(createBundle(tokenAccount, { maxDelay: 43199 }), /InvalidTimeframe/)
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+})
@@ -0,0 +1,79 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Closing subscriptions: implied cancellation, rent refunds and manager cleanup
+
+const MANAGER_CLOSE_DELAY = 7776000
+
+describe('close_subscription', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let sponsor
+
+    async function managerClose(subscrData) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        return await tokenAgent.rpc.managerClose({
+            accounts: {
+                subscrData: subscrData,
+                managerKey: agent.managerSK.publicKey,
+                managerApproval: act.managerApproval,
+                rentPayer: act.rentPayer,
+                tokenLink: await agent.tokenLinkAddress(act.allowance),
+            },
+            signers: [agent.managerSK],
+        })
+    }
+
+    // Subscription with the subscription and token link rent paid by the sponsor
+    async function sponsoredSubscription() {
+        const tokenAccount = await agent.createTokenAccount()
+        return await agent.subscribe({ rentReclaimDelay: MANAGER_CLOSE_DELAY }, { tokenAccount: tokenAccount, rentPayer: sponsor })
+    }
+
+    before(async () => {
+        await agent.load()
+        sponsor = await agent.createUser()
+    })
+
+    it('Cancels and closes an active subscription', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        assert.ok((await tokenAgent.account.subscrData.fetch(subscrData)).active)
+        await agent.closeSubscription(subscrData)
+        assert.equal(await tokenAgent.account.subscrData.fetchNullable(subscrData), null)
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+
+    it('Refunds the rent to the rent payer', async () => {
+        const subscrData = await sponsoredSubscription()
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        const tokenLink = await agent.tokenLinkAddress(act.allowance)
+        assert.ok((await agent.tokenLink(act.tokenAccount)).rentPayer.equals(sponsor.publicKey))
+        const rent = (await provider.connection.getBalance(subscrData)) + (await provider.connection.getBalance(tokenLink))
+        const sponsorPre = await provider.connection.getBalance(sponsor.publicKey)
+        await agent.closeSubscription(subscrData)
+        assert.equal(await provider.connection.getBalance(sponsor.publicKey) - sponsorPre, rent)
+    })
+
+    it('Rejects a rent recipient other than the rent payer', async () => {
+        const subscrData = await sponsoredSubscription()
+        await assert.rejects(agent.closeSubscription(subscrData, { rentPayer: agent.wallet.publicKey }), /InvalidAccount/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.active)
+    })
+
+    it('Rejects manager cleanup of active and recently cancelled subscriptions', async () => {
+        const subscrData = await sponsoredSubscription()
+        await assert.rejects(managerClose(subscrData), /SubscriptionActive/)
+        await agent.updateSubscription(subscrData, { active: false })
+        await assert.rejects(managerClose(subscrData), /InvalidTimeframe/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(!subscr.active)
+        assert.ok(subscr.rentPayer.equals(sponsor.publicKey))
+    })
+})
@@ -0,0 +1,151 @@
+const { SystemProgram } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Merchant coupons redeemed at subscribe time or on an existing subscription
+
+const DISCOUNT_PERCENT = 0
+const DISCOUNT_FIXED = 1
+const DURATION = 3
+
+describe('coupon', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    async function couponAddress(couponId) {
+        return await agent.programAddress([agent.merchantAP.toBuffer(), couponId.toArrayLike(Buffer, 'le', 16), Buffer.from('coupon')])
+    }
+
+    // Creates or updates a coupon with a random id unless terms.couponId is given, returns the coupon address
+    async function updateCoupon(terms) {
+        const t = Object.assign({
+            couponId: new anchor.BN(Date.now()).muln(1000).addn(Math.floor(Math.random() * 1000)),
+            active: true,
+            discountType: DISCOUNT_PERCENT,
+            discountValue: 2000,
+            duration: DURATION,
+            maxRedemptions: 0,
+            expires: 0,
+        }, terms)
+        const coupon = await couponAddress(t.couponId)
+        await tokenAgent.rpc.updateCoupon(
+            t.couponId,                                     // inp_coupon_id
+            t.active,                                       // inp_active
+            t.discountType,                                 // inp_discount_type
+            new anchor.BN(t.discountValue),                 // inp_discount_value
+            t.duration,                                     // inp_duration
+            t.maxRedemptions,                               // inp_max_redemptions
+            new anchor.BN(t.expires),                       // inp_expires
+            {
+                accounts: {
+                    coupon: coupon,
+                    merchantApproval: agent.merchantAP,
+                    merchantKey: agent.merchantSK.publicKey,
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [agent.merchantSK],
+            }
+        )
+        return coupon
+    }
+
+    async function subscribe(coupon, initialAmount = 0) {
+        const tokenAccount = await agent.createTokenAccount(wallet.publicKey, 100000)
+        const subscrData = await agent.subscribe({ coupon: true, initialAmount: initialAmount }, {
+            tokenAccount: tokenAccount,
+            remainingAccounts: [{ pubkey: coupon, isWritable: true, isSigner: false }],
+        })
+        return { subscrData, tokenAccount }
+    }
+
+    async function redeemCoupon(subscrData, coupon) {
+        return await tokenAgent.rpc.redeemCoupon({
+            accounts: {
+                subscrData: subscrData,
+                userKey: wallet.publicKey,
+                coupon: coupon,
+            },
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Creates a coupon', async () => {
+        const couponId = new anchor.BN(Date.now())
+        const coupon = await updateCoupon({ couponId: couponId, discountType: DISCOUNT_FIXED, discountValue: 500, maxRedemptions: 10, expires: 2000000000 })
+        const cpn = await tokenAgent.account.coupon.fetch(coupon)
+        assert.ok(cpn.active)
+        assert.ok(cpn.merchantApproval.equals(agent.merchantAP))
+        assert.equal(cpn.couponId.toString(), couponId.toString())
+        assert.equal(cpn.discountType, DISCOUNT_FIXED)
+        assert.equal(cpn.discountValue.toString(), '500')
+        assert.equal(cpn.duration, DURATION)
+        assert.equal(cpn.maxRedemptions, 10)
+        assert.equal(cpn.redemptions, 0)
+        assert.equal(cpn.expires.toString(), '2000000000')
+    })
+
+    it('Discounts the initial payment at subscribe time', async () => {
+        const coupon = await updateCoupon({})
+        const merchantPre = await agent.tokenAmount(agent.merchantTK.pubkey)
+        const feesPre = await agent.tokenAmount(agent.feesTK.pubkey)
+        const { subscrData, tokenAccount } = await subscribe(coupon, 10000)
+        const charged = 100000n - await agent.tokenAmount(tokenAccount)
+        const merchantRecv = await agent.tokenAmount(agent.merchantTK.pubkey) - merchantPre
+        const feesRecv = await agent.tokenAmount(agent.feesTK.pubkey) - feesPre
+        assert.equal(charged, 8000n)
+        assert.equal(merchantRecv + feesRecv, charged)
+
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.coupon.equals(coupon))
+        assert.equal(subscr.couponType, DISCOUNT_PERCENT)
+        assert.equal(subscr.couponValue.toString(), '2000')
+        assert.equal(subscr.couponDuration, DURATION)
+        assert.equal(subscr.couponUses, 1)
+        const cpn = await tokenAgent.account.coupon.fetch(coupon)
+        assert.equal(cpn.redemptions, 1)
+    })
+
+    it('Redeems a coupon on an existing subscription', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        const coupon = await updateCoupon({})
+        await redeemCoupon(subscrData, coupon)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.coupon.equals(coupon))
+        assert.equal(subscr.couponUses, 0)
+        const cpn = await tokenAgent.account.coupon.fetch(coupon)
+        assert.equal(cpn.redemptions, 1)
+
+        // The coupon in use may not be replaced until its duration is reached
+        const other = await updateCoupon({})
+        await assert.rejects(redeemCoupon(subscrData, other), /InvalidCoupon/)
+        assert.equal((await tokenAgent.account.coupon.fetch(other)).redemptions, 0)
+    })
+
+    it('Rejects redemptions beyond the coupon maximum', async () => {
+        const coupon = await updateCoupon({ maxRedemptions: 1 })
+        await subscribe(coupon)
+        await assert.rejects(subscribe(coupon), /InvalidCoupon/)
+        const cpn = await tokenAgent.account.coupon.fetch(coupon)
+        assert.equal(cpn.redemptions, 1)
+    })
+
+    it('Rejects expired and inactive coupons', async () => {
+        const expired = await updateCoupon({ expires: 1 })
+        await assert.rejects(subscribe(expired), /InvalidCoupon/)
+        const inactive = await updateCoupon({ active: false })
+        await assert.rejects(subscribe(inactive), /InvalidCoupon/)
+    })
+
+    it('Rejects a percent discount above 10000 bps', async () => {
+        await assert.rejects(updateCoupon({ discountValue: 10001 }), /InvalidCoupon/)
+    })
+})
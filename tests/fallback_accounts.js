@@ -0,0 +1,98 @@
+const { SystemProgram } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, DELEGATE_PROGRAM } = require('./lib/agent')
+
+// Fallback funding accounts and their reservations on the fallback token links
+
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 12
+
+describe('fallback_accounts', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    let tokenAccount, subscrData, fallback1, fallback2
+
+    // Remaining accounts: the token links of the current linked fallbacks, then (token account, allowance, token link) triples
+    async function updateFallbacks(fallbacks) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        const remainingAccounts = []
+        for (const allowance of act.fallbackAllowances.slice(0, act.fallbackCount)) {
+            remainingAccounts.push({ pubkey: await agent.tokenLinkAddress(allowance), isWritable: true, isSigner: false })
+        }
+        for (const account of fallbacks) {
+            const allowance = await agent.allowanceAddress(account)
+            remainingAccounts.push({ pubkey: account, isWritable: true, isSigner: false })
+            remainingAccounts.push({ pubkey: allowance, isWritable: true, isSigner: false })
+            remainingAccounts.push({ pubkey: await agent.tokenLinkAddress(allowance), isWritable: true, isSigner: false })
+        }
+        return await tokenAgent.rpc.updateFallbackAccounts({
+            accounts: {
+                subscrData: subscrData,
+                rootKey: agent.rootKey[0],
+                userKey: wallet.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                delegateProgram: DELEGATE_PROGRAM,
+                delegateRoot: agent.delegateRoot[0],
+                systemProgram: SystemProgram.programId,
+                fundingAccount: act.tokenAccount,
+            },
+            remainingAccounts: remainingAccounts,
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+        tokenAccount = await agent.createTokenAccount()
+        subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        fallback1 = await agent.createTokenAccount()
+        fallback2 = await agent.createTokenAccount()
+    })
+
+    it('Reserves the subscription allowance on each fallback account', async () => {
+        await updateFallbacks([fallback1, fallback2])
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.fallbackCount, 2)
+        assert.ok(subscr.fallbackAccounts[0].equals(fallback1))
+        assert.ok(subscr.fallbackAccounts[1].equals(fallback2))
+        assert.ok(subscr.fallbackAllowances[0].equals(await agent.allowanceAddress(fallback1)))
+        for (const account of [fallback1, fallback2]) {
+            const link = await agent.tokenLink(account)
+            assert.equal(link.subscriptions, 1)
+            assert.equal(link.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+            assert.ok(link.tokenAccount.equals(account))
+        }
+        assert.equal(subscr.fallbackAmounts[0].toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+    })
+
+    it('Releases the reservations of replaced fallback accounts', async () => {
+        await updateFallbacks([fallback2])
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link1 = await agent.tokenLink(fallback1)
+        const link2 = await agent.tokenLink(fallback2)
+        assert.equal(subscr.fallbackCount, 1)
+        assert.ok(subscr.fallbackAccounts[0].equals(fallback2))
+        assert.equal(link1.subscriptions, 0)
+        assert.equal(link1.approved.toString(), '0')
+        assert.equal(link2.subscriptions, 1)
+        assert.equal(link2.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+    })
+
+    it('Rejects a fallback account of another mint', async () => {
+        const otherMint = await Token.createMint(provider.connection, wallet, wallet.publicKey, null, 4, TOKEN_PROGRAM_ID)
+        const otherAccount = await otherMint.createAccount(wallet.publicKey)
+        await assert.rejects(updateFallbacks([otherAccount]), /InvalidAccount/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.fallbackCount, 1)
+    })
+
+    it('Rejects the primary token account as a fallback', async () => {
+        await assert.rejects(updateFallbacks([tokenAccount]), /InvalidAccount/)
+    })
+})
@@ -0,0 +1,154 @@
+const { PublicKey, SystemProgram } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, associatedTokenAddress, importSecretKey } = require('./lib/agent')
+
+// Network fee policies: flat fees, minimum and maximum fees and the fee payer
+
+const FEES_BPS = 500
+const FEE_PAYER_MERCHANT = 0
+const FEE_PAYER_USER = 1
+
+describe('fee_policy', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    let mint, feesSK, feePolicy, merchantMint, merchantToken, feesToken, userToken
+
+    function feeSchedule(schedule) {
+        const s = Object.assign({ feesBps: 0, flatFee: 0, minFee: 0, maxFee: 0, feePayer: FEE_PAYER_MERCHANT }, schedule)
+        return {
+            feesBps: s.feesBps,
+            flatFee: new anchor.BN(s.flatFee),
+            minFee: new anchor.BN(s.minFee),
+            maxFee: new anchor.BN(s.maxFee),
+            feePayer: s.feePayer,
+        }
+    }
+
+    async function setFeePolicy(active, paymentFees, feeAuthority = feesSK) {
+        return await tokenAgent.rpc.setFeePolicy(
+            active,                                         // inp_active
+            feeSchedule({ feesBps: 5000 }),                 // inp_subscr_fees
+            feeSchedule(paymentFees),                       // inp_payment_fees
+            {
+                accounts: {
+                    feePolicy: feePolicy,
+                    merchantApproval: agent.merchantAP,
+                    feeAuthority: feeAuthority.publicKey,
+                    tokenMint: mint.publicKey,
+                    approvalFeesAccount: new PublicKey(agent.netData.feesAccount1),
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: agent.signers([feeAuthority]),
+            }
+        )
+    }
+
+    // Balance changes of the user, merchant and fees accounts for a payment in the test mint
+    async function payment(amount) {
+        const accounts = [userToken, merchantToken.pubkey, feesToken]
+        const pre = []
+        for (const account of accounts) {
+            pre.push(await agent.tokenAmount(account, mint))
+        }
+        await agent.merchantPayment(amount, {
+            tokenMint: mint.publicKey,
+            tokenAccount: userToken,
+            merchantToken: merchantToken,
+            feesAccount: feesToken,
+            remainingAccounts: [{ pubkey: merchantMint, isWritable: false, isSigner: false }],
+        })
+        const post = []
+        for (const account of accounts) {
+            post.push(await agent.tokenAmount(account, mint))
+        }
+        return { charged: pre[0] - post[0], merchant: post[1] - pre[1], fees: post[2] - pre[2] }
+    }
+
+    before(async () => {
+        await agent.load()
+        feesSK = importSecretKey(agent.netData.fees1_secret)
+        mint = await Token.createMint(provider.connection, wallet, wallet.publicKey, null, 6, TOKEN_PROGRAM_ID)
+        feePolicy = await agent.feePolicyAddress(agent.merchantAP, mint.publicKey)
+        merchantMint = await agent.programAddress([agent.merchantAP.toBuffer(), mint.publicKey.toBuffer()])
+        merchantToken = await associatedTokenAddress(agent.merchantSK.publicKey, mint.publicKey)
+        feesToken = (await associatedTokenAddress(feesSK.publicKey, mint.publicKey)).pubkey
+        await mint.createAssociatedTokenAccount(agent.merchantSK.publicKey)
+        await mint.createAssociatedTokenAccount(feesSK.publicKey)
+        userToken = await mint.createAccount(wallet.publicKey)
+        await mint.mintTo(userToken, wallet, [], 10000000)
+        await tokenAgent.rpc.registerMerchantMint(
+            true,                                           // inp_active
+            agent.merchantSK.publicKey,                     // inp_dest_account
+            FEES_BPS,                                       // inp_fees_bps
+            {
+                accounts: {
+                    merchantMint: merchantMint,
+                    merchantApproval: agent.merchantAP,
+                    merchantKey: agent.merchantSK.publicKey,
+                    tokenMint: mint.publicKey,
+                    approvalFeesAccount: new PublicKey(agent.netData.feesAccount1),
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [agent.merchantSK],
+            }
+        )
+    })
+
+    it('Charges the settlement fees without a policy', async () => {
+        const res = await payment(10000)
+        assert.equal(res.charged, 10000n)
+        assert.equal(res.merchant, 9500n)
+        assert.equal(res.fees, 500n)
+    })
+
+    it('Adds user-paid flat and proportional fees to the payment', async () => {
+        await setFeePolicy(true, { feesBps: 100, flatFee: 50, feePayer: FEE_PAYER_USER })
+        const policy = await tokenAgent.account.feePolicy.fetch(feePolicy)
+        assert.ok(policy.active)
+        assert.ok(policy.tokenMint.equals(mint.publicKey))
+        assert.ok(policy.feeAuthority.equals(feesSK.publicKey))
+        assert.equal(policy.paymentFees.flatFee.toString(), '50')
+        assert.equal(policy.subscrFees.feesBps, 5000)
+
+        // One-off payments use the payment fees, not the subscription fees
+        const res = await payment(10000)
+        assert.equal(res.charged, 10150n)
+        assert.equal(res.merchant, 10000n)
+        assert.equal(res.fees, 150n)
+    })
+
+    it('Applies the minimum and maximum fee', async () => {
+        await setFeePolicy(true, { feesBps: 100, minFee: 300, maxFee: 1000 })
+        const small = await payment(10000)
+        assert.equal(small.fees, 300n)
+        assert.equal(small.merchant, 9700n)
+        const large = await payment(1000000)
+        assert.equal(large.fees, 1000n)
+        assert.equal(large.merchant, 999000n)
+    })
+
+    it('Charges the settlement fees while the policy is inactive', async () => {
+        await setFeePolicy(false, { feesBps: 100 })
+        const res = await payment(10000)
+        assert.equal(res.fees, 500n)
+    })
+
+    it('Rejects invalid fee schedules', async () => {
+        await assert.rejects(setFeePolicy(true, { minFee: 1000, maxFee: 300 }), /InvalidFees/)
+        await assert.rejects(setFeePolicy(true, { feePayer: 2 }), /InvalidFees/)
+        await assert.rejects(setFeePolicy(true, { feesBps: 10001 }), /InvalidFees/)
+    })
+
+    it('Rejects a fee authority other than the fees account owner', async () => {
+        await assert.rejects(setFeePolicy(true, { feesBps: 0 }, wallet), /InvalidAccount/)
+        const policy = await tokenAgent.account.feePolicy.fetch(feePolicy)
+        assert.ok(!policy.active)
+    })
+})
@@ -0,0 +1,198 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require('luxon')
+const { PublicKey, SystemProgram, Keypair } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const base32 = require('base32.js')
+const assert = require('assert')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+
+// Fiat pricing oracle tests against mock price accounts (programs/mock-market)
+// The token agent must be built with the "mock-oracle" feature: anchor build -- --features mock-oracle
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+const ORACLE_MOCK = 0
+const ORACLE_PYTH = 1
+
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    return { 'pubkey': addr[0], 'nonce': addr[1] }
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+describe('fiat_pricing', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const mockMarket = anchor.workspace.MockMarket
+    const wallet = provider.wallet.payer
+
+    let netData, tokenMint, merchantSK, subscrData
+
+    async function createPrice(price, conf, expo, timestamp) {
+        const priceAccount = Keypair.generate()
+        await mockMarket.rpc.initializePrice({
+            accounts: {
+                priceAccount: priceAccount.publicKey,
+                admin: wallet.publicKey,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [priceAccount],
+        })
+        await mockMarket.rpc.setPrice(new anchor.BN(price), new anchor.BN(conf), expo, new anchor.BN(timestamp), {
+            accounts: {
+                priceAccount: priceAccount.publicKey,
+                admin: wallet.publicKey,
+            },
+        })
+        return priceAccount.publicKey
+    }
+
+    async function setFiatPricing(oracleType, priceOracle) {
+        return await tokenAgent.rpc.setFiatPricing(
+            true,                                           // inp_active
+            oracleType,                                     // inp_oracle_type
+            new anchor.BN(2500),                            // inp_fiat_max_amount ($25.00)
+            2,                                              // inp_fiat_decimals (USD cents)
+            new anchor.BN(60),                              // inp_oracle_max_age (seconds)
+            100,                                            // inp_oracle_max_conf_bps (1%)
+            {
+                accounts: {
+                    subscrData: subscrData.publicKey,
+                    userKey: wallet.publicKey,
+                    merchantKey: merchantSK.publicKey,
+                    priceOracle: priceOracle,
+                    tokenMint: tokenMint,
+                },
+                signers: [merchantSK],
+            }
+        )
+    }
+
+    function now() {
+        return Math.floor(Date.now() / 1000)
+    }
+
+    before(async () => {
+        netData = JSON.parse((await fs.readFile('../data/net.json')).toString())
+        const netAuth = new PublicKey(netData.netAuthorityProgram)
+        tokenMint = new PublicKey(netData.tokenMintUSDV)
+        merchantSK = importSecretKey(netData.merchant1_secret)
+        const merchantAP = new PublicKey(netData.merchantApproval1)
+        const merchantTK = await associatedTokenAddress(new PublicKey(netData.merchant1), tokenMint)
+        const managerAP = new PublicKey(netData.managerApproval1)
+        const feesTK = await associatedTokenAddress(new PublicKey(netData.fees1), tokenMint)
+        const tokenAccount = (await associatedTokenAddress(wallet.publicKey, tokenMint)).pubkey
+        const rootKey = await PublicKey.findProgramAddress([tokenAgent.programId.toBuffer()], tokenAgent.programId)
+        const delegateProgram = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+        const delegateRoot = await PublicKey.findProgramAddress([delegateProgram.toBuffer()], delegateProgram)
+        const allowance = await PublicKey.findProgramAddress([tokenAccount.toBuffer(), rootKey[0].toBuffer()], delegateProgram)
+
+        // Subscription without an initial payment or token link
+        subscrData = Keypair.generate()
+        const subscrDataBytes = tokenAgent.account.subscrData.size
+        var dt0 = DateTime.now().setZone('utc')
+        dt0 = dt0.minus({ days: dt0.day - 1, hours: dt0.hour, minutes: dt0.minute, seconds: dt0.second }).plus({ months: 1 })
+        const tx = new anchor.web3.Transaction()
+        tx.add(
+            SystemProgram.createAccount({
+                fromPubkey: wallet.publicKey,
+                newAccountPubkey: subscrData.publicKey,
+                space: subscrDataBytes,
+                lamports: await provider.connection.getMinimumBalanceForRentExemption(subscrDataBytes),
+                programId: tokenAgent.programId,
+            })
+        )
+        tx.add(tokenAgent.instruction.subscribe(
+            false,                                          // link_token
+            new anchor.BN(0),                               // initial_amount
+            merchantTK.nonce,                               // inp_merchant_nonce
+            rootKey[1],                                     // inp_root_nonce
+            new anchor.BN(777),                             // inp_subscr_id
+            new anchor.BN(888),                             // inp_payment_id
+            2,                                              // inp_period (2 = monthly)
+            new anchor.BN(10000),                           // inp_budget
+            false,                                          // inp_use_total
+            new anchor.BN(0),                               // inp_total_budget
+            new anchor.BN(Math.floor(dt0.toSeconds())),     // inp_next_rebill
+            0,                                              // inp_rebill_max
+            new anchor.BN(0),                               // inp_not_valid_before
+            new anchor.BN(0),                               // inp_not_valid_after
+            new anchor.BN(0),                               // inp_max_delay
+            false,                                          // inp_swap
+            false,                                          // inp_swap_direction
+            0,                                              // inp_swap_mode
+            0,                                              // inp_swap_root_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            0,                                              // inp_swap_dst_nonce
+            new anchor.BN(0),                               // inp_swap_max_input
+            0,                                              // inp_swap_slippage_bps
+            false,                                          // inp_swap_exact_output
+            PublicKey.default,                              // inp_referrer
+            0,                                              // inp_referral_bps
+            0,                                              // inp_referral_rebills
+            false,                                          // inp_coupon
+            new anchor.BN(0),                               // inp_rent_reclaim_delay
+            new anchor.BN(0),                               // inp_sponsor_amount
+            {
+                accounts: {
+                    subscrData: subscrData.publicKey,
+                    netAuth: netAuth,
+                    rootKey: rootKey[0],
+                    merchantApproval: merchantAP,
+                    merchantToken: merchantTK.pubkey,
+                    managerApproval: managerAP,
+                    userKey: wallet.publicKey,
+                    rentPayer: wallet.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    feesAccount: feesTK.pubkey,
+                    feePolicy: (await PublicKey.findProgramAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')], tokenAgent.programId))[0],
+                    delegateProgram: delegateProgram,
+                    delegateRoot: delegateRoot[0],
+                    allowance: allowance[0],
+                    tokenLink: (await PublicKey.findProgramAddress([allowance[0].toBuffer(), Buffer.from('token-link')], tokenAgent.programId))[0],
+                    systemProgram: SystemProgram.programId,
+                },
+            }
+        ))
+        await provider.sendAndConfirm(tx, [subscrData])
+    })
+
+    it('Accepts a current mock price', async () => {
+        const priceOracle = await createPrice(100000000, 10000, -8, now())
+        await setFiatPricing(ORACLE_MOCK, priceOracle)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData.publicKey)
+        assert.ok(subscr.priceOracle.equals(priceOracle))
+        assert.equal(subscr.oracleType, ORACLE_MOCK)
+    })
+
+    it('Rejects a stale price', async () => {
+        const priceOracle = await createPrice(100000000, 10000, -8, now() - 3600)
+        await assert.rejects(setFiatPricing(ORACLE_MOCK, priceOracle), /StaleOraclePrice/)
+    })
+
+    it('Rejects a price outside the confidence limit', async () => {
+        const priceOracle = await createPrice(100000000, 2000000, -8, now())
+        await assert.rejects(setFiatPricing(ORACLE_MOCK, priceOracle), /OracleConfidence/)
+    })
+
+    it('Rejects a mock price account not owned by the mock program', async () => {
+        await assert.rejects(setFiatPricing(ORACLE_MOCK, subscrData.publicKey), /InvalidOracle/)
+    })
+
+    it('Rejects a Pyth price account not owned by the Pyth program', async () => {
+        const priceOracle = await createPrice(100000000, 10000, -8, now())
+        await assert.rejects(setFiatPricing(ORACLE_PYTH, priceOracle), /InvalidOracle/)
+    })
+})
@@ -0,0 +1,463 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require('luxon')
+const { PublicKey, SystemProgram, Keypair, LAMPORTS_PER_SOL } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const base32 = require('base32.js')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+
+// Shared fixtures and instruction helpers for the token agent tests (mocha does not load this directory as a test file)
+// Uses the network fixtures from data/net.json, the provider wallet must hold settlement tokens (USDV)
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+const DELEGATE_PROGRAM = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+const MAX_ALLOWANCE_REBILLS = 12
+
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    return { 'pubkey': addr[0], 'nonce': addr[1] }
+}
+
+function importSecretKey(keyStr) {
+    var dec = new base32.Decoder({ type: "crockford" })
+    var spec = dec.write(keyStr).finalize()
+    return Keypair.fromSecretKey(new Uint8Array(spec))
+}
+
+// Start of the next month (UTC), a valid next_rebill for monthly subscriptions
+function nextRebill() {
+    var dt0 = DateTime.now().setZone('utc')
+    dt0 = dt0.minus({ days: dt0.day - 1, hours: dt0.hour, minutes: dt0.minute, seconds: dt0.second }).plus({ months: 1 })
+    return new anchor.BN(Math.floor(dt0.toSeconds()))
+}
+
+function paymentId() {
+    return new anchor.BN(Date.now() + Math.floor(Math.random() * 1000000))
+}
+
+class Agent {
+    constructor(provider, tokenAgent) {
+        this.provider = provider
+        this.tokenAgent = tokenAgent
+        this.wallet = provider.wallet.payer
+    }
+
+    async load() {
+        this.netData = JSON.parse((await fs.readFile('../data/net.json')).toString())
+        this.netAuth = new PublicKey(this.netData.netAuthorityProgram)
+        this.tokenMint = new PublicKey(this.netData.tokenMintUSDV)
+        this.mint = new Token(this.provider.connection, this.tokenMint, TOKEN_PROGRAM_ID, this.wallet)
+        this.merchantSK = importSecretKey(this.netData.merchant1_secret)
+        this.merchantAP = new PublicKey(this.netData.merchantApproval1)
+        this.merchantTK = await associatedTokenAddress(new PublicKey(this.netData.merchant1), this.tokenMint)
+        this.managerSK = importSecretKey(this.netData.manager1_secret)
+        this.managerAP = new PublicKey(this.netData.managerApproval1)
+        this.feesTK = await associatedTokenAddress(new PublicKey(this.netData.fees1), this.tokenMint)
+        this.rootKey = await PublicKey.findProgramAddress([this.tokenAgent.programId.toBuffer()], this.tokenAgent.programId)
+        this.delegateRoot = await PublicKey.findProgramAddress([DELEGATE_PROGRAM.toBuffer()], DELEGATE_PROGRAM)
+        this.walletToken = (await associatedTokenAddress(this.wallet.publicKey, this.tokenMint)).pubkey
+        return this
+    }
+
+    async programAddress(seeds) {
+        return (await PublicKey.findProgramAddress(seeds, this.tokenAgent.programId))[0]
+    }
+
+    async allowanceAddress(account) {
+        return (await PublicKey.findProgramAddress([account.toBuffer(), this.rootKey[0].toBuffer()], DELEGATE_PROGRAM))[0]
+    }
+
+    async tokenLinkAddress(allowance) {
+        return await this.programAddress([allowance.toBuffer(), Buffer.from('token-link')])
+    }
+
+    async feePolicyAddress(merchantApproval, tokenMint) {
+        return await this.programAddress([merchantApproval.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')])
+    }
+
+    async spendingPolicyAddress(user, tokenMint) {
+        return await this.programAddress([user.toBuffer(), tokenMint.toBuffer(), Buffer.from('spending')])
+    }
+
+    async revenueSplitAddress(merchantApproval) {
+        return await this.programAddress([merchantApproval.toBuffer(), Buffer.from('split')])
+    }
+
+    async sessionAddress(user, sessionKey) {
+        return await this.programAddress([user.toBuffer(), sessionKey.toBuffer(), Buffer.from('session')])
+    }
+
+    async tokenLink(account) {
+        return await this.tokenAgent.account.tokenLink.fetchNullable(await this.tokenLinkAddress(await this.allowanceAddress(account)))
+    }
+
+    async tokenAmount(account, mint = this.mint) {
+        return BigInt((await mint.getAccountInfo(account)).amount.toString())
+    }
+
+    // New wallet funded with SOL for account rent
+    async createUser(lamports = LAMPORTS_PER_SOL) {
+        const user = Keypair.generate()
+        const tx = new anchor.web3.Transaction()
+        tx.add(SystemProgram.transfer({ fromPubkey: this.wallet.publicKey, toPubkey: user.publicKey, lamports: lamports }))
+        await this.provider.sendAndConfirm(tx)
+        return user
+    }
+
+    // New settlement token account, so the test has its own allowance and token link
+    async createTokenAccount(owner = this.wallet.publicKey, amount = 0) {
+        const account = await this.mint.createAccount(owner)
+        if (amount > 0) {
+            await this.mint.transfer(this.walletToken, account, this.wallet, [], amount)
+        }
+        return account
+    }
+
+    async subscribe(terms = {}, accounts = {}) {
+        const t = Object.assign({
+            linkToken: true,
+            initialAmount: 0,
+            subscrId: 777,
+            period: 2,                                      // monthly
+            periodBudget: 10000,
+            useTotal: false,
+            totalBudget: 0,
+            nextRebill: nextRebill(),
+            rebillMax: MAX_ALLOWANCE_REBILLS,
+            maxDelay: 0,
+            swap: false,
+            swapMode: 0,
+            swapMaxInput: 0,
+            swapSlippageBps: 0,
+            swapExactOutput: false,
+            referrer: PublicKey.default,
+            referralBps: 0,
+            referralRebills: 0,
+            coupon: false,
+            rentReclaimDelay: 0,
+            sponsorAmount: 0,
+        }, terms)
+        const user = accounts.user || this.wallet
+        const rentPayer = accounts.rentPayer || user
+        const tokenAccount = accounts.tokenAccount || this.walletToken
+        const allowance = await this.allowanceAddress(accounts.fundingAccount || tokenAccount)
+        const subscrData = Keypair.generate()
+        const subscrDataBytes = this.tokenAgent.account.subscrData.size
+        const tx = new anchor.web3.Transaction()
+        tx.add(
+            SystemProgram.createAccount({
+                fromPubkey: rentPayer.publicKey,
+                newAccountPubkey: subscrData.publicKey,
+                space: subscrDataBytes,
+                lamports: await this.provider.connection.getMinimumBalanceForRentExemption(subscrDataBytes),
+                programId: this.tokenAgent.programId,
+            })
+        )
+        tx.add(this.tokenAgent.instruction.subscribe(
+            t.linkToken,                                    // link_token
+            new anchor.BN(t.initialAmount),                 // initial_amount
+            this.merchantTK.nonce,                          // inp_merchant_nonce
+            this.rootKey[1],                                // inp_root_nonce
+            new anchor.BN(t.subscrId),                      // inp_subscr_id
+            paymentId(),                                    // inp_payment_id
+            t.period,                                       // inp_period
+            new anchor.BN(t.periodBudget),                  // inp_budget
+            t.useTotal,                                     // inp_use_total
+            new anchor.BN(t.totalBudget),                   // inp_total_budget
+            t.nextRebill,                                   // inp_next_rebill
+            t.rebillMax,                                    // inp_rebill_max
+            new anchor.BN(0),                               // inp_not_valid_before
+            new anchor.BN(0),                               // inp_not_valid_after
+            new anchor.BN(t.maxDelay),                      // inp_max_delay
+            t.swap,                                         // inp_swap
+            false,                                          // inp_swap_direction
+            t.swapMode,                                     // inp_swap_mode
+            0,                                              // inp_swap_root_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            0,                                              // inp_swap_dst_nonce
+            new anchor.BN(t.swapMaxInput),                  // inp_swap_max_input
+            t.swapSlippageBps,                              // inp_swap_slippage_bps
+            t.swapExactOutput,                              // inp_swap_exact_output
+            t.referrer,                                     // inp_referrer
+            t.referralBps,                                  // inp_referral_bps
+            t.referralRebills,                              // inp_referral_rebills
+            t.coupon,                                       // inp_coupon
+            new anchor.BN(t.rentReclaimDelay),              // inp_rent_reclaim_delay
+            new anchor.BN(t.sponsorAmount),                 // inp_sponsor_amount
+            {
+                accounts: {
+                    subscrData: subscrData.publicKey,
+                    netAuth: this.netAuth,
+                    rootKey: this.rootKey[0],
+                    merchantApproval: this.merchantAP,
+                    merchantToken: this.merchantTK.pubkey,
+                    managerApproval: this.managerAP,
+                    userKey: user.publicKey,
+                    rentPayer: rentPayer.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    feesAccount: this.feesTK.pubkey,
+                    feePolicy: await this.feePolicyAddress(this.merchantAP, this.tokenMint),
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: this.delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: await this.tokenLinkAddress(allowance),
+                    systemProgram: SystemProgram.programId,
+                },
+                remainingAccounts: accounts.remainingAccounts || [],
+            }
+        ))
+        await this.provider.sendAndConfirm(tx, this.signers([subscrData, user, rentPayer]))
+        return subscrData.publicKey
+    }
+
+    // Update with the current terms unless overridden, moving to accounts.tokenAccount if given
+    async updateSubscription(subscrData, terms = {}, accounts = {}) {
+        const act = await this.tokenAgent.account.subscrData.fetch(subscrData)
+        const t = Object.assign({
+            active: true,
+            linkToken: false,
+            amount: 0,
+            nextRebill: act.nextRebill,
+            rebillMax: act.rebillMax,
+            period: act.period,
+            periodBudget: act.periodBudget,
+            useTotal: act.useTotal,
+            totalBudget: act.totalBudget.add(act.budgetSpent),
+            maxDelay: act.maxDelay,
+        }, terms)
+        const user = accounts.user || this.wallet
+        const tokenAccount = accounts.tokenAccount || act.tokenAccount
+        const allowance = await this.allowanceAddress(tokenAccount)
+        const prevAllowance = act.allowance.equals(PublicKey.default) ? allowance : act.allowance
+        return await this.tokenAgent.rpc.updateSubscription(
+            this.merchantTK.nonce,                          // inp_merchant_nonce
+            this.rootKey[1],                                // inp_root_nonce
+            t.active,                                       // inp_active
+            t.linkToken,                                    // inp_link_token
+            new anchor.BN(t.amount),                        // inp_amount
+            paymentId(),                                    // inp_payment_id
+            t.nextRebill,                                   // inp_next_rebill
+            t.rebillMax,                                    // inp_rebill_max
+            t.period,                                       // inp_period
+            new anchor.BN(t.periodBudget),                  // inp_period_budget
+            t.useTotal,                                     // inp_use_total
+            new anchor.BN(t.totalBudget),                   // inp_total_budget (lifetime cap)
+            new anchor.BN(t.maxDelay),                      // inp_max_delay
+            act.notValidBefore,                             // inp_not_valid_before
+            act.notValidAfter,                              // inp_not_valid_after
+            false,                                          // inp_swap
+            false,                                          // inp_swap_direction
+            0,                                              // inp_swap_mode
+            0,                                              // inp_swap_data_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            0,                                              // inp_swap_dst_nonce
+            act.swapMaxInput,                               // inp_swap_max_input
+            act.swapSlippageBps,                            // inp_swap_slippage_bps
+            act.swapExactOutput,                            // inp_swap_exact_output
+            {
+                accounts: {
+                    subscrData: subscrData,
+                    netAuth: this.netAuth,
+                    rootKey: this.rootKey[0],
+                    merchantApproval: act.merchantApproval,
+                    merchantToken: this.merchantTK.pubkey,
+                    managerApproval: act.managerApproval,
+                    userKey: user.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    feesAccount: this.feesTK.pubkey,
+                    feePolicy: await this.feePolicyAddress(act.merchantApproval, this.tokenMint),
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: this.delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: await this.tokenLinkAddress(allowance),
+                    prevTokenLink: await this.tokenLinkAddress(prevAllowance),
+                    linkPayer: await this.linkPayer(prevAllowance, user.publicKey),
+                    systemProgram: SystemProgram.programId,
+                    prevAllowance: prevAllowance,
+                    prevTokenAccount: act.swap ? act.swapAccount : act.tokenAccount,
+                },
+                remainingAccounts: accounts.remainingAccounts || [],
+                signers: this.signers([user]),
+            }
+        )
+    }
+
+    async closeSubscription(subscrData, accounts = {}) {
+        const act = await this.tokenAgent.account.subscrData.fetch(subscrData)
+        const user = accounts.user || this.wallet
+        const allowance = act.allowance.equals(PublicKey.default) ? await this.allowanceAddress(act.tokenAccount) : act.allowance
+        return await this.tokenAgent.rpc.closeSubscription({
+            accounts: {
+                subscrData: subscrData,
+                rootKey: this.rootKey[0],
+                userKey: user.publicKey,
+                rentPayer: accounts.rentPayer || (act.rentPayer.equals(PublicKey.default) ? act.userKey : act.rentPayer),
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: act.tokenAccount,
+                delegateProgram: DELEGATE_PROGRAM,
+                delegateRoot: this.delegateRoot[0],
+                allowance: allowance,
+                tokenLink: await this.tokenLinkAddress(allowance),
+                linkPayer: await this.linkPayer(allowance, user.publicKey),
+                systemProgram: SystemProgram.programId,
+            },
+            remainingAccounts: accounts.remainingAccounts || [],
+            signers: this.signers([user]),
+        })
+    }
+
+    // Merchant payment without a swap, in the settlement mint unless accounts.tokenMint is given
+    async merchantPayment(amount, accounts = {}) {
+        const user = accounts.user || this.wallet
+        const tokenMint = accounts.tokenMint || this.tokenMint
+        const merchantToken = accounts.merchantToken || this.merchantTK
+        return await this.tokenAgent.rpc.merchantPayment(
+            merchantToken.nonce,                            // inp_dest_nonce
+            this.rootKey[1],                                // inp_root_nonce
+            paymentId(),                                    // inp_payment_id
+            new anchor.BN(amount),                          // inp_amount
+            false,                                          // inp_swap
+            false,                                          // inp_swap_direction
+            0,                                              // inp_swap_mode
+            0,                                              // inp_swap_data_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            0,                                              // inp_swap_dst_nonce
+            new anchor.BN(0),                               // inp_swap_max_input
+            false,                                          // inp_swap_exact_output
+            {
+                accounts: {
+                    netAuth: this.netAuth,
+                    rootKey: this.rootKey[0],
+                    merchantApproval: this.merchantAP,
+                    merchantToken: merchantToken.pubkey,
+                    userKey: user.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: accounts.tokenAccount || this.walletToken,
+                    feesAccount: accounts.feesAccount || this.feesTK.pubkey,
+                    revenueSplit: await this.revenueSplitAddress(this.merchantAP),
+                    feePolicy: await this.feePolicyAddress(this.merchantAP, tokenMint),
+                },
+                remainingAccounts: accounts.remainingAccounts || [],
+                signers: this.signers([user]),
+            }
+        )
+    }
+
+    // Session for accounts.sessionKey paying the merchant from accounts.tokenAccount, expires in one hour unless accounts.expires is given
+    async openSession(budget, accounts) {
+        const user = accounts.user || this.wallet
+        const allowance = await this.allowanceAddress(accounts.tokenAccount)
+        const expires = accounts.expires || Math.floor(Date.now() / 1000) + 3600
+        return await this.tokenAgent.rpc.openSession(
+            new anchor.BN(budget),                          // inp_budget
+            new anchor.BN(expires),                         // inp_expires
+            accounts.merchants || [this.merchantAP],        // inp_merchants
+            {
+                accounts: {
+                    sessionData: await this.sessionAddress(user.publicKey, accounts.sessionKey.publicKey),
+                    rootKey: this.rootKey[0],
+                    userKey: user.publicKey,
+                    sessionKey: accounts.sessionKey.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: accounts.tokenAccount,
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: this.delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: await this.tokenLinkAddress(allowance),
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: this.signers([user]),
+            }
+        )
+    }
+
+    // Payment to the merchant signed by the session key
+    async sessionPayment(amount, accounts) {
+        const user = accounts.user || this.wallet
+        const sessionData = await this.sessionAddress(user.publicKey, accounts.sessionKey.publicKey)
+        const session = await this.tokenAgent.account.sessionKey.fetch(sessionData)
+        const merchantAP = accounts.merchantApproval || this.merchantAP
+        return await this.tokenAgent.rpc.sessionPayment(
+            this.merchantTK.nonce,                          // inp_dest_nonce
+            this.rootKey[1],                                // inp_root_nonce
+            paymentId(),                                    // inp_payment_id
+            new anchor.BN(amount),                          // inp_amount
+            {
+                accounts: {
+                    sessionData: sessionData,
+                    netAuth: this.netAuth,
+                    rootKey: this.rootKey[0],
+                    merchantApproval: merchantAP,
+                    merchantToken: this.merchantTK.pubkey,
+                    revenueSplit: await this.revenueSplitAddress(merchantAP),
+                    userKey: user.publicKey,
+                    sessionKey: accounts.sessionKey.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: session.tokenAccount,
+                    feesAccount: this.feesTK.pubkey,
+                    feePolicy: await this.feePolicyAddress(merchantAP, session.tokenMint),
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: this.delegateRoot[0],
+                    allowance: session.allowance,
+                    spendingPolicy: accounts.spendingPolicy || await this.spendingPolicyAddress(user.publicKey, session.tokenMint),
+                    tokenLink: await this.tokenLinkAddress(session.allowance),
+                },
+                remainingAccounts: accounts.remainingAccounts || [],
+                signers: [accounts.sessionKey],
+            }
+        )
+    }
+
+    async closeSession(accounts) {
+        const user = accounts.user || this.wallet
+        const sessionData = await this.sessionAddress(user.publicKey, accounts.sessionKey.publicKey)
+        const session = await this.tokenAgent.account.sessionKey.fetch(sessionData)
+        return await this.tokenAgent.rpc.closeSession({
+            accounts: {
+                sessionData: sessionData,
+                rootKey: this.rootKey[0],
+                userKey: user.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: session.tokenAccount,
+                delegateProgram: DELEGATE_PROGRAM,
+                delegateRoot: this.delegateRoot[0],
+                allowance: session.allowance,
+                tokenLink: await this.tokenLinkAddress(session.allowance),
+                linkPayer: await this.linkPayer(session.allowance, user.publicKey),
+                systemProgram: SystemProgram.programId,
+            },
+            signers: this.signers([user]),
+        })
+    }
+
+    // Rent payer recorded on the token link, the user if the link does not exist yet
+    async linkPayer(allowance, user) {
+        const link = await this.tokenAgent.account.tokenLink.fetchNullable(await this.tokenLinkAddress(allowance))
+        return link === null ? user : link.rentPayer
+    }
+
+    // The provider wallet signs every transaction
+    signers(keys) {
+        return keys.filter((key, i) => !key.publicKey.equals(this.wallet.publicKey) && keys.indexOf(key) === i)
+    }
+}
+
+module.exports = {
+    SPL_ASSOCIATED_TOKEN,
+    DELEGATE_PROGRAM,
+    MAX_ALLOWANCE_REBILLS,
+    Agent,
+    associatedTokenAddress,
+    importSecretKey,
+    nextRebill,
+    paymentId,
+}
@@ -0,0 +1,95 @@
+const { PublicKey, SystemProgram } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, associatedTokenAddress } = require('./lib/agent')
+
+// Additional settlement mints registered on a merchant approval
+
+const FEES_BPS = 500
+
+describe('merchant_mint', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    let mint, merchantMint, merchantToken, feesToken, userToken
+
+    async function registerMerchantMint(tokenMint, active, feesBps = FEES_BPS) {
+        return await tokenAgent.rpc.registerMerchantMint(
+            active,                                         // inp_active
+            agent.merchantSK.publicKey,                     // inp_dest_account
+            feesBps,                                        // inp_fees_bps
+            {
+                accounts: {
+                    merchantMint: await agent.programAddress([agent.merchantAP.toBuffer(), tokenMint.toBuffer()]),
+                    merchantApproval: agent.merchantAP,
+                    merchantKey: agent.merchantSK.publicKey,
+                    tokenMint: tokenMint,
+                    approvalFeesAccount: new PublicKey(agent.netData.feesAccount1),
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [agent.merchantSK],
+            }
+        )
+    }
+
+    function payment(amount) {
+        return agent.merchantPayment(amount, {
+            tokenMint: mint.publicKey,
+            tokenAccount: userToken,
+            merchantToken: merchantToken,
+            feesAccount: feesToken,
+            remainingAccounts: [{ pubkey: merchantMint, isWritable: false, isSigner: false }],
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+        mint = await Token.createMint(provider.connection, wallet, wallet.publicKey, null, 6, TOKEN_PROGRAM_ID)
+        merchantMint = await agent.programAddress([agent.merchantAP.toBuffer(), mint.publicKey.toBuffer()])
+        merchantToken = await associatedTokenAddress(agent.merchantSK.publicKey, mint.publicKey)
+        feesToken = (await associatedTokenAddress(new PublicKey(agent.netData.fees1), mint.publicKey)).pubkey
+        await mint.createAssociatedTokenAccount(agent.merchantSK.publicKey)
+        await mint.createAssociatedTokenAccount(new PublicKey(agent.netData.fees1))
+        userToken = await mint.createAccount(wallet.publicKey)
+        await mint.mintTo(userToken, wallet, [], 1000000)
+    })
+
+    it('Registers a settlement mint for the merchant', async () => {
+        await registerMerchantMint(mint.publicKey, true)
+        const mm = await tokenAgent.account.merchantMint.fetch(merchantMint)
+        assert.ok(mm.active)
+        assert.ok(mm.merchantApproval.equals(agent.merchantAP))
+        assert.ok(mm.tokenMint.equals(mint.publicKey))
+        assert.ok(mm.destAccount.equals(agent.merchantSK.publicKey))
+        assert.ok(mm.feesAccount.equals(feesToken))
+        assert.equal(mm.feesBps, FEES_BPS)
+    })
+
+    it('Settles a payment in the registered mint', async () => {
+        const merchantPre = await agent.tokenAmount(merchantToken.pubkey, mint)
+        const feesPre = await agent.tokenAmount(feesToken, mint)
+        await payment(10000)
+        assert.equal(await agent.tokenAmount(merchantToken.pubkey, mint) - merchantPre, 9500n)
+        assert.equal(await agent.tokenAmount(feesToken, mint) - feesPre, 500n)
+    })
+
+    it('Rejects payments in an inactive mint', async () => {
+        await registerMerchantMint(mint.publicKey, false)
+        const mm = await tokenAgent.account.merchantMint.fetch(merchantMint)
+        assert.ok(!mm.active)
+        await assert.rejects(payment(10000), /NotApproved/)
+    })
+
+    it('Rejects registering the approval mint', async () => {
+        await assert.rejects(registerMerchantMint(agent.tokenMint, true), /InvalidAccount/)
+    })
+
+    it('Rejects fees above 10000 bps', async () => {
+        await assert.rejects(registerMerchantMint(mint.publicKey, true, 10001), /InvalidFees/)
+    })
+})
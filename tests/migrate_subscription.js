@@ -0,0 +1,53 @@
+const { SystemProgram } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Subscription data versioning and the migrate_subscription instruction
+
+const SUBSCR_DATA_VERSION = 1
+
+describe('migrate_subscription', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let tokenAccount, subscrData
+
+    async function migrate(account) {
+        return await tokenAgent.rpc.migrateSubscription({
+            accounts: {
+                subscrData: account,
+                payer: provider.wallet.publicKey,
+                systemProgram: SystemProgram.programId,
+            },
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+        tokenAccount = await agent.createTokenAccount()
+        subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+    })
+
+    it('Creates subscriptions with the current data version', async () => {
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const info = await provider.connection.getAccountInfo(subscrData)
+        assert.equal(subscr.version, SUBSCR_DATA_VERSION)
+        assert.equal(info.data.length, tokenAgent.account.subscrData.size)
+    })
+
+    it('Rejects migrating a current subscription', async () => {
+        const pre = await provider.connection.getAccountInfo(subscrData)
+        await assert.rejects(migrate(subscrData), /AlreadyMigrated/)
+        const post = await provider.connection.getAccountInfo(subscrData)
+        assert.ok(post.data.equals(pre.data))
+    })
+
+    it('Rejects migrating an account that is not a subscription', async () => {
+        const tokenLink = await agent.tokenLinkAddress(await agent.allowanceAddress(tokenAccount))
+        await assert.rejects(migrate(tokenLink), /InvalidAccount/)
+    })
+})
@@ -0,0 +1,56 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Lifetime charge and fee totals recorded on the subscription
+
+describe('payment_history', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let tokenAccount, subscrData
+
+    before(async () => {
+        await agent.load()
+        tokenAccount = await agent.createTokenAccount(provider.wallet.publicKey, 100000)
+    })
+
+    it('Records the initial payment', async () => {
+        const userPre = await agent.tokenAmount(tokenAccount)
+        const feesPre = await agent.tokenAmount(agent.feesTK.pubkey)
+        subscrData = await agent.subscribe({ initialAmount: 1000 }, { tokenAccount: tokenAccount })
+        const charged = userPre - await agent.tokenAmount(tokenAccount)
+        const fees = await agent.tokenAmount(agent.feesTK.pubkey) - feesPre
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.totalCharged.toString(), charged.toString())
+        assert.equal(subscr.totalFees.toString(), fees.toString())
+        assert.equal(subscr.lastPayment.toString(), charged.toString())
+        assert.ok(subscr.lastPaymentTs.toNumber() > 0)
+    })
+
+    it('Adds update payments to the totals', async () => {
+        const prev = await tokenAgent.account.subscrData.fetch(subscrData)
+        const userPre = await agent.tokenAmount(tokenAccount)
+        const feesPre = await agent.tokenAmount(agent.feesTK.pubkey)
+        await agent.updateSubscription(subscrData, { amount: 500 })
+        const charged = userPre - await agent.tokenAmount(tokenAccount)
+        const fees = await agent.tokenAmount(agent.feesTK.pubkey) - feesPre
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.totalCharged.sub(prev.totalCharged).toString(), charged.toString())
+        assert.equal(subscr.totalFees.sub(prev.totalFees).toString(), fees.toString())
+        assert.equal(subscr.lastPayment.toString(), charged.toString())
+        assert.ok(subscr.lastPaymentTs.gte(prev.lastPaymentTs))
+    })
+
+    it('Leaves the totals unchanged by an update without a payment', async () => {
+        const prev = await tokenAgent.account.subscrData.fetch(subscrData)
+        await agent.updateSubscription(subscrData, {})
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.totalCharged.toString(), prev.totalCharged.toString())
+        assert.equal(subscr.lastPayment.toString(), prev.lastPayment.toString())
+        assert.equal(subscr.lastPaymentTs.toString(), prev.lastPaymentTs.toString())
+    })
+})
@@ -0,0 +1,100 @@
+const { Keypair, SystemProgram } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Referral commission terms attached to subscriptions within the merchant's affiliate limits
+
+const MAX_BPS = 1000
+const MAX_REBILLS = 3
+
+describe('referral', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let referrer, affiliate
+
+    async function registerAffiliate(active, maxBps = MAX_BPS) {
+        return await tokenAgent.rpc.registerAffiliate(
+            active,                                         // inp_active
+            maxBps,                                         // inp_max_bps
+            MAX_REBILLS,                                    // inp_max_rebills
+            {
+                accounts: {
+                    affiliate: affiliate,
+                    merchantApproval: agent.merchantAP,
+                    merchantKey: agent.merchantSK.publicKey,
+                    referrerKey: referrer,
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [agent.merchantSK],
+            }
+        )
+    }
+
+    async function subscribe(referralBps, referralRebills) {
+        const tokenAccount = await agent.createTokenAccount()
+        return await agent.subscribe({
+            referrer: referrer,
+            referralBps: referralBps,
+            referralRebills: referralRebills,
+        }, {
+            tokenAccount: tokenAccount,
+            remainingAccounts: [{ pubkey: affiliate, isWritable: false, isSigner: false }],
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+        referrer = Keypair.generate().publicKey
+        affiliate = await agent.programAddress([agent.merchantAP.toBuffer(), referrer.toBuffer(), Buffer.from('affiliate')])
+    })
+
+    it('Registers the referrer as an affiliate', async () => {
+        await registerAffiliate(true)
+        const afl = await tokenAgent.account.affiliate.fetch(affiliate)
+        assert.ok(afl.active)
+        assert.ok(afl.merchantApproval.equals(agent.merchantAP))
+        assert.ok(afl.referrer.equals(referrer))
+        assert.equal(afl.maxBps, MAX_BPS)
+        assert.equal(afl.maxRebills, MAX_REBILLS)
+    })
+
+    it('Stores the referral terms on the subscription', async () => {
+        const subscrData = await subscribe(500, 2)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.referrer.equals(referrer))
+        assert.equal(subscr.referralBps, 500)
+        assert.equal(subscr.referralRebills, 2)
+        assert.equal(subscr.referralCount, 0)
+        assert.equal(subscr.totalReferral.toString(), '0')
+    })
+
+    it('Rejects a commission above the affiliate maximum', async () => {
+        await assert.rejects(subscribe(MAX_BPS + 1, 2), /InvalidReferral/)
+    })
+
+    it('Rejects unlimited rebills for a limited affiliate', async () => {
+        await assert.rejects(subscribe(500, 0), /InvalidReferral/)
+        await assert.rejects(subscribe(500, MAX_REBILLS + 1), /InvalidReferral/)
+    })
+
+    it('Rejects a referrer without the affiliate account', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        await assert.rejects(agent.subscribe({ referrer: referrer, referralBps: 500, referralRebills: 2 }, { tokenAccount: tokenAccount }), /InvalidAccount/)
+    })
+
+    it('Rejects referrals for an inactive affiliate', async () => {
+        await registerAffiliate(false)
+        const afl = await tokenAgent.account.affiliate.fetch(affiliate)
+        assert.ok(!afl.active)
+        await assert.rejects(subscribe(500, 2), /NotApproved/)
+    })
+
+    it('Rejects an affiliate maximum above 10000 bps', async () => {
+        await assert.rejects(registerAffiliate(true, 10001), /InvalidReferral/)
+    })
+})
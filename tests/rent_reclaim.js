@@ -0,0 +1,74 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Rent payers recorded on subscriptions and rent reclaimed by sponsors
+
+const RENT_RECLAIM_DELAY = 7776000
+
+describe('rent_reclaim', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let sponsor
+
+    async function reclaimRent(subscrData, rentPayer = sponsor) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        return await tokenAgent.rpc.reclaimRent({
+            accounts: {
+                subscrData: subscrData,
+                rentPayer: rentPayer.publicKey,
+                tokenLink: await agent.tokenLinkAddress(act.allowance),
+            },
+            signers: agent.signers([rentPayer]),
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+        sponsor = await agent.createUser()
+    })
+
+    it('Records the sponsor as the rent payer', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const sponsorPre = await provider.connection.getBalance(sponsor.publicKey)
+        const subscrData = await agent.subscribe({ rentReclaimDelay: RENT_RECLAIM_DELAY }, { tokenAccount: tokenAccount, rentPayer: sponsor })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.rentPayer.equals(sponsor.publicKey))
+        assert.equal(subscr.rentReclaimDelay.toString(), RENT_RECLAIM_DELAY.toString())
+        // The sponsor also funds the new token link
+        const link = await agent.tokenLink(tokenAccount)
+        assert.ok(link.rentPayer.equals(sponsor.publicKey))
+        const rent = (await provider.connection.getBalance(subscrData)) + (await provider.connection.getBalance(await agent.tokenLinkAddress(subscr.allowance)))
+        assert.equal(sponsorPre - await provider.connection.getBalance(sponsor.publicKey), rent)
+    })
+
+    it('Records the user as the rent payer of unsponsored subscriptions', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.rentPayer.equals(agent.wallet.publicKey))
+        await assert.rejects(reclaimRent(subscrData, agent.wallet), /AccessDenied/)
+    })
+
+    it('Rejects a sponsor reclaim delay below the manager close delay', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        await assert.rejects(agent.subscribe({ rentReclaimDelay: RENT_RECLAIM_DELAY - 1 }, { tokenAccount: tokenAccount, rentPayer: sponsor }), /InvalidTimeframe/)
+    })
+
+    it('Rejects reclaiming rent before the reclaim delay', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ rentReclaimDelay: RENT_RECLAIM_DELAY }, { tokenAccount: tokenAccount, rentPayer: sponsor })
+        await assert.rejects(reclaimRent(subscrData), /SubscriptionActive/)
+        await agent.updateSubscription(subscrData, { active: false })
+        await assert.rejects(reclaimRent(subscrData), /InvalidTimeframe/)
+        const other = await agent.createUser()
+        await assert.rejects(reclaimRent(subscrData, other), /InvalidAccount/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(!subscr.active)
+        assert.ok(subscr.cancelledAt.toNumber() > 0)
+    })
+})
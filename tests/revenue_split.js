@@ -0,0 +1,102 @@
+const { Keypair, SystemProgram } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, associatedTokenAddress } = require('./lib/agent')
+
+// Revenue split of the merchant's net amount between the recipients of a merchant approval
+
+const SHARES_BPS = [2000, 1000]
+
+describe('revenue_split', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let revenueSplit, recipients, recipientTokens
+
+    async function updateRevenueSplit(recipients, shares) {
+        return await tokenAgent.rpc.updateRevenueSplit(
+            recipients,                                     // inp_recipients
+            shares,                                         // inp_shares_bps
+            {
+                accounts: {
+                    revenueSplit: revenueSplit,
+                    merchantApproval: agent.merchantAP,
+                    merchantKey: agent.merchantSK.publicKey,
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [agent.merchantSK],
+            }
+        )
+    }
+
+    function splitAccounts(tokens) {
+        return tokens.map(pubkey => ({ pubkey: pubkey, isWritable: true, isSigner: false }))
+    }
+
+    before(async () => {
+        await agent.load()
+        revenueSplit = await agent.revenueSplitAddress(agent.merchantAP)
+        recipients = [Keypair.generate().publicKey, Keypair.generate().publicKey]
+        recipientTokens = []
+        for (const recipient of recipients) {
+            await agent.mint.createAssociatedTokenAccount(recipient)
+            recipientTokens.push((await associatedTokenAddress(recipient, agent.tokenMint)).pubkey)
+        }
+    })
+
+    after(async () => {
+        // The split applies to every payment of the merchant approval
+        await updateRevenueSplit([], [])
+    })
+
+    it('Stores the recipients and shares', async () => {
+        await updateRevenueSplit(recipients, SHARES_BPS)
+        const split = await tokenAgent.account.revenueSplit.fetch(revenueSplit)
+        assert.ok(split.merchantApproval.equals(agent.merchantAP))
+        assert.equal(split.recipientCount, 2)
+        assert.ok(split.recipients[0].equals(recipients[0]))
+        assert.ok(split.recipients[1].equals(recipients[1]))
+        assert.deepEqual(split.sharesBps.slice(0, 2), SHARES_BPS)
+    })
+
+    it('Pays each recipient its share of the net amount', async () => {
+        const merchantPre = await agent.tokenAmount(agent.merchantTK.pubkey)
+        const recipientPre = []
+        for (const token of recipientTokens) {
+            recipientPre.push(await agent.tokenAmount(token))
+        }
+        await agent.merchantPayment(100000, { remainingAccounts: splitAccounts(recipientTokens) })
+        const merchantRecv = await agent.tokenAmount(agent.merchantTK.pubkey) - merchantPre
+        const recipientRecv = []
+        for (let i = 0; i < recipientTokens.length; i++) {
+            recipientRecv.push(await agent.tokenAmount(recipientTokens[i]) - recipientPre[i])
+        }
+        const netAmount = merchantRecv + recipientRecv[0] + recipientRecv[1]
+        assert.ok(recipientRecv[0] > 0n)
+        for (let i = 0; i < SHARES_BPS.length; i++) {
+            assert.equal(recipientRecv[i], netAmount * BigInt(SHARES_BPS[i]) / 10000n)
+        }
+    })
+
+    it('Rejects payments without the recipient token accounts', async () => {
+        await assert.rejects(agent.merchantPayment(100000, { remainingAccounts: splitAccounts(recipientTokens.slice(0, 1)) }), /InvalidAccount/)
+    })
+
+    it('Rejects shares above 10000 bps', async () => {
+        await assert.rejects(updateRevenueSplit(recipients, [6000, 5000]), /InvalidRevenueSplit/)
+        const split = await tokenAgent.account.revenueSplit.fetch(revenueSplit)
+        assert.deepEqual(split.sharesBps.slice(0, 2), SHARES_BPS)
+    })
+
+    it('Disables the split with no recipients', async () => {
+        await updateRevenueSplit([], [])
+        const split = await tokenAgent.account.revenueSplit.fetch(revenueSplit)
+        assert.equal(split.recipientCount, 0)
+        const merchantPre = await agent.tokenAmount(agent.merchantTK.pubkey)
+        await agent.merchantPayment(100000)
+        assert.ok(await agent.tokenAmount(agent.merchantTK.pubkey) - merchantPre > 0n)
+    })
+})
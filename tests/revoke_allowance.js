@@ -0,0 +1,85 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Delegate allowances revoked or reduced when the subscriptions using them end
+
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 12
+
+describe('revoke_allowance', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    async function managerCancel(subscrData) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        return await tokenAgent.rpc.managerCancel({
+            accounts: {
+                subscrData: subscrData,
+                managerKey: agent.managerSK.publicKey,
+                managerApproval: act.managerApproval,
+                tokenLink: await agent.tokenLinkAddress(act.allowance),
+            },
+            signers: [agent.managerSK],
+        })
+    }
+
+    async function accountExists(pubkey) {
+        return (await provider.connection.getAccountInfo(pubkey)) !== null
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Revokes the allowance when the last subscription is closed', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        assert.ok(await accountExists(allowance))
+        await agent.closeSubscription(subscrData)
+        assert.ok(!await accountExists(subscrData))
+        assert.ok(!await accountExists(allowance))
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+
+    it('Revokes the allowance when the last subscription is deactivated', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        await agent.updateSubscription(subscrData, { active: false })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(!subscr.active)
+        assert.ok(subscr.allowance.equals(anchor.web3.PublicKey.default))
+        assert.ok(!await accountExists(allowance))
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+
+    it('Keeps the allowance for the remaining subscriptions', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        const subscr1 = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: 6 }, { tokenAccount: tokenAccount })
+        await agent.closeSubscription(subscr1)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.ok(await accountExists(allowance))
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * 6).toString())
+    })
+
+    it('Releases the reservation when the manager cancels', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        await managerCancel(subscrData)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.ok(!subscr.active)
+        assert.ok(subscr.cancelledAt.toNumber() > 0)
+        assert.equal(subscr.allowanceAmount.toString(), '0')
+        assert.equal(link.subscriptions, 0)
+        assert.equal(link.approved.toString(), '0')
+    })
+})
@@ -0,0 +1,86 @@
+const { Keypair } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Session keys making merchant payments within a budget reserved on the user's token link
+
+const BUDGET = 20000
+
+describe('session', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    let sessionKey, sessionData, tokenAccount
+
+    before(async () => {
+        await agent.load()
+        sessionKey = Keypair.generate()
+        sessionData = await agent.sessionAddress(wallet.publicKey, sessionKey.publicKey)
+        tokenAccount = await agent.createTokenAccount(wallet.publicKey, 100000)
+    })
+
+    it('Reserves the session budget on the token link', async () => {
+        await agent.openSession(BUDGET, { sessionKey: sessionKey, tokenAccount: tokenAccount })
+        const session = await tokenAgent.account.sessionKey.fetch(sessionData)
+        assert.ok(session.userKey.equals(wallet.publicKey))
+        assert.ok(session.sessionKey.equals(sessionKey.publicKey))
+        assert.ok(session.tokenAccount.equals(tokenAccount))
+        assert.ok(session.allowance.equals(await agent.allowanceAddress(tokenAccount)))
+        assert.equal(session.budget.toString(), BUDGET.toString())
+        assert.equal(session.spent.toString(), '0')
+        assert.equal(session.merchantCount, 1)
+        assert.ok(session.merchants[0].equals(agent.merchantAP))
+
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), BUDGET.toString())
+    })
+
+    it('Pays the merchant with the session key', async () => {
+        const userPre = await agent.tokenAmount(tokenAccount)
+        const merchantPre = await agent.tokenAmount(agent.merchantTK.pubkey)
+        const feesPre = await agent.tokenAmount(agent.feesTK.pubkey)
+        await agent.sessionPayment(10000, { sessionKey: sessionKey })
+        const charged = userPre - await agent.tokenAmount(tokenAccount)
+        const merchantRecv = await agent.tokenAmount(agent.merchantTK.pubkey) - merchantPre
+        const feesRecv = await agent.tokenAmount(agent.feesTK.pubkey) - feesPre
+        assert.equal(merchantRecv + feesRecv, charged)
+
+        const session = await tokenAgent.account.sessionKey.fetch(sessionData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(session.spent.toString(), charged.toString())
+        assert.equal(link.approved.toString(), (BigInt(BUDGET) - charged).toString())
+    })
+
+    it('Rejects payments above the session budget', async () => {
+        const prev = await tokenAgent.account.sessionKey.fetch(sessionData)
+        await assert.rejects(agent.sessionPayment(BUDGET, { sessionKey: sessionKey }), /TotalBudgetExceeded/)
+        const session = await tokenAgent.account.sessionKey.fetch(sessionData)
+        assert.equal(session.spent.toString(), prev.spent.toString())
+    })
+
+    it('Rejects payments to merchants outside the session', async () => {
+        await assert.rejects(agent.sessionPayment(1000, { sessionKey: sessionKey, merchantApproval: agent.managerAP }), /AccessDenied/)
+    })
+
+    it('Rejects invalid session terms', async () => {
+        const otherAccount = await agent.createTokenAccount()
+        const expired = Math.floor(Date.now() / 1000) - 60
+        await assert.rejects(agent.openSession(BUDGET, { sessionKey: Keypair.generate(), tokenAccount: otherAccount, expires: expired }), /InvalidTimeframe/)
+        await assert.rejects(agent.openSession(0, { sessionKey: Keypair.generate(), tokenAccount: otherAccount }), /InvalidSession/)
+        await assert.rejects(agent.openSession(BUDGET, { sessionKey: Keypair.generate(), tokenAccount: otherAccount, merchants: [] }), /InvalidSession/)
+        assert.equal(await agent.tokenLink(otherAccount), null)
+    })
+
+    it('Closes the session and revokes the allowance', async () => {
+        await agent.closeSession({ sessionKey: sessionKey })
+        assert.equal(await tokenAgent.account.sessionKey.fetchNullable(sessionData), null)
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+        assert.equal(await provider.connection.getAccountInfo(await agent.allowanceAddress(tokenAccount)), null)
+    })
+})
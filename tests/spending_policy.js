@@ -0,0 +1,97 @@
+const { Keypair, SystemProgram } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Per-user daily and monthly limits on delegated outflow
+
+const DAILY_LIMIT = 15000
+
+describe('spending_policy', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let user, sessionKey, tokenAccount, spendingPolicy
+
+    async function setSpendingPolicy(active, dailyLimit, monthlyLimit) {
+        return await tokenAgent.rpc.setSpendingPolicy(
+            active,                                         // inp_active
+            new anchor.BN(dailyLimit),                      // inp_daily_limit
+            new anchor.BN(monthlyLimit),                    // inp_monthly_limit
+            {
+                accounts: {
+                    spendingPolicy: spendingPolicy,
+                    userKey: user.publicKey,
+                    tokenMint: agent.tokenMint,
+                    systemProgram: SystemProgram.programId,
+                },
+                signers: [user],
+            }
+        )
+    }
+
+    // Session payments are transferred with the user's allowance, so they count toward the limits
+    async function payment(amount, accounts = {}) {
+        return await agent.sessionPayment(amount, Object.assign({ user: user, sessionKey: sessionKey }, accounts))
+    }
+
+    before(async () => {
+        await agent.load()
+        user = await agent.createUser()
+        sessionKey = Keypair.generate()
+        tokenAccount = await agent.createTokenAccount(user.publicKey, 100000)
+        spendingPolicy = await agent.spendingPolicyAddress(user.publicKey, agent.tokenMint)
+        await agent.openSession(50000, { user: user, sessionKey: sessionKey, tokenAccount: tokenAccount })
+    })
+
+    it('Stores the spending limits', async () => {
+        await setSpendingPolicy(true, DAILY_LIMIT, 0)
+        const policy = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        assert.ok(policy.active)
+        assert.ok(policy.userKey.equals(user.publicKey))
+        assert.ok(policy.tokenMint.equals(agent.tokenMint))
+        assert.equal(policy.dailyLimit.toString(), DAILY_LIMIT.toString())
+        assert.equal(policy.monthlyLimit.toString(), '0')
+        assert.equal(policy.daySpent.toString(), '0')
+    })
+
+    it('Records delegated payments against the limits', async () => {
+        const userPre = await agent.tokenAmount(tokenAccount)
+        await payment(10000)
+        const charged = userPre - await agent.tokenAmount(tokenAccount)
+        const policy = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        assert.equal(policy.daySpent.toString(), charged.toString())
+        assert.equal(policy.monthSpent.toString(), charged.toString())
+        assert.equal(policy.day.toNumber(), Math.floor(Date.now() / 1000 / 86400))
+    })
+
+    it('Rejects payments above the daily limit', async () => {
+        const prev = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        const userPre = await agent.tokenAmount(tokenAccount)
+        await assert.rejects(payment(6000), /SpendingLimitExceeded/)
+        const policy = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        assert.equal(policy.daySpent.toString(), prev.daySpent.toString())
+        assert.equal(await agent.tokenAmount(tokenAccount), userPre)
+    })
+
+    it('Applies the monthly limit', async () => {
+        await setSpendingPolicy(true, 0, DAILY_LIMIT)
+        await assert.rejects(payment(6000), /SpendingLimitExceeded/)
+    })
+
+    it('Skips the limits while the policy is inactive', async () => {
+        await setSpendingPolicy(false, DAILY_LIMIT, 0)
+        const prev = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        await payment(6000)
+        const policy = await tokenAgent.account.spendingPolicy.fetch(spendingPolicy)
+        assert.equal(policy.daySpent.toString(), prev.daySpent.toString())
+    })
+
+    it('Rejects the spending policy of another user', async () => {
+        const otherPolicy = await agent.spendingPolicyAddress(agent.wallet.publicKey, agent.tokenMint)
+        await assert.rejects(payment(1000, { spendingPolicy: otherPolicy }), /InvalidAccount/)
+    })
+})
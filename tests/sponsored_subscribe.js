@@ -0,0 +1,80 @@
+const { Keypair } = require('@solana/web3.js')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, associatedTokenAddress } = require('./lib/agent')
+
+// Subscriptions created by a sponsor for users without SOL, reimbursed from the initial payment
+
+const RENT_RECLAIM_DELAY = 7776000
+
+describe('sponsored_subscribe', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let sponsor, sponsorToken
+
+    function sponsorAccounts(user, tokenAccount, remainingAccounts = []) {
+        return { user: user, rentPayer: sponsor, tokenAccount: tokenAccount, remainingAccounts: remainingAccounts }
+    }
+
+    before(async () => {
+        await agent.load()
+        sponsor = await agent.createUser()
+        await agent.mint.createAssociatedTokenAccount(sponsor.publicKey)
+        sponsorToken = (await associatedTokenAddress(sponsor.publicKey, agent.tokenMint)).pubkey
+    })
+
+    it('Creates a subscription for a user without SOL', async () => {
+        // The user's allowance account is rent the user pays, so the token account is linked later
+        const user = Keypair.generate()
+        const tokenAccount = await agent.createTokenAccount(user.publicKey, 100000)
+        const subscrData = await agent.subscribe({ linkToken: false, rentReclaimDelay: RENT_RECLAIM_DELAY }, sponsorAccounts(user, tokenAccount))
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.userKey.equals(user.publicKey))
+        assert.ok(subscr.rentPayer.equals(sponsor.publicKey))
+        assert.ok(subscr.active)
+        assert.equal(await provider.connection.getBalance(user.publicKey), 0)
+    })
+
+    it('Reimburses the sponsor from the token account', async () => {
+        const user = Keypair.generate()
+        const tokenAccount = await agent.createTokenAccount(user.publicKey, 100000)
+        const sponsorPre = await agent.tokenAmount(sponsorToken)
+        const merchantPre = await agent.tokenAmount(agent.merchantTK.pubkey)
+        const feesPre = await agent.tokenAmount(agent.feesTK.pubkey)
+        const subscrData = await agent.subscribe(
+            { linkToken: false, initialAmount: 1000, sponsorAmount: 500, rentReclaimDelay: RENT_RECLAIM_DELAY },
+            sponsorAccounts(user, tokenAccount, [{ pubkey: sponsorToken, isWritable: true, isSigner: false }]),
+        )
+        const charged = 100000n - await agent.tokenAmount(tokenAccount)
+        const merchantRecv = await agent.tokenAmount(agent.merchantTK.pubkey) - merchantPre
+        const feesRecv = await agent.tokenAmount(agent.feesTK.pubkey) - feesPre
+        assert.equal(await agent.tokenAmount(sponsorToken) - sponsorPre, 500n)
+        assert.equal(charged, merchantRecv + feesRecv + 500n)
+
+        // The reimbursement is not part of the subscription payment totals
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.totalCharged.toString(), (charged - 500n).toString())
+    })
+
+    it('Rejects a reimbursement without a separate rent payer', async () => {
+        const tokenAccount = await agent.createTokenAccount(agent.wallet.publicKey, 100000)
+        await assert.rejects(agent.subscribe({ initialAmount: 1000, sponsorAmount: 500 }, {
+            tokenAccount: tokenAccount,
+            remainingAccounts: [{ pubkey: agent.walletToken, isWritable: true, isSigner: false }],
+        }), /AccessDenied/)
+    })
+
+    it('Rejects a reimbursement to a token account the sponsor does not own', async () => {
+        const user = Keypair.generate()
+        const tokenAccount = await agent.createTokenAccount(user.publicKey, 100000)
+        await assert.rejects(agent.subscribe(
+            { linkToken: false, initialAmount: 1000, sponsorAmount: 500, rentReclaimDelay: RENT_RECLAIM_DELAY },
+            sponsorAccounts(user, tokenAccount, [{ pubkey: agent.walletToken, isWritable: true, isSigner: false }]),
+        ), /InvalidAccount/)
+        assert.equal(await agent.tokenAmount(tokenAccount), 100000n)
+    })
+})
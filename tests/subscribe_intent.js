@@ -0,0 +1,245 @@
+const { Buffer } = require('buffer')
+const { DateTime } = require('luxon')
+const { Ed25519Program, PublicKey, SystemProgram, Keypair, SYSVAR_INSTRUCTIONS_PUBKEY } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const assert = require('assert')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+
+// Subscriptions authorized by signed off-chain intents
+// Uses the network fixtures from data/net.json, the provider wallet must hold settlement tokens (USDV)
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+const DELEGATE_PROGRAM = new PublicKey('TDLGbdMdskdC2DPz2eSeW3tuxtqRchjt5JMsUrdGTGm')
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 12
+
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    return { 'pubkey': addr[0], 'nonce': addr[1] }
+}
+
+describe('subscribe_intent', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+
+    let netAuth, tokenMint, merchantAP, merchantTK, managerAP, feesTK, rootKey, delegateRoot
+    let tokenAccount, allowance, tokenLink
+
+    async function allowanceAddress(account) {
+        return (await PublicKey.findProgramAddress([account.toBuffer(), rootKey[0].toBuffer()], DELEGATE_PROGRAM))[0]
+    }
+
+    async function tokenLinkAddress(allowanceKey) {
+        return (await PublicKey.findProgramAddress([allowanceKey.toBuffer(), Buffer.from('token-link')], tokenAgent.programId))[0]
+    }
+
+    function nextRebill() {
+        var dt0 = DateTime.now().setZone('utc')
+        dt0 = dt0.minus({ days: dt0.day - 1, hours: dt0.hour, minutes: dt0.minute, seconds: dt0.second }).plus({ months: 1 })
+        return new anchor.BN(Math.floor(dt0.toSeconds()))
+    }
+
+    function createIntent(account, expires = DateTime.now().plus({ hours: 1 })) {
+        return {
+            programId: tokenAgent.programId,
+            userKey: wallet.publicKey,
+            merchantApproval: merchantAP,
+            managerApproval: managerAP,
+            tokenAccount: account,
+            subscrId: new anchor.BN(777),
+            period: 2,                                      // monthly
+            periodBudget: new anchor.BN(PERIOD_BUDGET),
+            rebillMax: REBILL_MAX,
+            useTotal: false,
+            totalBudget: new anchor.BN(0),
+            nextRebill: nextRebill(),
+            notValidBefore: new anchor.BN(0),
+            notValidAfter: new anchor.BN(0),
+            nonce: new anchor.BN(Date.now() + Math.floor(Math.random() * 1000000)),
+            expires: new anchor.BN(Math.floor(expires.toSeconds())),
+        }
+    }
+
+    async function subscribeIntent(intent, accounts, signer = wallet) {
+        const subscrData = Keypair.generate()
+        const subscrDataBytes = tokenAgent.account.subscrData.size
+        const message = Buffer.concat([
+            Buffer.from('atellix-subscription-intent:'),
+            tokenAgent.coder.types.encode('SubscrIntent', intent),
+        ])
+        const nonceBytes = intent.nonce.toArrayLike(Buffer, 'le', 8)
+        const intentReceipt = await PublicKey.findProgramAddress([wallet.publicKey.toBuffer(), nonceBytes, Buffer.from('intent')], tokenAgent.programId)
+        const tx = new anchor.web3.Transaction()
+        tx.add(
+            SystemProgram.createAccount({
+                fromPubkey: wallet.publicKey,
+                newAccountPubkey: subscrData.publicKey,
+                space: subscrDataBytes,
+                lamports: await provider.connection.getMinimumBalanceForRentExemption(subscrDataBytes),
+                programId: tokenAgent.programId,
+            })
+        )
+        tx.add(Ed25519Program.createInstructionWithPrivateKey({ privateKey: signer.secretKey, message: message }))
+        tx.add(tokenAgent.instruction.subscribeIntent(
+            intent,
+            {
+                accounts: {
+                    subscrData: subscrData.publicKey,
+                    netAuth: netAuth,
+                    merchantApproval: merchantAP,
+                    managerApproval: managerAP,
+                    userKey: wallet.publicKey,
+                    tokenAccount: intent.tokenAccount,
+                    allowance: accounts.allowance,
+                    tokenLink: accounts.tokenLink,
+                    intentReceipt: intentReceipt[0],
+                    rentPayer: wallet.publicKey,
+                    instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+                    systemProgram: SystemProgram.programId,
+                },
+            }
+        ))
+        await provider.sendAndConfirm(tx, [subscrData])
+        return subscrData.publicKey
+    }
+
+    before(async () => {
+        const netData = JSON.parse((await fs.readFile('../data/net.json')).toString())
+        netAuth = new PublicKey(netData.netAuthorityProgram)
+        tokenMint = new PublicKey(netData.tokenMintUSDV)
+        merchantAP = new PublicKey(netData.merchantApproval1)
+        merchantTK = await associatedTokenAddress(new PublicKey(netData.merchant1), tokenMint)
+        managerAP = new PublicKey(netData.managerApproval1)
+        feesTK = await associatedTokenAddress(new PublicKey(netData.fees1), tokenMint)
+        rootKey = await PublicKey.findProgramAddress([tokenAgent.programId.toBuffer()], tokenAgent.programId)
+        delegateRoot = await PublicKey.findProgramAddress([DELEGATE_PROGRAM.toBuffer()], DELEGATE_PROGRAM)
+        tokenAccount = (await associatedTokenAddress(wallet.publicKey, tokenMint)).pubkey
+        allowance = await allowanceAddress(tokenAccount)
+        tokenLink = await tokenLinkAddress(allowance)
+
+        // Link the token account with a signed subscription so the token link exists
+        const subscrData = Keypair.generate()
+        const subscrDataBytes = tokenAgent.account.subscrData.size
+        const tx = new anchor.web3.Transaction()
+        tx.add(
+            SystemProgram.createAccount({
+                fromPubkey: wallet.publicKey,
+                newAccountPubkey: subscrData.publicKey,
+                space: subscrDataBytes,
+                lamports: await provider.connection.getMinimumBalanceForRentExemption(subscrDataBytes),
+                programId: tokenAgent.programId,
+            })
+        )
+        tx.add(tokenAgent.instruction.subscribe(
+            true,                                           // link_token
+            new anchor.BN(0),                               // initial_amount
+            merchantTK.nonce,                               // inp_merchant_nonce
+            rootKey[1],                                     // inp_root_nonce
+            new anchor.BN(776),                             // inp_subscr_id
+            new anchor.BN(886),                             // inp_payment_id
+            2,                                              // inp_period (2 = monthly)
+            new anchor.BN(PERIOD_BUDGET),                   // inp_budget
+            false,                                          // inp_use_total
+            new anchor.BN(0),                               // inp_total_budget
+            nextRebill(),                                   // inp_next_rebill
+            REBILL_MAX,                                     // inp_rebill_max
+            new anchor.BN(0),                               // inp_not_valid_before
+            new anchor.BN(0),                               // inp_not_valid_after
+            new anchor.BN(0),                               // inp_max_delay
+            false,                                          // inp_swap
+            false,                                          // inp_swap_direction
+            0,                                              // inp_swap_mode
+            0,                                              // inp_swap_root_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            0,                                              // inp_swap_dst_nonce
+            new anchor.BN(0),                               // inp_swap_max_input
+            0,                                              // inp_swap_slippage_bps
+            false,                                          // inp_swap_exact_output
+            PublicKey.default,                              // inp_referrer
+            0,                                              // inp_referral_bps
+            0,                                              // inp_referral_rebills
+            false,                                          // inp_coupon
+            new anchor.BN(0),                               // inp_rent_reclaim_delay
+            new anchor.BN(0),                               // inp_sponsor_amount
+            {
+                accounts: {
+                    subscrData: subscrData.publicKey,
+                    netAuth: netAuth,
+                    rootKey: rootKey[0],
+                    merchantApproval: merchantAP,
+                    merchantToken: merchantTK.pubkey,
+                    managerApproval: managerAP,
+                    userKey: wallet.publicKey,
+                    rentPayer: wallet.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    feesAccount: feesTK.pubkey,
+                    feePolicy: (await PublicKey.findProgramAddress([merchantAP.toBuffer(), tokenMint.toBuffer(), Buffer.from('fee-policy')], tokenAgent.programId))[0],
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: tokenLink,
+                    systemProgram: SystemProgram.programId,
+                },
+            }
+        ))
+        await provider.sendAndConfirm(tx, [subscrData])
+    })
+
+    it('Reserves the intent allowance on the existing token link', async () => {
+        const linkPre = await tokenAgent.account.tokenLink.fetch(tokenLink)
+        const subscrData = await subscribeIntent(createIntent(tokenAccount), { allowance: allowance, tokenLink: tokenLink })
+        const linkPost = await tokenAgent.account.tokenLink.fetch(tokenLink)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.allowance.equals(allowance))
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.equal(linkPost.subscriptions, linkPre.subscriptions + 1)
+        assert.equal(linkPost.approved.sub(linkPre.approved).toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+    })
+
+    it('Rejects an intent for a token account without a token link', async () => {
+        const mint = new Token(provider.connection, tokenMint, TOKEN_PROGRAM_ID, wallet)
+        const unlinkedAccount = await mint.createAccount(wallet.publicKey)
+        const unlinkedAllowance = await allowanceAddress(unlinkedAccount)
+        await assert.rejects(
+            subscribeIntent(createIntent(unlinkedAccount), { allowance: unlinkedAllowance, tokenLink: await tokenLinkAddress(unlinkedAllowance) }),
+            /InvalidIntent/
+        )
+    })
+
+    it('Rejects an allowance that does not match the token link', async () => {
+        const otherAllowance = await allowanceAddress(Keypair.generate().publicKey)
+        await assert.rejects(
+            subscribeIntent(createIntent(tokenAccount), { allowance: otherAllowance, tokenLink: tokenLink }),
+            /InvalidAccount/
+        )
+    })
+
+    it('Rejects an intent signed by another key', async () => {
+        await assert.rejects(
+            subscribeIntent(createIntent(tokenAccount), { allowance: allowance, tokenLink: tokenLink }, Keypair.generate()),
+            /InvalidIntent/
+        )
+    })
+
+    it('Rejects an expired intent', async () => {
+        await assert.rejects(
+            subscribeIntent(createIntent(tokenAccount, DateTime.now().minus({ minutes: 5 })), { allowance: allowance, tokenLink: tokenLink }),
+            /Expired/
+        )
+    })
+
+    it('Rejects a reused intent nonce', async () => {
+        const intent = createIntent(tokenAccount)
+        await subscribeIntent(intent, { allowance: allowance, tokenLink: tokenLink })
+        await assert.rejects(subscribeIntent(intent, { allowance: allowance, tokenLink: tokenLink }), /already in use/)
+    })
+})
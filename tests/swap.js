@@ -0,0 +1,204 @@
+const { Buffer } = require('buffer')
+const { PublicKey, SystemProgram, Keypair } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const assert = require('assert')
+const fs = require('fs').promises
+
+const anchor = require('@project-serum/anchor')
+
+// Swap backend tests against the mock constant product pool (programs/mock-market)
+// Uses the network fixtures from data/net.json, the provider wallet must hold settlement tokens (USDV)
+// Build the token agent with the mock-market feature so the pool program is accepted as the constant product backend
+
+const SPL_ASSOCIATED_TOKEN = new PublicKey('ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL')
+const SWAP_MODE_CONSTANT_PRODUCT = 1
+const POOL_FEE_BPS = 30
+
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    const addr = await PublicKey.findProgramAddress(
+        [walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()],
+        SPL_ASSOCIATED_TOKEN
+    )
+    return { 'pubkey': addr[0], 'nonce': addr[1] }
+}
+
+// Same rounding as the pool and the token agent quote
+function quoteInput(reserveIn, reserveOut, amountOut, feeBps) {
+    const q3 = (reserveIn * amountOut + (reserveOut - amountOut) - 1n) / (reserveOut - amountOut)
+    const feeDiv = 10000n - BigInt(feeBps)
+    return (q3 * 10000n + feeDiv - 1n) / feeDiv
+}
+
+describe('swap', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const mockMarket = anchor.workspace.MockMarket
+    const wallet = provider.wallet.payer
+
+    let netData, netAuth, settlementMint, inputMint
+    let rootKey, poolState, poolAuthority, vaultIn, vaultOut, userSwapToken, agentSwapToken
+    let merchantAP, merchantTK, feesTK
+
+    async function tokenAmount(mint, account) {
+        return BigInt((await mint.getAccountInfo(account)).amount.toString())
+    }
+
+    function swapAccounts() {
+        return [
+            { pubkey: userSwapToken, isWritable: true, isSigner: false },
+            { pubkey: mockMarket.programId, isWritable: false, isSigner: false },
+            { pubkey: poolState.publicKey, isWritable: true, isSigner: false },
+            { pubkey: poolAuthority[0], isWritable: false, isSigner: false },
+            { pubkey: vaultIn, isWritable: true, isSigner: false },
+            { pubkey: vaultOut, isWritable: true, isSigner: false },
+        ]
+    }
+
+    async function merchantPayment(amount, maxInput, remainingAccounts = swapAccounts()) {
+        return await tokenAgent.rpc.merchantPayment(
+            merchantTK.nonce,                               // inp_dest_nonce
+            rootKey[1],                                     // inp_root_nonce
+            new anchor.BN(1),                               // inp_payment_id
+            new anchor.BN(amount.toString()),               // inp_amount
+            true,                                           // inp_swap
+            false,                                          // inp_swap_direction
+            SWAP_MODE_CONSTANT_PRODUCT,                     // inp_swap_mode
+            0,                                              // inp_swap_data_nonce
+            0,                                              // inp_swap_inb_nonce
+            0,                                              // inp_swap_out_nonce
+            agentSwapToken.nonce,                           // inp_swap_dst_nonce
+            new anchor.BN(maxInput.toString()),             // inp_swap_max_input
+            true,                                           // inp_swap_exact_output
+            {
+                accounts: {
+                    netAuth: netAuth,
+                    rootKey: rootKey[0],
+                    merchantApproval: merchantAP,
+                    merchantToken: merchantTK.pubkey,
+                    userKey: wallet.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: agentSwapToken.pubkey,
+                    feesAccount: feesTK.pubkey,
+                    feePolicy: (await PublicKey.findProgramAddress([merchantAP.toBuffer(), settlementMint.publicKey.toBuffer(), Buffer.from('fee-policy')], tokenAgent.programId))[0],
+                    revenueSplit: (await PublicKey.findProgramAddress([merchantAP.toBuffer(), Buffer.from('split')], tokenAgent.programId))[0],
+                },
+                remainingAccounts: remainingAccounts,
+            }
+        )
+    }
+
+    before(async () => {
+        netData = JSON.parse((await fs.readFile('../data/net.json')).toString())
+        netAuth = new PublicKey(netData.netAuthorityProgram)
+        settlementMint = new Token(provider.connection, new PublicKey(netData.tokenMintUSDV), TOKEN_PROGRAM_ID, wallet)
+        merchantAP = new PublicKey(netData.merchantApproval1)
+        merchantTK = await associatedTokenAddress(new PublicKey(netData.merchant1_dest), settlementMint.publicKey)
+        feesTK = await associatedTokenAddress(new PublicKey(netData.fees1), settlementMint.publicKey)
+        rootKey = await PublicKey.findProgramAddress([tokenAgent.programId.toBuffer()], tokenAgent.programId)
+        agentSwapToken = await associatedTokenAddress(rootKey[0], settlementMint.publicKey)
+
+        // Input mint and pool: 1,000,000 input tokens against 1,000,000 settlement tokens
+        inputMint = await Token.createMint(provider.connection, wallet, wallet.publicKey, null, 4, TOKEN_PROGRAM_ID)
+        poolState = Keypair.generate()
+        poolAuthority = await PublicKey.findProgramAddress([poolState.publicKey.toBuffer()], mockMarket.programId)
+        vaultIn = await inputMint.createAccount(poolAuthority[0])
+        vaultOut = await settlementMint.createAccount(poolAuthority[0])
+        userSwapToken = await inputMint.createAccount(wallet.publicKey)
+        await inputMint.mintTo(vaultIn, wallet, [], 1000000)
+        await inputMint.mintTo(userSwapToken, wallet, [], 1000000)
+        const walletSettlement = await associatedTokenAddress(wallet.publicKey, settlementMint.publicKey)
+        await settlementMint.transfer(walletSettlement.pubkey, vaultOut, wallet, [], 1000000)
+        await mockMarket.rpc.initializePool(poolAuthority[1], POOL_FEE_BPS, {
+            accounts: {
+                poolState: poolState.publicKey,
+                admin: wallet.publicKey,
+                vaultA: vaultIn,
+                vaultB: vaultOut,
+                systemProgram: SystemProgram.programId,
+            },
+            signers: [poolState],
+        })
+    })
+
+    it('Swaps exact output through the mock pool', async () => {
+        const reserveIn = await tokenAmount(inputMint, vaultIn)
+        const reserveOut = await tokenAmount(settlementMint, vaultOut)
+        const quote = quoteInput(reserveIn, reserveOut, 10000n, POOL_FEE_BPS)
+        const userOut = await settlementMint.createAccount(wallet.publicKey)
+        const userPre = await tokenAmount(inputMint, userSwapToken)
+        await mockMarket.rpc.swapExactOutput(new anchor.BN(10000), new anchor.BN(quote.toString()), {
+            accounts: {
+                poolState: poolState.publicKey,
+                poolAuthority: poolAuthority[0],
+                userAuthority: wallet.publicKey,
+                userTokenIn: userSwapToken,
+                userTokenOut: userOut,
+                poolVaultIn: vaultIn,
+                poolVaultOut: vaultOut,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            },
+        })
+        assert.equal(await tokenAmount(settlementMint, userOut), 10000n)
+        assert.equal(userPre - await tokenAmount(inputMint, userSwapToken), quote)
+    })
+
+    it('Pays the merchant the exact amount from a constant product swap', async () => {
+        const amount = 20000n
+        const reserveIn = await tokenAmount(inputMint, vaultIn)
+        const reserveOut = await tokenAmount(settlementMint, vaultOut)
+        const quote = quoteInput(reserveIn, reserveOut, amount, POOL_FEE_BPS)
+        const userPre = await tokenAmount(inputMint, userSwapToken)
+        const merchantPre = await tokenAmount(settlementMint, merchantTK.pubkey)
+        const feesPre = await tokenAmount(settlementMint, feesTK.pubkey)
+        const agentPre = await tokenAmount(settlementMint, agentSwapToken.pubkey)
+        await merchantPayment(amount, quote)
+        const merchantRecv = await tokenAmount(settlementMint, merchantTK.pubkey) - merchantPre
+        const feesRecv = await tokenAmount(settlementMint, feesTK.pubkey) - feesPre
+        assert.equal(merchantRecv + feesRecv, amount)
+        assert.equal(userPre - await tokenAmount(inputMint, userSwapToken), quote)
+        // Only the quoted input is swapped, nothing is left over in the token agent's swap account
+        assert.equal(await tokenAmount(settlementMint, agentSwapToken.pubkey), agentPre)
+        assert.equal(reserveOut - await tokenAmount(settlementMint, vaultOut), merchantRecv + feesRecv)
+    })
+
+    it('Rejects an exact output beyond the pool liquidity', async () => {
+        const reserveOut = await tokenAmount(settlementMint, vaultOut)
+        const userPre = await tokenAmount(inputMint, userSwapToken)
+        await assert.rejects(merchantPayment(reserveOut, 1000000000n), /InsufficientFunds/)
+        assert.equal(await tokenAmount(inputMint, userSwapToken), userPre)
+    })
+
+    it('Rejects a swap when the quote exceeds the maximum input', async () => {
+        const amount = 20000n
+        const reserveIn = await tokenAmount(inputMint, vaultIn)
+        const reserveOut = await tokenAmount(settlementMint, vaultOut)
+        const quote = quoteInput(reserveIn, reserveOut, amount, POOL_FEE_BPS)
+        await assert.rejects(merchantPayment(amount, quote - 1n), /SlippageExceeded|Swap quote/)
+    })
+
+    it('Rejects a swap program other than the pool program', async () => {
+        const accounts = swapAccounts()
+        accounts[1] = { pubkey: tokenAgent.programId, isWritable: false, isSigner: false }
+        const userPre = await tokenAmount(inputMint, userSwapToken)
+        await assert.rejects(merchantPayment(10000n, 1000000n, accounts), /InvalidSwapProgram/)
+        assert.equal(await tokenAmount(inputMint, userSwapToken), userPre)
+    })
+
+    it('Rejects a missing swap account', async () => {
+        await assert.rejects(merchantPayment(10000n, 1000000n, swapAccounts().slice(0, 5)), /InvalidSwapAccounts/)
+    })
+
+    it('Rejects pool state not owned by the swap program', async () => {
+        const accounts = swapAccounts()
+        accounts[2] = { pubkey: vaultIn, isWritable: true, isSigner: false }
+        await assert.rejects(merchantPayment(10000n, 1000000n, accounts), /InvalidSwapAccounts/)
+    })
+
+    it('Rejects pool vaults with mismatched mints', async () => {
+        const accounts = swapAccounts()
+        accounts[4] = { pubkey: vaultOut, isWritable: true, isSigner: false }
+        accounts[5] = { pubkey: vaultIn, isWritable: true, isSigner: false }
+        await assert.rejects(merchantPayment(10000n, 1000000n, accounts), /InvalidSwapMint/)
+    })
+})
@@ -0,0 +1,45 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent } = require('./lib/agent')
+
+// Swap slippage and exact-output terms stored on subscriptions
+
+const SWAP_MODE_ATX = 0
+const SWAP_MODE_CONSTANT_PRODUCT = 1
+
+describe('swap_slippage', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    function swapTerms(terms) {
+        return Object.assign({ linkToken: false, swap: true, swapMode: SWAP_MODE_CONSTANT_PRODUCT, swapMaxInput: 20000 }, terms)
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Stores the swap bounds on the subscription', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe(swapTerms({ swapSlippageBps: 50, swapExactOutput: true }), { tokenAccount: tokenAccount })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.swap)
+        assert.equal(subscr.swapMode, SWAP_MODE_CONSTANT_PRODUCT)
+        assert.equal(subscr.swapSlippageBps, 50)
+        assert.ok(subscr.swapExactOutput)
+        assert.equal(subscr.swapMaxInput.toString(), '20000')
+    })
+
+    it('Rejects slippage above 10000 bps', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        await assert.rejects(agent.subscribe(swapTerms({ swapSlippageBps: 10001 }), { tokenAccount: tokenAccount }), /InvalidSlippage/)
+    })
+
+    it('Rejects slippage bounds for a swap mode without quotes', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        await assert.rejects(agent.subscribe(swapTerms({ swapMode: SWAP_MODE_ATX, swapSlippageBps: 50 }), { tokenAccount: tokenAccount }), /InvalidSwapMode/)
+    })
+})
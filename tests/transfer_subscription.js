@@ -0,0 +1,78 @@
+const { SystemProgram } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, DELEGATE_PROGRAM } = require('./lib/agent')
+
+// Handing a subscription over to a new owner wallet and token account
+
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 12
+
+describe('transfer_subscription', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    async function transfer(subscrData, newUser, tokenAccount) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        return await tokenAgent.rpc.transferSubscription({
+            accounts: {
+                subscrData: subscrData,
+                rootKey: agent.rootKey[0],
+                userKey: wallet.publicKey,
+                newUserKey: newUser.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                tokenAccount: tokenAccount,
+                delegateProgram: DELEGATE_PROGRAM,
+                delegateRoot: agent.delegateRoot[0],
+                allowance: allowance,
+                tokenLink: await agent.tokenLinkAddress(allowance),
+                prevTokenLink: await agent.tokenLinkAddress(act.allowance),
+                systemProgram: SystemProgram.programId,
+                prevAllowance: act.allowance,
+                prevTokenAccount: act.tokenAccount,
+                linkPayer: await agent.linkPayer(act.allowance, wallet.publicKey),
+            },
+            signers: [newUser],
+        })
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Moves the subscription and its reservation to the new owner', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        const newUser = await agent.createUser()
+        const newAccount = await agent.createTokenAccount(newUser.publicKey)
+        await transfer(subscrData, newUser, newAccount)
+
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(newAccount)
+        assert.ok(subscr.userKey.equals(newUser.publicKey))
+        assert.ok(subscr.tokenAccount.equals(newAccount))
+        assert.ok(subscr.allowance.equals(await agent.allowanceAddress(newAccount)))
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.ok(link.rentPayer.equals(newUser.publicKey))
+        // The previous owner's allowance is revoked when no other subscription uses it
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+    })
+
+    it('Rejects a token account not owned by the new owner', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        const newUser = await agent.createUser()
+        const otherAccount = await agent.createTokenAccount()
+        await assert.rejects(transfer(subscrData, newUser, otherAccount), /InvalidAccount/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.userKey.equals(wallet.publicKey))
+    })
+})
@@ -0,0 +1,54 @@
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, MAX_ALLOWANCE_REBILLS } = require('./lib/agent')
+
+// Rebill limit and lifetime budget changes through update_subscription
+
+const PERIOD_BUDGET = 10000
+
+describe('update_subscription', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const agent = new Agent(provider, tokenAgent)
+
+    let tokenAccount, subscrData
+
+    before(async () => {
+        await agent.load()
+        tokenAccount = await agent.createTokenAccount()
+        subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: 12 }, { tokenAccount: tokenAccount })
+    })
+
+    it('Lowers the rebill limit and sets a total budget', async () => {
+        await agent.updateSubscription(subscrData, { rebillMax: 6, useTotal: true, totalBudget: 50000 })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(subscr.rebillMax, 6)
+        assert.ok(subscr.useTotal)
+        assert.equal(subscr.totalBudget.toString(), '50000')
+        assert.equal(subscr.budgetSpent.toString(), '0')
+        // The reservation is the smaller of the remaining rebills and the total budget
+        assert.equal(subscr.allowanceAmount.toString(), '50000')
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), '50000')
+    })
+
+    it('Removes the total budget', async () => {
+        await agent.updateSubscription(subscrData, { useTotal: false, totalBudget: 0 })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(tokenAccount)
+        assert.equal(subscr.rebillMax, 6)
+        assert.ok(!subscr.useTotal)
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * 6).toString())
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * 6).toString())
+    })
+
+    it('Reserves the default rebills without a rebill limit', async () => {
+        await agent.updateSubscription(subscrData, { rebillMax: 0 })
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.equal(subscr.rebillMax, 0)
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * MAX_ALLOWANCE_REBILLS).toString())
+    })
+})
@@ -0,0 +1,91 @@
+const { SystemProgram } = require('@solana/web3.js')
+const { Token, TOKEN_PROGRAM_ID } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+
+const { Agent, DELEGATE_PROGRAM } = require('./lib/agent')
+
+// Switching the funding token account without changing the billing terms
+
+const PERIOD_BUDGET = 10000
+const REBILL_MAX = 12
+
+describe('update_token_account', () => {
+    const provider = anchor.AnchorProvider.env()
+    anchor.setProvider(provider)
+    const tokenAgent = anchor.workspace.TokenAgent
+    const wallet = provider.wallet.payer
+    const agent = new Agent(provider, tokenAgent)
+
+    async function updateTokenAccount(subscrData, tokenAccount) {
+        const act = await tokenAgent.account.subscrData.fetch(subscrData)
+        const allowance = await agent.allowanceAddress(tokenAccount)
+        return await tokenAgent.rpc.updateTokenAccount(
+            false,                                          // inp_swap
+            false,                                          // inp_swap_direction
+            0,                                              // inp_swap_mode
+            0,                                              // inp_swap_dst_nonce
+            {
+                accounts: {
+                    subscrData: subscrData,
+                    rootKey: agent.rootKey[0],
+                    userKey: wallet.publicKey,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                    tokenAccount: tokenAccount,
+                    delegateProgram: DELEGATE_PROGRAM,
+                    delegateRoot: agent.delegateRoot[0],
+                    allowance: allowance,
+                    tokenLink: await agent.tokenLinkAddress(allowance),
+                    prevTokenLink: await agent.tokenLinkAddress(act.allowance),
+                    systemProgram: SystemProgram.programId,
+                    prevAllowance: act.allowance,
+                    prevTokenAccount: act.tokenAccount,
+                    linkPayer: await agent.linkPayer(act.allowance, wallet.publicKey),
+                },
+            }
+        )
+    }
+
+    before(async () => {
+        await agent.load()
+    })
+
+    it('Moves the reservation to the new token account', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({ periodBudget: PERIOD_BUDGET, rebillMax: REBILL_MAX }, { tokenAccount: tokenAccount })
+        const prev = await tokenAgent.account.subscrData.fetch(subscrData)
+        const newAccount = await agent.createTokenAccount()
+        await updateTokenAccount(subscrData, newAccount)
+
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        const link = await agent.tokenLink(newAccount)
+        assert.ok(subscr.tokenAccount.equals(newAccount))
+        assert.ok(subscr.allowance.equals(await agent.allowanceAddress(newAccount)))
+        assert.equal(subscr.allowanceAmount.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.equal(link.subscriptions, 1)
+        assert.equal(link.approved.toString(), (PERIOD_BUDGET * REBILL_MAX).toString())
+        assert.equal(await agent.tokenLink(tokenAccount), null)
+        // Billing terms are unchanged
+        assert.equal(subscr.periodBudget.toString(), prev.periodBudget.toString())
+        assert.equal(subscr.rebillMax, prev.rebillMax)
+        assert.equal(subscr.nextRebill.toString(), prev.nextRebill.toString())
+    })
+
+    it('Rejects a token account of another mint', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        const otherMint = await Token.createMint(provider.connection, wallet, wallet.publicKey, null, 4, TOKEN_PROGRAM_ID)
+        const otherAccount = await otherMint.createAccount(wallet.publicKey)
+        await assert.rejects(updateTokenAccount(subscrData, otherAccount), /InvalidAccount/)
+        const subscr = await tokenAgent.account.subscrData.fetch(subscrData)
+        assert.ok(subscr.tokenAccount.equals(tokenAccount))
+    })
+
+    it('Rejects a token account owned by another wallet', async () => {
+        const tokenAccount = await agent.createTokenAccount()
+        const subscrData = await agent.subscribe({}, { tokenAccount: tokenAccount })
+        const otherUser = await agent.createUser()
+        const otherAccount = await agent.createTokenAccount(otherUser.publicKey)
+        await assert.rejects(updateTokenAccount(subscrData, otherAccount), /InvalidAccount/)
+    })
+})